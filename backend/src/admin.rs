@@ -0,0 +1,79 @@
+//! Account administration that's shared by the interactive shell and the admin subcommands. Errors are meant to be
+//! shown to the admin as is.
+
+use std::error::Error;
+
+use crate::{
+  constants,
+  database::{ClaimCodeData, Database},
+  util::{generate_claim_code, get_unix_timestamp_secs}
+};
+
+pub struct NewClaimCode {
+  pub storage_quota: u64,
+  pub max_uses: u64,
+
+  /// The number of days until the code expires, or 0 if it never expires.
+  pub days_valid: u64,
+
+  pub note: Option<String>
+}
+
+/// Generates and stores a new claim code. `created_by` records where the code was created, e.g. "shell".
+pub fn create_claim_code(database: &mut Database, new_claim_code: NewClaimCode, created_by: &str) -> Result<ClaimCodeData, Box<dyn Error>> {
+  if new_claim_code.max_uses == 0 {
+    return Err("A claim code must have at least 1 use.".into());
+  }
+
+  let created_time = get_unix_timestamp_secs();
+
+  let claim_code_data = ClaimCodeData {
+    claim_code: generate_claim_code(),
+    storage_quota: new_claim_code.storage_quota,
+    expiry_time: (new_claim_code.days_valid > 0).then(|| created_time + new_claim_code.days_valid * 86400),
+    max_uses: new_claim_code.max_uses,
+    use_count: 0,
+    note: new_claim_code.note.filter(|note| !note.trim().is_empty()),
+    created_by: Some(created_by.to_string()),
+    created_time: Some(created_time),
+    revoked_time: None
+  };
+
+  database.insert_new_claim_code(&claim_code_data)?;
+
+  Ok(claim_code_data)
+}
+
+pub fn find_user_id(database: &mut Database, username: &String) -> Result<u64, Box<dyn Error>> {
+  match database.get_user_data(username) {
+    Ok(data) => Ok(data.user_id.unwrap()),
+    Err(rusqlite::Error::QueryReturnedNoRows) => Err("User not found.".into()),
+    Err(err) => Err(err.into())
+  }
+}
+
+/// Changes a user's username after checking the new one follows the same rules as when claiming an account.
+pub fn rename_user(database: &mut Database, username: &String, new_username: &str) -> Result<(), Box<dyn Error>> {
+  let user_id = find_user_id(database, username)?;
+
+  let is_valid_length = (constants::MIN_USERNAME_LENGTH..=constants::MAX_USERNAME_LENGTH).contains(&new_username.len());
+
+  if !is_valid_length || !new_username.chars().all(|c| c.is_ascii_alphanumeric()) {
+    return Err(
+      format!(
+        "Usernames must be {}-{} alphanumeric characters.",
+        constants::MIN_USERNAME_LENGTH,
+        constants::MAX_USERNAME_LENGTH
+      ).into()
+    );
+  }
+
+  // A user can change the case of their own username
+  if !new_username.eq_ignore_ascii_case(username) && database.is_username_taken_case_insensitive(new_username)? {
+    return Err("Username is taken.".into());
+  }
+
+  database.rename_user(user_id, new_username)?;
+
+  Ok(())
+}
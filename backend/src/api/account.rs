@@ -1,31 +1,30 @@
 use axum::{
-  extract::{Query, State, Path}, response::IntoResponse, Json
-};
-
-use argon2::{
-  password_hash::{
-    rand_core::OsRng,
-    PasswordHasher, SaltString
-  },
-  Argon2, Params
+  extract::{ConnectInfo, Query, State, Path}, response::{IntoResponse, Response}, Json
 };
 
+use argon2::Params;
 use base64::{engine::general_purpose, Engine as _};
 use std::sync::Arc;
-use http::StatusCode;
+use std::net::SocketAddr;
+use http::{header::RETRY_AFTER, StatusCode};
 use serde::{Serialize, Deserialize};
 use tower_sessions::Session;
 use tokio::sync::Mutex;
 use std::error::Error;
-use log::error;
+use log::{error, info};
 
 use crate::{
   AppState,
   constants,
+  api::utils::auth_utils::{auth_key_hash_needs_upgrade, get_user_session_data, hash_auth_key, verify_auth_key, LoginAttemptIdentifiers},
   database::{
+    ClaimCodeStatus,
     ClaimUserRequest,
+    Database,
     UserData
   },
+  get_session_data_or_return_unauthorized,
+  util::get_unix_timestamp_secs,
   validate_base64_byte_size,
   validate_string_is_ascii_alphanumeric,
   validate_string_length,
@@ -64,7 +63,12 @@ pub async fn get_claim_code_api(
   let mut app_state = state.lock().await;
   let database = app_state.database.as_mut().unwrap();
 
-  if let Ok(info) = database.get_claim_code_info(&params.code) {
+  // Expired, revoked and used up codes are reported the same as codes that don't exist
+  let claim_code_info = database.get_claim_code_info(&params.code)
+    .ok()
+    .filter(|info| info.get_status(get_unix_timestamp_secs()) == ClaimCodeStatus::Available);
+
+  if let Some(info) = claim_code_info {
     Json(ClaimCodeResponse {
       is_valid: true,
       storage_quota: info.storage_quota
@@ -104,7 +108,14 @@ pub struct ClaimAccountRequest {
   #[serde(rename = "x25519PublicKey")]
   x25519_public_key: String,
   
-  salt: String
+  salt: String,
+
+  // Optional account recovery. Both must be provided together.
+  #[serde(rename = "recoveryAuthKey")]
+  recovery_auth_key: Option<String>,
+
+  #[serde(rename = "encryptedRecoveryMasterKey")]
+  encrypted_recovery_master_key: Option<String>
 }
 
 impl ClaimAccountRequest {
@@ -120,10 +131,39 @@ impl ClaimAccountRequest {
     validate_base64_byte_size!(self, x25519_public_key, constants::CURVE25519_KEY_SIZE);
     validate_base64_byte_size!(self, salt, constants::USER_AUTH_HASH_SALT_SIZE);
 
+    match (&self.recovery_auth_key, &self.encrypted_recovery_master_key) {
+      (Some(recovery_auth_key), Some(encrypted_recovery_master_key)) => {
+        validate_base64_byte_size!(recovery_auth_key, constants::AUTH_KEY_SIZE);
+        validate_base64_byte_size!(encrypted_recovery_master_key, constants::ENCRYPTED_MASTER_KEY_SIZE);
+      },
+      (None, None) => (),
+      _ => return Err("'recovery_auth_key' and 'encrypted_recovery_master_key' must be provided together.".into())
+    };
+
     Ok(())
   }
 }
 
+/// Checks that the claim code can be used and that the username isn't taken. Returns the response to send instead
+/// if the account can't be claimed.
+fn check_can_claim(database: &mut Database, claim_code: &String, username: &str) -> Option<Response> {
+  let is_claim_code_available = database.get_claim_code_info(claim_code)
+    .is_ok_and(|info| info.get_status(get_unix_timestamp_secs()) == ClaimCodeStatus::Available);
+
+  if !is_claim_code_available {
+    return Some((StatusCode::FORBIDDEN, "Claim code is invalid.").into_response());
+  }
+
+  match database.is_username_taken_case_insensitive(username) {
+    Ok(false) => None,
+    Ok(true) => Some((StatusCode::CONFLICT, "Username is taken!").into_response()),
+    Err(err) => {
+      error!("Is username taken check error: {}", err);
+      Some(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+    }
+  }
+}
+
 pub async fn claim_api(
   _session: Session,
   State(state): State<Arc<Mutex<AppState>>>,
@@ -134,40 +174,52 @@ pub async fn claim_api(
     return (StatusCode::BAD_REQUEST, err.to_string()).into_response();
   }
 
-  // Acquire database
-  let mut app_state = state.lock().await;
-  let database = app_state.database.as_mut().unwrap();
+  // Check the claim before spending time on hashing
+  let argon2_params = {
+    let mut app_state = state.lock().await;
+    let argon2_params = app_state.config.argon2_params().unwrap();
 
-  // Ensure the username isn't already taken
-  let is_username_taken = match database.is_username_taken_case_insensitive(&req.username) {
-    Ok(taken) => taken,
-    Err(err) => {
-      error!("Is username taken check error: {}", err);
-      return StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    if let Some(response) = check_can_claim(app_state.database.as_mut().unwrap(), &req.claim_code, &req.username) {
+      return response;
     }
-  };
 
-  if is_username_taken {
-    return (StatusCode::CONFLICT, "Username is taken!").into_response();
-  }
+    argon2_params
+  };
 
-  // Hash the authentication key
+  // Hash the authentication key, and the recovery authentication key if recovery is being set up. This is done
+  // without holding the app state lock and on a blocking thread since it's intentionally slow.
   let auth_key_bytes = general_purpose::STANDARD.decode(&req.auth_key).unwrap();
-  
-  let salt = SaltString::generate(&mut OsRng); // Random salt for the hash
+  let recovery_auth_key_bytes = req.recovery_auth_key.map(|key| general_purpose::STANDARD.decode(key).unwrap());
+
+  let hashes = tokio::task::spawn_blocking(move || {
+    let auth_key_hash = hash_auth_key(&auth_key_bytes, &argon2_params)?;
+    let recovery_key_hash = recovery_auth_key_bytes
+      .map(|bytes| hash_auth_key(&bytes, &argon2_params))
+      .transpose()?;
+
+    Ok::<_, argon2::password_hash::Error>((auth_key_hash, recovery_key_hash))
+  }).await;
+
+  let (auth_key_hash, recovery_key_hash) = match hashes {
+    Ok(Ok(hashes)) => hashes,
+    Ok(Err(err)) => {
+      error!("Failed to hash auth key: {}", err);
+      return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    },
+    Err(err) => {
+      error!("Failed to join hashing task: {}", err);
+      return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+  };
 
-  let argon2 = Argon2::new(
-    argon2::Algorithm::Argon2id,
-    argon2::Version::V0x13,
-    Params::new(
-      constants::ARGON2_MEMORY_SIZE as u32,
-      constants::ARGON2_ITERATIONS as u32,
-      constants::ARGON2_PARALLELISM as u32,
-      None // Use default output length
-    ).unwrap()
-  );
+  // Acquire database
+  let mut app_state = state.lock().await;
+  let database = app_state.database.as_mut().unwrap();
 
-  let auth_key_hash = argon2.hash_password(&auth_key_bytes, &salt).unwrap().to_string();
+  // The code may have been used up or the username taken while hashing
+  if let Some(response) = check_can_claim(database, &req.claim_code, &req.username) {
+    return response;
+  }
 
   // Decode Base64
   let claim_user_data = UserData {
@@ -179,8 +231,12 @@ pub async fn claim_api(
     ed25519_public_key: general_purpose::STANDARD.decode(req.ed25519_public_key).unwrap(),
     encrypted_x25519_private_key: general_purpose::STANDARD.decode(req.encrypted_x25519_private_key).unwrap(),
     x25519_public_key: general_purpose::STANDARD.decode(req.x25519_public_key).unwrap(),
+    recovery_key_hash,
+    encrypted_recovery_master_key: req.encrypted_recovery_master_key.map(|key| general_purpose::STANDARD.decode(key).unwrap()),
     storage_quota: None,
-    user_id: None
+    user_id: None,
+    disabled: false,
+    is_admin: false
   };
 
   let claim_request = ClaimUserRequest {
@@ -190,6 +246,7 @@ pub async fn claim_api(
 
   match database.claim_user(&claim_request) {
     Ok(_) => StatusCode::OK.into_response(),
+    Err(rusqlite::Error::QueryReturnedNoRows) => (StatusCode::FORBIDDEN, "Claim code is invalid.").into_response(),
     Err(err) => {
       error!("database.claim_user error: {}", err);
       StatusCode::INTERNAL_SERVER_ERROR.into_response()
@@ -250,3 +307,304 @@ pub async fn get_salt_api(
     }
   }
 }
+
+// ----------------------------------------------
+// API - Set up recovery
+// ----------------------------------------------
+
+#[derive(Deserialize)]
+pub struct SetRecoveryRequest {
+  #[serde(rename = "recoveryAuthKey")]
+  recovery_auth_key: String, // Base64 encoded
+
+  #[serde(rename = "encryptedRecoveryMasterKey")]
+  encrypted_recovery_master_key: String // Base64 encoded
+}
+
+impl SetRecoveryRequest {
+  pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+    validate_base64_byte_size!(self, recovery_auth_key, constants::AUTH_KEY_SIZE);
+    validate_base64_byte_size!(self, encrypted_recovery_master_key, constants::ENCRYPTED_MASTER_KEY_SIZE);
+
+    Ok(())
+  }
+}
+
+/// Sets up or replaces the recovery key of the logged in user. The client wraps the master key under a random
+/// recovery key and sends that along with an authentication key derived from the recovery key.
+pub async fn set_recovery_api(
+  session: Session,
+  State(state): State<Arc<Mutex<AppState>>>,
+  Json(req): Json<SetRecoveryRequest>
+) -> impl IntoResponse {
+  let session_data = get_session_data_or_return_unauthorized!(session, state);
+
+  // Validate request
+  if let Err(err) = req.validate() {
+    return (StatusCode::BAD_REQUEST, err.to_string()).into_response();
+  }
+
+  // Hash the recovery authentication key without holding the app state lock
+  let argon2_params = state.lock().await.config.argon2_params().unwrap();
+  let recovery_auth_key_bytes = general_purpose::STANDARD.decode(&req.recovery_auth_key).unwrap();
+
+  let recovery_key_hash = match tokio::task::spawn_blocking(move || hash_auth_key(&recovery_auth_key_bytes, &argon2_params)).await {
+    Ok(Ok(hash)) => hash,
+    Ok(Err(err)) => {
+      error!("Failed to hash recovery key: {}", err);
+      return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    },
+    Err(err) => {
+      error!("Failed to join hashing task: {}", err);
+      return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+  };
+  let encrypted_recovery_master_key = general_purpose::STANDARD.decode(&req.encrypted_recovery_master_key).unwrap();
+
+  // Acquire database
+  let mut app_state = state.lock().await;
+  let database = app_state.database.as_mut().unwrap();
+
+  match database.set_user_recovery(session_data.user_id, &recovery_key_hash, &encrypted_recovery_master_key) {
+    Ok(_) => StatusCode::OK.into_response(),
+    Err(err) => {
+      error!("rusqlite error: {}", err);
+      StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    }
+  }
+}
+
+/// A user whose recovery authentication key was verified.
+struct VerifiedRecovery {
+  user_data: UserData,
+
+  /// The recovery key re-hashed with the current Argon2 parameters if the stored hash was created under an older
+  /// policy.
+  upgraded_recovery_key_hash: Option<String>
+}
+
+/// Verifies the recovery authentication key of a user that has recovery set up. The key is verified on a blocking
+/// thread since it's intentionally slow, so this must be called without holding the app state lock.
+async fn verify_recovery_auth_key(user_data: Option<UserData>, recovery_auth_key: &str, argon2_params: Params) -> Option<VerifiedRecovery> {
+  let user_data = user_data.filter(|data| !data.disabled)?;
+  let recovery_key_hash = user_data.recovery_key_hash.clone()?;
+  let recovery_auth_key_bytes = general_purpose::STANDARD.decode(recovery_auth_key).ok()?;
+
+  let upgraded_recovery_key_hash = tokio::task::spawn_blocking(move || {
+    if !verify_auth_key(&recovery_auth_key_bytes, &recovery_key_hash) {
+      return None;
+    }
+
+    if auth_key_hash_needs_upgrade(&recovery_key_hash, &argon2_params) {
+      Some(hash_auth_key(&recovery_auth_key_bytes, &argon2_params).ok())
+    } else {
+      Some(None)
+    }
+  })
+  .await
+  .ok()??;
+
+  Some(VerifiedRecovery { user_data, upgraded_recovery_key_hash })
+}
+
+/// Stores the upgraded recovery key hash of a verified recovery, if there is one.
+fn upgrade_recovery_key_hash(database: &mut Database, verified_recovery: &VerifiedRecovery) {
+  if let Some(recovery_key_hash) = &verified_recovery.upgraded_recovery_key_hash {
+    let user_data = &verified_recovery.user_data;
+
+    match database.update_user_recovery_key_hash(user_data.user_id.unwrap(), recovery_key_hash) {
+      Ok(_) => info!("Upgraded the recovery key hash parameters of user {}.", user_data.username),
+      Err(err) => error!("Failed to upgrade recovery key hash: {}", err)
+    };
+  }
+}
+
+/// Reserves a recovery attempt against the login lockouts, since recovery keys can be guessed the same way as
+/// passwords, and gets the user's data along with the current Argon2 parameters. Returns the response to send
+/// instead if the attempt is rejected.
+async fn reserve_recovery_attempt(
+  state: &Arc<Mutex<AppState>>,
+  identifiers: &LoginAttemptIdentifiers,
+  username: &String
+) -> Result<(Option<UserData>, Params), Response> {
+  let mut app_state = state.lock().await;
+  let argon2_params = app_state.config.argon2_params().unwrap();
+  let database = app_state.database.as_mut().unwrap();
+
+  match identifiers.reserve_attempt(database, get_unix_timestamp_secs()) {
+    Ok(Some(retry_after)) => Err((StatusCode::TOO_MANY_REQUESTS, [(RETRY_AFTER, retry_after.to_string())]).into_response()),
+    Ok(None) => Ok((database.get_user_data(username).ok(), argon2_params)),
+    Err(err) => {
+      error!("rusqlite error: {}", err);
+      Err(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+    }
+  }
+}
+
+// ----------------------------------------------
+// API - Get recovery key
+// ----------------------------------------------
+
+#[derive(Deserialize)]
+pub struct GetRecoveryKeyRequest {
+  username: String,
+
+  #[serde(rename = "recoveryAuthKey")]
+  recovery_auth_key: String // Base64 encoded
+}
+
+impl GetRecoveryKeyRequest {
+  pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+    validate_string_is_ascii_alphanumeric!(self, username);
+    validate_string_length_range!(self, username, constants::MIN_USERNAME_LENGTH, constants::MAX_USERNAME_LENGTH);
+    validate_base64_byte_size!(self, recovery_auth_key, constants::AUTH_KEY_SIZE);
+
+    Ok(())
+  }
+}
+
+#[derive(Serialize)]
+pub struct GetRecoveryKeyResponse {
+  #[serde(rename = "encryptedRecoveryMasterKey")]
+  encrypted_recovery_master_key: String // Base64 encoded
+}
+
+/// Returns the master key wrapped under the recovery key so the client can unwrap it and re-wrap it under a new
+/// password with `recover_password_api`.
+pub async fn get_recovery_key_api(
+  _session: Session,
+  State(state): State<Arc<Mutex<AppState>>>,
+  ConnectInfo(address): ConnectInfo<SocketAddr>,
+  Json(req): Json<GetRecoveryKeyRequest>
+) -> impl IntoResponse {
+  // Validate request
+  if let Err(err) = req.validate() {
+    return (StatusCode::BAD_REQUEST, err.to_string()).into_response();
+  }
+
+  let identifiers = LoginAttemptIdentifiers::new(&address, &req.username);
+
+  let (user_data, argon2_params) = match reserve_recovery_attempt(&state, &identifiers, &req.username).await {
+    Ok(reserved) => reserved,
+    Err(response) => return response
+  };
+
+  let verified_recovery = match verify_recovery_auth_key(user_data, &req.recovery_auth_key, argon2_params).await {
+    Some(verified_recovery) => verified_recovery,
+    None => return StatusCode::UNAUTHORIZED.into_response()
+  };
+
+  // Acquire database
+  let mut app_state = state.lock().await;
+  let database = app_state.database.as_mut().unwrap();
+
+  if let Err(err) = identifiers.clear(database) {
+    error!("rusqlite error: {}", err);
+    return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+  }
+
+  upgrade_recovery_key_hash(database, &verified_recovery);
+
+  // The recovery key hash is only set alongside the encrypted recovery master key
+  let encrypted_recovery_master_key = verified_recovery.user_data.encrypted_recovery_master_key.unwrap_or_default();
+
+  Json(GetRecoveryKeyResponse {
+    encrypted_recovery_master_key: general_purpose::STANDARD.encode(encrypted_recovery_master_key)
+  }).into_response()
+}
+
+// ----------------------------------------------
+// API - Recover password
+// ----------------------------------------------
+
+#[derive(Deserialize)]
+pub struct RecoverPasswordRequest {
+  username: String,
+
+  // Everything below is encoded in Base64
+  #[serde(rename = "recoveryAuthKey")]
+  recovery_auth_key: String,
+
+  #[serde(rename = "authKey")]
+  auth_key: String,
+
+  #[serde(rename = "encryptedMasterKey")]
+  encrypted_master_key: String,
+
+  salt: String
+}
+
+impl RecoverPasswordRequest {
+  pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+    validate_string_is_ascii_alphanumeric!(self, username);
+    validate_string_length_range!(self, username, constants::MIN_USERNAME_LENGTH, constants::MAX_USERNAME_LENGTH);
+    validate_base64_byte_size!(self, recovery_auth_key, constants::AUTH_KEY_SIZE);
+    validate_base64_byte_size!(self, auth_key, constants::AUTH_KEY_SIZE);
+    validate_base64_byte_size!(self, encrypted_master_key, constants::ENCRYPTED_MASTER_KEY_SIZE);
+    validate_base64_byte_size!(self, salt, constants::USER_AUTH_HASH_SALT_SIZE);
+
+    Ok(())
+  }
+}
+
+/// Sets a new password for a user that proves possession of their recovery key. The client re-wraps the same
+/// master key under the new password derived key, so the recovery key remains valid afterwards. Every existing
+/// session of the user is logged out.
+pub async fn recover_password_api(
+  _session: Session,
+  State(state): State<Arc<Mutex<AppState>>>,
+  ConnectInfo(address): ConnectInfo<SocketAddr>,
+  Json(req): Json<RecoverPasswordRequest>
+) -> impl IntoResponse {
+  // Validate request
+  if let Err(err) = req.validate() {
+    return (StatusCode::BAD_REQUEST, err.to_string()).into_response();
+  }
+
+  let identifiers = LoginAttemptIdentifiers::new(&address, &req.username);
+
+  let (user_data, argon2_params) = match reserve_recovery_attempt(&state, &identifiers, &req.username).await {
+    Ok(reserved) => reserved,
+    Err(response) => return response
+  };
+
+  let verified_recovery = match verify_recovery_auth_key(user_data, &req.recovery_auth_key, argon2_params.clone()).await {
+    Some(verified_recovery) => verified_recovery,
+    None => return StatusCode::UNAUTHORIZED.into_response()
+  };
+
+  // Hash the new authentication key without holding the app state lock
+  let auth_key_bytes = general_purpose::STANDARD.decode(&req.auth_key).unwrap();
+
+  let auth_key_hash = match tokio::task::spawn_blocking(move || hash_auth_key(&auth_key_bytes, &argon2_params)).await {
+    Ok(Ok(hash)) => hash,
+    Ok(Err(err)) => {
+      error!("Failed to hash auth key: {}", err);
+      return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    },
+    Err(err) => {
+      error!("Failed to join hashing task: {}", err);
+      return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+  };
+
+  let salt = general_purpose::STANDARD.decode(&req.salt).unwrap();
+  let encrypted_master_key = general_purpose::STANDARD.decode(&req.encrypted_master_key).unwrap();
+
+  // Acquire database
+  let mut app_state = state.lock().await;
+  let database = app_state.database.as_mut().unwrap();
+
+  upgrade_recovery_key_hash(database, &verified_recovery);
+
+  let result = database.update_user_password(verified_recovery.user_data.user_id.unwrap(), &auth_key_hash, &salt, &encrypted_master_key)
+    .and_then(|_| identifiers.clear(database));
+
+  match result {
+    Ok(_) => StatusCode::OK.into_response(),
+    Err(err) => {
+      error!("rusqlite error: {}", err);
+      StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    }
+  }
+}
@@ -0,0 +1,458 @@
+use axum::{
+  extract::{Path, State}, response::IntoResponse, Json
+};
+
+use std::sync::Arc;
+use std::error::Error;
+use http::StatusCode;
+use serde::{Serialize, Deserialize};
+use tower_sessions::Session;
+use tokio::sync::Mutex;
+use log::{error, info};
+
+use crate::{
+  AppState,
+  admin::{create_claim_code, NewClaimCode},
+  api::utils::auth_utils::get_user_session_data,
+  backup::create_backup,
+  constants,
+  database::ClaimCodeData,
+  get_admin_session_data_or_return_forbidden,
+  storage::scrubber::run_scrub,
+  util::get_unix_timestamp_secs,
+  validate_integer_max_value
+};
+
+// ----------------------------------------------
+// API - List users
+// ----------------------------------------------
+
+#[derive(Serialize)]
+pub struct AdminUserResponse {
+  #[serde(rename = "userId")]
+  user_id: u64,
+
+  username: String,
+
+  #[serde(rename = "storageQuota")]
+  storage_quota: u64,
+
+  #[serde(rename = "storageUsed")]
+  storage_used: u64,
+
+  #[serde(rename = "hasRecovery")]
+  has_recovery: bool,
+
+  disabled: bool,
+
+  #[serde(rename = "isAdmin")]
+  is_admin: bool
+}
+
+pub async fn get_users_api(
+  session: Session,
+  State(state): State<Arc<Mutex<AppState>>>
+) -> impl IntoResponse {
+  get_admin_session_data_or_return_forbidden!(session, state);
+
+  // Acquire database
+  let mut app_state = state.lock().await;
+  let database = app_state.database.as_mut().unwrap();
+
+  let all_users = match database.get_all_users() {
+    Ok(users) => users,
+    Err(err) => {
+      error!("rusqlite error: {}", err);
+      return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+  };
+
+  let mut users = Vec::with_capacity(all_users.len());
+
+  for user in all_users {
+    let user_id = user.user_id.unwrap();
+
+    let storage_used = match database.get_user_storage_used(user_id) {
+      Ok(storage_used) => storage_used,
+      Err(err) => {
+        error!("rusqlite error: {}", err);
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+      }
+    };
+
+    users.push(AdminUserResponse {
+      user_id,
+      username: user.username,
+      storage_quota: user.storage_quota.unwrap(),
+      storage_used,
+      has_recovery: user.recovery_key_hash.is_some(),
+      disabled: user.disabled,
+      is_admin: user.is_admin
+    });
+  }
+
+  Json(users).into_response()
+}
+
+// ----------------------------------------------
+// API - Set user storage quota
+// ----------------------------------------------
+
+#[derive(Deserialize)]
+pub struct SetUserQuotaPathParams {
+  username: String
+}
+
+#[derive(Deserialize)]
+pub struct SetUserQuotaRequest {
+  #[serde(rename = "storageQuota")]
+  storage_quota: u64
+}
+
+/// Existing files are kept when the new quota is below the user's storage used, but they can't upload more until
+/// they're under it.
+pub async fn set_user_quota_api(
+  session: Session,
+  State(state): State<Arc<Mutex<AppState>>>,
+  Path(path_params): Path<SetUserQuotaPathParams>,
+  Json(req): Json<SetUserQuotaRequest>
+) -> impl IntoResponse {
+  let session_data = get_admin_session_data_or_return_forbidden!(session, state);
+
+  // Acquire database
+  let mut app_state = state.lock().await;
+  let database = app_state.database.as_mut().unwrap();
+
+  let user_id = match database.get_user_data(&path_params.username) {
+    Ok(user_data) => user_data.user_id.unwrap(),
+    Err(rusqlite::Error::QueryReturnedNoRows) => return (StatusCode::NOT_FOUND, "User not found.").into_response(),
+    Err(err) => {
+      error!("rusqlite error: {}", err);
+      return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+  };
+
+  match database.set_user_storage_quota(user_id, req.storage_quota) {
+    Ok(_) => {
+      info!("{} set the storage quota of {} to {} bytes.", session_data.username, path_params.username, req.storage_quota);
+      StatusCode::OK.into_response()
+    },
+    Err(err) => {
+      error!("rusqlite error: {}", err);
+      StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    }
+  }
+}
+
+// ----------------------------------------------
+// API - List claim codes
+// ----------------------------------------------
+
+#[derive(Serialize)]
+pub struct AdminClaimCodeResponse {
+  #[serde(rename = "claimCode")]
+  claim_code: String,
+
+  #[serde(rename = "storageQuota")]
+  storage_quota: u64,
+
+  status: &'static str,
+
+  #[serde(rename = "expiryTime")]
+  expiry_time: Option<u64>,
+
+  #[serde(rename = "maxUses")]
+  max_uses: u64,
+
+  #[serde(rename = "useCount")]
+  use_count: u64,
+
+  note: Option<String>,
+
+  #[serde(rename = "createdBy")]
+  created_by: Option<String>,
+
+  #[serde(rename = "createdTime")]
+  created_time: Option<u64>,
+
+  #[serde(rename = "revokedTime")]
+  revoked_time: Option<u64>
+}
+
+impl From<ClaimCodeData> for AdminClaimCodeResponse {
+  fn from(data: ClaimCodeData) -> Self {
+    Self {
+      status: data.get_status(get_unix_timestamp_secs()).as_str(),
+      claim_code: data.claim_code,
+      storage_quota: data.storage_quota,
+      expiry_time: data.expiry_time,
+      max_uses: data.max_uses,
+      use_count: data.use_count,
+      note: data.note,
+      created_by: data.created_by,
+      created_time: data.created_time,
+      revoked_time: data.revoked_time
+    }
+  }
+}
+
+pub async fn get_claim_codes_api(
+  session: Session,
+  State(state): State<Arc<Mutex<AppState>>>
+) -> impl IntoResponse {
+  get_admin_session_data_or_return_forbidden!(session, state);
+
+  // Acquire database
+  let mut app_state = state.lock().await;
+  let database = app_state.database.as_mut().unwrap();
+
+  match database.get_all_claim_codes() {
+    Ok(claim_codes) => {
+      let claim_codes: Vec<AdminClaimCodeResponse> = claim_codes.into_iter()
+        .map(AdminClaimCodeResponse::from)
+        .collect();
+
+      Json(claim_codes).into_response()
+    },
+    Err(err) => {
+      error!("rusqlite error: {}", err);
+      StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    }
+  }
+}
+
+// ----------------------------------------------
+// API - Create claim code
+// ----------------------------------------------
+
+#[derive(Deserialize)]
+pub struct CreateClaimCodeRequest {
+  #[serde(rename = "storageQuota")]
+  storage_quota: u64,
+
+  #[serde(rename = "maxUses")]
+  max_uses: u64,
+
+  /// 0 never expires
+  #[serde(rename = "daysValid")]
+  days_valid: u64,
+
+  note: Option<String>
+}
+
+impl CreateClaimCodeRequest {
+  pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+    if self.max_uses == 0 {
+      return Err("Integer 'max_uses' must be at least 1.".into());
+    }
+
+    validate_integer_max_value!(self, days_valid, constants::MAX_CLAIM_CODE_DAYS_VALID);
+
+    if self.note.as_ref().is_some_and(|note| note.len() > constants::MAX_CLAIM_CODE_NOTE_LENGTH) {
+      return Err(format!("String 'note' can't be longer than {}.", constants::MAX_CLAIM_CODE_NOTE_LENGTH).into());
+    }
+
+    Ok(())
+  }
+}
+
+pub async fn create_claim_code_api(
+  session: Session,
+  State(state): State<Arc<Mutex<AppState>>>,
+  Json(req): Json<CreateClaimCodeRequest>
+) -> impl IntoResponse {
+  let session_data = get_admin_session_data_or_return_forbidden!(session, state);
+
+  if let Err(err) = req.validate() {
+    return (StatusCode::BAD_REQUEST, err.to_string()).into_response();
+  }
+
+  // Acquire database
+  let mut app_state = state.lock().await;
+  let database = app_state.database.as_mut().unwrap();
+
+  let new_claim_code = NewClaimCode {
+    storage_quota: req.storage_quota,
+    max_uses: req.max_uses,
+    days_valid: req.days_valid,
+    note: req.note
+  };
+
+  // Recorded as e.g. "api:alice" so codes can be traced back to the admin who created them
+  let created_by = format!("api:{}", session_data.username);
+
+  match create_claim_code(database, new_claim_code, &created_by) {
+    Ok(claim_code_data) => Json(AdminClaimCodeResponse::from(claim_code_data)).into_response(),
+    Err(err) => {
+      error!("Failed to create claim code: {}", err);
+      StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    }
+  }
+}
+
+// ----------------------------------------------
+// API - Revoke claim code
+// ----------------------------------------------
+
+#[derive(Deserialize)]
+pub struct RevokeClaimCodePathParams {
+  code: String
+}
+
+pub async fn revoke_claim_code_api(
+  session: Session,
+  State(state): State<Arc<Mutex<AppState>>>,
+  Path(path_params): Path<RevokeClaimCodePathParams>
+) -> impl IntoResponse {
+  get_admin_session_data_or_return_forbidden!(session, state);
+
+  // Acquire database
+  let mut app_state = state.lock().await;
+  let database = app_state.database.as_mut().unwrap();
+
+  match database.revoke_claim_code(&path_params.code, get_unix_timestamp_secs()) {
+    Ok(0) => (StatusCode::NOT_FOUND, "No unrevoked claim code found.").into_response(),
+    Ok(_) => StatusCode::OK.into_response(),
+    Err(err) => {
+      error!("rusqlite error: {}", err);
+      StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    }
+  }
+}
+
+// ----------------------------------------------
+// API - List active transfers
+// ----------------------------------------------
+
+#[derive(Serialize)]
+pub struct ActiveUploadResponse {
+  handle: String,
+
+  #[serde(rename = "userId")]
+  user_id: u64,
+
+  #[serde(rename = "fileSize")]
+  file_size: u64,
+
+  #[serde(rename = "writtenBytes")]
+  written_bytes: u64
+}
+
+#[derive(Serialize)]
+pub struct ActiveDownloadResponse {
+  handle: String,
+
+  #[serde(rename = "fileSize")]
+  file_size: u64,
+
+  #[serde(rename = "fromMirror")]
+  from_mirror: bool
+}
+
+#[derive(Serialize)]
+pub struct GetTransfersResponse {
+  uploads: Vec<ActiveUploadResponse>,
+  downloads: Vec<ActiveDownloadResponse>
+}
+
+pub async fn get_transfers_api(
+  session: Session,
+  State(state): State<Arc<Mutex<AppState>>>
+) -> impl IntoResponse {
+  get_admin_session_data_or_return_forbidden!(session, state);
+
+  let app_state = state.lock().await;
+
+  let uploads = app_state.uploads_manager.active_uploads_map.iter()
+    .map(|(handle, upload)| ActiveUploadResponse {
+      handle: handle.clone(),
+      user_id: upload.user_id,
+      file_size: upload.file_size,
+      written_bytes: upload.written_bytes
+    })
+    .collect();
+
+  let downloads = app_state.downloads_manager.get_active_downloads().await
+    .into_iter()
+    .map(|(handle, download)| ActiveDownloadResponse {
+      handle,
+      file_size: download.file_size,
+      from_mirror: download.from_mirror
+    })
+    .collect();
+
+  Json(GetTransfersResponse { uploads, downloads }).into_response()
+}
+
+// ----------------------------------------------
+// API - Start maintenance job
+// ----------------------------------------------
+
+#[derive(Deserialize)]
+pub struct StartJobPathParams {
+  job: String
+}
+
+/// Starts a maintenance job in the background and returns straight away. The outcome is logged, and scrub reports
+/// are also saved to the database.
+pub async fn start_job_api(
+  session: Session,
+  State(state): State<Arc<Mutex<AppState>>>,
+  Path(path_params): Path<StartJobPathParams>
+) -> impl IntoResponse {
+  let session_data = get_admin_session_data_or_return_forbidden!(session, state);
+  let shared_app_state = state.clone();
+
+  match path_params.job.as_str() {
+    "scrub" => {
+      let quarantine_orphans = state.lock().await.config.scrub_quarantine_orphans;
+
+      tokio::spawn(async move {
+        if let Err(err) = run_scrub(shared_app_state, quarantine_orphans).await {
+          error!("Scrub failed: {}", err);
+        }
+      });
+    },
+    "backup" => {
+      tokio::spawn(async move {
+        if let Err(err) = create_backup(shared_app_state).await {
+          error!("Database backup failed: {}", err);
+        }
+      });
+    },
+    "resync" => {
+      let replication_manager = match state.lock().await.replication_manager.clone() {
+        Some(manager) => manager,
+        None => return (StatusCode::CONFLICT, "Mirroring is disabled.").into_response()
+      };
+
+      tokio::spawn(async move {
+        match replication_manager.resync().await {
+          Ok((copy_count, delete_count)) => info!("Queued {} copies and {} deletes to re-sync the mirror.", copy_count, delete_count),
+          Err(err) => error!("Failed to re-sync the mirror: {}", err)
+        };
+      });
+    },
+    "encryptstorage" => {
+      let encrypted_stores = state.lock().await.encrypted_stores.clone();
+
+      if encrypted_stores.is_empty() {
+        return (StatusCode::CONFLICT, "Encryption at rest is disabled.").into_response();
+      }
+
+      tokio::spawn(async move {
+        for encrypted_store in encrypted_stores {
+          match encrypted_store.wrap_existing_blobs().await {
+            Ok(count) => info!("Encrypted {} user file(s).", count),
+            Err(err) => error!("Failed to encrypt user files: {}", err)
+          };
+        }
+      });
+    },
+    _ => return (StatusCode::NOT_FOUND, "Unknown job.").into_response()
+  };
+
+  info!("{} started the {} job.", session_data.username, path_params.job);
+
+  StatusCode::ACCEPTED.into_response()
+}
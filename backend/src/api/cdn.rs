@@ -1,5 +1,5 @@
 use axum::{
-  body::Body, extract::Path, response::IntoResponse
+  body::Body, extract::{Path, State}, response::IntoResponse
 };
 
 use tokio::fs::File;
@@ -7,9 +7,12 @@ use tokio_util::io::ReaderStream;
 use http::{header::{CACHE_CONTROL, CONTENT_TYPE}, HeaderMap, StatusCode};
 use tower_sessions::Session;
 use serde::Deserialize;
+use std::sync::Arc;
+use tokio::sync::Mutex;
 use log::error;
 
 use crate::{
+  AppState,
   api::utils::auth_utils::get_user_session_data,
   get_session_data_or_return_unauthorized
 };
@@ -25,23 +28,26 @@ pub struct CDNPathParams {
 
 pub async fn cdn_api(
   session: Session,
+  State(state): State<Arc<Mutex<AppState>>>,
   Path(path_params): Path<CDNPathParams>
 ) -> impl IntoResponse {
   // Ensure only authorised users can use the CDN
-  let _ = get_session_data_or_return_unauthorized!(session);
+  let _ = get_session_data_or_return_unauthorized!(session, state);
 
   // Determine the path of the requested file
-  let file_path: &str = match path_params.name.as_str() {
-    "ffmpegcorewasm" => "../cdn/ffmpeg/ffmpeg-core.wasm", // TODO: cache and compress this on the first load into memory + .env setting for that feature
-    "ffmpegcorejs" => "../cdn/ffmpeg/ffmpeg-core.js",
+  let file_name: &str = match path_params.name.as_str() {
+    "ffmpegcorewasm" => "ffmpeg/ffmpeg-core.wasm", // TODO: cache and compress this on the first load into memory + .env setting for that feature
+    "ffmpegcorejs" => "ffmpeg/ffmpeg-core.js",
     _ => return StatusCode::NOT_FOUND.into_response()
   };
 
+  let file_path = std::path::Path::new(&state.lock().await.config.cdn_directory).join(file_name);
+
   // Open the file
-  let file = match File::open(file_path).await {
+  let file = match File::open(&file_path).await {
     Ok(file) => file,
     Err(err) => {
-      error!("CDN error for path {}: {}", file_path, err);
+      error!("CDN error for path {}: {}", file_path.display(), err);
       return StatusCode::INTERNAL_SERVER_ERROR.into_response();
     }
   };
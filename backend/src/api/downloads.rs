@@ -11,7 +11,7 @@ use serde::Deserialize;
 use log::error;
 
 use crate::{
-  api::utils::auth_utils::get_user_session_data, constants, AppState
+  api::utils::{auth_utils::get_user_session_data, download_utils::CorruptChunkError}, constants, AppState
 };
 
 use crate::{
@@ -44,25 +44,47 @@ pub async fn download_chunk_api(
   State(state): State<Arc<Mutex<AppState>>>,
   Path(path_params): Path<DownloadChunkPathParams>
 ) -> impl IntoResponse {
-  let session_data = get_session_data_or_return_unauthorized!(session);
+  let session_data = get_session_data_or_return_unauthorized!(session, state);
 
   // Validate
   if let Err(err) = path_params.validate() {
     return (StatusCode::BAD_REQUEST, err.to_string()).into_response();
   }
 
-  let mut app_state = state.lock().await;
-  
-  match app_state.downloads_manager.try_read_chunk_as_stream(
-    session_data.user_id,
-    &path_params.handle,
-    path_params.chunk
-  ).await {
+  // Find the chunk while holding the app state lock, and then read it without holding it
+  let (chunk_read, expected_checksum) = {
+    let mut app_state = state.lock().await;
+
+    // Files uploaded before checksums were recorded don't have any
+    let expected_checksum = match app_state.database.as_mut().unwrap().get_chunk_checksum(&path_params.handle, path_params.chunk) {
+      Ok(checksum) => checksum,
+      Err(err) => {
+        error!("rusqlite error: {}", err);
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+      }
+    };
+
+    let chunk_read = app_state.downloads_manager.prepare_chunk_read(session_data.user_id, &path_params.handle, path_params.chunk).await;
+
+    match chunk_read {
+      Ok(chunk_read) => (chunk_read, expected_checksum),
+      Err(err) => {
+        error!("Prepare chunk read error: {}", err);
+        return StatusCode::BAD_REQUEST.into_response();
+      }
+    }
+  };
+
+  match chunk_read.read(expected_checksum).await {
     Ok(stream) => {
       Body::from_stream(stream).into_response()
     },
+    Err(err) if err.is::<CorruptChunkError>() => {
+      error!("Refusing to serve corrupted data: {}", err);
+      StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    },
     Err(err) => {
-      error!("Try read chunk as stream error: {}", err);
+      error!("Read chunk error: {}", err);
       StatusCode::BAD_REQUEST.into_response()
     }
   }
@@ -18,6 +18,7 @@ use crate::{
   validate_string_length,
   AppState,
   api::utils::auth_utils::get_user_session_data,
+  config::TransferLimits,
   util::generate_file_handle,
   database,
   database::UserFileEntry,
@@ -38,7 +39,7 @@ pub async fn get_usage_api(
   session: Session,
   State(state): State<Arc<Mutex<AppState>>>
 ) -> impl IntoResponse {
-  let session_data = get_session_data_or_return_unauthorized!(session);
+  let session_data = get_session_data_or_return_unauthorized!(session, state);
 
   // Acquire database
   let mut app_state = state.lock().await;
@@ -98,7 +99,7 @@ pub async fn get_items_api(
   State(state): State<Arc<Mutex<AppState>>>,
   Query(params): Query<GetItemsParams>
 ) -> impl IntoResponse {
-  let session_data = get_session_data_or_return_unauthorized!(session);
+  let session_data = get_session_data_or_return_unauthorized!(session, state);
 
   // Validate
   if let Err(err) = params.validate() {
@@ -166,10 +167,10 @@ pub struct CreateFolderResponse {
 }
 
 impl CreateFolderRequest {
-  pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+  pub fn validate(&self, transfer_limits: &TransferLimits) -> Result<(), Box<dyn Error>> {
     validate_string_is_ascii_alphanumeric!(self, parent_handle);
     validate_string_length!(self.parent_handle, constants::FILE_HANDLE_LENGTH);
-    validate_base64_max_byte_size!(self, encrypted_metadata, constants::ENCRYPTED_FILE_METADATA_MAX_SIZE);
+    validate_base64_max_byte_size!(self, encrypted_metadata, transfer_limits.encrypted_file_metadata_max_size);
 
     Ok(())
   }
@@ -180,15 +181,16 @@ pub async fn create_folder_api(
   State(state): State<Arc<Mutex<AppState>>>,
   Json(req): Json<CreateFolderRequest>
 ) -> impl IntoResponse {
-  let session_data = get_session_data_or_return_unauthorized!(session);
+  let session_data = get_session_data_or_return_unauthorized!(session, state);
+  
+  // Acquire database
+  let mut app_state = state.lock().await;
 
   // Validate
-  if let Err(err) = req.validate() {
+  if let Err(err) = req.validate(&app_state.config.transfer_limits) {
     return (StatusCode::BAD_REQUEST, err.to_string()).into_response();
   }
-  
-  // Acquire database
-  let mut app_state = state.lock().await;
+
   let database = app_state.database.as_mut().unwrap();
 
   // Create user file entry for the folter
@@ -224,9 +226,9 @@ pub struct PutMetadataRequest {
 }
 
 impl PutMetadataRequest {
-  pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+  pub fn validate(&self, transfer_limits: &TransferLimits) -> Result<(), Box<dyn Error>> {
     validate_string_length!(self.handle, constants::FILE_HANDLE_LENGTH);
-    validate_base64_max_byte_size!(self, encrypted_metadata, constants::ENCRYPTED_FILE_METADATA_MAX_SIZE);
+    validate_base64_max_byte_size!(self, encrypted_metadata, transfer_limits.encrypted_file_metadata_max_size);
 
     Ok(())
   }
@@ -237,17 +239,17 @@ pub async fn put_metadata_api(
   State(state): State<Arc<Mutex<AppState>>>,
   Json(req): Json<Vec<PutMetadataRequest>>
 ) -> impl IntoResponse {
-  let session_data = get_session_data_or_return_unauthorized!(session);
+  let session_data = get_session_data_or_return_unauthorized!(session, state);
+
+  // Acquire database
+  let mut app_state = state.lock().await;
 
   // Validate
   for entry in req.iter() {
-    if let Err(err) = entry.validate() {
+    if let Err(err) = entry.validate(&app_state.config.transfer_limits) {
       return (StatusCode::BAD_REQUEST, err.to_string()).into_response();
     }
   }
-  
-  // Acquire database
-  let mut app_state = state.lock().await;
   let database = app_state.database.as_mut().unwrap();
 
   // Create requests for the database
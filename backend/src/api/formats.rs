@@ -1,8 +1,8 @@
 use crate::constants;
 
-pub fn calc_file_chunk_count(raw_file_size: u64) -> u64 {
-  let quotient = raw_file_size / (constants::CHUNK_DATA_SIZE as u64);
-  let remainder = raw_file_size % (constants::CHUNK_DATA_SIZE as u64);
+pub fn calc_file_chunk_count(raw_file_size: u64, chunk_data_size: usize) -> u64 {
+  let quotient = raw_file_size / (chunk_data_size as u64);
+  let remainder = raw_file_size % (chunk_data_size as u64);
 
   if remainder == 0 {
     quotient
@@ -11,12 +11,17 @@ pub fn calc_file_chunk_count(raw_file_size: u64) -> u64 {
   }
 }
 
-pub fn calc_encrypted_file_size(raw_file_size: u64) -> u64 {
-  let chunk_count = calc_file_chunk_count(raw_file_size);
+pub fn calc_encrypted_file_size(raw_file_size: u64, chunk_data_size: usize) -> u64 {
+  let chunk_count = calc_file_chunk_count(raw_file_size, chunk_data_size);
   let header_size = constants::ENCRYPTED_FILE_HEADER_SIZE as u64;
   let overhead = header_size + (chunk_count * (constants::ENCRYPTED_CHUNK_EXTRA_DATA_SIZE as u64));
 
-  return overhead + raw_file_size;
+  overhead + raw_file_size
+}
+
+/// The BLAKE3 hash of an encrypted chunk which is used to detect stored chunks becoming corrupted.
+pub fn calc_chunk_checksum(encrypted_chunk: &[u8]) -> Vec<u8> {
+  blake3::hash(encrypted_chunk).as_bytes().to_vec()
 }
 
 /// Assumes encrypted_chunk_size is not below constants::CHUNK_EXTRA_DATA_SIZE
@@ -1,30 +1,36 @@
 use axum::{
-  extract::State,
-  response::IntoResponse,
+  extract::{ConnectInfo, State},
+  response::{IntoResponse, Response},
   Json
 };
 
-use argon2::{
-  password_hash::{
-    PasswordHash, PasswordVerifier
-  },
-  Argon2
-};
-
 use base64::{engine::general_purpose, Engine as _};
-use log::error;
+use log::{error, info};
 use serde_json::json;
 
 use std::sync::Arc;
 use std::error::Error;
-use http::StatusCode;
+use std::net::SocketAddr;
+use http::{header::RETRY_AFTER, StatusCode};
 use serde::{Serialize, Deserialize};
 use tower_sessions::Session;
 use tokio::sync::Mutex;
 
 use crate::{
+  config::TransferLimits,
   constants,
-  api::utils::auth_utils::get_user_session_data,
+  api::utils::auth_utils::{
+    auth_key_hash_needs_upgrade,
+    clear_pending_login,
+    get_user_session_data,
+    hash_auth_key,
+    start_pending_login,
+    verify_auth_key,
+    LoginAttemptIdentifiers,
+    PendingLogin
+  },
+  database::{Database, UserData},
+  util::get_unix_timestamp_secs,
   AppState,
   get_session_data_or_return_unauthorized,
   validate_base64_byte_size,
@@ -44,22 +50,45 @@ pub struct GetSessionInfoResponse {
   username: String,
 
   #[serde(rename = "storageQuota")]
-  storage_quota: u64
+  storage_quota: u64,
+
+  #[serde(rename = "isAdmin")]
+  is_admin: bool
 }
 
 pub async fn get_session_data_api(
   session: Session,
-  State(_state): State<Arc<Mutex<AppState>>>
+  State(state): State<Arc<Mutex<AppState>>>
 ) -> impl IntoResponse {
-  let session_data = get_session_data_or_return_unauthorized!(session);
+  let session_data = get_session_data_or_return_unauthorized!(session, state);
 
   Json(GetSessionInfoResponse {
     user_id: session_data.user_id,
     username: session_data.username,
-    storage_quota: session_data.storage_quota
+    storage_quota: session_data.storage_quota,
+    is_admin: session_data.is_admin
   }).into_response()
 }
 
+// ----------------------------------------------
+// API - Get server info
+// ----------------------------------------------
+
+#[derive(Serialize)]
+pub struct GetServerInfoResponse {
+  #[serde(flatten)]
+  transfer_limits: TransferLimits
+}
+
+/// The server's settings that clients need to adapt to. Available without logging in since nothing in it is secret.
+pub async fn get_server_info_api(
+  State(state): State<Arc<Mutex<AppState>>>
+) -> impl IntoResponse {
+  let transfer_limits = state.lock().await.config.transfer_limits;
+
+  Json(GetServerInfoResponse { transfer_limits }).into_response()
+}
+
 // ----------------------------------------------
 // API - Login
 // ----------------------------------------------
@@ -94,9 +123,17 @@ pub struct LoginResponse {
   encrypted_x25519_private_key: String
 }
 
+/// Sent instead of `LoginResponse` when the user must complete a second login step.
+#[derive(Serialize)]
+pub struct LoginSecondFactorResponse {
+  #[serde(rename = "secondFactors")]
+  second_factors: Vec<String>
+}
+
 pub async fn login_api(
   session: Session,
   State(state): State<Arc<Mutex<AppState>>>,
+  ConnectInfo(address): ConnectInfo<SocketAddr>,
   Json(req): Json<LoginRequest>
 ) -> impl IntoResponse {
   // Validate request
@@ -104,31 +141,157 @@ pub async fn login_api(
     return (StatusCode::BAD_REQUEST, err.to_string()).into_response();
   }
 
-  // Acquire database
+  let identifiers = LoginAttemptIdentifiers::new(&address, &req.username);
+
+  let (user_data, argon2_params) = {
+    // Acquire database
+    let mut app_state = state.lock().await;
+    let argon2_params = app_state.config.argon2_params().unwrap();
+    let database = app_state.database.as_mut().unwrap();
+
+    // Reject the attempt if the ip address or username is locked out. Otherwise the attempt is counted as a failure
+    // until the login succeeds, since the auth key is verified after the lock is released.
+    match identifiers.reserve_attempt(database, get_unix_timestamp_secs()) {
+      Ok(Some(retry_after)) => {
+        return (StatusCode::TOO_MANY_REQUESTS, [(RETRY_AFTER, retry_after.to_string())]).into_response();
+      },
+      Ok(None) => (),
+      Err(err) => {
+        error!("rusqlite error: {}", err);
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+      }
+    }
+
+    // Get user data from username
+    (database.get_user_data(&req.username).ok(), argon2_params)
+  };
+
+  // Verify auth hash by decoding base64 string and verifying it with Argon2. This is done without holding the app
+  // state lock and on a blocking thread since it's intentionally slow. If the stored hash is weaker than the
+  // configured policy, the auth key is re-hashed with the current parameters while it's available.
+  let (verified, upgraded_auth_key_hash) = match &user_data {
+    Some(data) => {
+      let auth_key_bytes = general_purpose::STANDARD.decode(req.auth_key).unwrap();
+      let auth_key_hash = data.auth_key_hash.clone();
+
+      tokio::task::spawn_blocking(move || {
+        if !verify_auth_key(&auth_key_bytes, &auth_key_hash) {
+          return (false, None);
+        }
+
+        if auth_key_hash_needs_upgrade(&auth_key_hash, &argon2_params) {
+          (true, hash_auth_key(&auth_key_bytes, &argon2_params).ok())
+        } else {
+          (true, None)
+        }
+      })
+      .await
+      .unwrap_or((false, None))
+    },
+    None => (false, None)
+  };
+
+  // The failed attempt was already recorded when it was reserved
+  let user_data = match user_data {
+    Some(data) if verified => data,
+    _ => return StatusCode::UNAUTHORIZED.into_response()
+  };
+
+  // Acquire database again
   let mut app_state = state.lock().await;
   let database = app_state.database.as_mut().unwrap();
 
-  // Get user data from username
-  let user_data = match database.get_user_data(&req.username) {
-    Ok(data) => data,
-    Err(_) => return StatusCode::UNAUTHORIZED.into_response()
+  if let Some(auth_key_hash) = upgraded_auth_key_hash {
+    match database.update_user_auth_key_hash(user_data.user_id.unwrap(), &auth_key_hash) {
+      Ok(_) => info!("Upgraded the auth key hash parameters of user {}.", user_data.username),
+      Err(err) => error!("Failed to upgrade auth key hash: {}", err)
+    };
+  }
+
+  // Require a second step before the session is logged in if the user has any second factors
+  let second_factors = match get_user_second_factors(database, user_data.user_id.unwrap()) {
+    Ok(factors) => factors,
+    Err(err) => {
+      error!("rusqlite error: {}", err);
+      return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
   };
 
-  // Verify auth hash by decoding base64 string and verifying it with Argon2
-  let auth_key_bytes = general_purpose::STANDARD.decode(req.auth_key).unwrap();
-  let auth_key_hash = PasswordHash::new(user_data.auth_key_hash.as_str()).unwrap();
-  let verified = Argon2::default().verify_password(auth_key_bytes.as_ref(), &auth_key_hash).is_ok();
+  if !second_factors.is_empty() {
+    start_pending_login(&session, &user_data.username, &second_factors).await;
+
+    return Json(LoginSecondFactorResponse { second_factors }).into_response();
+  }
+
+  complete_login(&session, database, &identifiers, user_data).await
+}
+
+/// Returns the second factors a user must pass to finish logging in. Every enabled factor is required, so a TOTP code
+/// or backup code can't stand in for a security key. An empty list means none are required.
+fn get_user_second_factors(database: &mut Database, user_id: u64) -> Result<Vec<String>, rusqlite::Error> {
+  let mut second_factors = Vec::new();
 
-  if !verified {
-    return StatusCode::UNAUTHORIZED.into_response();
+  if database.get_user_totp(user_id)?.is_some_and(|totp| totp.enabled) {
+    second_factors.push(constants::SECOND_FACTOR_TOTP.to_string());
   }
 
+  if !database.get_user_webauthn_credentials(user_id)?.is_empty() {
+    second_factors.push(constants::SECOND_FACTOR_WEBAUTHN.to_string());
+  }
+
+  Ok(second_factors)
+}
+
+/// Marks one of the pending login's second factors as passed. The session is only logged in once every factor has
+/// passed, otherwise the factors that remain are sent back the same way as in `login_api`.
+pub async fn complete_login_factor(
+  session: &Session,
+  database: &mut Database,
+  identifiers: &LoginAttemptIdentifiers,
+  user_data: UserData,
+  mut pending_login: PendingLogin,
+  factor: &str
+) -> Response {
+  pending_login.remaining_factors.retain(|remaining| remaining != factor);
+
+  if pending_login.remaining_factors.is_empty() {
+    return complete_login(session, database, identifiers, user_data).await;
+  }
+
+  session.insert_value(constants::SESSION_PENDING_LOGIN_FACTORS_KEY, json!(pending_login.remaining_factors)).await.unwrap();
+
+  Json(LoginSecondFactorResponse { second_factors: pending_login.remaining_factors }).into_response()
+}
+
+/// Marks the session as logged in after all login steps have succeeded and responds with the user's encrypted keys.
+pub async fn complete_login(session: &Session, database: &mut Database, identifiers: &LoginAttemptIdentifiers, user_data: UserData) -> Response {
   let user_id = user_data.user_id.unwrap();
 
+  // Only users that have passed every login step are told their account is disabled
+  if user_data.disabled {
+    return (StatusCode::FORBIDDEN, "Account is disabled.").into_response();
+  }
+
+  // Failed attempts are only forgotten once the whole login succeeds
+  if let Err(err) = identifiers.clear(database) {
+    error!("rusqlite error: {}", err);
+    return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+  }
+
+  let session_generation = match database.get_user_account_status(user_id) {
+    Ok(status) => status.map(|status| status.session_generation).unwrap_or(0),
+    Err(err) => {
+      error!("rusqlite error: {}", err);
+      return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+  };
+
   // Update user session to be logged in
+  clear_pending_login(session).await;
   session.insert_value(constants::SESSION_USER_ID_KEY, json!(user_id)).await.unwrap();
   session.insert_value(constants::SESSION_USERNAME_KEY, json!(user_data.username)).await.unwrap();
   session.insert_value(constants::SESSION_STORAGE_QUOTA_KEY, json!(user_data.storage_quota)).await.unwrap();
+  session.insert_value(constants::SESSION_GENERATION_KEY, json!(session_generation)).await.unwrap();
 
   Json(LoginResponse {
     encrypted_master_key: general_purpose::STANDARD.encode(user_data.encrypted_master_key),
@@ -1,5 +1,7 @@
 pub mod general;
 pub mod account;
+pub mod totp;
+pub mod webauthn;
 pub mod filesystem;
 pub mod uploads;
 pub mod downloads;
@@ -8,3 +10,4 @@ pub mod multipart;
 pub mod formats;
 pub mod utils;
 pub mod cdn;
+pub mod admin;
@@ -0,0 +1,297 @@
+use axum::{
+  extract::{ConnectInfo, State}, response::IntoResponse, Json
+};
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use totp_rs::{Builder, Totp, TotpError};
+use http::{header::RETRY_AFTER, StatusCode};
+use std::sync::Arc;
+use std::error::Error;
+use std::net::SocketAddr;
+use tower_sessions::Session;
+use serde::{Serialize, Deserialize};
+use tokio::sync::Mutex;
+use log::error;
+
+use crate::{
+  AppState,
+  constants,
+  api::{
+    general::complete_login_factor,
+    utils::auth_utils::{get_pending_login, get_user_session_data, LoginAttemptIdentifiers}
+  },
+  util::{generate_backup_code, get_unix_timestamp_secs},
+  get_session_data_or_return_unauthorized,
+  validate_string_length
+};
+
+/// Creates the TOTP generator for a user's secret with the standard parameters that authenticator apps expect.
+pub fn build_totp(secret: &[u8], username: &str) -> Result<Totp, TotpError> {
+  Builder::new()
+    .with_secret(secret)
+    .with_account_name(username)
+    .with_issuer(Some(constants::TOTP_ISSUER))
+    .build()
+}
+
+/// Backup codes are random so they are stored as a plain hash instead of with Argon2.
+fn hash_backup_code(code: &str) -> String {
+  blake3::hash(code.trim().to_ascii_lowercase().as_bytes()).to_hex().to_string()
+}
+
+// ----------------------------------------------
+// API - Enrol TOTP
+// ----------------------------------------------
+
+#[derive(Serialize)]
+pub struct EnrolTotpResponse {
+  secret: String, // Base32 encoded
+
+  url: String // The otpauth:// url used to create QR codes
+}
+
+/// Generates a new TOTP secret for the logged in user. The secret isn't required at login until it has been confirmed
+/// with `confirm_totp_api`.
+pub async fn enrol_totp_api(
+  session: Session,
+  State(state): State<Arc<Mutex<AppState>>>
+) -> impl IntoResponse {
+  let session_data = get_session_data_or_return_unauthorized!(session, state);
+
+  // Acquire database
+  let mut app_state = state.lock().await;
+  let database = app_state.database.as_mut().unwrap();
+
+  // Two-factor authentication must be reset by an admin before a new secret can be enrolled
+  match database.get_user_totp(session_data.user_id) {
+    Ok(Some(totp)) if totp.enabled => return (StatusCode::CONFLICT, "TOTP is already enabled.").into_response(),
+    Ok(_) => (),
+    Err(err) => {
+      error!("rusqlite error: {}", err);
+      return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+  };
+
+  let mut secret = [0u8; constants::TOTP_SECRET_SIZE];
+  OsRng.fill_bytes(&mut secret);
+
+  let totp = match build_totp(&secret, &session_data.username) {
+    Ok(totp) => totp,
+    Err(err) => {
+      error!("Failed to create TOTP: {}", err);
+      return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+  };
+
+  let url = match totp.to_url() {
+    Ok(url) => url,
+    Err(err) => {
+      error!("Failed to create TOTP url: {}", err);
+      return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+  };
+
+  match database.set_pending_user_totp(session_data.user_id, &secret) {
+    Ok(_) => Json(EnrolTotpResponse { secret: totp.secret().to_base32(), url }).into_response(),
+    Err(err) => {
+      error!("rusqlite error: {}", err);
+      StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    }
+  }
+}
+
+// ----------------------------------------------
+// API - Confirm TOTP
+// ----------------------------------------------
+
+#[derive(Deserialize)]
+pub struct ConfirmTotpRequest {
+  code: String
+}
+
+impl ConfirmTotpRequest {
+  pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+    validate_string_length!(self, code, constants::TOTP_CODE_LENGTH);
+
+    Ok(())
+  }
+}
+
+#[derive(Serialize)]
+pub struct ConfirmTotpResponse {
+  #[serde(rename = "backupCodes")]
+  backup_codes: Vec<String>
+}
+
+/// Enables the logged in user's enrolled TOTP secret once they prove their authenticator generates valid codes. The
+/// single-use backup codes are only ever shown in this response.
+pub async fn confirm_totp_api(
+  session: Session,
+  State(state): State<Arc<Mutex<AppState>>>,
+  Json(req): Json<ConfirmTotpRequest>
+) -> impl IntoResponse {
+  let session_data = get_session_data_or_return_unauthorized!(session, state);
+
+  // Validate request
+  if let Err(err) = req.validate() {
+    return (StatusCode::BAD_REQUEST, err.to_string()).into_response();
+  }
+
+  // Acquire database
+  let mut app_state = state.lock().await;
+  let database = app_state.database.as_mut().unwrap();
+
+  let user_totp = match database.get_user_totp(session_data.user_id) {
+    Ok(Some(totp)) if totp.enabled => return (StatusCode::CONFLICT, "TOTP is already enabled.").into_response(),
+    Ok(Some(totp)) => totp,
+    Ok(None) => return (StatusCode::BAD_REQUEST, "TOTP has not been enrolled.").into_response(),
+    Err(err) => {
+      error!("rusqlite error: {}", err);
+      return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+  };
+
+  let confirmed_step = match build_totp(&user_totp.secret, &session_data.username) {
+    Ok(totp) => totp.check(&req.code, get_unix_timestamp_secs()),
+    Err(err) => {
+      error!("Failed to create TOTP: {}", err);
+      return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+  };
+
+  let confirmed_step = match confirmed_step {
+    Some(step) => step,
+    None => return StatusCode::UNAUTHORIZED.into_response()
+  };
+
+  // Generate backup codes
+  let backup_codes: Vec<String> = (0..constants::TOTP_BACKUP_CODE_COUNT)
+    .map(|_| generate_backup_code())
+    .collect();
+
+  let backup_code_hashes: Vec<String> = backup_codes.iter()
+    .map(|code| hash_backup_code(code))
+    .collect();
+
+  match database.enable_user_totp(session_data.user_id, confirmed_step, &backup_code_hashes) {
+    Ok(_) => Json(ConfirmTotpResponse { backup_codes }).into_response(),
+    Err(err) => {
+      error!("rusqlite error: {}", err);
+      StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    }
+  }
+}
+
+// ----------------------------------------------
+// API - Login with TOTP
+// ----------------------------------------------
+
+#[derive(Deserialize)]
+pub struct LoginTotpRequest {
+  code: Option<String>,
+
+  #[serde(rename = "backupCode")]
+  backup_code: Option<String>
+}
+
+impl LoginTotpRequest {
+  pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+    match (&self.code, &self.backup_code) {
+      (Some(code), None) => validate_string_length!(code, constants::TOTP_CODE_LENGTH),
+      (None, Some(backup_code)) => validate_string_length!(backup_code, constants::TOTP_BACKUP_CODE_LENGTH),
+      _ => return Err("Exactly one of 'code' or 'backup_code' must be provided.".into())
+    };
+
+    Ok(())
+  }
+}
+
+/// The second login step for users with TOTP enabled. Must be called after `login_api` with the same session. Users
+/// that also have WebAuthn credentials must pass the login assertion as well.
+pub async fn login_totp_api(
+  session: Session,
+  State(state): State<Arc<Mutex<AppState>>>,
+  ConnectInfo(address): ConnectInfo<SocketAddr>,
+  Json(req): Json<LoginTotpRequest>
+) -> impl IntoResponse {
+  // Validate request
+  if let Err(err) = req.validate() {
+    return (StatusCode::BAD_REQUEST, err.to_string()).into_response();
+  }
+
+  let pending_login = match get_pending_login(&session).await {
+    Some(pending_login) if pending_login.requires(constants::SECOND_FACTOR_TOTP) => pending_login,
+    _ => return StatusCode::UNAUTHORIZED.into_response()
+  };
+
+  let username = pending_login.username.clone();
+  let identifiers = LoginAttemptIdentifiers::new(&address, &username);
+  let now = get_unix_timestamp_secs();
+
+  // Acquire database
+  let mut app_state = state.lock().await;
+  let database = app_state.database.as_mut().unwrap();
+
+  // Codes are rate limited the same way as passwords
+  match identifiers.get_retry_after_seconds(database, now) {
+    Ok(Some(retry_after)) => {
+      return (StatusCode::TOO_MANY_REQUESTS, [(RETRY_AFTER, retry_after.to_string())]).into_response();
+    },
+    Ok(None) => (),
+    Err(err) => {
+      error!("rusqlite error: {}", err);
+      return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+  }
+
+  let user_data = match database.get_user_data(&username) {
+    Ok(data) => data,
+    Err(_) => return StatusCode::UNAUTHORIZED.into_response()
+  };
+
+  let user_id = user_data.user_id.unwrap();
+
+  let user_totp = match database.get_user_totp(user_id) {
+    Ok(Some(totp)) if totp.enabled => totp,
+    Ok(_) => return StatusCode::UNAUTHORIZED.into_response(),
+    Err(err) => {
+      error!("rusqlite error: {}", err);
+      return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+  };
+
+  let verified = if let Some(code) = &req.code {
+    let step = match build_totp(&user_totp.secret, &username) {
+      Ok(totp) => totp.check(code, now),
+      Err(err) => {
+        error!("Failed to create TOTP: {}", err);
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+      }
+    };
+
+    // Each code can only be used once, so codes from the same or an earlier time step are rejected
+    match step {
+      Some(step) if step > user_totp.last_used_step => database.set_user_totp_last_used_step(user_id, step).map(|_| true),
+      _ => Ok(false)
+    }
+  } else {
+    let backup_code = req.backup_code.as_deref().unwrap_or_default();
+    database.use_totp_backup_code(user_id, &hash_backup_code(backup_code))
+  };
+
+  match verified {
+    Ok(true) => complete_login_factor(&session, database, &identifiers, user_data, pending_login, constants::SECOND_FACTOR_TOTP).await,
+    Ok(false) => {
+      if let Err(err) = identifiers.record_failure(database, now) {
+        error!("rusqlite error: {}", err);
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+      }
+
+      StatusCode::UNAUTHORIZED.into_response()
+    },
+    Err(err) => {
+      error!("rusqlite error: {}", err);
+      StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    }
+  }
+}
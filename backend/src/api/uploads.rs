@@ -8,13 +8,13 @@ use std::sync::Arc;
 use std::error::Error;
 use tower_sessions::Session;
 use serde::{Serialize, Deserialize};
-use log::{debug, error, warn};
+use log::{error, warn};
 use base64::{engine::general_purpose, Engine as _};
 
 use crate::{
   api::{
     utils::auth_utils::get_user_session_data, multipart::*
-  }, constants, database::UserFileEntry, AppState
+  }, config::TransferLimits, constants, database::UserFileEntry, AppState
 };
 
 use crate::util::generate_file_handle;
@@ -52,8 +52,8 @@ pub struct StartUploadResponse {
 }
 
 impl StartUploadRequest {
-  pub fn validate(&self) -> Result<(), Box<dyn Error>> {
-    validate_integer_max_value!(self, file_size, constants::MAX_FILE_SIZE);
+  pub fn validate(&self, transfer_limits: &TransferLimits) -> Result<(), Box<dyn Error>> {
+    validate_integer_max_value!(self, file_size, transfer_limits.max_file_size);
 
     Ok(())
   }
@@ -64,15 +64,20 @@ pub async fn start_upload_api(
   State(state): State<Arc<Mutex<AppState>>>,
   Json(req): Json<StartUploadRequest>
 ) -> impl IntoResponse {
-  let session_data = get_session_data_or_return_unauthorized!(session);
+  let session_data = get_session_data_or_return_unauthorized!(session, state);
+  
+  // Acquire app state
+  let mut app_state = state.lock().await;
 
   // Validate
-  if let Err(err) = req.validate() {
+  if let Err(err) = req.validate(&app_state.config.transfer_limits) {
     return (StatusCode::BAD_REQUEST, err.to_string()).into_response();
   }
-  
-  // Acquire app state
-  let mut app_state = state.lock().await;
+
+  if !app_state.uploads_manager.is_accepting_chunks() {
+    return (StatusCode::SERVICE_UNAVAILABLE, "The server is stopping.").into_response();
+  }
+
   let handle = generate_file_handle();
 
   match app_state.uploads_manager.new_upload(session_data.user_id, &handle, req.file_size).await {
@@ -89,11 +94,11 @@ pub async fn start_upload_api(
 // ----------------------------------------------
 
 #[derive(Deserialize)]
-pub struct FinaliseUploadPathParams {
+pub struct UploadPathParams {
   handle: String
 }
 
-impl FinaliseUploadPathParams {
+impl UploadPathParams {
   pub fn validate(&self) -> Result<(), Box<dyn Error>> {
     validate_string_is_ascii_alphanumeric!(self, handle);
     validate_string_length!(self, handle, constants::FILE_HANDLE_LENGTH);
@@ -117,10 +122,10 @@ pub struct FinaliseUploadRequest {
 }
 
 impl FinaliseUploadRequest {
-  pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+  pub fn validate(&self, transfer_limits: &TransferLimits) -> Result<(), Box<dyn Error>> {
     validate_string_is_ascii_alphanumeric!(self, parent_handle);
     validate_string_length!(self, parent_handle, constants::FILE_HANDLE_LENGTH);
-    validate_base64_max_byte_size!(self, encrypted_metadata, constants::ENCRYPTED_FILE_METADATA_MAX_SIZE);
+    validate_base64_max_byte_size!(self, encrypted_metadata, transfer_limits.encrypted_file_metadata_max_size);
     validate_base64_byte_size!(self, encrypted_file_crypt_key, constants::ENCRYPTED_FILE_CRYPT_KEY_SIZE);
     validate_base64_byte_size!(self, signature, constants::ED25519_SIGNATURE_SIZE);
 
@@ -131,23 +136,23 @@ impl FinaliseUploadRequest {
 pub async fn finalise_upload_api(
   session: Session,
   State(state): State<Arc<Mutex<AppState>>>,
-  axum::extract::Path(path_params): axum::extract::Path<FinaliseUploadPathParams>,
+  axum::extract::Path(path_params): axum::extract::Path<UploadPathParams>,
   Json(req): Json<FinaliseUploadRequest>
 ) -> impl IntoResponse {
-  let session_data = get_session_data_or_return_unauthorized!(session);
+  let session_data = get_session_data_or_return_unauthorized!(session, state);
 
   // Validate
   if let Err(err) = path_params.validate() {
     return (StatusCode::BAD_REQUEST, err.to_string()).into_response();
   }
 
-  if let Err(err) = req.validate() {
-    return (StatusCode::BAD_REQUEST, err.to_string()).into_response();
-  }
-  
   // Acquire app state and database
   let mut app_state = state.lock().await;
 
+  if let Err(err) = req.validate(&app_state.config.transfer_limits) {
+    return (StatusCode::BAD_REQUEST, err.to_string()).into_response();
+  }
+
   // Get upload
   let active_upload = match app_state.uploads_manager.get_active_upload(&path_params.handle).await {
     Some(upload) => upload,
@@ -157,8 +162,8 @@ pub async fn finalise_upload_api(
   let upload_size = active_upload.file_size;
 
   // Finalise the upload
-  match app_state.uploads_manager.finalise_upload(&path_params.handle).await {
-    Ok(_) => (),
+  let chunk_checksums = match app_state.uploads_manager.finalise_upload(&path_params.handle).await {
+    Ok(chunk_checksums) => chunk_checksums,
     Err(err) => {
       error!("Finalise upload error: {}", err);
 
@@ -185,13 +190,66 @@ pub async fn finalise_upload_api(
 
   let database = app_state.database.as_mut().unwrap();
 
-  let _ = database.insert_new_user_file(&new_file)
-    .map_err(|err| {
+  match database.insert_new_user_file_with_checksums(&new_file, &chunk_checksums) {
+    Ok(_) => StatusCode::OK.into_response(),
+    Err(err) => {
       error!("rusqlite error: {}", err);
-      return StatusCode::INTERNAL_SERVER_ERROR.into_response();
-    });
 
-  StatusCode::OK.into_response()
+      // Don't leave behind a stored file that no database entry points to
+      let file_name = path_params.handle.clone() + constants::TREASURY_FILE_EXTENSION;
+      
+      if let Err(err) = app_state.uploads_manager.blob_store.delete(&file_name).await {
+        error!("Failed to delete orphaned file {}: {}", file_name, err);
+      }
+
+      StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    }
+  }
+}
+
+// ----------------------------------------------
+// API - Get upload progress
+// ----------------------------------------------
+
+#[derive(Serialize)]
+pub struct GetUploadProgressResponse {
+  #[serde(rename = "fileSize")]
+  file_size: u64,
+
+  #[serde(rename = "writtenBytes")]
+  written_bytes: u64,
+
+  /// Chunks from this id onwards need to be sent again
+  #[serde(rename = "nextChunkId")]
+  next_chunk_id: i64
+}
+
+/// Lets a client continue an upload after the connection was lost or the server restarted.
+pub async fn get_upload_progress_api(
+  session: Session,
+  State(state): State<Arc<Mutex<AppState>>>,
+  axum::extract::Path(path_params): axum::extract::Path<UploadPathParams>
+) -> impl IntoResponse {
+  let session_data = get_session_data_or_return_unauthorized!(session, state);
+
+  // Validate
+  if let Err(err) = path_params.validate() {
+    return (StatusCode::BAD_REQUEST, err.to_string()).into_response();
+  }
+
+  // Acquire app state
+  let mut app_state = state.lock().await;
+
+  match app_state.uploads_manager.get_active_upload(&path_params.handle).await {
+    Some(upload) if upload.user_id == session_data.user_id => {
+      Json(GetUploadProgressResponse {
+        file_size: upload.file_size,
+        written_bytes: upload.written_bytes,
+        next_chunk_id: upload.prev_written_chunk_id + 1
+      }).into_response()
+    },
+    _ => StatusCode::NOT_FOUND.into_response()
+  }
 }
 
 // ----------------------------------------------
@@ -203,18 +261,22 @@ pub async fn upload_chunk_api(
   State(state): State<Arc<Mutex<AppState>>>,
   mut multipart: Multipart
 ) -> impl IntoResponse {
-  let session_data = get_session_data_or_return_unauthorized!(session);
+  let session_data = get_session_data_or_return_unauthorized!(session, state);
 
   // Read multipart data
   let handle = read_next_multipart_data_as_string_or_bad_request!(multipart, "handle");
   let chunk_id = read_next_multipart_data_as_i64_or_bad_request!(multipart, "chunkId");
   let data = read_next_multipart_data_as_bytes_or_bad_request!(multipart, "data");
+
+  // Acquire app state
+  let mut app_state = state.lock().await;
+  let transfer_limits = app_state.config.transfer_limits;
   
   // Validate
   let validate = || -> Result<(), Box<dyn Error>> {
     validate_string_length!(handle, constants::FILE_HANDLE_LENGTH);
     validate_integer_is_positive!(chunk_id);
-    validate_vector_length_range!(data, constants::ENCRYPTED_CHUNK_EXTRA_DATA_SIZE, constants::ENCRYPTED_CHUNK_SIZE);
+    validate_vector_length_range!(data, constants::ENCRYPTED_CHUNK_EXTRA_DATA_SIZE, transfer_limits.encrypted_chunk_size());
 
     Ok(())
   };
@@ -223,8 +285,10 @@ pub async fn upload_chunk_api(
     return (StatusCode::BAD_REQUEST, err.to_string()).into_response();
   }
 
-  // Acquire app state
-  let mut app_state = state.lock().await;
+  // The upload is continued after the server restarts
+  if !app_state.uploads_manager.is_accepting_chunks() {
+    return (StatusCode::SERVICE_UNAVAILABLE, "The server is stopping.").into_response();
+  }
 
   // Get active upload by the handle
   let active_upload = match app_state.uploads_manager.get_active_upload(&handle).await {
@@ -248,7 +312,7 @@ pub async fn upload_chunk_api(
   }
   
   // Ensure not too many chunks are buffered
-  if active_upload.buffered_chunks.len() >= constants::MAX_UPLOAD_CONCURRENT_CHUNKS {
+  if active_upload.buffered_chunks.len() >= transfer_limits.max_upload_concurrent_chunks {
     warn!("User {} reached max amount of concurrent upload chunks.", session_data.user_id);
 
     return (
@@ -258,13 +322,10 @@ pub async fn upload_chunk_api(
   }
 
   // Add chunk to buffer
-  let _ = active_upload.try_write_chunk(chunk_id, data)
-    .await
-    .map_err(|err| {
-      return (StatusCode::BAD_REQUEST, err.to_string()).into_response()
-    });
-
-  StatusCode::OK.into_response()
+  match active_upload.try_write_chunk(chunk_id, data).await {
+    Ok(_) => StatusCode::OK.into_response(),
+    Err(err) => (StatusCode::BAD_REQUEST, err.to_string()).into_response()
+  }
 }
 
 // TODO: possibly allow only a max number of buffered chunks per user for many uploads.
@@ -1,42 +1,288 @@
 use tower_sessions::Session;
-use crate::constants;
+use serde_json::json;
+use std::cmp;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use log::error;
+use argon2::{
+  password_hash::{
+    rand_core::OsRng,
+    PasswordHash, PasswordHasher, PasswordVerifier, SaltString
+  },
+  Argon2, Params
+};
+
+use crate::{
+  AppState,
+  constants,
+  database::{Database, LoginLockout, LoginLockoutKind},
+  util::get_unix_timestamp_secs
+};
 
 pub struct UserSessionData {
   pub user_id: u64, 
   pub username: String,
-  pub storage_quota: u64
+  pub storage_quota: u64,
+  pub is_admin: bool
 }
 
-pub async fn get_user_session_data(session: &Session) -> Option<UserSessionData> {
+/// Gets the logged in user's session data. The user's account is read from the database every time so that changes
+/// made by an admin, such as a new storage quota or username, also apply to existing sessions. Sessions of disabled or
+/// deleted users, and sessions from before the user's password was changed, are logged out.
+pub async fn get_user_session_data(session: &Session, state: &Arc<Mutex<AppState>>) -> Option<UserSessionData> {
   let user_id = match session.get::<u64>(constants::SESSION_USER_ID_KEY).await.unwrap() {
     Some(id) => id,
     None => return None
   };
 
-  let username = match session.get::<String>(constants::SESSION_USERNAME_KEY).await.unwrap() {
-    Some(name) => name,
-    None => return None
-  };
+  let session_generation = session.get::<u64>(constants::SESSION_GENERATION_KEY).await.unwrap().unwrap_or(0);
+  let account_status = state.lock().await.database.as_mut().unwrap().get_user_account_status(user_id);
 
-  let storage_quota = match session.get::<u64>(constants::SESSION_STORAGE_QUOTA_KEY).await.unwrap() {
-    Some(value) => value,
-    None => return None
+  let account_status = match account_status {
+    Ok(Some(status)) if !status.disabled && status.session_generation == session_generation => status,
+    Ok(_) => {
+      let _ = session.flush().await;
+      return None;
+    },
+    Err(err) => {
+      error!("rusqlite error: {}", err);
+      return None;
+    }
   };
 
+  // Keep the cached values up to date
+  if session.get::<String>(constants::SESSION_USERNAME_KEY).await.unwrap().as_ref() != Some(&account_status.username) {
+    session.insert_value(constants::SESSION_USERNAME_KEY, json!(account_status.username)).await.unwrap();
+  }
+
+  if session.get::<u64>(constants::SESSION_STORAGE_QUOTA_KEY).await.unwrap() != Some(account_status.storage_quota) {
+    session.insert_value(constants::SESSION_STORAGE_QUOTA_KEY, json!(account_status.storage_quota)).await.unwrap();
+  }
+
   Some(UserSessionData {
     user_id,
-    username,
-    storage_quota
+    username: account_status.username,
+    storage_quota: account_status.storage_quota,
+    is_admin: account_status.is_admin
   })
 }
 
+/// Hashes an authentication key (or recovery key) with Argon2id and a random salt, returning the PHC string.
+pub fn hash_auth_key(auth_key_bytes: &[u8], params: &Params) -> Result<String, argon2::password_hash::Error> {
+  let salt = SaltString::generate(&mut OsRng); // Random salt for the hash
+  let argon2 = Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params.clone());
+
+  Ok(argon2.hash_password(auth_key_bytes, &salt)?.to_string())
+}
+
+/// Verifies an authentication key (or recovery key) against a PHC string created by `hash_auth_key`. The algorithm,
+/// version and parameters are all read from the PHC string so hashes created under an older policy still verify.
+pub fn verify_auth_key(auth_key_bytes: &[u8], auth_key_hash: &str) -> bool {
+  let hash = match PasswordHash::new(auth_key_hash) {
+    Ok(hash) => hash,
+    Err(_) => return false
+  };
+
+  // PHC strings without a version were created with the original version of Argon2
+  let version = argon2::Version::try_from(hash.version.unwrap_or(argon2::Version::V0x10.into()));
+
+  let argon2 = match (argon2::Algorithm::try_from(hash.algorithm), version, Params::try_from(&hash)) {
+    (Ok(algorithm), Ok(version), Ok(params)) => Argon2::new(algorithm, version, params),
+    _ => return false
+  };
+
+  argon2.verify_password(auth_key_bytes, &hash).is_ok()
+}
+
+/// Returns true when a PHC string was created with a different algorithm, version or parameters than the current
+/// policy, which means it should be re-hashed the next time the plain authentication key (or recovery key) is
+/// available.
+pub fn auth_key_hash_needs_upgrade(auth_key_hash: &str, params: &Params) -> bool {
+  let hash = match PasswordHash::new(auth_key_hash) {
+    Ok(hash) => hash,
+    Err(_) => return true
+  };
+
+  let is_argon2id = argon2::Algorithm::try_from(hash.algorithm) == Ok(argon2::Algorithm::Argon2id);
+
+  match Params::try_from(&hash) {
+    Ok(hash_params) => {
+      !is_argon2id
+      || hash.version != Some(argon2::Version::V0x13.into())
+      || hash_params.m_cost() != params.m_cost()
+      || hash_params.t_cost() != params.t_cost()
+      || hash_params.p_cost() != params.p_cost()
+    },
+    Err(_) => true
+  }
+}
+
+/// Calculates how long an identifier is locked out of logging in for after a number of failed attempts. Every
+/// failure past the free attempts doubles the lockout time up to `LOGIN_LOCKOUT_MAX_SECONDS`.
+pub fn calc_login_lockout_seconds(failed_attempts: u64, free_attempts: u64) -> u64 {
+  if failed_attempts < free_attempts {
+    return 0;
+  }
+
+  let exponent = cmp::min(failed_attempts - free_attempts, 32) as u32;
+  let lockout_seconds = constants::LOGIN_LOCKOUT_BASE_SECONDS.saturating_mul(2u64.pow(exponent));
+
+  cmp::min(lockout_seconds, constants::LOGIN_LOCKOUT_MAX_SECONDS)
+}
+
+/// Records a failed login attempt for the identifier and updates its lockout time.
+fn record_login_failure(database: &mut Database, kind: LoginLockoutKind, identifier: &str, free_attempts: u64, now: u64) -> Result<(), rusqlite::Error> {
+  let mut failed_attempts = match database.get_login_lockout(kind, identifier)? {
+    // Forget old failures after a long enough period of inactivity
+    Some(lockout) if now.saturating_sub(lockout.last_failure_time) < constants::LOGIN_FAILURES_RESET_SECONDS => lockout.failed_attempts,
+    _ => 0
+  };
+
+  failed_attempts += 1;
+
+  let lockout = LoginLockout {
+    failed_attempts,
+    last_failure_time: now,
+    locked_until: now + calc_login_lockout_seconds(failed_attempts, free_attempts)
+  };
+
+  database.set_login_lockout(kind, identifier, &lockout)?;
+
+  Ok(())
+}
+
+/// The identifiers that failed login attempts are tracked by. Usernames are tracked case insensitively.
+pub struct LoginAttemptIdentifiers {
+  ip_address: String,
+  username: String
+}
+
+impl LoginAttemptIdentifiers {
+  pub fn new(address: &SocketAddr, username: &str) -> Self {
+    Self {
+      ip_address: address.ip().to_string(),
+      username: username.to_ascii_lowercase()
+    }
+  }
+
+  fn as_array(&self) -> [(LoginLockoutKind, &str, u64); 2] {
+    [
+      (LoginLockoutKind::IpAddress, self.ip_address.as_str(), constants::LOGIN_IP_ADDRESS_FREE_ATTEMPTS),
+      (LoginLockoutKind::Username, self.username.as_str(), constants::LOGIN_USERNAME_FREE_ATTEMPTS)
+    ]
+  }
+
+  /// Returns the number of seconds left until a login can be attempted again, or `None` if none of the identifiers
+  /// are locked out.
+  pub fn get_retry_after_seconds(&self, database: &mut Database, now: u64) -> Result<Option<u64>, rusqlite::Error> {
+    let mut retry_after: Option<u64> = None;
+
+    for (kind, identifier, _) in self.as_array() {
+      if let Some(lockout) = database.get_login_lockout(kind, identifier)? {
+        if lockout.locked_until > now {
+          retry_after = Some(cmp::max(retry_after.unwrap_or(0), lockout.locked_until - now));
+        }
+      }
+    }
+
+    Ok(retry_after)
+  }
+
+  /// Records a failed login attempt for every identifier.
+  pub fn record_failure(&self, database: &mut Database, now: u64) -> Result<(), rusqlite::Error> {
+    for (kind, identifier, free_attempts) in self.as_array() {
+      record_login_failure(database, kind, identifier, free_attempts, now)?;
+    }
+
+    Ok(())
+  }
+
+  /// Checks the lockouts and counts the attempt as a failure up front, so parallel attempts can't all pass the check
+  /// before any of them is recorded. Must be called while holding the app state lock. Returns the number of seconds
+  /// left until a login can be attempted again if the attempt was rejected. A successful login clears the attempt
+  /// with `clear`.
+  pub fn reserve_attempt(&self, database: &mut Database, now: u64) -> Result<Option<u64>, rusqlite::Error> {
+    if let Some(retry_after) = self.get_retry_after_seconds(database, now)? {
+      return Ok(Some(retry_after));
+    }
+
+    self.record_failure(database, now)?;
+
+    Ok(None)
+  }
+
+  /// Forgets the failed attempts of every identifier after a successful login.
+  pub fn clear(&self, database: &mut Database) -> Result<(), rusqlite::Error> {
+    for (kind, identifier, _) in self.as_array() {
+      database.clear_login_lockout(kind, identifier)?;
+    }
+
+    Ok(())
+  }
+}
+
+/// A login that has passed the password step and still has to pass the second factors in `remaining_factors`.
+pub struct PendingLogin {
+  pub username: String,
+  pub remaining_factors: Vec<String>
+}
+
+impl PendingLogin {
+  pub fn requires(&self, factor: &str) -> bool {
+    self.remaining_factors.iter().any(|remaining| remaining == factor)
+  }
+}
+
+/// Starts a pending login for a user that has passed the password step and must pass every one of `factors` next.
+/// Any login assertion started for an earlier pending login is discarded.
+pub async fn start_pending_login(session: &Session, username: &str, factors: &[String]) {
+  let _ = session.remove_value(constants::SESSION_WEBAUTHN_AUTHENTICATION_KEY).await;
+  session.insert_value(constants::SESSION_PENDING_LOGIN_USERNAME_KEY, json!(username)).await.unwrap();
+  session.insert_value(constants::SESSION_PENDING_LOGIN_FACTORS_KEY, json!(factors)).await.unwrap();
+  session.insert_value(constants::SESSION_PENDING_LOGIN_TIME_KEY, json!(get_unix_timestamp_secs())).await.unwrap();
+}
+
+/// Returns the session's pending login if the user has passed the password step recently enough.
+pub async fn get_pending_login(session: &Session) -> Option<PendingLogin> {
+  let username = session.get::<String>(constants::SESSION_PENDING_LOGIN_USERNAME_KEY).await.ok()??;
+  let remaining_factors = session.get::<Vec<String>>(constants::SESSION_PENDING_LOGIN_FACTORS_KEY).await.ok()??;
+  let login_time = session.get::<u64>(constants::SESSION_PENDING_LOGIN_TIME_KEY).await.ok()??;
+
+  if get_unix_timestamp_secs().saturating_sub(login_time) > constants::PENDING_LOGIN_EXPIRY_TIME_SECONDS {
+    return None;
+  }
+
+  Some(PendingLogin { username, remaining_factors })
+}
+
+/// Removes the session's pending login once it has finished.
+pub async fn clear_pending_login(session: &Session) {
+  let _ = session.remove_value(constants::SESSION_PENDING_LOGIN_USERNAME_KEY).await;
+  let _ = session.remove_value(constants::SESSION_PENDING_LOGIN_FACTORS_KEY).await;
+  let _ = session.remove_value(constants::SESSION_PENDING_LOGIN_TIME_KEY).await;
+}
+
 /// Get's the user's session data. However if they are unauthorised, it will automatically return the unauthorised status code.
 #[macro_export]
 macro_rules! get_session_data_or_return_unauthorized {
-  ($session:ident) => {
-    match get_user_session_data(&$session).await {
+  ($session:ident, $state:ident) => {
+    match get_user_session_data(&$session, &$state).await {
       Some(data) => data,
       None => return StatusCode::UNAUTHORIZED.into_response()
     } 
   };
 }
+
+/// Get's the user's session data if they are an admin. Otherwise it will automatically return the unauthorised status
+/// code when they aren't logged in, or the forbidden status code when they aren't an admin.
+#[macro_export]
+macro_rules! get_admin_session_data_or_return_forbidden {
+  ($session:ident, $state:ident) => {
+    match get_user_session_data(&$session, &$state).await {
+      Some(data) if data.is_admin => data,
+      Some(_) => return StatusCode::FORBIDDEN.into_response(),
+      None => return StatusCode::UNAUTHORIZED.into_response()
+    }
+  };
+}
@@ -1,26 +1,54 @@
-use log::debug;
+use log::{debug, warn};
 use tokio_util::io::ReaderStream;
-use std::io::SeekFrom;
-use std::path::PathBuf;
-use tokio::{fs::File, io::{AsyncReadExt, AsyncSeekExt}, sync::mpsc::{Receiver, Sender}, task::JoinHandle, time::{sleep, Duration}};
+use tokio::io::AsyncReadExt;
+use tokio::{sync::mpsc::{Receiver, Sender}, task::JoinHandle, time::{sleep, Duration}};
 use tokio::sync::mpsc;
 use tokio::sync::Mutex;
 use std::sync::Arc;
 use std::collections::HashMap;
 use std::error::Error;
+use std::fmt;
+use std::io::Cursor;
 
 use crate::{
-  config::Config, constants
+  api::formats::calc_chunk_checksum, config::TransferLimits, constants, storage::{BlobReader, BlobStore, StorageResult}
 };
 
+/// Returned when a stored chunk no longer matches the checksum recorded when it was uploaded.
+#[derive(Debug)]
+pub struct CorruptChunkError {
+  pub file_name: String,
+  pub chunk_id: u64
+}
+
+impl fmt::Display for CorruptChunkError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "Chunk {} of {} doesn't match its checksum.", self.chunk_id, self.file_name)
+  }
+}
+
+impl Error for CorruptChunkError {}
+
 #[derive(Clone)]
 pub struct ActiveDownload {
+  /// The name of the file in the blob store
+  pub file_name: String,
+
   pub file_size: u64,
-  pub file: Arc<File>
+
+  /// The store the file is read from which is the mirror when the file is missing from the primary store.
+  pub blob_store: Arc<dyn BlobStore>,
+
+  pub from_mirror: bool
 }
 
 pub struct DownloadsManager {
-  user_files_root_directory: PathBuf,
+  blob_store: Arc<dyn BlobStore>,
+
+  /// Read from when a file is missing or corrupted in the primary store
+  mirror_blob_store: Option<Arc<dyn BlobStore>>,
+
+  transfer_limits: TransferLimits,
 
   /// Maps a file's handle string to an active download
   active_downloads_map: Arc<Mutex<HashMap<String, ActiveDownload>>>,
@@ -34,11 +62,13 @@ pub struct DownloadsManager {
 }
 
 impl DownloadsManager {
-  pub fn new(config: &Config) -> Self	{
+  pub fn new(blob_store: Arc<dyn BlobStore>, mirror_blob_store: Option<Arc<dyn BlobStore>>, transfer_limits: TransferLimits) -> Self	{
     let (tx, rx) = mpsc::channel(constants::DOWNLOADS_EXPIRY_MPSC_CHANNEL_BUFFER_SIZE);
 
     Self {
-      user_files_root_directory: PathBuf::from(config.user_files_root_directory.clone()),
+      blob_store,
+      mirror_blob_store,
+      transfer_limits,
       active_downloads_map: Arc::new(Mutex::new(HashMap::new())),
       download_expiry_task_map: Arc::new(Mutex::new(HashMap::new())),
       download_expiry_tx: tx,
@@ -71,9 +101,10 @@ impl DownloadsManager {
   pub async fn set_download_for_expiry(&mut self, handle: String) {
     let tx = self.download_expiry_tx.clone();
     let handle_clone = handle.clone();
+    let expiry_time_ms = self.transfer_limits.active_download_expiry_time_ms;
 
     let task_handle = tokio::spawn(async move {
-      sleep(Duration::from_millis(constants::ACTIVE_DOWNLOAD_EXPIRY_TIME_MS as u64)).await;
+      sleep(Duration::from_millis(expiry_time_ms)).await;
       let _ = tx.send(handle_clone).await;
     });
 
@@ -82,18 +113,64 @@ impl DownloadsManager {
     }
   }
 
+  /// Returns the handles and details of the files that are currently open for download.
+  pub async fn get_active_downloads(&self) -> Vec<(String, ActiveDownload)> {
+    self.active_downloads_map.lock().await.iter()
+      .map(|(handle, download)| (handle.clone(), download.clone()))
+      .collect()
+  }
+
+  /// Closes the downloads of the given files straight away instead of waiting for them to expire, e.g. when the files
+  /// are deleted along with their owner.
+  pub async fn close_downloads(&mut self, handles: &[String]) {
+    let mut downloads_map = self.active_downloads_map.lock().await;
+    let mut expiry_map = self.download_expiry_task_map.lock().await;
+
+    for handle in handles {
+      downloads_map.remove(handle);
+
+      if let Some(expiry_task) = expiry_map.remove(handle) {
+        expiry_task.abort();
+      }
+    }
+  }
+
   /// Opens a file for download
-  pub async fn open_file_for_download(&mut self, user_id: u64, handle: &String) -> Result<(), Box<dyn Error>> {
-    // Create the file path
+  pub async fn open_file_for_download(&mut self, _user_id: u64, handle: &String) -> Result<(), Box<dyn Error>> {
     let file_name = handle.clone() + constants::TREASURY_FILE_EXTENSION;
-    let path = self.user_files_root_directory.join(file_name);
 
-    let file = File::open(&path).await?;
-    let metadata = tokio::fs::metadata(&path).await?;
-    
-    let download = ActiveDownload {
-      file_size: metadata.len(),
-      file: Arc::new(file)
+    let primary_stat = match self.blob_store.stat(&file_name).await {
+      Ok(stat) => stat,
+      Err(err) if self.mirror_blob_store.is_some() => {
+        warn!("Failed to stat {} in the primary store: {}", file_name, err);
+        None
+      },
+      Err(err) => return Err(err.to_string().into())
+    };
+
+    let download = match (primary_stat, &self.mirror_blob_store) {
+      (Some(stat), _) => ActiveDownload {
+        file_name,
+        file_size: stat.size,
+        blob_store: self.blob_store.clone(),
+        from_mirror: false
+      },
+      (None, Some(mirror_blob_store)) => {
+        let stat = mirror_blob_store.stat(&file_name)
+          .await
+          .map_err(|err| err.to_string())?
+          .ok_or("File not found in the blob store or its mirror.")?;
+
+        warn!("{} is missing from the primary store. Reading from the mirror instead.", file_name);
+
+        ActiveDownload {
+          file_name,
+          file_size: stat.size,
+          blob_store: mirror_blob_store.clone(),
+          from_mirror: true
+        }
+      },
+      (None, None) => return Err("File not found in the blob store.".into())
     };
 
     let map = self.active_downloads_map.clone();
@@ -126,26 +203,24 @@ impl DownloadsManager {
       let mut map = self.active_downloads_map.lock().await;
 
       if let Some(download) = map.get_mut(handle) {
-        return Ok(download.clone());
+        Ok(download.clone())
       } else {
-        return Err("Failed to start a download! This shouldn't happen!".into());
+        Err("Failed to start a download! This shouldn't happen!".into())
       }
     }
   }
 
-  /// Tries to read a chunk from an active download. If the provided handle doesn't point to any 
-  /// active download, then it will try and start one.
-  pub async fn try_read_chunk_as_stream(&mut self, user_id: u64, handle: &String, chunk_id: u64) 
-    -> Result<ReaderStream<tokio::io::Take<File>>, Box<dyn Error>> 
-  {
+  /// Prepares a chunk of an active download to be read. If the provided handle doesn't point to any active download,
+  /// then it will try and start one. The chunk itself is read with `ChunkRead::read` after the app state lock has
+  /// been released, since reading it can mean a request to a remote store.
+  pub async fn prepare_chunk_read(&mut self, user_id: u64, handle: &String, chunk_id: u64) -> Result<ChunkRead, Box<dyn Error>> {
     // Try get download from the map
     let download = self.get_download_or_start(user_id, handle).await?;
 
-    // Calculate read size and offset which ignores the chunk header
-    let enc_chunk_size_u64 = constants::ENCRYPTED_CHUNK_SIZE as u64;
+    // Calculate read offset which ignores the chunk header
+    let enc_chunk_size_u64 = self.transfer_limits.encrypted_chunk_size() as u64;
     let enc_file_header_size_u64 = constants::ENCRYPTED_FILE_HEADER_SIZE as u64;
     let read_offset = chunk_id * enc_chunk_size_u64 + enc_file_header_size_u64;
-    let read_size = std::cmp::min(enc_chunk_size_u64, download.file_size - read_offset);
     
     // Validate read offset
     if read_offset > download.file_size { 
@@ -160,15 +235,89 @@ impl DownloadsManager {
       );
     }
 
-    // Create read stream from the file at the location
-    let file = download.file.clone();
-    let mut file = file.as_ref().try_clone().await?;
-    file.seek(SeekFrom::Start(read_offset)).await?;
-    let stream = ReaderStream::new(file.take(read_size));
+    let read_size = std::cmp::min(enc_chunk_size_u64, download.file_size - read_offset);
 
     // Set download for expiry (resets timer)
     self.set_download_for_expiry(handle.clone()).await;
 
-    Ok(stream)
+    Ok(ChunkRead {
+      mirror_blob_store: self.mirror_blob_store.clone().filter(|_| !download.from_mirror),
+      download,
+      chunk_id,
+      read_offset,
+      read_size
+    })
+  }
+}
+
+/// The location of a chunk in a stored file, from `DownloadsManager::prepare_chunk_read`.
+pub struct ChunkRead {
+  download: ActiveDownload,
+
+  /// Read from when the chunk is corrupted, unless the download is already reading from the mirror
+  mirror_blob_store: Option<Arc<dyn BlobStore>>,
+
+  chunk_id: u64,
+  read_offset: u64,
+  read_size: u64
+}
+
+impl ChunkRead {
+  /// Creates a read stream for the chunk. When a checksum is provided, the chunk is verified before it's streamed
+  /// and a `CorruptChunkError` is returned if it doesn't match.
+  pub async fn read(self, expected_checksum: Option<Vec<u8>>) -> Result<ReaderStream<BlobReader>, Box<dyn Error>> {
+    let result = read_chunk(
+      self.download.blob_store.as_ref(),
+      &self.download.file_name,
+      self.chunk_id,
+      self.read_offset,
+      self.read_size,
+      expected_checksum.as_deref()
+    ).await;
+
+    // Corrupted chunks are read from the mirror instead if there is one
+    let reader = match (result, &self.mirror_blob_store) {
+      (Err(err), Some(mirror_blob_store)) if err.is::<CorruptChunkError>() => {
+        warn!("{} Reading it from the mirror instead.", err);
+
+        read_chunk(
+          mirror_blob_store.as_ref(),
+          &self.download.file_name,
+          self.chunk_id,
+          self.read_offset,
+          self.read_size,
+          expected_checksum.as_deref()
+        ).await.map_err(|err| err as Box<dyn Error>)?
+      },
+      (result, _) => result.map_err(|err| err as Box<dyn Error>)?
+    };
+
+    Ok(ReaderStream::new(reader))
+  }
+}
+
+/// Reads a chunk from a store. When a checksum is provided, the whole chunk is read into memory and verified before
+/// it's returned.
+async fn read_chunk(
+  blob_store: &dyn BlobStore,
+  file_name: &str,
+  chunk_id: u64,
+  read_offset: u64,
+  read_size: u64,
+  expected_checksum: Option<&[u8]>
+) -> StorageResult<BlobReader> {
+  let mut reader = blob_store.read_range(file_name, read_offset, read_size).await?;
+
+  if let Some(expected_checksum) = expected_checksum {
+    let mut chunk = Vec::with_capacity(read_size as usize);
+    reader.read_to_end(&mut chunk).await?;
+
+    if calc_chunk_checksum(&chunk) != expected_checksum {
+      return Err(CorruptChunkError { file_name: file_name.to_string(), chunk_id }.into());
+    }
+
+    reader = Box::pin(Cursor::new(chunk));
   }
+
+  Ok(reader)
 }
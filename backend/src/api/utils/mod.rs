@@ -1,3 +1,4 @@
 pub mod auth_utils;
 pub mod upload_utils;
 pub mod download_utils;
+pub mod webauthn_utils;
@@ -1,13 +1,18 @@
 use std::collections::BTreeMap;
 use std::path::PathBuf;
-use tokio::{fs::File, io::{AsyncWriteExt, BufWriter}};
+use std::sync::Arc;
+use tokio::{fs::{File, OpenOptions}, io::{AsyncSeekExt, AsyncWriteExt, BufWriter, SeekFrom}};
 use std::collections::HashMap;
 use std::error::Error;
-use log::error;
+use log::{error, warn};
 use std::cmp;
 
 use crate::{
-  api::formats::calc_raw_chunk_size, config::Config, constants
+  api::formats::{calc_chunk_checksum, calc_encrypted_file_size, calc_raw_chunk_size},
+  config::{Config, TransferLimits},
+  constants,
+  database::SuspendedUpload,
+  storage::BlobStore
 };
 
 pub struct ActiveUpload {
@@ -20,6 +25,9 @@ pub struct ActiveUpload {
   /// The original unencrypted file size
   pub file_size: u64,
 
+  /// The size of the unencrypted data in every chunk except the last.
+  pub chunk_data_size: usize,
+
   /// The amount of bytes written to the file excluding file format overhead (inc. encryption overhead).
   pub written_bytes: u64,
 
@@ -27,7 +35,10 @@ pub struct ActiveUpload {
   pub prev_written_chunk_id: i64,
 
   /// The buffered chunks which are automatically ordered by their chunk id using a BTreeMap.
-  pub buffered_chunks: BTreeMap<i64, Vec<u8>>
+  pub buffered_chunks: BTreeMap<i64, Vec<u8>>,
+
+  /// The checksums of the written encrypted chunks ordered by chunk id.
+  pub chunk_checksums: Vec<Vec<u8>>
 }
 
 impl ActiveUpload {
@@ -36,7 +47,7 @@ impl ActiveUpload {
     self.buffered_chunks.insert(new_chunk_id, data);
 
     // Try to write as many buffered chunks as possible
-    let mut written_chunk_ids: Vec<i64> = Vec::with_capacity(constants::MAX_UPLOAD_CONCURRENT_CHUNKS);
+    let mut written_chunk_ids: Vec<i64> = Vec::with_capacity(self.buffered_chunks.len());
 
     for (chunk_id, chunk) in self.buffered_chunks.iter_mut() {
       let enc_chunk_size = chunk.len() as u64;
@@ -47,7 +58,7 @@ impl ActiveUpload {
 
       let expected_enc_chunk_size = cmp::min(
         bytes_left_to_write + constants::ENCRYPTED_CHUNK_EXTRA_DATA_SIZE as i64,
-        constants::ENCRYPTED_CHUNK_SIZE as i64
+        (self.chunk_data_size + constants::ENCRYPTED_CHUNK_EXTRA_DATA_SIZE) as i64
       );
 
       // Ensure chunk size meets expected encrypted chunk size
@@ -67,7 +78,8 @@ impl ActiveUpload {
         self.prev_written_chunk_id = *chunk_id;
 
         // Write data
-        self.buf_writer.write_all(&chunk).await?;
+        self.buf_writer.write_all(chunk).await?;
+        self.chunk_checksums.push(calc_chunk_checksum(chunk));
 
         // Update
         self.written_bytes += raw_chunk_size;
@@ -88,26 +100,35 @@ impl ActiveUpload {
 }
 
 pub struct UploadsManager {
-  pub user_files_root_directory: PathBuf,
+  /// Where finalised uploads are stored
+  pub blob_store: Arc<dyn BlobStore>,
+
   pub user_upload_directory: PathBuf,
 
+  transfer_limits: TransferLimits,
+
   /// Maps a file's handle string to an active upload
-  pub active_uploads_map: HashMap<String, ActiveUpload>
+  pub active_uploads_map: HashMap<String, ActiveUpload>,
+
+  /// Turned off when the server starts stopping so uploads aren't changed while they're being suspended.
+  accepting_chunks: bool
 }
 
 impl UploadsManager {
-  pub fn new(config: &Config) -> Self	{
+  pub fn new(config: &Config, blob_store: Arc<dyn BlobStore>) -> Self	{
     Self {
-      user_files_root_directory: PathBuf::from(config.user_files_root_directory.clone()),
+      blob_store,
       user_upload_directory: PathBuf::from(config.user_upload_directory.clone()),
-      active_uploads_map: HashMap::new()
+      transfer_limits: config.transfer_limits,
+      active_uploads_map: HashMap::new(),
+      accepting_chunks: true
     }
   }
 
   /// Creates a new upload with the given parameters 
-  pub async fn new_upload(&mut self, user_id: u64, handle: &String, file_size: u64) -> Result<(), Box<dyn Error>> {
+  pub async fn new_upload(&mut self, user_id: u64, handle: &str, file_size: u64) -> Result<(), Box<dyn Error>> {
     // Create the file path
-    let file_name = handle.clone() + constants::TREASURY_FILE_EXTENSION;
+    let file_name = handle.to_string() + constants::TREASURY_FILE_EXTENSION;
     let path = self.user_upload_directory.join(file_name);
 
     // Create the file
@@ -118,24 +139,26 @@ impl UploadsManager {
       buf_writer: BufWriter::new(file),
       upload_file_path: path,
       file_size,
+      chunk_data_size: self.transfer_limits.chunk_data_size,
       written_bytes: 0,
       prev_written_chunk_id: -1,
-      buffered_chunks: BTreeMap::new()
+      buffered_chunks: BTreeMap::new(),
+      chunk_checksums: Vec::new()
     };
 
     // Write header immediately
     upload.buf_writer.write_all(&constants::ENCRYPTED_FILE_MAGIC_NUMBER).await?;
 
     // Insert new active upload into the map
-    self.active_uploads_map.insert(handle.clone(), upload);
+    self.active_uploads_map.insert(handle.to_string(), upload);
 
     Ok(())
   }
 
   /// Removes the upload from the active uploads map and flushes all the written data to the disk.
-  /// It will then move the file from the temporary uploads directory to the user files directory.
-  /// If it fails to finalise, the temporary upload file will be deleted.
-  pub async fn finalise_upload(&mut self, handle: &String) -> Result<(), Box<dyn Error>> {
+  /// It will then move the file from the temporary uploads directory into the blob store.
+  /// If it fails to finalise, the temporary upload file will be deleted. Returns the checksums of the file's chunks.
+  pub async fn finalise_upload(&mut self, handle: &String) -> Result<Vec<Vec<u8>>, Box<dyn Error>> {
     // Ensure handle is valid
     if !self.is_handle_valid(handle) {
       return Err("No active upload with the provided handle was found.".into());
@@ -145,27 +168,10 @@ impl UploadsManager {
     let mut upload = self.active_uploads_map.remove(handle).unwrap();
     upload.buf_writer.shutdown().await?;
 
-    // Move uploaded file to user files directory
-    let file_name = handle.clone() + constants::TREASURY_FILE_EXTENSION;
-
-    let new_file_path = PathBuf::from(self.user_files_root_directory.clone())
-      .join(file_name);
-
-    let _ = tokio::fs::rename(&upload.upload_file_path, &new_file_path)
-      .await
-      .map_err(|err| {
-        error!(
-          "Failed to move file from uploads to user files directory! Operation: {:?} -> {:?} and error was: {}",
-          upload.upload_file_path,
-          new_file_path,
-          err
-        );
-
-        err
-      })?;
-
     // Ensure correct number of bytes have been written
     if upload.written_bytes != upload.file_size {
+      let _ = tokio::fs::remove_file(&upload.upload_file_path).await;
+
       return Err(
         format!(
           "Can't finalise. Bytes left to write: {}",
@@ -174,9 +180,133 @@ impl UploadsManager {
       );
     }
 
+    // Move uploaded file into the blob store
+    let file_name = handle.clone() + constants::TREASURY_FILE_EXTENSION;
+
+    if let Err(err) = self.blob_store.put(&file_name, &upload.upload_file_path).await {
+      error!(
+        "Failed to move file from uploads directory to the blob store! Operation: {:?} -> {} and error was: {}",
+        upload.upload_file_path,
+        file_name,
+        err
+      );
+
+      let _ = tokio::fs::remove_file(&upload.upload_file_path).await;
+
+      return Err(err.to_string().into());
+    }
+
+    Ok(upload.chunk_checksums)
+  }
+
+  pub fn stop_accepting_chunks(&mut self) {
+    self.accepting_chunks = false;
+  }
+
+  pub fn is_accepting_chunks(&self) -> bool {
+    self.accepting_chunks
+  }
+
+  /// Flushes and syncs every active upload's temporary file to disk and returns their progress so it can be saved.
+  /// Chunks that are buffered but not written yet are dropped, the client sends them again when continuing.
+  pub async fn suspend_uploads(&mut self) -> Vec<SuspendedUpload> {
+    let mut suspended_uploads = Vec::with_capacity(self.active_uploads_map.len());
+
+    for (handle, mut upload) in self.active_uploads_map.drain() {
+      let flush_result = match upload.buf_writer.flush().await {
+        Ok(_) => upload.buf_writer.get_ref().sync_all().await,
+        Err(err) => Err(err)
+      };
+
+      if let Err(err) = flush_result {
+        error!("Failed to flush active upload {}: {}", handle, err);
+        continue;
+      }
+
+      suspended_uploads.push(SuspendedUpload {
+        handle,
+        owner_id: upload.user_id,
+        file_size: upload.file_size,
+        written_bytes: upload.written_bytes,
+        prev_written_chunk_id: upload.prev_written_chunk_id,
+        chunk_checksums: upload.chunk_checksums
+      });
+    }
+
+    suspended_uploads
+  }
+
+  /// Makes uploads that were suspended when the server stopped active again. Each temporary file is cut back to the
+  /// saved progress in case the server crashed after more was written. Uploads whose temporary file is missing or too
+  /// short are dropped. Returns the number of uploads restored.
+  pub async fn restore_uploads(&mut self, suspended_uploads: Vec<SuspendedUpload>) -> usize {
+    let mut restored_count = 0;
+
+    for suspended_upload in suspended_uploads {
+      let handle = suspended_upload.handle.clone();
+
+      match self.restore_upload(suspended_upload).await {
+        Ok(_) => restored_count += 1,
+        Err(err) => warn!("Couldn't restore upload {}: {}", handle, err)
+      };
+    }
+
+    restored_count
+  }
+
+  async fn restore_upload(&mut self, suspended_upload: SuspendedUpload) -> Result<(), Box<dyn Error>> {
+    let file_name = suspended_upload.handle.clone() + constants::TREASURY_FILE_EXTENSION;
+    let path = self.user_upload_directory.join(file_name);
+
+    // Every chunk but the last is full sized, so the expected size can be calculated like for a whole file
+    let expected_file_size = calc_encrypted_file_size(suspended_upload.written_bytes, self.transfer_limits.chunk_data_size);
+
+    let mut file = OpenOptions::new().write(true).open(&path).await?;
+    let file_size = file.metadata().await?.len();
+
+    if file_size < expected_file_size {
+      let _ = tokio::fs::remove_file(&path).await;
+      return Err(format!("The temporary file is {} bytes but should be {} bytes.", file_size, expected_file_size).into());
+    }
+
+    file.set_len(expected_file_size).await?;
+    file.seek(SeekFrom::End(0)).await?;
+
+    self.active_uploads_map.insert(suspended_upload.handle, ActiveUpload {
+      user_id: suspended_upload.owner_id,
+      buf_writer: BufWriter::new(file),
+      upload_file_path: path,
+      file_size: suspended_upload.file_size,
+      chunk_data_size: self.transfer_limits.chunk_data_size,
+      written_bytes: suspended_upload.written_bytes,
+      prev_written_chunk_id: suspended_upload.prev_written_chunk_id,
+      buffered_chunks: BTreeMap::new(),
+      chunk_checksums: suspended_upload.chunk_checksums
+    });
+
     Ok(())
   }
 
+  /// Drops a user's active uploads and deletes their temporary files, e.g. when the user is deleted. Returns the number
+  /// of uploads cancelled.
+  pub async fn cancel_user_uploads(&mut self, user_id: u64) -> usize {
+    let handles: Vec<String> = self.active_uploads_map.iter()
+      .filter(|(_, upload)| upload.user_id == user_id)
+      .map(|(handle, _)| handle.clone())
+      .collect();
+
+    for handle in &handles {
+      let upload = self.active_uploads_map.remove(handle).unwrap();
+      drop(upload.buf_writer);
+
+      if let Err(err) = tokio::fs::remove_file(&upload.upload_file_path).await {
+        warn!("Failed to delete cancelled upload {}: {}", handle, err);
+      }
+    }
+
+    handles.len()
+  }
+
   pub async fn get_active_upload(&mut self, handle: &String) -> Option<&mut ActiveUpload> {
     self.active_uploads_map.get_mut(handle)
   }
@@ -0,0 +1,116 @@
+use webauthn_rs::prelude::*;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tower_sessions::Session;
+use std::error::Error;
+
+use crate::{
+  config::Config, constants, database::WebauthnCredentialEntry, util::get_unix_timestamp_secs
+};
+
+/// The state of a registration or login assertion that is waiting for the authenticator's response. It's kept in the
+/// session of the user that started it, which is stored on the server, so it never reaches the client.
+#[derive(Serialize, Deserialize)]
+struct PendingCeremony<T> {
+  state: T,
+  user_id: u64,
+  start_time: u64
+}
+
+pub struct WebauthnManager {
+  webauthn: Webauthn
+}
+
+impl WebauthnManager {
+  pub fn new(config: &Config) -> Result<Self, Box<dyn Error>> {
+    let rp_origin = Url::parse(&config.webauthn_rp_origin)?;
+
+    let webauthn = WebauthnBuilder::new(&config.webauthn_rp_id, &rp_origin)?
+      .rp_name(constants::WEBAUTHN_RP_NAME)
+      .build()?;
+
+    Ok(Self { webauthn })
+  }
+
+  /// Creates the challenge for registering a new credential. The user's existing credentials are excluded so the
+  /// same authenticator can't be registered twice.
+  pub async fn start_registration(&self, session: &Session, user_id: u64, username: &str, existing_passkeys: &[Passkey]) -> Result<CreationChallengeResponse, Box<dyn Error>> {
+    let user_unique_id = Uuid::from_u64_pair(0, user_id);
+    let exclude_credentials = existing_passkeys.iter()
+      .map(|passkey| passkey.cred_id().clone())
+      .collect();
+
+    let (challenge, registration) = self.webauthn.start_passkey_registration(
+      user_unique_id,
+      username,
+      username,
+      Some(exclude_credentials)
+    )?;
+
+    insert_pending_ceremony(session, constants::SESSION_WEBAUTHN_REGISTRATION_KEY, registration, user_id).await?;
+
+    Ok(challenge)
+  }
+
+  /// Verifies the authenticator's response to the challenge from `start_registration`. The challenge can only be
+  /// used once.
+  pub async fn finish_registration(&self, session: &Session, user_id: u64, response: &RegisterPublicKeyCredential) -> Result<Passkey, Box<dyn Error>> {
+    let registration: PasskeyRegistration = take_pending_ceremony(session, constants::SESSION_WEBAUTHN_REGISTRATION_KEY, user_id).await?
+      .ok_or("No credential registration was started.")?;
+
+    Ok(self.webauthn.finish_passkey_registration(response, &registration)?)
+  }
+
+  /// Creates the challenge that one of the user's credentials must sign to finish logging in.
+  pub async fn start_authentication(&self, session: &Session, user_id: u64, passkeys: &[Passkey]) -> Result<RequestChallengeResponse, Box<dyn Error>> {
+    let (challenge, authentication) = self.webauthn.start_passkey_authentication(passkeys)?;
+
+    insert_pending_ceremony(session, constants::SESSION_WEBAUTHN_AUTHENTICATION_KEY, authentication, user_id).await?;
+
+    Ok(challenge)
+  }
+
+  /// Verifies the assertion for the challenge from `start_authentication`. The challenge can only be used once.
+  pub async fn finish_authentication(&self, session: &Session, user_id: u64, response: &PublicKeyCredential) -> Result<AuthenticationResult, Box<dyn Error>> {
+    let authentication: PasskeyAuthentication = take_pending_ceremony(session, constants::SESSION_WEBAUTHN_AUTHENTICATION_KEY, user_id).await?
+      .ok_or("No login assertion was started.")?;
+
+    Ok(self.webauthn.finish_passkey_authentication(response, &authentication)?)
+  }
+}
+
+async fn insert_pending_ceremony<T: Serialize>(session: &Session, key: &str, state: T, user_id: u64) -> Result<(), Box<dyn Error>> {
+  let pending = PendingCeremony { state, user_id, start_time: get_unix_timestamp_secs() };
+  session.insert(key, pending).await?;
+
+  Ok(())
+}
+
+/// Removes the pending ceremony from the session and returns its state if it belongs to the user and hasn't expired.
+/// Ceremonies expire after the same time as pending logins.
+async fn take_pending_ceremony<T: DeserializeOwned>(session: &Session, key: &str, user_id: u64) -> Result<Option<T>, Box<dyn Error>> {
+  let pending = match session.remove::<PendingCeremony<T>>(key).await? {
+    Some(pending) => pending,
+    None => return Ok(None)
+  };
+
+  let is_expired = get_unix_timestamp_secs().saturating_sub(pending.start_time) > constants::PENDING_LOGIN_EXPIRY_TIME_SECONDS;
+
+  if pending.user_id != user_id || is_expired {
+    return Ok(None);
+  }
+
+  Ok(Some(pending.state))
+}
+
+pub fn passkey_to_credential_entry(passkey: &Passkey) -> Result<WebauthnCredentialEntry, serde_json::Error> {
+  Ok(WebauthnCredentialEntry {
+    credential_id: passkey.cred_id().to_vec(),
+    credential: serde_json::to_string(passkey)?
+  })
+}
+
+pub fn credential_entries_to_passkeys(entries: &[WebauthnCredentialEntry]) -> Result<Vec<Passkey>, serde_json::Error> {
+  entries.iter()
+    .map(|entry| serde_json::from_str(&entry.credential))
+    .collect()
+}
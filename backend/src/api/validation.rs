@@ -111,7 +111,7 @@ macro_rules! validate_string_length_range {
     {
       let length = $self.$property.len();
       
-      if length < $min_len || length > $max_len {
+      if !($min_len..=$max_len).contains(&length) {
         return Err(
           format!(
             "String '{}' length out of range. Got length {} but valid range is {}-{} inclusive.",
@@ -125,6 +125,7 @@ macro_rules! validate_string_length_range {
 
 #[macro_export]
 macro_rules! validate_base64_byte_size {
+  // Match when 'self' is provided
   ($self:ident, $property:ident, $expected_len:expr) => {
     {
       if let Ok(bytes) = general_purpose::STANDARD.decode(&$self.$property) {
@@ -141,6 +142,24 @@ macro_rules! validate_base64_byte_size {
       }
     }
   };
+
+  // Match when there is no 'self'
+  ($string:expr, $expected_len:expr) => {
+    {
+      if let Ok(bytes) = general_purpose::STANDARD.decode($string) {
+        if bytes.len() != $expected_len {
+          return Err(
+            format!(
+              "Expected base64 '{}' size to be {} but got size {}.",
+              stringify!($string), $expected_len, bytes.len()
+            ).into()
+          );
+        }
+      } else {
+        return Err(format!("Base64 '{}' is invalid.", stringify!($string)).into());
+      }
+    }
+  };
 }
 
 /// Asserts that a base64 string represents a byte size that doesn't exceed a specified limit.
@@ -0,0 +1,243 @@
+use axum::{
+  extract::{ConnectInfo, State}, response::IntoResponse, Json
+};
+
+use webauthn_rs::prelude::{PublicKeyCredential, RegisterPublicKeyCredential};
+use http::{header::RETRY_AFTER, StatusCode};
+use std::sync::Arc;
+use std::net::SocketAddr;
+use tower_sessions::Session;
+use tokio::sync::Mutex;
+use log::{error, warn};
+
+use crate::{
+  AppState,
+  constants,
+  api::{
+    general::complete_login_factor,
+    utils::{
+      auth_utils::{get_pending_login, get_user_session_data, LoginAttemptIdentifiers},
+      webauthn_utils::{credential_entries_to_passkeys, passkey_to_credential_entry}
+    }
+  },
+  util::get_unix_timestamp_secs,
+  get_session_data_or_return_unauthorized
+};
+
+// ----------------------------------------------
+// API - Start credential registration
+// ----------------------------------------------
+
+pub async fn start_registration_api(
+  session: Session,
+  State(state): State<Arc<Mutex<AppState>>>
+) -> impl IntoResponse {
+  let session_data = get_session_data_or_return_unauthorized!(session, state);
+
+  // Acquire app state
+  let mut app_state = state.lock().await;
+  let database = app_state.database.as_mut().unwrap();
+
+  let existing_passkeys = match database.get_user_webauthn_credentials(session_data.user_id) {
+    Ok(entries) => credential_entries_to_passkeys(&entries),
+    Err(err) => {
+      error!("rusqlite error: {}", err);
+      return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+  };
+
+  let existing_passkeys = match existing_passkeys {
+    Ok(passkeys) => passkeys,
+    Err(err) => {
+      error!("Failed to deserialise WebAuthn credentials: {}", err);
+      return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+  };
+
+  match app_state.webauthn_manager.start_registration(&session, session_data.user_id, &session_data.username, &existing_passkeys).await {
+    Ok(challenge) => Json(challenge).into_response(),
+    Err(err) => {
+      error!("Start WebAuthn registration error: {}", err);
+      StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    }
+  }
+}
+
+// ----------------------------------------------
+// API - Finish credential registration
+// ----------------------------------------------
+
+pub async fn finish_registration_api(
+  session: Session,
+  State(state): State<Arc<Mutex<AppState>>>,
+  Json(req): Json<RegisterPublicKeyCredential>
+) -> impl IntoResponse {
+  let session_data = get_session_data_or_return_unauthorized!(session, state);
+
+  // Acquire app state
+  let mut app_state = state.lock().await;
+
+  let passkey = match app_state.webauthn_manager.finish_registration(&session, session_data.user_id, &req).await {
+    Ok(passkey) => passkey,
+    Err(err) => return (StatusCode::BAD_REQUEST, err.to_string()).into_response()
+  };
+
+  let entry = match passkey_to_credential_entry(&passkey) {
+    Ok(entry) => entry,
+    Err(err) => {
+      error!("Failed to serialise WebAuthn credential: {}", err);
+      return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+  };
+
+  let database = app_state.database.as_mut().unwrap();
+
+  // A credential can't be registered to more than one account
+  match database.is_webauthn_credential_registered(&entry.credential_id) {
+    Ok(true) => return (StatusCode::CONFLICT, "Credential is already registered.").into_response(),
+    Ok(false) => (),
+    Err(err) => {
+      error!("rusqlite error: {}", err);
+      return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+  };
+
+  match database.insert_webauthn_credential(session_data.user_id, &entry) {
+    Ok(_) => StatusCode::OK.into_response(),
+    Err(err) => {
+      error!("rusqlite error: {}", err);
+      StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    }
+  }
+}
+
+// ----------------------------------------------
+// API - Start login assertion
+// ----------------------------------------------
+
+/// Creates the challenge for the second login step of users with WebAuthn credentials. Must be called after
+/// `login_api` with the same session.
+pub async fn login_webauthn_start_api(
+  session: Session,
+  State(state): State<Arc<Mutex<AppState>>>
+) -> impl IntoResponse {
+  let username = match get_pending_login(&session).await {
+    Some(pending_login) if pending_login.requires(constants::SECOND_FACTOR_WEBAUTHN) => pending_login.username,
+    _ => return StatusCode::UNAUTHORIZED.into_response()
+  };
+
+  // Acquire app state
+  let mut app_state = state.lock().await;
+  let database = app_state.database.as_mut().unwrap();
+
+  let user_id = match database.get_user_data(&username) {
+    Ok(data) => data.user_id.unwrap(),
+    Err(_) => return StatusCode::UNAUTHORIZED.into_response()
+  };
+
+  let passkeys = match database.get_user_webauthn_credentials(user_id) {
+    Ok(entries) => credential_entries_to_passkeys(&entries),
+    Err(err) => {
+      error!("rusqlite error: {}", err);
+      return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+  };
+
+  let passkeys = match passkeys {
+    Ok(passkeys) if !passkeys.is_empty() => passkeys,
+    Ok(_) => return (StatusCode::BAD_REQUEST, "User has no WebAuthn credentials.").into_response(),
+    Err(err) => {
+      error!("Failed to deserialise WebAuthn credentials: {}", err);
+      return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+  };
+
+  match app_state.webauthn_manager.start_authentication(&session, user_id, &passkeys).await {
+    Ok(challenge) => Json(challenge).into_response(),
+    Err(err) => {
+      error!("Start WebAuthn authentication error: {}", err);
+      StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    }
+  }
+}
+
+// ----------------------------------------------
+// API - Finish login assertion
+// ----------------------------------------------
+
+pub async fn login_webauthn_finish_api(
+  session: Session,
+  State(state): State<Arc<Mutex<AppState>>>,
+  ConnectInfo(address): ConnectInfo<SocketAddr>,
+  Json(req): Json<PublicKeyCredential>
+) -> impl IntoResponse {
+  let pending_login = match get_pending_login(&session).await {
+    Some(pending_login) if pending_login.requires(constants::SECOND_FACTOR_WEBAUTHN) => pending_login,
+    _ => return StatusCode::UNAUTHORIZED.into_response()
+  };
+
+  let username = pending_login.username.clone();
+  let identifiers = LoginAttemptIdentifiers::new(&address, &username);
+  let now = get_unix_timestamp_secs();
+
+  // Acquire app state
+  let mut app_state = state.lock().await;
+  let app_state = &mut *app_state;
+  let database = app_state.database.as_mut().unwrap();
+
+  // Assertions are rate limited the same way as passwords
+  match identifiers.get_retry_after_seconds(database, now) {
+    Ok(Some(retry_after)) => {
+      return (StatusCode::TOO_MANY_REQUESTS, [(RETRY_AFTER, retry_after.to_string())]).into_response();
+    },
+    Ok(None) => (),
+    Err(err) => {
+      error!("rusqlite error: {}", err);
+      return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+  }
+
+  let user_data = match database.get_user_data(&username) {
+    Ok(data) => data,
+    Err(_) => return StatusCode::UNAUTHORIZED.into_response()
+  };
+
+  let result = match app_state.webauthn_manager.finish_authentication(&session, user_data.user_id.unwrap(), &req).await {
+    Ok(result) => result,
+    Err(err) => {
+      warn!("WebAuthn assertion failed for {}: {}", username, err);
+
+      if let Err(err) = identifiers.record_failure(database, now) {
+        error!("rusqlite error: {}", err);
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+      }
+
+      return StatusCode::UNAUTHORIZED.into_response();
+    }
+  };
+
+  // Store the credential's new signature counter
+  let entries = match database.get_user_webauthn_credentials(user_data.user_id.unwrap()) {
+    Ok(entries) => entries,
+    Err(err) => {
+      error!("rusqlite error: {}", err);
+      return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+  };
+
+  for mut passkey in credential_entries_to_passkeys(&entries).unwrap_or_default() {
+    if passkey.update_credential(&result) != Some(true) {
+      continue;
+    }
+
+    let updated = passkey_to_credential_entry(&passkey)
+      .map_err(|err| err.to_string())
+      .and_then(|entry| database.update_webauthn_credential(&entry).map_err(|err| err.to_string()));
+
+    if let Err(err) = updated {
+      error!("Failed to update WebAuthn credential: {}", err);
+    }
+  }
+
+  complete_login_factor(&session, database, &identifiers, user_data, pending_login, constants::SECOND_FACTOR_WEBAUTHN).await
+}
@@ -0,0 +1,277 @@
+use serde::{Deserialize, Serialize};
+use rusqlite::backup::Backup;
+use std::cmp;
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tokio::time::{interval, Duration};
+use log::{error, info, warn};
+
+use crate::{
+  AppState,
+  config::Config,
+  constants,
+  database::{check_integrity, get_schema_version, get_stored_chunk_data_size, open_connection, LATEST_SCHEMA_VERSION},
+  storage::BlobStore,
+  util::get_unix_timestamp_secs
+};
+
+type BackupResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+/// Written next to each snapshot so a restore can tell which stored user files the snapshot expects to exist.
+#[derive(Serialize, Deserialize)]
+pub struct BackupManifest {
+  /// Unix timestamp in seconds of when the snapshot was taken.
+  pub created_time: u64,
+
+  pub schema_version: u32,
+
+  /// The keys of every stored user file when the snapshot was taken.
+  pub blob_keys: Vec<String>
+}
+
+pub struct SnapshotInfo {
+  pub path: PathBuf,
+
+  /// Unix timestamp in seconds of when the snapshot was taken.
+  pub created_time: u64
+}
+
+pub struct BackupReport {
+  pub snapshot_path: PathBuf,
+
+  /// Whether the manifest was written. Without it a restore can't compare the stored user files with the snapshot.
+  pub has_manifest: bool
+}
+
+pub struct RestoreReport {
+  pub schema_version: u32,
+
+  /// Whether the snapshot had a manifest to compare the stored user files against.
+  pub has_manifest: bool,
+
+  /// Stored user files that existed when the snapshot was taken but don't anymore.
+  pub missing_blob_count: usize,
+
+  /// Stored user files that were added after the snapshot was taken and are now orphaned.
+  pub orphaned_blob_count: usize
+}
+
+/// Takes a consistent snapshot of the database with SQLite's online backup API while the server keeps running, writes
+/// its manifest and deletes snapshots beyond the retention count. The snapshot is kept even if its manifest can't be
+/// written, since it can still be restored, but the report says so.
+pub async fn create_backup(shared_app_state: Arc<Mutex<AppState>>) -> BackupResult<BackupReport> {
+  let (config, blob_store) = {
+    let app_state = shared_app_state.lock().await;
+    (app_state.config.clone(), app_state.uploads_manager.blob_store.clone())
+  };
+
+  let created_time = get_unix_timestamp_secs();
+  let snapshot_path = get_snapshot_path(&config, created_time);
+
+  // The backup copies pages on its own connection so the app state doesn't have to be locked
+  let schema_version = {
+    let config = config.clone();
+    let snapshot_path = snapshot_path.clone();
+
+    tokio::task::spawn_blocking(move || backup_database(&config, &snapshot_path).map_err(|err| err.to_string()))
+      .await??
+  };
+
+  // Stored files are listed after the snapshot so every file the snapshot refers to is included
+  let has_manifest = match write_manifest(blob_store.as_ref(), &snapshot_path, created_time, schema_version).await {
+    Ok(_) => true,
+    Err(err) => {
+      error!("Failed to write the manifest for {}: {}", snapshot_path.display(), err);
+      false
+    }
+  };
+
+  info!("Backed up the database to: {}", snapshot_path.display());
+
+  if let Err(err) = prune_snapshots(&config) {
+    warn!("Failed to delete old database backups: {}", err);
+  }
+
+  Ok(BackupReport { snapshot_path, has_manifest })
+}
+
+/// Replaces the database with a snapshot. The app state stays locked while restoring so no requests can write to the
+/// database halfway through. Snapshots that fail SQLite's integrity check are refused. Snapshots from older schema
+/// versions are migrated, newer ones are refused, as are ones whose files use a different chunk size than
+/// CHUNK_DATA_SIZE.
+pub async fn restore_backup(shared_app_state: Arc<Mutex<AppState>>, snapshot_path: &Path) -> BackupResult<RestoreReport> {
+  let (config, blob_store) = {
+    let app_state = shared_app_state.lock().await;
+    (app_state.config.clone(), app_state.uploads_manager.blob_store.clone())
+  };
+
+  let snapshot = open_connection(snapshot_path, config.at_rest_key.as_ref().map(|key| key.as_slice()))
+    .map_err(|err| err.to_string())?;
+
+  // Badly damaged databases fail the check itself rather than reporting problems
+  let problems = check_integrity(&snapshot).unwrap_or_else(|err| vec![err.to_string()]);
+
+  if !problems.is_empty() {
+    return Err(format!("The snapshot failed its integrity check: {}", problems.join("; ")).into());
+  }
+
+  let schema_version = get_schema_version(&snapshot)?;
+
+  if schema_version == 0 || schema_version > LATEST_SCHEMA_VERSION {
+    return Err(
+      format!(
+        "The snapshot's schema version is {} but only versions 1 to {} can be restored.",
+        schema_version,
+        LATEST_SCHEMA_VERSION
+      ).into()
+    );
+  }
+
+  // Checked before anything is replaced since the files couldn't be read after restoring
+  let chunk_data_size = config.transfer_limits.chunk_data_size;
+
+  if let Some(stored_chunk_data_size) = get_stored_chunk_data_size(&snapshot)?.filter(|size| *size != chunk_data_size) {
+    return Err(
+      format!(
+        "The snapshot's files use a chunk size of {} but CHUNK_DATA_SIZE is {}.",
+        stored_chunk_data_size,
+        chunk_data_size
+      ).into()
+    );
+  }
+
+  {
+    let mut app_state = shared_app_state.lock().await;
+    let database = app_state.database.as_mut().unwrap();
+
+    database.restore_from(&snapshot, Path::new(&config.database_path))
+      .map_err(|err| err.to_string())?;
+
+    database.check_chunk_data_size(chunk_data_size)
+      .map_err(|err| err.to_string())?;
+  }
+
+  info!("Restored the database from: {}", snapshot_path.display());
+
+  let mut report = RestoreReport {
+    schema_version,
+    has_manifest: false,
+    missing_blob_count: 0,
+    orphaned_blob_count: 0
+  };
+
+  // Compare the stored files with the ones that existed when the snapshot was taken
+  if let Ok(manifest_json) = fs::read_to_string(get_manifest_path(snapshot_path)) {
+    let manifest: BackupManifest = serde_json::from_str(&manifest_json)?;
+    let manifest_keys: HashSet<String> = manifest.blob_keys.into_iter().collect();
+    let current_keys: HashSet<String> = blob_store.list().await?.into_iter().collect();
+
+    report.has_manifest = true;
+    report.missing_blob_count = manifest_keys.difference(&current_keys).count();
+    report.orphaned_blob_count = current_keys.difference(&manifest_keys).count();
+  }
+
+  Ok(report)
+}
+
+/// Lists the snapshots in the backup directory from newest to oldest.
+pub fn list_snapshots(config: &Config) -> BackupResult<Vec<SnapshotInfo>> {
+  let mut snapshots = Vec::new();
+
+  for entry in fs::read_dir(&config.backup_directory)? {
+    let entry = entry?;
+    let file_name = entry.file_name().to_string_lossy().to_string();
+
+    let created_time = file_name.strip_prefix(constants::DATABASE_BACKUP_FILE_PREFIX)
+      .and_then(|name| name.strip_suffix(constants::DATABASE_BACKUP_FILE_EXTENSION))
+      .and_then(|timestamp| timestamp.parse().ok());
+
+    if let Some(created_time) = created_time {
+      snapshots.push(SnapshotInfo { path: entry.path(), created_time });
+    }
+  }
+
+  snapshots.sort_by_key(|snapshot| cmp::Reverse(snapshot.created_time));
+
+  Ok(snapshots)
+}
+
+/// Spawns a task that takes a snapshot every `interval_hours`. The first snapshot is taken one interval after starting.
+pub fn start_scheduled_backups(shared_app_state: Arc<Mutex<AppState>>, interval_hours: u64) {
+  tokio::spawn(async move {
+    let mut backup_interval = interval(Duration::from_secs(interval_hours * 60 * 60));
+
+    // The first tick completes immediately
+    backup_interval.tick().await;
+
+    loop {
+      backup_interval.tick().await;
+
+      if let Err(err) = create_backup(shared_app_state.clone()).await {
+        error!("Scheduled database backup failed: {}", err);
+      }
+    }
+  });
+}
+
+/// Copies the database into a new snapshot file and returns the snapshot's schema version. The snapshot is written
+/// under a temporary name first so an interrupted backup is never mistaken for a complete one.
+fn backup_database(config: &Config, snapshot_path: &Path) -> Result<u32, Box<dyn Error>> {
+  let at_rest_key = config.at_rest_key.as_ref().map(|key| key.as_slice());
+  let partial_path = snapshot_path.with_extension("partial");
+
+  {
+    // Snapshots are encrypted with the same key as the database
+    let source = open_connection(Path::new(&config.database_path), at_rest_key)?;
+    let mut snapshot = open_connection(&partial_path, at_rest_key)?;
+
+    Backup::new(&source, &mut snapshot)?.run_to_completion(
+      constants::DATABASE_BACKUP_PAGES_PER_STEP,
+      std::time::Duration::from_millis(constants::DATABASE_BACKUP_STEP_PAUSE_MS),
+      None
+    )?;
+  }
+
+  fs::rename(&partial_path, snapshot_path)?;
+
+  let schema_version = get_schema_version(&open_connection(snapshot_path, at_rest_key)?)?;
+
+  Ok(schema_version)
+}
+
+async fn write_manifest(blob_store: &dyn BlobStore, snapshot_path: &Path, created_time: u64, schema_version: u32) -> BackupResult<()> {
+  let manifest = BackupManifest { created_time, schema_version, blob_keys: blob_store.list().await? };
+  fs::write(get_manifest_path(snapshot_path), serde_json::to_string(&manifest)?)?;
+
+  Ok(())
+}
+
+/// Deletes the oldest snapshots and their manifests beyond the retention count. A retention count of 0 keeps all.
+fn prune_snapshots(config: &Config) -> BackupResult<()> {
+  if config.backup_retention_count == 0 {
+    return Ok(());
+  }
+
+  for snapshot in list_snapshots(config)?.iter().skip(config.backup_retention_count) {
+    fs::remove_file(&snapshot.path)?;
+    let _ = fs::remove_file(get_manifest_path(&snapshot.path));
+
+    info!("Deleted old database backup: {}", snapshot.path.display());
+  }
+
+  Ok(())
+}
+
+fn get_snapshot_path(config: &Config, created_time: u64) -> PathBuf {
+  Path::new(&config.backup_directory).join(
+    format!("{}{}{}", constants::DATABASE_BACKUP_FILE_PREFIX, created_time, constants::DATABASE_BACKUP_FILE_EXTENSION)
+  )
+}
+
+fn get_manifest_path(snapshot_path: &Path) -> PathBuf {
+  snapshot_path.with_extension(constants::DATABASE_BACKUP_MANIFEST_EXTENSION.trim_start_matches('.'))
+}
@@ -0,0 +1,363 @@
+//! Command line arguments. Running the binary without a subcommand starts the server, with the interactive shell
+//! unless `--no-shell` is given. The `admin` subcommands work directly on the database instead, so admin tasks can be
+//! scripted without the interactive shell.
+
+use clap::{arg, command, value_parser, Arg, ArgMatches, Command};
+use serde::Serialize;
+use std::error::Error;
+
+use crate::{
+  admin::{self, create_claim_code, rename_user, NewClaimCode},
+  config::{get_setting_flag_name, Config},
+  database::{ClaimCodeData, Database, UserData},
+  util::{get_unix_timestamp_secs, parse_byte_size_str}
+};
+
+/// Recorded as the creator of claim codes made with the admin subcommands.
+const CLAIM_CODE_CREATOR: &str = "cli";
+
+#[derive(Serialize)]
+struct ClaimCodeOutput {
+  claim_code: String,
+  storage_quota: u64,
+  status: &'static str,
+  expiry_time: Option<u64>,
+  max_uses: u64,
+  use_count: u64,
+  note: Option<String>,
+  created_by: Option<String>,
+  created_time: Option<u64>,
+  revoked_time: Option<u64>
+}
+
+impl From<ClaimCodeData> for ClaimCodeOutput {
+  fn from(data: ClaimCodeData) -> Self {
+    Self {
+      status: data.get_status(get_unix_timestamp_secs()).as_str(),
+      claim_code: data.claim_code,
+      storage_quota: data.storage_quota,
+      expiry_time: data.expiry_time,
+      max_uses: data.max_uses,
+      use_count: data.use_count,
+      note: data.note,
+      created_by: data.created_by,
+      created_time: data.created_time,
+      revoked_time: data.revoked_time
+    }
+  }
+}
+
+#[derive(Serialize)]
+struct UserOutput {
+  user_id: u64,
+  username: String,
+  storage_quota: u64,
+  storage_used: u64,
+  has_recovery: bool,
+  disabled: bool,
+  is_admin: bool
+}
+
+pub fn build_cli() -> Command {
+  let mut command = command!();
+
+  // Every setting can be given as a flag, which overrides the config file and the environment. The values are
+  // parsed and validated along with the other layers by `Config::initialise`.
+  for name in Config::setting_names() {
+    let flag_name = get_setting_flag_name(name);
+
+    let arg = Arg::new(flag_name.clone())
+      .long(flag_name)
+      .value_name("value")
+      .help(format!("Sets {}.", name))
+      .help_heading("Settings");
+
+    // The names of the flags from before every setting had one
+    let arg = match name {
+      "IP_ADDRESS" => arg.alias("address"),
+      "SECURE_COOKIES" => arg.alias("securecookies"),
+      _ => arg
+    };
+
+    command = command.arg(arg);
+  }
+
+  command
+    .arg(
+      arg!(--config <path> "The TOML config file to read. Defaults to treasury.toml if it exists.")
+        .required(false)
+        .value_parser(value_parser!(String))
+    )
+    .arg(
+      arg!(--"print-config" "Print the config from every source with secrets redacted and exit.")
+    )
+    .arg(
+      arg!(--"no-shell" "Run without the interactive shell and stop on SIGTERM or CTRL+C, e.g. under systemd or in a container.")
+    )
+    .subcommand(
+      Command::new("admin")
+        .about("Administer accounts without starting the server.")
+        .subcommand_required(true)
+        .arg(arg!(--json "Print the output as JSON.").global(true))
+        .subcommand(
+          Command::new("newcode")
+            .about("Create a claim code that lets people register accounts.")
+            .arg(arg!(--quota <size> "The storage quota of accounts claimed with the code, e.g. 10GB.").required(true))
+            .arg(
+              arg!(--"max-uses" <number> "The number of accounts that can be claimed with the code.")
+                .value_parser(value_parser!(u64))
+                .default_value("1")
+            )
+            .arg(
+              arg!(--days <number> "The number of days until the code expires. 0 never expires.")
+                .value_parser(value_parser!(u64))
+                .default_value("0")
+            )
+            .arg(arg!(--note <text> "A note about who the code is for.").required(false))
+        )
+        .subcommand(
+          Command::new("codes")
+            .about("Manage claim codes.")
+            .subcommand_required(true)
+            .subcommand(Command::new("list").about("List all claim codes."))
+            .subcommand(
+              Command::new("revoke")
+                .about("Stop a claim code from being used.")
+                .arg(arg!(<code> "The claim code to revoke."))
+            )
+        )
+        .subcommand(
+          Command::new("users")
+            .about("Manage users.")
+            .subcommand_required(true)
+            .subcommand(Command::new("list").about("List all users."))
+            .subcommand(
+              Command::new("setquota")
+                .about("Change a user's storage quota.")
+                .arg(arg!(<username> "The user to change."))
+                .arg(arg!(<quota> "The new storage quota, e.g. 10GB."))
+            )
+            .subcommand(
+              Command::new("disable")
+                .about("Stop a user from logging in and log out their sessions.")
+                .arg(arg!(<username> "The user to disable."))
+            )
+            .subcommand(
+              Command::new("enable")
+                .about("Let a disabled user log in again.")
+                .arg(arg!(<username> "The user to enable."))
+            )
+            .subcommand(
+              Command::new("grantadmin")
+                .about("Let a user use the admin API.")
+                .arg(arg!(<username> "The user to make an admin."))
+            )
+            .subcommand(
+              Command::new("revokeadmin")
+                .about("Stop a user from using the admin API.")
+                .arg(arg!(<username> "The user to stop being an admin."))
+            )
+            .subcommand(
+              Command::new("rename")
+                .about("Change a user's username.")
+                .arg(arg!(<username> "The user to rename."))
+                .arg(arg!(<new_username> "The new username."))
+            )
+        )
+    )
+}
+
+/// Runs an `admin` subcommand against the database. The output is printed to stdout and logs go to stderr.
+pub fn run_admin_command(config: &Config, args: &ArgMatches) -> Result<(), Box<dyn Error>> {
+  let json = args.get_flag("json");
+  let mut database = Database::open(config)?;
+
+  let result = match args.subcommand() {
+    Some(("newcode", args)) => new_claim_code_command(&mut database, args, json),
+    Some(("codes", args)) => match args.subcommand() {
+      Some(("list", _)) => list_claim_codes_command(&mut database, json),
+      Some(("revoke", args)) => revoke_claim_code_command(&mut database, args, json),
+      _ => unreachable!("A subcommand is required.")
+    },
+    Some(("users", args)) => match args.subcommand() {
+      Some(("list", _)) => list_users_command(&mut database, json),
+      Some(("setquota", args)) => set_quota_command(&mut database, args, json),
+      Some(("disable", args)) => set_user_disabled_command(&mut database, args, true, json),
+      Some(("enable", args)) => set_user_disabled_command(&mut database, args, false, json),
+      Some(("grantadmin", args)) => set_user_admin_command(&mut database, args, true, json),
+      Some(("revokeadmin", args)) => set_user_admin_command(&mut database, args, false, json),
+      Some(("rename", args)) => rename_user_command(&mut database, args, json),
+      _ => unreachable!("A subcommand is required.")
+    },
+    _ => unreachable!("A subcommand is required.")
+  };
+
+  database.close();
+
+  result
+}
+
+fn print_json(value: &impl Serialize) -> Result<(), Box<dyn Error>> {
+  println!("{}", serde_json::to_string_pretty(value)?);
+  Ok(())
+}
+
+fn new_claim_code_command(database: &mut Database, args: &ArgMatches, json: bool) -> Result<(), Box<dyn Error>> {
+  let storage_quota = parse_byte_size_str(args.get_one::<String>("quota").unwrap().clone())
+    .map_err(|err| format!("Invalid storage quota: {}", err))?;
+
+  let new_claim_code = NewClaimCode {
+    storage_quota,
+    max_uses: *args.get_one::<u64>("max-uses").unwrap(),
+    days_valid: *args.get_one::<u64>("days").unwrap(),
+    note: args.get_one::<String>("note").cloned()
+  };
+
+  let claim_code_data = create_claim_code(database, new_claim_code, CLAIM_CODE_CREATOR)?;
+
+  if json {
+    print_json(&ClaimCodeOutput::from(claim_code_data))
+  } else {
+    println!("{}", claim_code_data.claim_code);
+    Ok(())
+  }
+}
+
+fn list_claim_codes_command(database: &mut Database, json: bool) -> Result<(), Box<dyn Error>> {
+  let claim_codes: Vec<ClaimCodeOutput> = database.get_all_claim_codes()?
+    .into_iter()
+    .map(ClaimCodeOutput::from)
+    .collect();
+
+  if json {
+    return print_json(&claim_codes);
+  }
+
+  for code in claim_codes {
+    println!(
+      "{}  {}  {}  {}/{}  {}",
+      code.claim_code,
+      bytesize::to_string(code.storage_quota, false),
+      code.status,
+      code.use_count,
+      code.max_uses,
+      code.note.unwrap_or_default()
+    );
+  }
+
+  Ok(())
+}
+
+fn revoke_claim_code_command(database: &mut Database, args: &ArgMatches, json: bool) -> Result<(), Box<dyn Error>> {
+  let claim_code = args.get_one::<String>("code").unwrap();
+
+  if database.revoke_claim_code(claim_code, get_unix_timestamp_secs())? == 0 {
+    return Err("No unrevoked claim code found.".into());
+  }
+
+  let claim_code_data = database.get_claim_code_info(claim_code)?;
+
+  if json {
+    print_json(&ClaimCodeOutput::from(claim_code_data))
+  } else {
+    println!("Revoked claim code {}", claim_code);
+    Ok(())
+  }
+}
+
+fn list_users_command(database: &mut Database, json: bool) -> Result<(), Box<dyn Error>> {
+  let mut users = Vec::new();
+
+  for user_data in database.get_all_users()? {
+    users.push(get_user_output(database, user_data)?);
+  }
+
+  if json {
+    return print_json(&users);
+  }
+
+  for user in users {
+    print_user(&user);
+  }
+
+  Ok(())
+}
+
+fn set_quota_command(database: &mut Database, args: &ArgMatches, json: bool) -> Result<(), Box<dyn Error>> {
+  let username = args.get_one::<String>("username").unwrap();
+  let storage_quota = parse_byte_size_str(args.get_one::<String>("quota").unwrap().clone())
+    .map_err(|err| format!("Invalid storage quota: {}", err))?;
+
+  let user_id = admin::find_user_id(database, username)?;
+  database.set_user_storage_quota(user_id, storage_quota)?;
+
+  print_user_status(database, user_id, json)
+}
+
+fn set_user_disabled_command(database: &mut Database, args: &ArgMatches, disabled: bool, json: bool) -> Result<(), Box<dyn Error>> {
+  let username = args.get_one::<String>("username").unwrap();
+
+  let user_id = admin::find_user_id(database, username)?;
+  database.set_user_disabled(user_id, disabled)?;
+
+  print_user_status(database, user_id, json)
+}
+
+fn set_user_admin_command(database: &mut Database, args: &ArgMatches, is_admin: bool, json: bool) -> Result<(), Box<dyn Error>> {
+  let username = args.get_one::<String>("username").unwrap();
+
+  let user_id = admin::find_user_id(database, username)?;
+  database.set_user_admin(user_id, is_admin)?;
+
+  print_user_status(database, user_id, json)
+}
+
+fn rename_user_command(database: &mut Database, args: &ArgMatches, json: bool) -> Result<(), Box<dyn Error>> {
+  let username = args.get_one::<String>("username").unwrap();
+  let new_username = args.get_one::<String>("new_username").unwrap();
+
+  let user_id = admin::find_user_id(database, username)?;
+  rename_user(database, username, new_username)?;
+
+  print_user_status(database, user_id, json)
+}
+
+/// Prints a user's account after it has been changed.
+fn print_user_status(database: &mut Database, user_id: u64, json: bool) -> Result<(), Box<dyn Error>> {
+  let status = database.get_user_account_status(user_id)?.ok_or("User not found.")?;
+  let user_data = database.get_user_data(&status.username)?;
+  let user = get_user_output(database, user_data)?;
+
+  if json {
+    return print_json(&user);
+  }
+
+  print_user(&user);
+
+  Ok(())
+}
+
+fn get_user_output(database: &mut Database, user_data: UserData) -> Result<UserOutput, Box<dyn Error>> {
+  let user_id = user_data.user_id.unwrap();
+
+  Ok(UserOutput {
+    user_id,
+    username: user_data.username,
+    storage_quota: user_data.storage_quota.unwrap(),
+    storage_used: database.get_user_storage_used(user_id)?,
+    has_recovery: user_data.recovery_key_hash.is_some(),
+    disabled: user_data.disabled,
+    is_admin: user_data.is_admin
+  })
+}
+
+fn print_user(user: &UserOutput) {
+  println!(
+    "{}  {}/{}{}{}",
+    user.username,
+    bytesize::to_string(user.storage_used, false),
+    bytesize::to_string(user.storage_quota, false),
+    if user.is_admin { "  (admin)" } else { "" },
+    if user.disabled { "  (disabled)" } else { "" }
+  );
+}
@@ -1,11 +1,29 @@
 use std::{env, fs};
-use std::path::Path;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use tower_sessions::cookie::Key;
 use log::info;
-use clap::{arg, command, value_parser};
+use clap::ArgMatches;
+use argon2::Params;
 use base64::{engine::general_purpose, Engine as _};
+use serde::Serialize;
 
-/// A struct of all the settings found in the .env file.
+use crate::constants;
+
+/// The config file that's read when `--config` isn't given, if it exists.
+const DEFAULT_CONFIG_FILE_PATH: &str = "treasury.toml";
+
+/// Printed instead of secrets.
+const REDACTED_VALUE: &str = "<redacted>";
+
+/// All of the server's settings. Each setting is read from these layers, with later layers overriding earlier ones:
+/// 1. The defaults in `Config::default()`.
+/// 2. The TOML config file, where settings are named in lower case, e.g. `port = 3001`.
+/// 3. Environment variables, including the ones in the .env file, e.g. `PORT=3001`.
+/// 4. Command line flags, e.g. `--port 3001`.
 #[derive(Clone)]
 pub struct Config {
   /// The ip address of the server without the port. e.g. 127.0.0.1
@@ -28,114 +46,534 @@ pub struct Config {
 
   /// Whether session cookies should be secure.
   pub secure_cookies: bool,
+
+  /// The PEM certificate chain to serve HTTPS with. HTTPS is enabled when this and the key path are set.
+  pub tls_cert_path: String,
+
+  /// The PEM private key of the certificate.
+  pub tls_key_path: String,
+
+  /// The port of a plain HTTP listener that redirects every request to HTTPS, or 0 to not listen on one.
+  pub http_redirect_port: u16,
+
+  /// The WebAuthn relying party id which is the domain the site is served on. e.g. "treasury.example.com"
+  pub webauthn_rp_id: String,
+
+  /// The origin that WebAuthn credentials are scoped to. e.g. "https://treasury.example.com"
+  pub webauthn_rp_origin: String,
+
+  /// The storage backend where finalised user files are stored. Either "filesystem" or "s3".
+  pub storage_backend: String,
+
+  /// The endpoint url of the S3-compatible server. e.g. "http://127.0.0.1:9000"
+  pub s3_endpoint: String,
+
+  /// The S3 region name. Most self-hosted S3-compatible servers accept any value.
+  pub s3_region: String,
+
+  /// The name of the bucket user files are stored in.
+  pub s3_bucket: String,
+
+  pub s3_access_key: String,
+
+  pub s3_secret_key: String,
+
+  /// Whether to use path-style urls (endpoint/bucket/key) instead of virtual-hosted-style urls (bucket.endpoint/key).
+  pub s3_path_style: bool,
+
+  /// A prefix added to the key of every stored object. e.g. "userfiles/"
+  pub s3_key_prefix: String,
+
+  /// A directory (ideally on another disk) that finalised user files are mirrored to. Empty disables mirroring.
+  pub mirror_root_directory: String,
+
+  /// The server-held key that the database and stored user files are encrypted with at rest. Supplied as base64 with
+  /// either AT_REST_KEY or AT_REST_KEY_FILE in the .env file. `None` disables encryption at rest.
+  pub at_rest_key: Option<[u8; constants::AT_REST_KEY_SIZE]>,
+
+  /// Where database snapshots are written.
+  pub backup_directory: String,
+
+  /// How often a database snapshot is taken in hours. Set to 0 to disable scheduled backups.
+  pub backup_interval_hours: u64,
+
+  /// The number of most recent snapshots to keep. Older snapshots are deleted after each backup.
+  pub backup_retention_count: usize,
+
+  /// How often the storage integrity scrub runs in hours. Set to 0 to disable scheduled scrubs.
+  pub scrub_interval_hours: u64,
+
+  /// Whether scheduled scrubs move stored files that have no database entry into quarantine.
+  pub scrub_quarantine_orphans: bool,
+
+  /// The Argon2id memory size in KiB used when hashing authentication keys.
+  pub argon2_memory_size: u32,
+
+  /// The Argon2id iteration count used when hashing authentication keys.
+  pub argon2_iterations: u32,
+
+  /// The Argon2id parallelism used when hashing authentication keys.
+  pub argon2_parallelism: u32,
+
+  /// The frontend's built index.html which is served for every page.
+  pub index_html_path: String,
+
+  /// The directory of the frontend's built assets.
+  pub dist_assets_path: String,
+
+  /// The directory of the files served by the CDN api, e.g. "ffmpeg/ffmpeg-core.wasm".
+  pub cdn_directory: String,
+
+  pub transfer_limits: TransferLimits
+}
+
+/// Limits on uploads and downloads which clients also need to know. They're sent to clients by the server info api.
+#[derive(Clone, Copy, Serialize)]
+pub struct TransferLimits {
+  /// The size of the unencrypted data in every chunk except the last. Stored files keep the chunk size they were
+  /// uploaded with, so this can't be changed once there are files.
+  #[serde(rename = "chunkDataSize")]
+  pub chunk_data_size: usize,
+
+  /// The number of chunks of one upload that can be buffered while waiting for an earlier chunk.
+  #[serde(rename = "maxUploadConcurrentChunks")]
+  pub max_upload_concurrent_chunks: usize,
+
+  /// The largest unencrypted file that can be uploaded in bytes.
+  #[serde(rename = "maxFileSize")]
+  pub max_file_size: u64,
+
+  /// How long a file stays open after its last chunk was downloaded.
+  #[serde(rename = "activeDownloadExpiryTimeMs")]
+  pub active_download_expiry_time_ms: u64,
+
+  #[serde(rename = "encryptedFileMetadataMaxSize")]
+  pub encrypted_file_metadata_max_size: usize
+}
+
+impl TransferLimits {
+  /// The size of a full chunk as it's uploaded and stored.
+  pub fn encrypted_chunk_size(&self) -> usize {
+    self.chunk_data_size + constants::ENCRYPTED_CHUNK_EXTRA_DATA_SIZE
+  }
+
+  fn validate(&self) -> Vec<String> {
+    let mut errors = Vec::new();
+
+    if !(constants::MIN_CHUNK_DATA_SIZE..=constants::MAX_CHUNK_DATA_SIZE).contains(&self.chunk_data_size) {
+      errors.push(
+        format!(
+          "CHUNK_DATA_SIZE must be between {} and {} bytes.",
+          constants::MIN_CHUNK_DATA_SIZE,
+          constants::MAX_CHUNK_DATA_SIZE
+        )
+      );
+    } else if self.max_file_size / self.chunk_data_size as u64 >= u32::MAX as u64 {
+      // Chunk ids are stored in 4 bytes
+      errors.push("MAX_FILE_SIZE has too many chunks of CHUNK_DATA_SIZE for a file.".to_string());
+    }
+
+    if self.max_upload_concurrent_chunks == 0 {
+      errors.push("MAX_UPLOAD_CONCURRENT_CHUNKS must be at least 1.".to_string());
+    }
+
+    if self.max_file_size == 0 {
+      errors.push("MAX_FILE_SIZE must be at least 1.".to_string());
+    }
+
+    if self.active_download_expiry_time_ms == 0 {
+      errors.push("ACTIVE_DOWNLOAD_EXPIRY_TIME_MS must be at least 1.".to_string());
+    }
+
+    if self.encrypted_file_metadata_max_size == 0 {
+      errors.push("ENCRYPTED_FILE_METADATA_MAX_SIZE must be at least 1.".to_string());
+    }
+
+    errors
+  }
+}
+
+/// The config file, environment and command line flags that settings are read from. Problems are collected so they
+/// can all be reported at once.
+struct ConfigSources {
+  file_path: PathBuf,
+  file_table: toml::Table,
+
+  /// The values of the setting flags that were given, by setting name.
+  flag_values: HashMap<String, String>,
+
+  /// The lower case names of the settings that have been read, used to find unknown keys in the config file.
+  read_names: HashSet<String>,
+
+  errors: Vec<String>
 }
 
-/// Gets an environment variable's value by its name or panics if the key couldn't be found.
-fn get_env_var(key: &str) -> String {
-  env::var(key).expect(format!("Missing {} in .env", key).as_str())
+impl ConfigSources {
+  /// Reads the config file given with `--config`, or the default config file if it exists, and the setting flags.
+  fn load(args: &ArgMatches) -> Result<Self, Box<dyn Error>> {
+    let file_path = args.get_one::<String>("config");
+
+    let flag_values = Config::setting_names()
+      .into_iter()
+      .filter_map(|name| {
+        args.get_one::<String>(&get_setting_flag_name(name)).map(|value| (name.to_string(), value.clone()))
+      })
+      .collect();
+
+    let mut sources = Self {
+      file_path: PathBuf::from(file_path.map(String::as_str).unwrap_or(DEFAULT_CONFIG_FILE_PATH)),
+      file_table: toml::Table::new(),
+      flag_values,
+      read_names: HashSet::new(),
+      errors: Vec::new()
+    };
+
+    if file_path.is_none() && !sources.file_path.exists() {
+      return Ok(sources);
+    }
+
+    let contents = fs::read_to_string(&sources.file_path)
+      .map_err(|err| format!("Failed to read the config file {}: {}", sources.file_path.display(), err))?;
+
+    sources.file_table = contents.parse()
+      .map_err(|err| format!("Invalid config file {}: {}", sources.file_path.display(), err))?;
+
+    Ok(sources)
+  }
+
+  /// Gets a setting's value and where it came from, checking the command line, the environment and then the config
+  /// file. `name` is the environment variable's name and the config file key is the same name in lower case.
+  fn get(&mut self, name: &str) -> Option<(String, String)> {
+    let key = name.to_lowercase();
+    self.read_names.insert(key.clone());
+
+    if let Some(value) = self.flag_values.get(name) {
+      return Some((value.clone(), "the command line".to_string()));
+    }
+
+    if let Ok(value) = env::var(name) {
+      return Some((value, "the environment".to_string()));
+    }
+
+    let value = match self.file_table.get(&key)? {
+      toml::Value::String(value) => value.clone(),
+      value @ (toml::Value::Integer(_) | toml::Value::Float(_) | toml::Value::Boolean(_)) => value.to_string(),
+      _ => {
+        self.errors.push(format!("'{}' in {} must be a string, number or boolean.", key, self.file_path.display()));
+        return None;
+      }
+    };
+
+    Some((value, self.file_path.display().to_string()))
+  }
+
+  /// Replaces `value` with the setting's value if it's set.
+  fn read<T: FromStr>(&mut self, name: &str, value: &mut T) where T::Err: Display {
+    if let Some((raw_value, source)) = self.get(name) {
+      match raw_value.trim().parse() {
+        Ok(parsed_value) => *value = parsed_value,
+        Err(err) => self.errors.push(format!("{} '{}' from {} is invalid: {}", name, raw_value, source, err))
+      };
+    }
+  }
+
+  /// Records an error for every key in the config file that isn't a setting, which is most likely a typo.
+  fn check_unknown_keys(&mut self) {
+    for key in self.file_table.keys() {
+      if !self.read_names.contains(key) {
+        self.errors.push(format!("Unknown setting '{}' in {}.", key, self.file_path.display()));
+      }
+    }
+  }
 }
 
 impl Config {
   pub fn default() -> Config {
-    return Config {
+    Config {
       ip_address: "0.0.0.0".to_string(),
       port: 3001,
       session_secret_key: Key::generate(),
       database_path: "../databases/database.db".to_string(),
       user_upload_directory: "../uploads".to_string(),
       user_files_root_directory: "../userfiles".to_string(),
-      secure_cookies: true
-    };
+      secure_cookies: true,
+      tls_cert_path: String::new(),
+      tls_key_path: String::new(),
+      http_redirect_port: 0,
+      webauthn_rp_id: "localhost".to_string(),
+      webauthn_rp_origin: "http://localhost:3001".to_string(),
+      storage_backend: "filesystem".to_string(),
+      s3_endpoint: "http://127.0.0.1:9000".to_string(),
+      s3_region: "us-east-1".to_string(),
+      s3_bucket: "treasury".to_string(),
+      s3_access_key: String::new(),
+      s3_secret_key: String::new(),
+      s3_path_style: true,
+      s3_key_prefix: String::new(),
+      mirror_root_directory: String::new(),
+      at_rest_key: None,
+      backup_directory: "../backups".to_string(),
+      backup_interval_hours: 24,
+      backup_retention_count: 7,
+      scrub_interval_hours: 24,
+      scrub_quarantine_orphans: false,
+      argon2_memory_size: constants::ARGON2_MEMORY_SIZE as u32,
+      argon2_iterations: constants::ARGON2_ITERATIONS as u32,
+      argon2_parallelism: constants::ARGON2_PARALLELISM as u32,
+      index_html_path: "../dist/index.html".to_string(),
+      dist_assets_path: "../dist/assets".to_string(),
+      cdn_directory: "../cdn".to_string(),
+      transfer_limits: TransferLimits {
+        chunk_data_size: constants::DEFAULT_CHUNK_DATA_SIZE,
+        max_upload_concurrent_chunks: constants::DEFAULT_MAX_UPLOAD_CONCURRENT_CHUNKS,
+        max_file_size: constants::DEFAULT_MAX_FILE_SIZE,
+        active_download_expiry_time_ms: constants::DEFAULT_ACTIVE_DOWNLOAD_EXPIRY_TIME_MS,
+        encrypted_file_metadata_max_size: constants::DEFAULT_ENCRYPTED_FILE_METADATA_MAX_SIZE
+      }
+    }
   }
 
-  pub fn initialise() -> Result<Config, Box<dyn std::error::Error>> {
-    // Create .env file with default values if one doesn't exist already.
+  /// Loads the .env file, creating it first if needed, and then reads the config from every layer. The error lists
+  /// every invalid setting.
+  pub fn initialise(args: &ArgMatches) -> Result<Config, Box<dyn Error>> {
+    // Create .env file with a new session secret key if one doesn't exist already.
     if !Path::new(".env").exists() {
       info!("Creating new .env file since none was found.");
-      
-      // Load default config
-      let config = Config::default();
+      fs::write(".env", Config::default().to_default_env_file())?;
+    }
+
+    // Read .env file using dotenvy
+    dotenvy::dotenv()?;
+
+    Config::read(args)
+  }
+
+  /// Reads the config from the config file, the environment and the command line flags in `args`.
+  pub fn read(args: &ArgMatches) -> Result<Config, Box<dyn Error>> {
+    let mut sources = ConfigSources::load(args)?;
+    let mut config = Config::default();
 
-      // Convert secret key to a base64 string
-      let session_secret_key_bytes = config.session_secret_key.master();
-      let session_secret_key_base64 = general_purpose::STANDARD.encode(session_secret_key_bytes);
+    sources.read("IP_ADDRESS", &mut config.ip_address);
+    sources.read("PORT", &mut config.port);
+    sources.read("DATABASE_PATH", &mut config.database_path);
+    sources.read("USER_UPLOAD_DIRECTORY", &mut config.user_upload_directory);
+    sources.read("USER_FILES_ROOT_DIRECTORY", &mut config.user_files_root_directory);
+    sources.read("SECURE_COOKIES", &mut config.secure_cookies);
+    sources.read("TLS_CERT_PATH", &mut config.tls_cert_path);
+    sources.read("TLS_KEY_PATH", &mut config.tls_key_path);
+    sources.read("HTTP_REDIRECT_PORT", &mut config.http_redirect_port);
+    sources.read("WEBAUTHN_RP_ID", &mut config.webauthn_rp_id);
+    sources.read("WEBAUTHN_RP_ORIGIN", &mut config.webauthn_rp_origin);
+    sources.read("STORAGE_BACKEND", &mut config.storage_backend);
+    sources.read("S3_ENDPOINT", &mut config.s3_endpoint);
+    sources.read("S3_REGION", &mut config.s3_region);
+    sources.read("S3_BUCKET", &mut config.s3_bucket);
+    sources.read("S3_ACCESS_KEY", &mut config.s3_access_key);
+    sources.read("S3_SECRET_KEY", &mut config.s3_secret_key);
+    sources.read("S3_PATH_STYLE", &mut config.s3_path_style);
+    sources.read("S3_KEY_PREFIX", &mut config.s3_key_prefix);
+    sources.read("MIRROR_ROOT_DIRECTORY", &mut config.mirror_root_directory);
+    sources.read("BACKUP_DIRECTORY", &mut config.backup_directory);
+    sources.read("BACKUP_INTERVAL_HOURS", &mut config.backup_interval_hours);
+    sources.read("BACKUP_RETENTION_COUNT", &mut config.backup_retention_count);
+    sources.read("SCRUB_INTERVAL_HOURS", &mut config.scrub_interval_hours);
+    sources.read("SCRUB_QUARANTINE_ORPHANS", &mut config.scrub_quarantine_orphans);
+    sources.read("ARGON2_MEMORY_SIZE", &mut config.argon2_memory_size);
+    sources.read("ARGON2_ITERATIONS", &mut config.argon2_iterations);
+    sources.read("ARGON2_PARALLELISM", &mut config.argon2_parallelism);
+    sources.read("INDEX_HTML_PATH", &mut config.index_html_path);
+    sources.read("DIST_ASSETS_PATH", &mut config.dist_assets_path);
+    sources.read("CDN_DIRECTORY", &mut config.cdn_directory);
+    sources.read("CHUNK_DATA_SIZE", &mut config.transfer_limits.chunk_data_size);
+    sources.read("MAX_UPLOAD_CONCURRENT_CHUNKS", &mut config.transfer_limits.max_upload_concurrent_chunks);
+    sources.read("MAX_FILE_SIZE", &mut config.transfer_limits.max_file_size);
+    sources.read("ACTIVE_DOWNLOAD_EXPIRY_TIME_MS", &mut config.transfer_limits.active_download_expiry_time_ms);
+    sources.read("ENCRYPTED_FILE_METADATA_MAX_SIZE", &mut config.transfer_limits.encrypted_file_metadata_max_size);
 
-      // Create the default .env file content
-      let mut contents = String::new();
-      contents.push_str(format!("IP_ADDRESS={}\n", config.ip_address).as_str());
-      contents.push_str(format!("PORT={}\n", config.port).as_str());
-      contents.push_str(format!("SESSION_SECRET_KEY={}\n", session_secret_key_base64).as_str());
-      contents.push_str(format!("DATABASE_PATH={}\n", config.database_path).as_str());
-      contents.push_str(format!("USER_UPLOAD_DIRECTORY={}\n", config.user_upload_directory).as_str());
-      contents.push_str(format!("USER_FILES_ROOT_DIRECTORY={}\n", config.user_files_root_directory).as_str());
-      contents.push_str(format!("SECURE_COOKIES={}\n", config.secure_cookies).as_str());
-      contents.push_str("RUST_LOG=info,tracing::span=warn\n");
+    config.storage_backend = config.storage_backend.to_lowercase();
+    config.session_secret_key = read_session_secret_key(&mut sources).unwrap_or(config.session_secret_key);
+    config.at_rest_key = read_at_rest_key(&mut sources);
 
-      fs::write(".env", contents)?;
+    // RUST_LOG is read by the logger when it's initialised, so pass it on if it's only set in the config file
+    if let Some((rust_log, _)) = sources.get("RUST_LOG") {
+      env::set_var("RUST_LOG", rust_log);
     }
 
-    // Read .env file using dotenvy
-    dotenvy::dotenv()?;
+    sources.check_unknown_keys();
 
-    // Fill config with environment variables
-    let mut config: Config = Config::default();
+    let mut errors = sources.errors;
+    errors.extend(config.validate());
 
-    config.ip_address = get_env_var("IP_ADDRESS");
-    config.port = get_env_var("PORT").trim().parse()?;
-    config.database_path = get_env_var("DATABASE_PATH");
-    config.user_upload_directory = get_env_var("USER_UPLOAD_DIRECTORY");
-    config.user_files_root_directory = get_env_var("USER_FILES_ROOT_DIRECTORY");
+    if !errors.is_empty() {
+      return Err(format!("Invalid configuration:\n  - {}", errors.join("\n  - ")).into());
+    }
 
-    // TODO: is config.secure_cookies handled here? :/
+    Ok(config)
+  }
 
-    // Session secret key is stored as base64 in the .env file so we have to handle that.
-    let session_secret_key_b64 = get_env_var("SESSION_SECRET_KEY");
-    let session_secret_key_bytes = general_purpose::STANDARD.decode(session_secret_key_b64)?;
-    let session_secret_key = Key::try_from(&session_secret_key_bytes[..])?;
-    config.session_secret_key = session_secret_key;
+  /// Checks the settings that can be parsed but still aren't usable.
+  fn validate(&self) -> Vec<String> {
+    let mut errors = Vec::new();
 
-    // The database path cannot be a directory! It must be the actual path to the database file.
-    assert_eq!(
-      Path::new(config.database_path.as_str()).is_dir(), false,
-      "The DATABASE_PATH in the .env file CANNOT be a directory! It must be a path to a file."
-    );
-
-    // Process program parameters
-    let args = command!()
-    .arg(
-      arg!(--address <string> "The ip address the server listens on.")
-        .required(false)
-        .value_parser(value_parser!(String))
-    )
-    .arg(
-      arg!(--port <number> "The port the server listens on.")
-        .required(false)
-        .value_parser(value_parser!(u16))
-    )
-    .arg(
-      arg!(--securecookies <boolean> "Whether session cookies should be secure or not.")
-        .required(false)
-        .value_parser(value_parser!(bool))
-    )
-    .get_matches();
+    if self.storage_backend != "filesystem" && self.storage_backend != "s3" {
+      errors.push(format!("Unknown STORAGE_BACKEND '{}'. Expected 'filesystem' or 's3'.", self.storage_backend));
+    }
 
-    // Override some config values with program parameters
-    if let Some(address) = args.get_one::<String>("address") {
-      config.ip_address = address.clone();
+    if self.storage_backend == "s3" && self.s3_bucket.trim().is_empty() {
+      errors.push("S3_BUCKET can't be empty when STORAGE_BACKEND is 's3'.".to_string());
     }
 
-    if let Some(port) = args.get_one::<u16>("port") {
-      config.port = *port;
+    // Ensure the Argon2 parameters are usable before any passwords are hashed with them
+    if let Err(err) = self.argon2_params() {
+      errors.push(format!("Invalid Argon2 parameters: {}", err));
     }
 
-    if let Some(secure) = args.get_one::<bool>("securecookies") {
-      config.secure_cookies = *secure;
+    if self.tls_cert_path.trim().is_empty() != self.tls_key_path.trim().is_empty() {
+      errors.push("TLS_CERT_PATH and TLS_KEY_PATH must both be set to enable HTTPS.".to_string());
     }
 
-    Ok(config)
+    for (name, path) in [("TLS_CERT_PATH", &self.tls_cert_path), ("TLS_KEY_PATH", &self.tls_key_path)] {
+      if !path.trim().is_empty() && !Path::new(path).is_file() {
+        errors.push(format!("{} '{}' isn't a file.", name, path));
+      }
+    }
+
+    if self.http_redirect_port != 0 && !self.is_tls_enabled() {
+      errors.push("HTTP_REDIRECT_PORT can only be set when HTTPS is enabled.".to_string());
+    }
+
+    if self.http_redirect_port != 0 && self.http_redirect_port == self.port {
+      errors.push("HTTP_REDIRECT_PORT must be different from PORT.".to_string());
+    }
+
+    // The database path cannot be a directory! It must be the actual path to the database file.
+    if Path::new(&self.database_path).is_dir() {
+      errors.push(format!("DATABASE_PATH '{}' is a directory. It must be a path to a file.", self.database_path));
+    }
+
+    let required_paths = [
+      ("DATABASE_PATH", &self.database_path),
+      ("USER_UPLOAD_DIRECTORY", &self.user_upload_directory),
+      ("USER_FILES_ROOT_DIRECTORY", &self.user_files_root_directory),
+      ("BACKUP_DIRECTORY", &self.backup_directory),
+      ("INDEX_HTML_PATH", &self.index_html_path),
+      ("DIST_ASSETS_PATH", &self.dist_assets_path),
+      ("CDN_DIRECTORY", &self.cdn_directory)
+    ];
+
+    for (name, path) in required_paths {
+      if path.trim().is_empty() {
+        errors.push(format!("{} can't be empty.", name));
+      }
+    }
+
+    errors.extend(self.transfer_limits.validate());
+
+    errors
+  }
+
+  /// Every setting's name and value in the order they're declared. Secrets are redacted when `redact_secrets` is set.
+  fn settings(&self, redact_secrets: bool) -> Vec<(&'static str, toml::Value)> {
+    let redact = |value: String| -> toml::Value {
+      if redact_secrets && !value.is_empty() { REDACTED_VALUE.into() } else { value.into() }
+    };
+
+    vec![
+      ("IP_ADDRESS", self.ip_address.clone().into()),
+      ("PORT", i64::from(self.port).into()),
+      ("SESSION_SECRET_KEY", redact(general_purpose::STANDARD.encode(self.session_secret_key.master()))),
+      ("DATABASE_PATH", self.database_path.clone().into()),
+      ("USER_UPLOAD_DIRECTORY", self.user_upload_directory.clone().into()),
+      ("USER_FILES_ROOT_DIRECTORY", self.user_files_root_directory.clone().into()),
+      ("SECURE_COOKIES", self.secure_cookies.into()),
+      ("TLS_CERT_PATH", self.tls_cert_path.clone().into()),
+      ("TLS_KEY_PATH", self.tls_key_path.clone().into()),
+      ("HTTP_REDIRECT_PORT", i64::from(self.http_redirect_port).into()),
+      ("WEBAUTHN_RP_ID", self.webauthn_rp_id.clone().into()),
+      ("WEBAUTHN_RP_ORIGIN", self.webauthn_rp_origin.clone().into()),
+      ("STORAGE_BACKEND", self.storage_backend.clone().into()),
+      ("S3_ENDPOINT", self.s3_endpoint.clone().into()),
+      ("S3_REGION", self.s3_region.clone().into()),
+      ("S3_BUCKET", self.s3_bucket.clone().into()),
+      ("S3_ACCESS_KEY", self.s3_access_key.clone().into()),
+      ("S3_SECRET_KEY", redact(self.s3_secret_key.clone())),
+      ("S3_PATH_STYLE", self.s3_path_style.into()),
+      ("S3_KEY_PREFIX", self.s3_key_prefix.clone().into()),
+      ("MIRROR_ROOT_DIRECTORY", self.mirror_root_directory.clone().into()),
+      ("AT_REST_KEY", redact(self.at_rest_key.map(|key| general_purpose::STANDARD.encode(key)).unwrap_or_default())),
+      ("BACKUP_DIRECTORY", self.backup_directory.clone().into()),
+      ("BACKUP_INTERVAL_HOURS", (self.backup_interval_hours as i64).into()),
+      ("BACKUP_RETENTION_COUNT", (self.backup_retention_count as i64).into()),
+      ("SCRUB_INTERVAL_HOURS", (self.scrub_interval_hours as i64).into()),
+      ("SCRUB_QUARANTINE_ORPHANS", self.scrub_quarantine_orphans.into()),
+      ("ARGON2_MEMORY_SIZE", i64::from(self.argon2_memory_size).into()),
+      ("ARGON2_ITERATIONS", i64::from(self.argon2_iterations).into()),
+      ("ARGON2_PARALLELISM", i64::from(self.argon2_parallelism).into()),
+      ("INDEX_HTML_PATH", self.index_html_path.clone().into()),
+      ("DIST_ASSETS_PATH", self.dist_assets_path.clone().into()),
+      ("CDN_DIRECTORY", self.cdn_directory.clone().into()),
+      ("CHUNK_DATA_SIZE", (self.transfer_limits.chunk_data_size as i64).into()),
+      ("MAX_UPLOAD_CONCURRENT_CHUNKS", (self.transfer_limits.max_upload_concurrent_chunks as i64).into()),
+      ("MAX_FILE_SIZE", (self.transfer_limits.max_file_size as i64).into()),
+      ("ACTIVE_DOWNLOAD_EXPIRY_TIME_MS", (self.transfer_limits.active_download_expiry_time_ms as i64).into()),
+      ("ENCRYPTED_FILE_METADATA_MAX_SIZE", (self.transfer_limits.encrypted_file_metadata_max_size as i64).into())
+    ]
+  }
+
+  /// The names of every setting in the order they're declared. Each one has a command line flag.
+  pub fn setting_names() -> Vec<&'static str> {
+    Config::default().settings(false).into_iter().map(|(name, _)| name).collect()
+  }
+
+  /// The config as it would be written in the config file, with secrets redacted. Used by `--print-config`.
+  pub fn to_toml_string(&self) -> String {
+    self.settings(true)
+      .into_iter()
+      .map(|(name, value)| format!("{} = {}\n", name.to_lowercase(), value))
+      .collect()
+  }
+
+  /// The .env file created on the first run. Only the session secret key is set so that the other settings can still
+  /// be changed in the config file, the rest are listed as comments.
+  fn to_default_env_file(&self) -> String {
+    let mut contents = String::new();
+
+    contents.push_str("# Settings in this file override the ones in the config file (treasury.toml by default).\n");
+    contents.push_str("RUST_LOG=info,tracing::span=warn\n");
+
+    for (name, value) in self.settings(false) {
+      let value_str = match value {
+        toml::Value::String(value) => value,
+        value => value.to_string()
+      };
+
+      if name == "SESSION_SECRET_KEY" {
+        contents.push_str(format!("{}={}\n", name, value_str).as_str());
+      } else {
+        contents.push_str(format!("# {}={}\n", name, value_str).as_str());
+      }
+    }
+
+    contents
+  }
+
+  /// Whether the server terminates TLS itself instead of relying on a reverse proxy.
+  pub fn is_tls_enabled(&self) -> bool {
+    !self.tls_cert_path.trim().is_empty() && !self.tls_key_path.trim().is_empty()
+  }
+
+  /// The Argon2id parameters that new authentication key hashes are created with. Existing hashes with weaker
+  /// parameters are upgraded to these on the next successful login.
+  pub fn argon2_params(&self) -> Result<Params, argon2::Error> {
+    Params::new(
+      self.argon2_memory_size,
+      self.argon2_iterations,
+      self.argon2_parallelism,
+      None // Use default output length
+    )
   }
-  
-  pub fn initialise_directories(&self) -> Result<(), Box<dyn std::error::Error>> {
+
+  pub fn initialise_directories(&self) -> Result<(), Box<dyn Error>> {
     let database_path = Path::new(self.database_path.as_str());
     let user_upload_directory = Path::new(self.user_upload_directory.as_str());
     let user_files_root_directory = Path::new(self.user_files_root_directory.as_str());
@@ -158,6 +596,78 @@ impl Config {
       fs::create_dir_all(user_files_root_directory)?;
     }
 
+    if !Path::exists(Path::new(self.backup_directory.as_str())) {
+      info!("Creating missing backup directory at: {}", self.backup_directory);
+      fs::create_dir_all(self.backup_directory.as_str())?;
+    }
+
+    if !self.mirror_root_directory.is_empty() && !Path::exists(Path::new(self.mirror_root_directory.as_str())) {
+      info!("Creating missing mirror root directory at: {}", self.mirror_root_directory);
+      fs::create_dir_all(self.mirror_root_directory.as_str())?;
+    }
+
     Ok(())
   }
 }
+
+/// The command line flag that sets a setting, e.g. `--ip-address` for IP_ADDRESS.
+pub fn get_setting_flag_name(name: &str) -> String {
+  name.to_lowercase().replace('_', "-")
+}
+
+/// Reads the session secret key which is stored as base64. It's required since a random key would log everyone out
+/// whenever the server restarts.
+fn read_session_secret_key(sources: &mut ConfigSources) -> Option<Key> {
+  let (key_b64, source) = match sources.get("SESSION_SECRET_KEY") {
+    Some(value) => value,
+    None => {
+      sources.errors.push("SESSION_SECRET_KEY is missing. Delete the .env file to generate a new one.".to_string());
+      return None;
+    }
+  };
+
+  let key = general_purpose::STANDARD.decode(key_b64.trim())
+    .map_err(|err| err.to_string())
+    .and_then(|key_bytes| Key::try_from(&key_bytes[..]).map_err(|err| err.to_string()));
+
+  match key {
+    Ok(key) => Some(key),
+    Err(err) => {
+      sources.errors.push(format!("SESSION_SECRET_KEY from {} is invalid: {}", source, err));
+      None
+    }
+  }
+}
+
+/// Reads the at-rest encryption key from the AT_REST_KEY value or the file at AT_REST_KEY_FILE.
+/// A key can be generated with `openssl rand -base64 32`.
+fn read_at_rest_key(sources: &mut ConfigSources) -> Option<[u8; constants::AT_REST_KEY_SIZE]> {
+  let key_b64 = sources.get("AT_REST_KEY").map(|(value, _)| value).unwrap_or_default();
+  let key_file = sources.get("AT_REST_KEY_FILE").map(|(value, _)| value).unwrap_or_default();
+
+  let key_b64 = match (key_b64.trim(), key_file.trim()) {
+    ("", "") => return None,
+    (key_b64, "") => key_b64.to_string(),
+    ("", key_file) => match fs::read_to_string(key_file) {
+      Ok(key_b64) => key_b64,
+      Err(err) => {
+        sources.errors.push(format!("Failed to read AT_REST_KEY_FILE '{}': {}", key_file, err));
+        return None;
+      }
+    },
+    _ => {
+      sources.errors.push("Only one of AT_REST_KEY and AT_REST_KEY_FILE can be set.".to_string());
+      return None;
+    }
+  };
+
+  let key = general_purpose::STANDARD.decode(key_b64.trim())
+    .ok()
+    .and_then(|key_bytes| key_bytes.try_into().ok());
+
+  if key.is_none() {
+    sources.errors.push(format!("The at-rest key must be {} bytes encoded as base64.", constants::AT_REST_KEY_SIZE));
+  }
+
+  key
+}
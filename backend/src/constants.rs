@@ -2,16 +2,40 @@
 pub const MIN_USERNAME_LENGTH: usize = 3;
 pub const MAX_USERNAME_LENGTH: usize = 20;
 
-// Argon2 settings
+// Default Argon2 settings (configurable in the .env file)
 pub const ARGON2_PARALLELISM: usize = 1;
 pub const ARGON2_ITERATIONS: usize = 3;
 pub const ARGON2_MEMORY_SIZE: usize = 12 * 1024; // In KiB
 
+// Login rate limiting
+pub const LOGIN_USERNAME_FREE_ATTEMPTS: u64 = 5;
+pub const LOGIN_IP_ADDRESS_FREE_ATTEMPTS: u64 = 20;
+pub const LOGIN_LOCKOUT_BASE_SECONDS: u64 = 2;
+pub const LOGIN_LOCKOUT_MAX_SECONDS: u64 = 3600;
+pub const LOGIN_FAILURES_RESET_SECONDS: u64 = 86400; // Failed attempts are forgotten after this much inactivity
+
 // Sessions
 pub const SESSION_USER_ID_KEY: &str = "user_id";
 pub const SESSION_USERNAME_KEY: &str = "username";
 pub const SESSION_STORAGE_QUOTA_KEY: &str = "storage_quota";
+pub const SESSION_GENERATION_KEY: &str = "session_generation";
+pub const SESSION_PENDING_LOGIN_USERNAME_KEY: &str = "pending_login_username";
+pub const SESSION_PENDING_LOGIN_TIME_KEY: &str = "pending_login_time";
+pub const SESSION_PENDING_LOGIN_FACTORS_KEY: &str = "pending_login_factors";
+pub const SESSION_WEBAUTHN_REGISTRATION_KEY: &str = "webauthn_registration";
+pub const SESSION_WEBAUTHN_AUTHENTICATION_KEY: &str = "webauthn_authentication";
 pub const SESSION_EXPIRY_TIME_SECONDS: i64 = 3 * 86400;
+pub const PENDING_LOGIN_EXPIRY_TIME_SECONDS: u64 = 300; // Time allowed to complete the second login step
+
+// Two-factor authentication
+pub const SECOND_FACTOR_TOTP: &str = "totp";
+pub const SECOND_FACTOR_WEBAUTHN: &str = "webauthn";
+pub const TOTP_ISSUER: &str = "Treasury";
+pub const TOTP_SECRET_SIZE: usize = 20;
+pub const TOTP_CODE_LENGTH: usize = 6;
+pub const TOTP_BACKUP_CODE_COUNT: usize = 10;
+pub const TOTP_BACKUP_CODE_LENGTH: usize = 11;
+pub const WEBAUTHN_RP_NAME: &str = "Treasury";
 
 // Crypto length constants
 pub const XCHACHA20_KEY_SIZE: usize = 32;
@@ -26,28 +50,47 @@ pub const ENCRYPTED_MASTER_KEY_SIZE: usize = XCHACHA20_KEY_SIZE + ENCRYPTED_BUFF
 pub const ENCRYPTED_FILE_CRYPT_KEY_SIZE: usize = XCHACHA20_KEY_SIZE + ENCRYPTED_BUFFER_EXTRA_SIZE;
 pub const ENCRYPTED_CURVE25519_KEY_SIZE: usize = CURVE25519_KEY_SIZE + ENCRYPTED_BUFFER_EXTRA_SIZE;
 
-// Transfers
-pub const ACTIVE_DOWNLOAD_EXPIRY_TIME_MS: usize = 5000;
-pub const MAX_UPLOAD_CONCURRENT_CHUNKS: usize = 4;
+// Transfers (the limits can be changed in the config, these are the defaults)
+pub const DEFAULT_ACTIVE_DOWNLOAD_EXPIRY_TIME_MS: u64 = 5000;
+pub const DEFAULT_MAX_UPLOAD_CONCURRENT_CHUNKS: usize = 4;
+pub const DEFAULT_MAX_FILE_SIZE: u64 = 1024 * 1024 * 1024 * 1024; // 1 TiB
+pub const DEFAULT_ENCRYPTED_FILE_METADATA_MAX_SIZE: usize = 1024; // In bytes
+pub const MIN_CHUNK_DATA_SIZE: usize = 64 * 1024; // 64 KiB
+pub const MAX_CHUNK_DATA_SIZE: usize = 64 * 1024 * 1024; // 64 MiB
 pub const DOWNLOADS_EXPIRY_MPSC_CHANNEL_BUFFER_SIZE: usize = 128;
+pub const SHUTDOWN_DRAIN_TIMEOUT_SECONDS: u64 = 30; // Time allowed for in-flight requests to finish when stopping
+pub const TLS_RELOAD_DEBOUNCE_MS: u64 = 500;
 
 // File formats
 pub const ENCRYPTED_FILE_MAGIC_NUMBER: [u8; 4] = [ 0x2E, 0x54, 0x45, 0x46 ];
 pub const ENCRYPTED_FILE_HEADER_SIZE: usize = ENCRYPTED_FILE_MAGIC_NUMBER.len();
 pub const CHUNK_ID_BYTE_SIZE: usize = 4;
-pub const CHUNK_DATA_SIZE: usize = 2 * 1024 * 1024; // 2 MiB
+pub const DEFAULT_CHUNK_DATA_SIZE: usize = 2 * 1024 * 1024; // 2 MiB
 pub const ENCRYPTED_CHUNK_EXTRA_DATA_SIZE: usize = CHUNK_ID_BYTE_SIZE + NONCE_BYTE_SIZE + POLY1305_TAG_BYTE_SIZE;
-pub const ENCRYPTED_CHUNK_SIZE: usize = CHUNK_DATA_SIZE + ENCRYPTED_CHUNK_EXTRA_DATA_SIZE;
+pub const CHUNK_CHECKSUM_SIZE: usize = blake3::OUT_LEN;
+
+// Database backups
+pub const DATABASE_BACKUP_PAGES_PER_STEP: std::ffi::c_int = 256;
+pub const DATABASE_BACKUP_STEP_PAUSE_MS: u64 = 10;
+pub const DATABASE_BACKUP_FILE_PREFIX: &str = "database-";
+pub const DATABASE_BACKUP_FILE_EXTENSION: &str = ".db";
+pub const DATABASE_BACKUP_MANIFEST_EXTENSION: &str = ".manifest.json";
 
-// File paths
-pub const INDEX_HTML_PATH: &str = "../dist/index.html";
-pub const DIST_ASSETS_PATH: &str = "../dist/assets";
+// Server-side encryption at rest
+pub const AT_REST_KEY_SIZE: usize = 32;
+pub const AT_REST_WRAPPED_MAGIC_NUMBER: [u8; 4] = [ 0x2E, 0x54, 0x53, 0x57 ];
+pub const AT_REST_SALT_SIZE: usize = 32;
+pub const AT_REST_WRAPPED_HEADER_SIZE: usize = AT_REST_WRAPPED_MAGIC_NUMBER.len() + AT_REST_SALT_SIZE;
+pub const AT_REST_SEGMENT_SIZE: usize = 64 * 1024; // 64 KiB
+pub const AT_REST_WRAPPED_SEGMENT_SIZE: usize = AT_REST_SEGMENT_SIZE + POLY1305_TAG_BYTE_SIZE;
+pub const AT_REST_DATABASE_KEY_CONTEXT: &str = "treasury 2024-06 at-rest database key";
+pub const AT_REST_BLOB_KEY_CONTEXT: &str = "treasury 2024-06 at-rest blob key";
 
 // Misc.
 pub const FILE_HANDLE_LENGTH: usize = 16;
 pub const CLAIM_CODE_LENGTH: usize = 23;
-pub const ENCRYPTED_FILE_METADATA_MAX_SIZE: usize = 1024; // In bytes
-pub const MAX_FILE_SIZE: u64 = 1 * 1024 * 1024 * 1024 * 1024;
+pub const MAX_CLAIM_CODE_NOTE_LENGTH: usize = 256;
+pub const MAX_CLAIM_CODE_DAYS_VALID: u64 = 3650;
 pub const TREASURY_FILE_EXTENSION: &str = ".tef";
 
 pub const ALPHANUMERIC_CHARS: [char; 62] = [
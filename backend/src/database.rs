@@ -1,8 +1,19 @@
-use rusqlite::{Connection, Result, params};
-use log::info;
+use rusqlite::{backup::Backup, Connection, OptionalExtension, Result, params};
+use log::{info, warn};
+use std::error::Error;
+use std::fs;
+use std::io::Read;
 use std::path::Path;
+use std::time::Duration;
 use path_absolutize::*;
-use crate::Config;
+use crate::{constants, util::get_unix_timestamp_secs, Config};
+
+mod migrations;
+
+pub use migrations::{get_schema_version, LATEST_SCHEMA_VERSION};
+
+/// The first bytes of every unencrypted SQLite database file.
+const SQLITE_FILE_HEADER: &[u8; 16] = b"SQLite format 3\0";
 
 pub struct Database {
   pub connection: Connection
@@ -10,7 +21,55 @@ pub struct Database {
 
 pub struct ClaimCodeData {
   pub claim_code: String,
-  pub storage_quota: u64
+  pub storage_quota: u64,
+
+  /// Unix timestamp in seconds after which the code can't be claimed, or `None` if it never expires.
+  pub expiry_time: Option<u64>,
+
+  /// The number of accounts that can be claimed with the code.
+  pub max_uses: u64,
+  pub use_count: u64,
+
+  pub note: Option<String>,
+
+  // Optional for codes created before they were recorded
+  pub created_by: Option<String>,
+  pub created_time: Option<u64>,
+
+  pub revoked_time: Option<u64>
+}
+
+#[derive(PartialEq)]
+pub enum ClaimCodeStatus {
+  Available,
+  Expired,
+  UsedUp,
+  Revoked
+}
+
+impl ClaimCodeStatus {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      ClaimCodeStatus::Available => "Available",
+      ClaimCodeStatus::Expired => "Expired",
+      ClaimCodeStatus::UsedUp => "Used up",
+      ClaimCodeStatus::Revoked => "Revoked"
+    }
+  }
+}
+
+impl ClaimCodeData {
+  pub fn get_status(&self, now: u64) -> ClaimCodeStatus {
+    if self.revoked_time.is_some() {
+      ClaimCodeStatus::Revoked
+    } else if self.expiry_time.is_some_and(|expiry_time| expiry_time <= now) {
+      ClaimCodeStatus::Expired
+    } else if self.use_count >= self.max_uses {
+      ClaimCodeStatus::UsedUp
+    } else {
+      ClaimCodeStatus::Available
+    }
+  }
 }
 
 pub struct UserData {
@@ -22,12 +81,31 @@ pub struct UserData {
   pub ed25519_public_key: Vec<u8>,
   pub encrypted_x25519_private_key: Vec<u8>,
   pub x25519_public_key: Vec<u8>,
+
+  // Optional since account recovery doesn't have to be set up
+  pub recovery_key_hash: Option<String>,
+  pub encrypted_recovery_master_key: Option<Vec<u8>>,
   
   // Optional for claim_user() where the storage quota is retrieved from the claim code's data
   pub storage_quota: Option<u64>,
 
   // Optional only when calling claim_user()
-  pub user_id: Option<u64>
+  pub user_id: Option<u64>,
+
+  /// Disabled users can't log in.
+  pub disabled: bool,
+
+  /// Admins can use the admin API.
+  pub is_admin: bool
+}
+
+/// The parts of a user's account that are cached in their sessions.
+pub struct UserAccountStatus {
+  pub username: String,
+  pub storage_quota: u64,
+  pub disabled: bool,
+  pub is_admin: bool,
+  pub session_generation: u64
 }
 
 pub struct UserFileEntry {
@@ -40,6 +118,90 @@ pub struct UserFileEntry {
   pub signature: Option<Vec<u8>>
 }
 
+/// The kind of identifier that failed login attempts are tracked by.
+#[derive(Clone, Copy)]
+pub enum LoginLockoutKind {
+  IpAddress,
+  Username
+}
+
+impl LoginLockoutKind {
+  fn as_str(&self) -> &'static str {
+    match self {
+      LoginLockoutKind::IpAddress => "ip",
+      LoginLockoutKind::Username => "username"
+    }
+  }
+}
+
+pub struct LoginLockout {
+  pub failed_attempts: u64,
+
+  /// Unix timestamp in seconds of the most recent failed attempt.
+  pub last_failure_time: u64,
+
+  /// Unix timestamp in seconds until which login attempts are rejected.
+  pub locked_until: u64
+}
+
+pub struct UserTotp {
+  pub secret: Vec<u8>,
+
+  /// Whether the secret has been confirmed with a code. Unconfirmed secrets aren't required at login.
+  pub enabled: bool,
+
+  /// The most recently accepted TOTP time step which prevents codes from being reused.
+  pub last_used_step: u64
+}
+
+pub struct WebauthnCredentialEntry {
+  pub credential_id: Vec<u8>,
+
+  /// The serialised credential which includes its public key and signature counter.
+  pub credential: String
+}
+
+/// An upload that was in progress when the server stopped. It's saved so the client can continue it after a restart.
+pub struct SuspendedUpload {
+  pub handle: String,
+  pub owner_id: u64,
+  pub file_size: u64,
+  pub written_bytes: u64,
+  pub prev_written_chunk_id: i64,
+
+  /// The checksums of the written encrypted chunks ordered by chunk id.
+  pub chunk_checksums: Vec<Vec<u8>>
+}
+
+/// A summary of a storage integrity scrub.
+#[derive(Default)]
+pub struct ScrubReport {
+  /// Unix timestamps in seconds of when the scrub started and finished.
+  pub started_time: u64,
+  pub finished_time: u64,
+
+  /// The number of stored files that have a database entry.
+  pub checked_files: u64,
+
+  /// Database entries whose stored file doesn't exist.
+  pub missing_files: u64,
+
+  /// Stored files that no database entry points to.
+  pub orphaned_files: u64,
+
+  /// Stored files whose size doesn't match the size expected from their database entry.
+  pub size_mismatches: u64,
+
+  /// Stored files that don't start with the encrypted file magic number.
+  pub bad_magic_numbers: u64,
+
+  /// Stored chunks whose checksum doesn't match the checksum recorded when they were uploaded.
+  pub corrupt_chunks: u64,
+
+  /// Orphaned files that were moved into quarantine.
+  pub quarantined_files: u64
+}
+
 pub struct ClaimUserRequest {
   pub claim_code: String,
   pub user_data: UserData
@@ -51,76 +213,47 @@ pub struct EditFileMetadataRequest {
 }
 
 impl Database {
-  pub fn open(config: &Config) -> Result<Database> {
+  pub fn open(config: &Config) -> Result<Database, Box<dyn Error>> {
     let path = Path::new(config.database_path.as_str());
     info!("Opening database at: {}", path.absolutize().unwrap().to_str().unwrap());
 
-    let connection = Connection::open(path)?;
+    let connection = match &config.at_rest_key {
+      Some(at_rest_key) => open_encrypted_connection(path, at_rest_key)?,
+      None => Connection::open(path)?
+    };
     
     // Use WAL mode
     connection.execute_batch("PRAGMA journal_mode=WAL")?;
 
     let mut database = Database {
-      connection: connection
+      connection
     };
 
-    // Initialise
-    database.initialise_tables()?;
+    // Bring the schema up to date
+    migrations::run_migrations(&mut database.connection, path)?;
 
-    Ok(database)
-  }
+    // Must be turned on outside of a transaction and after migrating since migrations rebuild tables
+    database.connection.execute_batch("PRAGMA foreign_keys = ON")?;
 
-  pub fn close(self) {
-    let _ = self.connection.close();
-    info!("Database closed.");
+    Ok(database)
   }
 
-  fn initialise_tables(&mut self) -> Result<()> {
-    let tx = self.connection.transaction()?;
-
-    tx.execute(
-      "CREATE TABLE IF NOT EXISTS claim_codes (
-        code TEXT NOT NULL,
-        storage_quota BIGINT NOT NULL DEFAULT 0
-      )",
-      ()
-    )?;
-
-    tx.execute(
-      "CREATE TABLE IF NOT EXISTS users (
-        id INTEGER PRIMARY KEY,
-        username TEXT NOT NULL,
-        storage_quota BIGINT NOT NULL DEFAULT 0,
-        auth_key_hash TEXT NOT NULL,
-        salt BLOB NOT NULL,
-        encrypted_master_key BLOB NOT NULL,
-        encrypted_ed25519_private_key BLOB NOT NULL,
-        ed25519_public_key BLOB NOT NULL,
-        encrypted_x25519_private_key BLOB NOT NULL,
-        x25519_public_key BLOB NOT NULL
-      )",
-      ()
-    )?;
+  /// Replaces the contents of the database with a snapshot, e.g. one created by `Backup`, and then brings its schema
+  /// up to date. The caller is responsible for ensuring the snapshot's schema version is supported.
+  pub fn restore_from(&mut self, snapshot: &Connection, database_path: &Path) -> Result<(), Box<dyn Error>> {
+    Backup::new(snapshot, &mut self.connection)?.run_to_completion(constants::DATABASE_BACKUP_PAGES_PER_STEP, Duration::ZERO, None)?;
 
-    tx.execute(
-      "CREATE TABLE IF NOT EXISTS filesystem (
-        owner_id INTEGER REFERENCES users(id),
-        handle TEXT NOT NULL,
-        parent_handle TEXT NOT NULL,
-        size BIGINT NOT NULL DEFAULT 0,
-        encrypted_file_crypt_key BLOB,
-        encrypted_metadata BLOB NOT NULL,
-        signature BLOB,
-        FOREIGN KEY(owner_id) REFERENCES users(id)
-      )",
-      ()
-    )?;
-
-    tx.commit()?;
+    migrations::run_migrations(&mut self.connection, database_path)?;
+    self.connection.execute_batch("PRAGMA foreign_keys = ON")?;
 
     Ok(())
   }
 
+  pub fn close(self) {
+    let _ = self.connection.close();
+    info!("Database closed.");
+  }
+
   pub fn edit_file_metadata_multiple(&mut self, owner_user_id: u64, requests: &Vec<EditFileMetadataRequest>) -> Result<(), rusqlite::Error> {
     let tx = self.connection.transaction()?;
 
@@ -136,14 +269,85 @@ impl Database {
     Ok(())
   }
 
-  pub fn insert_new_claim_code(&mut self, claim_code: &str, storage_quota: u64) -> Result<usize, rusqlite::Error> {
+  /// Inserts a new claim code. Its use count and revoked time are ignored.
+  pub fn insert_new_claim_code(&mut self, data: &ClaimCodeData) -> Result<usize, rusqlite::Error> {
     self.connection.execute(
-      "INSERT INTO claim_codes (code, storage_quota)
-      VALUES (?, ?)",
-      params![claim_code, storage_quota]
+      "INSERT INTO claim_codes (code, storage_quota, expiry_time, max_uses, note, created_by, created_time)
+      VALUES (?, ?, ?, ?, ?, ?, ?)",
+      params![
+        data.claim_code,
+        data.storage_quota,
+        data.expiry_time,
+        data.max_uses,
+        data.note,
+        data.created_by,
+        data.created_time
+      ]
+    )
+  }
+
+  /// Stops a claim code from being used. Returns the number of codes revoked.
+  pub fn revoke_claim_code(&mut self, claim_code: &str, revoked_time: u64) -> Result<usize, rusqlite::Error> {
+    self.connection.execute(
+      "UPDATE claim_codes SET revoked_time = ? WHERE code = ? AND revoked_time IS NULL",
+      params![revoked_time, claim_code]
     )
   }
   
+  /// Inserts an uploaded file along with the checksums of its encrypted chunks, ordered by chunk id.
+  pub fn insert_new_user_file_with_checksums(&mut self, entry: &UserFileEntry, chunk_checksums: &[Vec<u8>]) -> Result<(), rusqlite::Error> {
+    let tx = self.connection.transaction()?;
+
+    tx.execute(
+      "INSERT INTO filesystem (owner_id, handle, parent_handle, size, encrypted_file_crypt_key, encrypted_metadata, signature)
+      VALUES (?, ?, ?, ?, ?, ?, ?)",
+      params![
+        entry.owner_id,
+        entry.handle,
+        entry.parent_handle,
+        entry.size,
+        entry.encrypted_crypt_key,
+        entry.encrypted_metadata,
+        entry.signature
+      ]
+    )?;
+
+    for (chunk_id, checksum) in chunk_checksums.iter().enumerate() {
+      tx.execute(
+        "INSERT INTO chunk_checksums (handle, chunk_id, checksum) VALUES (?, ?, ?)",
+        params![entry.handle, chunk_id as u64, checksum]
+      )?;
+    }
+
+    tx.commit()?;
+
+    Ok(())
+  }
+
+  /// Gets the checksum of a file's chunk, or `None` if it was uploaded before checksums were recorded.
+  pub fn get_chunk_checksum(&mut self, handle: &str, chunk_id: u64) -> Result<Option<Vec<u8>>, rusqlite::Error> {
+    let mut statement = self.connection.prepare_cached(
+      "SELECT checksum FROM chunk_checksums WHERE handle = ? AND chunk_id = ?"
+    )?;
+
+    match statement.query_row(params![handle, chunk_id], |row| row.get(0)) {
+      Ok(checksum) => Ok(Some(checksum)),
+      Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+      Err(err) => Err(err)
+    }
+  }
+
+  /// Gets the checksums of all of a file's chunks ordered by chunk id.
+  pub fn get_chunk_checksums(&mut self, handle: &str) -> Result<Vec<Vec<u8>>, rusqlite::Error> {
+    let mut statement = self.connection.prepare_cached(
+      "SELECT checksum FROM chunk_checksums WHERE handle = ? ORDER BY chunk_id"
+    )?;
+
+    let result_iter = statement.query_map(params![handle], |row| row.get(0))?;
+
+    result_iter.collect()
+  }
+
   pub fn insert_new_user_file(&mut self, entry: &UserFileEntry) -> Result<usize, rusqlite::Error> {
     self.connection.execute(
       "INSERT INTO filesystem (owner_id, handle, parent_handle, size, encrypted_file_crypt_key, encrypted_metadata, signature)
@@ -166,17 +370,24 @@ impl Database {
     // Create a new transaction
     let tx = self.connection.transaction()?;
 
-    // Delete the claim code
-    tx.execute(
-      "DELETE FROM claim_codes WHERE code = ?",
-      [&request.claim_code]
+    // Use up the claim code, checking it's still claimable in the same statement so concurrent claims can't go over
+    // its max uses
+    let used_count = tx.execute(
+      "UPDATE claim_codes SET use_count = use_count + 1
+      WHERE code = ? AND revoked_time IS NULL AND (expiry_time IS NULL OR expiry_time > ?) AND use_count < max_uses",
+      params![request.claim_code, get_unix_timestamp_secs()]
     )?;
 
+    if used_count == 0 {
+      return Err(rusqlite::Error::QueryReturnedNoRows);
+    }
+
     // Create a new user
     tx.execute(
       "INSERT INTO users (username, storage_quota, auth_key_hash, salt, encrypted_master_key,
-      encrypted_ed25519_private_key, ed25519_public_key, encrypted_x25519_private_key, x25519_public_key)
-      VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+      encrypted_ed25519_private_key, ed25519_public_key, encrypted_x25519_private_key, x25519_public_key,
+      recovery_key_hash, encrypted_recovery_master_key)
+      VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
       params![
         request.user_data.username,
         claim_code_data.storage_quota,
@@ -186,7 +397,9 @@ impl Database {
         request.user_data.encrypted_ed25519_private_key,
         request.user_data.ed25519_public_key,
         request.user_data.encrypted_x25519_private_key,
-        request.user_data.x25519_public_key
+        request.user_data.x25519_public_key,
+        request.user_data.recovery_key_hash,
+        request.user_data.encrypted_recovery_master_key
       ]
     )?;
 
@@ -195,7 +408,7 @@ impl Database {
     Ok(())
   }
 
-  pub fn is_username_taken_case_insensitive(&mut self, username: &String) -> Result<bool, rusqlite::Error> {
+  pub fn is_username_taken_case_insensitive(&mut self, username: &str) -> Result<bool, rusqlite::Error> {
     let mut statement = self.connection.prepare_cached(
       "SELECT * FROM users WHERE LOWER(username) = ?"
     )?;
@@ -209,36 +422,22 @@ impl Database {
 
   pub fn get_claim_code_info(&mut self, claim_code: &String) -> Result<ClaimCodeData, rusqlite::Error> {
     let mut statement = self.connection.prepare_cached(
-      "SELECT code, storage_quota FROM claim_codes WHERE code = ?"
+      "SELECT code, storage_quota, expiry_time, max_uses, use_count, note, created_by, created_time, revoked_time
+      FROM claim_codes WHERE code = ?"
     )?;
 
-    statement.query_row([claim_code], |row| {
-      Ok(ClaimCodeData {
-        claim_code: row.get(0)?,
-        storage_quota: row.get(1)?
-      })
-    })
+    statement.query_row([claim_code], claim_code_data_from_row)
   }
 
-  pub fn get_available_claim_codes(&mut self) -> Result<Vec<ClaimCodeData>> {
+  pub fn get_all_claim_codes(&mut self) -> Result<Vec<ClaimCodeData>> {
     let mut statement = self.connection.prepare_cached(
-      "SELECT code, storage_quota FROM claim_codes"
+      "SELECT code, storage_quota, expiry_time, max_uses, use_count, note, created_by, created_time, revoked_time
+      FROM claim_codes ORDER BY created_time"
     )?;
 
-    let mut results: Vec<ClaimCodeData> = Vec::new();
-  
-    let result_iter = statement.query_map([], |row| {
-      Ok(ClaimCodeData {
-        claim_code: row.get(0)?,
-        storage_quota: row.get(1)?
-      })
-    })?;
-  
-    for result in result_iter {
-      results.push(result.unwrap());
-    }
+    let result_iter = statement.query_map([], claim_code_data_from_row)?;
 
-    Ok(results)
+    result_iter.collect()
   }
 
   pub fn get_all_users(&mut self) -> Result<Vec<UserData>> {
@@ -259,7 +458,11 @@ impl Database {
         encrypted_ed25519_private_key: row.get(6)?,
         ed25519_public_key: row.get(7)?,
         encrypted_x25519_private_key: row.get(8)?,
-        x25519_public_key: row.get(9)?
+        x25519_public_key: row.get(9)?,
+        recovery_key_hash: row.get(10)?,
+        encrypted_recovery_master_key: row.get(11)?,
+        disabled: row.get(12)?,
+        is_admin: row.get(13)?
       })
     })?;
   
@@ -273,7 +476,8 @@ impl Database {
   pub fn get_user_data(&mut self, username: &String) -> Result<UserData, rusqlite::Error> {
     let mut statement = self.connection.prepare_cached(
       "SELECT id, storage_quota, auth_key_hash, salt, encrypted_master_key, encrypted_ed25519_private_key,
-      ed25519_public_key, encrypted_x25519_private_key, x25519_public_key FROM users WHERE username = ?"
+      ed25519_public_key, encrypted_x25519_private_key, x25519_public_key, recovery_key_hash,
+      encrypted_recovery_master_key, disabled, is_admin FROM users WHERE username = ?"
     )?;
 
     statement.query_row([username], |row| {
@@ -287,19 +491,125 @@ impl Database {
         encrypted_ed25519_private_key: row.get(5)?,
         ed25519_public_key: row.get(6)?,
         encrypted_x25519_private_key: row.get(7)?,
-        x25519_public_key: row.get(8)?
+        x25519_public_key: row.get(8)?,
+        recovery_key_hash: row.get(9)?,
+        encrypted_recovery_master_key: row.get(10)?,
+        disabled: row.get(11)?,
+        is_admin: row.get(12)?
       })
     })
   }
 
+  /// Sets (or replaces) the recovery key hash and the master key wrapped under the recovery key for a user.
+  pub fn set_user_recovery(&mut self, user_id: u64, recovery_key_hash: &str, encrypted_recovery_master_key: &[u8]) -> Result<usize, rusqlite::Error> {
+    self.connection.execute(
+      "UPDATE users SET recovery_key_hash = ?, encrypted_recovery_master_key = ? WHERE id = ?",
+      params![recovery_key_hash, encrypted_recovery_master_key, user_id]
+    )
+  }
+
+  pub fn update_user_auth_key_hash(&mut self, user_id: u64, auth_key_hash: &str) -> Result<usize, rusqlite::Error> {
+    self.connection.execute(
+      "UPDATE users SET auth_key_hash = ? WHERE id = ?",
+      params![auth_key_hash, user_id]
+    )
+  }
+
+  pub fn update_user_recovery_key_hash(&mut self, user_id: u64, recovery_key_hash: &str) -> Result<usize, rusqlite::Error> {
+    self.connection.execute(
+      "UPDATE users SET recovery_key_hash = ? WHERE id = ?",
+      params![recovery_key_hash, user_id]
+    )
+  }
+
+  /// Replaces a user's password derived credentials. The private keys don't change since they are wrapped
+  /// under the master key which stays the same. Every existing session of the user is logged out.
+  pub fn update_user_password(&mut self, user_id: u64, auth_key_hash: &str, salt: &[u8], encrypted_master_key: &[u8]) -> Result<usize, rusqlite::Error> {
+    self.connection.execute(
+      "UPDATE users SET auth_key_hash = ?, salt = ?, encrypted_master_key = ?, session_generation = session_generation + 1
+      WHERE id = ?",
+      params![auth_key_hash, salt, encrypted_master_key, user_id]
+    )
+  }
+
+  pub fn get_user_account_status(&mut self, user_id: u64) -> Result<Option<UserAccountStatus>, rusqlite::Error> {
+    let mut statement = self.connection.prepare_cached(
+      "SELECT username, storage_quota, disabled, is_admin, session_generation FROM users WHERE id = ?"
+    )?;
+
+    let result = statement.query_row([user_id], |row| {
+      Ok(UserAccountStatus {
+        username: row.get(0)?,
+        storage_quota: row.get(1)?,
+        disabled: row.get(2)?,
+        is_admin: row.get(3)?,
+        session_generation: row.get(4)?
+      })
+    });
+
+    match result {
+      Ok(status) => Ok(Some(status)),
+      Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+      Err(err) => Err(err)
+    }
+  }
+
+  pub fn set_user_storage_quota(&mut self, user_id: u64, storage_quota: u64) -> Result<usize, rusqlite::Error> {
+    self.connection.execute(
+      "UPDATE users SET storage_quota = ? WHERE id = ?",
+      params![storage_quota, user_id]
+    )
+  }
+
+  pub fn set_user_disabled(&mut self, user_id: u64, disabled: bool) -> Result<usize, rusqlite::Error> {
+    self.connection.execute(
+      "UPDATE users SET disabled = ? WHERE id = ?",
+      params![disabled, user_id]
+    )
+  }
+
+  pub fn set_user_admin(&mut self, user_id: u64, is_admin: bool) -> Result<usize, rusqlite::Error> {
+    self.connection.execute(
+      "UPDATE users SET is_admin = ? WHERE id = ?",
+      params![is_admin, user_id]
+    )
+  }
+
+  /// Changes a user's username. The caller must check that the new username isn't taken.
+  pub fn rename_user(&mut self, user_id: u64, new_username: &str) -> Result<usize, rusqlite::Error> {
+    self.connection.execute(
+      "UPDATE users SET username = ? WHERE id = ?",
+      params![new_username, user_id]
+    )
+  }
+
+  /// Deletes a user and all of their filesystem entries. Everything else that belongs to the user is removed by the
+  /// foreign key constraints. Returns the handles of the user's stored files, which the caller must delete.
+  pub fn delete_user(&mut self, user_id: u64) -> Result<Vec<String>, rusqlite::Error> {
+    let tx = self.connection.transaction()?;
+
+    let stored_file_handles: Vec<String> = {
+      let mut statement = tx.prepare(
+        "SELECT handle FROM filesystem WHERE owner_id = ? AND encrypted_file_crypt_key IS NOT NULL"
+      )?;
+
+      let result_iter = statement.query_map([user_id], |row| row.get(0))?;
+      result_iter.collect::<Result<_, _>>()?
+    };
+
+    tx.execute("DELETE FROM filesystem WHERE owner_id = ?", [user_id])?;
+    tx.execute("DELETE FROM users WHERE id = ?", [user_id])?;
+    tx.commit()?;
+
+    Ok(stored_file_handles)
+  }
+
   pub fn get_user_storage_used(&mut self, user_id: u64) -> Result<u64, rusqlite::Error> {
     let mut statement = self.connection.prepare_cached(
       "SELECT COALESCE(SUM(size), 0) AS total FROM filesystem WHERE owner_id = ?"
     )?;
 
-    statement.query_row([user_id], |row| {
-      Ok(row.get(0)?)
-    })
+    statement.query_row([user_id], |row| row.get(0))
   }
 
   pub fn get_files_under_handle(&mut self, user_id: u64, handle: &String) -> Result<Vec<UserFileEntry>, rusqlite::Error> {
@@ -327,4 +637,406 @@ impl Database {
 
     Ok(results)
   }
+
+  pub fn get_login_lockout(&mut self, kind: LoginLockoutKind, identifier: &str) -> Result<Option<LoginLockout>, rusqlite::Error> {
+    let mut statement = self.connection.prepare_cached(
+      "SELECT failed_attempts, last_failure_time, locked_until FROM login_lockouts WHERE kind = ? AND identifier = ?"
+    )?;
+
+    let result = statement.query_row(params![kind.as_str(), identifier], |row| {
+      Ok(LoginLockout {
+        failed_attempts: row.get(0)?,
+        last_failure_time: row.get(1)?,
+        locked_until: row.get(2)?
+      })
+    });
+
+    match result {
+      Ok(lockout) => Ok(Some(lockout)),
+      Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+      Err(err) => Err(err)
+    }
+  }
+
+  pub fn set_login_lockout(&mut self, kind: LoginLockoutKind, identifier: &str, lockout: &LoginLockout) -> Result<usize, rusqlite::Error> {
+    self.connection.execute(
+      "INSERT INTO login_lockouts (kind, identifier, failed_attempts, last_failure_time, locked_until)
+      VALUES (?, ?, ?, ?, ?)
+      ON CONFLICT(kind, identifier) DO UPDATE SET
+        failed_attempts = excluded.failed_attempts,
+        last_failure_time = excluded.last_failure_time,
+        locked_until = excluded.locked_until",
+      params![kind.as_str(), identifier, lockout.failed_attempts, lockout.last_failure_time, lockout.locked_until]
+    )
+  }
+
+  /// Removes the failed login attempts recorded for an identifier. Returns the number of rows deleted.
+  pub fn clear_login_lockout(&mut self, kind: LoginLockoutKind, identifier: &str) -> Result<usize, rusqlite::Error> {
+    self.connection.execute(
+      "DELETE FROM login_lockouts WHERE kind = ? AND identifier = ?",
+      params![kind.as_str(), identifier]
+    )
+  }
+
+  pub fn get_user_totp(&mut self, user_id: u64) -> Result<Option<UserTotp>, rusqlite::Error> {
+    let mut statement = self.connection.prepare_cached(
+      "SELECT secret, enabled, last_used_step FROM user_totp WHERE user_id = ?"
+    )?;
+
+    let result = statement.query_row([user_id], |row| {
+      Ok(UserTotp {
+        secret: row.get(0)?,
+        enabled: row.get(1)?,
+        last_used_step: row.get(2)?
+      })
+    });
+
+    match result {
+      Ok(totp) => Ok(Some(totp)),
+      Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+      Err(err) => Err(err)
+    }
+  }
+
+  /// Stores a new unconfirmed TOTP secret for a user, replacing any previous unconfirmed secret.
+  pub fn set_pending_user_totp(&mut self, user_id: u64, secret: &[u8]) -> Result<usize, rusqlite::Error> {
+    self.connection.execute(
+      "INSERT INTO user_totp (user_id, secret, enabled, last_used_step)
+      VALUES (?, ?, 0, 0)
+      ON CONFLICT(user_id) DO UPDATE SET secret = excluded.secret, enabled = 0, last_used_step = 0",
+      params![user_id, secret]
+    )
+  }
+
+  /// Enables a user's TOTP secret after it has been confirmed and replaces their backup codes.
+  pub fn enable_user_totp(&mut self, user_id: u64, confirmed_step: u64, backup_code_hashes: &[String]) -> Result<(), rusqlite::Error> {
+    let tx = self.connection.transaction()?;
+
+    tx.execute(
+      "UPDATE user_totp SET enabled = 1, last_used_step = ? WHERE user_id = ?",
+      params![confirmed_step, user_id]
+    )?;
+
+    tx.execute("DELETE FROM totp_backup_codes WHERE user_id = ?", [user_id])?;
+
+    for code_hash in backup_code_hashes {
+      tx.execute(
+        "INSERT INTO totp_backup_codes (user_id, code_hash) VALUES (?, ?)",
+        params![user_id, code_hash]
+      )?;
+    }
+
+    tx.commit()?;
+
+    Ok(())
+  }
+
+  pub fn set_user_totp_last_used_step(&mut self, user_id: u64, step: u64) -> Result<usize, rusqlite::Error> {
+    self.connection.execute(
+      "UPDATE user_totp SET last_used_step = ? WHERE user_id = ?",
+      params![step, user_id]
+    )
+  }
+
+  /// Deletes a backup code so it can only be used once. Returns true if the backup code existed.
+  pub fn use_totp_backup_code(&mut self, user_id: u64, code_hash: &str) -> Result<bool, rusqlite::Error> {
+    let deleted_count = self.connection.execute(
+      "DELETE FROM totp_backup_codes WHERE user_id = ? AND code_hash = ?",
+      params![user_id, code_hash]
+    )?;
+
+    Ok(deleted_count > 0)
+  }
+
+  /// Removes a user's TOTP secret and backup codes.
+  pub fn delete_user_totp(&mut self, user_id: u64) -> Result<(), rusqlite::Error> {
+    let tx = self.connection.transaction()?;
+    tx.execute("DELETE FROM user_totp WHERE user_id = ?", [user_id])?;
+    tx.execute("DELETE FROM totp_backup_codes WHERE user_id = ?", [user_id])?;
+    tx.commit()?;
+
+    Ok(())
+  }
+
+  pub fn get_user_webauthn_credentials(&mut self, user_id: u64) -> Result<Vec<WebauthnCredentialEntry>, rusqlite::Error> {
+    let mut statement = self.connection.prepare_cached(
+      "SELECT credential_id, credential FROM webauthn_credentials WHERE user_id = ?"
+    )?;
+
+    let result_iter = statement.query_map([user_id], |row| {
+      Ok(WebauthnCredentialEntry {
+        credential_id: row.get(0)?,
+        credential: row.get(1)?
+      })
+    })?;
+
+    result_iter.collect()
+  }
+
+  pub fn is_webauthn_credential_registered(&mut self, credential_id: &[u8]) -> Result<bool, rusqlite::Error> {
+    let mut statement = self.connection.prepare_cached(
+      "SELECT 1 FROM webauthn_credentials WHERE credential_id = ?"
+    )?;
+
+    statement.exists([credential_id])
+  }
+
+  pub fn insert_webauthn_credential(&mut self, user_id: u64, entry: &WebauthnCredentialEntry) -> Result<usize, rusqlite::Error> {
+    self.connection.execute(
+      "INSERT INTO webauthn_credentials (user_id, credential_id, credential) VALUES (?, ?, ?)",
+      params![user_id, entry.credential_id, entry.credential]
+    )
+  }
+
+  pub fn update_webauthn_credential(&mut self, entry: &WebauthnCredentialEntry) -> Result<usize, rusqlite::Error> {
+    self.connection.execute(
+      "UPDATE webauthn_credentials SET credential = ? WHERE credential_id = ?",
+      params![entry.credential, entry.credential_id]
+    )
+  }
+
+  /// Removes all of a user's WebAuthn credentials. Returns the number of credentials deleted.
+  pub fn delete_user_webauthn_credentials(&mut self, user_id: u64) -> Result<usize, rusqlite::Error> {
+    self.connection.execute("DELETE FROM webauthn_credentials WHERE user_id = ?", [user_id])
+  }
+
+  /// Gets the handle and raw size of every file entry that has stored data (i.e. excluding folders).
+  pub fn get_all_stored_files(&mut self) -> Result<Vec<(String, u64)>, rusqlite::Error> {
+    let mut statement = self.connection.prepare(
+      "SELECT handle, size FROM filesystem WHERE encrypted_file_crypt_key IS NOT NULL"
+    )?;
+
+    let result_iter = statement.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+
+    result_iter.collect()
+  }
+
+  pub fn insert_scrub_report(&mut self, report: &ScrubReport) -> Result<usize, rusqlite::Error> {
+    self.connection.execute(
+      "INSERT INTO scrub_reports (started_time, finished_time, checked_files, missing_files, orphaned_files, size_mismatches, bad_magic_numbers, corrupt_chunks, quarantined_files)
+      VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+      params![
+        report.started_time,
+        report.finished_time,
+        report.checked_files,
+        report.missing_files,
+        report.orphaned_files,
+        report.size_mismatches,
+        report.bad_magic_numbers,
+        report.corrupt_chunks,
+        report.quarantined_files
+      ]
+    )
+  }
+
+  pub fn get_suspended_uploads(&mut self) -> Result<Vec<SuspendedUpload>, rusqlite::Error> {
+    let mut statement = self.connection.prepare_cached(
+      "SELECT handle, owner_id, file_size, written_bytes, prev_written_chunk_id, chunk_checksums FROM suspended_uploads"
+    )?;
+
+    let result_iter = statement.query_map([], |row| {
+      let chunk_checksums: Vec<u8> = row.get(5)?;
+
+      Ok(SuspendedUpload {
+        handle: row.get(0)?,
+        owner_id: row.get(1)?,
+        file_size: row.get(2)?,
+        written_bytes: row.get(3)?,
+        prev_written_chunk_id: row.get(4)?,
+        chunk_checksums: chunk_checksums.chunks(constants::CHUNK_CHECKSUM_SIZE).map(|checksum| checksum.to_vec()).collect()
+      })
+    })?;
+
+    result_iter.collect()
+  }
+
+  /// Replaces the saved uploads with the uploads that are in progress now. Uploads of users that have been deleted
+  /// are skipped.
+  pub fn replace_suspended_uploads(&mut self, uploads: &[SuspendedUpload]) -> Result<(), rusqlite::Error> {
+    let tx = self.connection.transaction()?;
+    let suspended_time = get_unix_timestamp_secs();
+
+    tx.execute("DELETE FROM suspended_uploads", ())?;
+
+    for upload in uploads {
+      tx.execute(
+        "INSERT INTO suspended_uploads (handle, owner_id, file_size, written_bytes, prev_written_chunk_id, chunk_checksums, suspended_time)
+        SELECT ?, id, ?, ?, ?, ?, ? FROM users WHERE id = ?",
+        params![
+          upload.handle,
+          upload.file_size,
+          upload.written_bytes,
+          upload.prev_written_chunk_id,
+          upload.chunk_checksums.concat(),
+          suspended_time,
+          upload.owner_id
+        ]
+      )?;
+    }
+
+    tx.commit()?;
+
+    Ok(())
+  }
+
+  /// Records the chunk size that files are stored with. Stored files and suspended uploads can only be read with
+  /// the chunk size they were written with, so this fails if there are any and the chunk size has changed.
+  pub fn check_chunk_data_size(&mut self, chunk_data_size: usize) -> Result<(), Box<dyn Error>> {
+    let stored_chunk_data_size = get_stored_chunk_data_size(&self.connection)?;
+
+    if let Some(stored_chunk_data_size) = stored_chunk_data_size.filter(|size| *size != chunk_data_size) {
+      return Err(
+        format!(
+          "CHUNK_DATA_SIZE is {} but the stored files use {}. The chunk size can't be changed once files are stored.",
+          chunk_data_size,
+          stored_chunk_data_size
+        ).into()
+      );
+    }
+
+    self.connection.execute(
+      "INSERT INTO storage_format (id, chunk_data_size) VALUES (0, ?)
+      ON CONFLICT (id) DO UPDATE SET chunk_data_size = excluded.chunk_data_size",
+      params![chunk_data_size]
+    )?;
+
+    Ok(())
+  }
+}
+
+/// Gets the chunk size that a database's stored files and suspended uploads were written with, or `None` if it has
+/// neither. Works on databases at any schema version from 1, e.g. snapshots that haven't been migrated yet, where
+/// files stored before the chunk size was recorded use 2 MiB chunks like the migration that records it assumes.
+pub fn get_stored_chunk_data_size(connection: &Connection) -> Result<Option<usize>> {
+  let has_table = |table: &str| -> Result<bool> {
+    connection.query_row(
+      "SELECT count(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = ?",
+      params![table],
+      |row| row.get(0)
+    )
+  };
+
+  let mut has_stored_data: bool = connection.query_row(
+    "SELECT EXISTS (SELECT 1 FROM filesystem WHERE encrypted_file_crypt_key IS NOT NULL)",
+    [],
+    |row| row.get(0)
+  )?;
+
+  if has_table("suspended_uploads")? {
+    has_stored_data |= connection.query_row("SELECT EXISTS (SELECT 1 FROM suspended_uploads)", [], |row| row.get::<_, bool>(0))?;
+  }
+
+  if !has_stored_data {
+    return Ok(None);
+  }
+
+  if !has_table("storage_format")? {
+    return Ok(Some(2 * 1024 * 1024));
+  }
+
+  connection.query_row(
+    "SELECT chunk_data_size FROM storage_format WHERE id = 0",
+    [],
+    |row| row.get(0)
+  ).optional()
+}
+
+/// Runs SQLite's integrity check on a database, e.g. a snapshot before it's restored. Returns the problems found, or
+/// an empty list if the database is intact.
+pub fn check_integrity(connection: &Connection) -> Result<Vec<String>> {
+  let mut statement = connection.prepare("PRAGMA integrity_check")?;
+  let messages: Vec<String> = statement.query_map([], |row| row.get(0))?.collect::<Result<_>>()?;
+
+  Ok(messages.into_iter().filter(|message| message != "ok").collect())
+}
+
+fn claim_code_data_from_row(row: &rusqlite::Row) -> Result<ClaimCodeData> {
+  Ok(ClaimCodeData {
+    claim_code: row.get(0)?,
+    storage_quota: row.get(1)?,
+    expiry_time: row.get(2)?,
+    max_uses: row.get(3)?,
+    use_count: row.get(4)?,
+    note: row.get(5)?,
+    created_by: row.get(6)?,
+    created_time: row.get(7)?,
+    revoked_time: row.get(8)?
+  })
+}
+
+/// Opens a database whose pages are encrypted with SQLCipher using a key derived from the at-rest key. An existing
+/// unencrypted database is encrypted first.
+fn open_encrypted_connection(path: &Path, at_rest_key: &[u8]) -> Result<Connection, Box<dyn Error>> {
+  if is_unencrypted_database(path)? {
+    warn!("Encrypting the existing unencrypted database. This only happens once.");
+    encrypt_database(path, &get_database_raw_key(at_rest_key))?;
+  }
+
+  open_connection(path, Some(at_rest_key))
+}
+
+/// Opens a connection to a database file, or a snapshot of one, using the key derived from the at-rest key if there
+/// is one. Unlike `Database::open` this doesn't migrate the database.
+pub fn open_connection(path: &Path, at_rest_key: Option<&[u8]>) -> Result<Connection, Box<dyn Error>> {
+  let connection = Connection::open(path)?;
+
+  if let Some(at_rest_key) = at_rest_key {
+    connection.pragma_update(None, "key", get_database_raw_key(at_rest_key))?;
+  }
+
+  // The key is only checked once the database is read
+  connection.query_row("SELECT count(*) FROM sqlite_master", [], |_| Ok(()))
+    .map_err(|_| "Failed to read the database. Is the at-rest key correct?")?;
+
+  Ok(connection)
+}
+
+/// The SQLCipher raw key literal for the key derived from the at-rest key.
+fn get_database_raw_key(at_rest_key: &[u8]) -> String {
+  format!("x'{}'", hex::encode(blake3::derive_key(constants::AT_REST_DATABASE_KEY_CONTEXT, at_rest_key)))
+}
+
+fn is_unencrypted_database(path: &Path) -> Result<bool, Box<dyn Error>> {
+  if !path.exists() {
+    return Ok(false);
+  }
+
+  let mut header = [0u8; SQLITE_FILE_HEADER.len()];
+
+  match fs::File::open(path)?.read_exact(&mut header) {
+    Ok(_) => Ok(&header == SQLITE_FILE_HEADER),
+    Err(_) => Ok(false) // Empty file
+  }
+}
+
+/// Replaces an unencrypted database with an encrypted copy.
+fn encrypt_database(path: &Path, raw_key: &str) -> Result<(), Box<dyn Error>> {
+  let encrypted_path = path.with_extension("encrypting");
+  let _ = fs::remove_file(&encrypted_path);
+
+  {
+    let connection = Connection::open(path)?;
+
+    // Move everything from the WAL into the main database file so it's included in the copy
+    connection.execute_batch("PRAGMA wal_checkpoint(TRUNCATE)")?;
+
+    connection.execute(
+      "ATTACH DATABASE ? AS encrypted KEY ?",
+      params![encrypted_path.to_string_lossy(), raw_key]
+    )?;
+
+    connection.query_row("SELECT sqlcipher_export('encrypted')", [], |_| Ok(()))?;
+    connection.execute_batch("DETACH DATABASE encrypted")?;
+  }
+
+  fs::rename(&encrypted_path, path)?;
+
+  // The old WAL and shared memory files belong to the unencrypted database
+  for suffix in ["-wal", "-shm"] {
+    let mut sidecar_path = path.as_os_str().to_owned();
+    sidecar_path.push(suffix);
+    let _ = fs::remove_file(sidecar_path);
+  }
+
+  Ok(())
 }
@@ -0,0 +1,440 @@
+//! Versioned schema migrations. The schema version is stored in the database with `PRAGMA user_version` and each
+//! migration moves the schema up by one version inside its own transaction.
+//!
+//! Migrations must never be edited once released. Changes to the schema are made by appending a new migration.
+//! Databases created before migrations existed are at version 0 with any subset of the early tables and columns,
+//! so the early migrations are written to be safe to apply to those too.
+
+use rusqlite::{Connection, Result, Transaction, params};
+use log::{info, warn};
+use std::error::Error;
+use std::path::Path;
+
+use crate::util::get_unix_timestamp_secs;
+
+struct Migration {
+  description: &'static str,
+  apply: fn(&Transaction) -> Result<()>
+}
+
+/// The migration at index `i` moves the schema to version `i + 1`.
+const MIGRATIONS: &[Migration] = &[
+  Migration { description: "Create the initial tables", apply: create_initial_tables },
+  Migration { description: "Add account recovery keys", apply: add_account_recovery },
+  Migration { description: "Add login lockouts", apply: add_login_lockouts },
+  Migration { description: "Add second factors", apply: add_second_factors },
+  Migration { description: "Add chunk checksums and scrub reports", apply: add_storage_integrity },
+  Migration { description: "Add keys, indexes and foreign key constraints", apply: add_keys_and_constraints },
+  Migration { description: "Add disabled accounts", apply: add_disabled_accounts },
+  Migration { description: "Add claim code expiry, revocation and use limits", apply: add_claim_code_lifecycle },
+  Migration { description: "Add admin accounts", apply: add_admin_accounts },
+  Migration { description: "Add suspended uploads", apply: add_suspended_uploads },
+  Migration { description: "Record the chunk size of stored files", apply: add_storage_format },
+  Migration { description: "Add session generations", apply: add_session_generations }
+];
+
+/// The schema version that this binary creates and expects.
+pub const LATEST_SCHEMA_VERSION: u32 = MIGRATIONS.len() as u32;
+
+pub fn get_schema_version(connection: &Connection) -> Result<u32> {
+  connection.pragma_query_value(None, "user_version", |row| row.get(0))
+}
+
+/// Brings the schema up to date. Existing databases are backed up next to the database file before anything is
+/// changed. Fails if the database was created by a newer version of the server.
+pub fn run_migrations(connection: &mut Connection, database_path: &Path) -> Result<(), Box<dyn Error>> {
+  let current_version = get_schema_version(connection)?;
+
+  if current_version > LATEST_SCHEMA_VERSION {
+    return Err(
+      format!(
+        "The database schema version is {} but this server only supports up to version {}. Please update the server.",
+        current_version,
+        LATEST_SCHEMA_VERSION
+      ).into()
+    );
+  }
+
+  if current_version == LATEST_SCHEMA_VERSION {
+    return Ok(());
+  }
+
+  // New databases have nothing worth backing up
+  let table_count: u64 = connection.query_row("SELECT count(*) FROM sqlite_master WHERE type = 'table'", [], |row| row.get(0))?;
+
+  if table_count > 0 {
+    let backup_path = format!("{}.v{}-{}.bak", database_path.display(), current_version, get_unix_timestamp_secs());
+    warn!("Migrating the database from schema version {}. Backing it up to: {}", current_version, backup_path);
+
+    connection.execute("VACUUM INTO ?", params![backup_path])?;
+  }
+
+  for (index, migration) in MIGRATIONS.iter().enumerate().skip(current_version as usize) {
+    let version = index as u32 + 1;
+    info!("Migrating database to schema version {}: {}", version, migration.description);
+
+    let tx = connection.transaction()?;
+    (migration.apply)(&tx)?;
+    tx.pragma_update(None, "user_version", version)?;
+    tx.commit()?;
+  }
+
+  Ok(())
+}
+
+fn add_column_if_missing(tx: &Transaction, table: &str, column: &str, definition: &str) -> Result<()> {
+  let exists: bool = tx.query_row(
+    "SELECT count(*) > 0 FROM pragma_table_info(?) WHERE name = ?",
+    params![table, column],
+    |row| row.get(0)
+  )?;
+
+  if !exists {
+    tx.execute(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition), ())?;
+  }
+
+  Ok(())
+}
+
+// ----------------------------------------------
+// Migrations
+// ----------------------------------------------
+
+fn create_initial_tables(tx: &Transaction) -> Result<()> {
+  tx.execute(
+    "CREATE TABLE IF NOT EXISTS claim_codes (
+      code TEXT NOT NULL,
+      storage_quota BIGINT NOT NULL DEFAULT 0
+    )",
+    ()
+  )?;
+
+  tx.execute(
+    "CREATE TABLE IF NOT EXISTS users (
+      id INTEGER PRIMARY KEY,
+      username TEXT NOT NULL,
+      storage_quota BIGINT NOT NULL DEFAULT 0,
+      auth_key_hash TEXT NOT NULL,
+      salt BLOB NOT NULL,
+      encrypted_master_key BLOB NOT NULL,
+      encrypted_ed25519_private_key BLOB NOT NULL,
+      ed25519_public_key BLOB NOT NULL,
+      encrypted_x25519_private_key BLOB NOT NULL,
+      x25519_public_key BLOB NOT NULL
+    )",
+    ()
+  )?;
+
+  tx.execute(
+    "CREATE TABLE IF NOT EXISTS filesystem (
+      owner_id INTEGER REFERENCES users(id),
+      handle TEXT NOT NULL,
+      parent_handle TEXT NOT NULL,
+      size BIGINT NOT NULL DEFAULT 0,
+      encrypted_file_crypt_key BLOB,
+      encrypted_metadata BLOB NOT NULL,
+      signature BLOB,
+      FOREIGN KEY(owner_id) REFERENCES users(id)
+    )",
+    ()
+  )?;
+
+  Ok(())
+}
+
+fn add_account_recovery(tx: &Transaction) -> Result<()> {
+  add_column_if_missing(tx, "users", "recovery_key_hash", "TEXT")?;
+  add_column_if_missing(tx, "users", "encrypted_recovery_master_key", "BLOB")?;
+
+  Ok(())
+}
+
+fn add_login_lockouts(tx: &Transaction) -> Result<()> {
+  tx.execute(
+    "CREATE TABLE IF NOT EXISTS login_lockouts (
+      kind TEXT NOT NULL,
+      identifier TEXT NOT NULL,
+      failed_attempts INTEGER NOT NULL DEFAULT 0,
+      last_failure_time BIGINT NOT NULL DEFAULT 0,
+      locked_until BIGINT NOT NULL DEFAULT 0,
+      PRIMARY KEY(kind, identifier)
+    )",
+    ()
+  )?;
+
+  Ok(())
+}
+
+fn add_second_factors(tx: &Transaction) -> Result<()> {
+  tx.execute(
+    "CREATE TABLE IF NOT EXISTS user_totp (
+      user_id INTEGER PRIMARY KEY REFERENCES users(id),
+      secret BLOB NOT NULL,
+      enabled INTEGER NOT NULL DEFAULT 0,
+      last_used_step BIGINT NOT NULL DEFAULT 0
+    )",
+    ()
+  )?;
+
+  tx.execute(
+    "CREATE TABLE IF NOT EXISTS totp_backup_codes (
+      user_id INTEGER NOT NULL REFERENCES users(id),
+      code_hash TEXT NOT NULL
+    )",
+    ()
+  )?;
+
+  tx.execute(
+    "CREATE TABLE IF NOT EXISTS webauthn_credentials (
+      user_id INTEGER NOT NULL REFERENCES users(id),
+      credential_id BLOB NOT NULL UNIQUE,
+      credential TEXT NOT NULL
+    )",
+    ()
+  )?;
+
+  Ok(())
+}
+
+fn add_storage_integrity(tx: &Transaction) -> Result<()> {
+  tx.execute(
+    "CREATE TABLE IF NOT EXISTS chunk_checksums (
+      handle TEXT NOT NULL,
+      chunk_id INTEGER NOT NULL,
+      checksum BLOB NOT NULL,
+      PRIMARY KEY(handle, chunk_id)
+    )",
+    ()
+  )?;
+
+  tx.execute(
+    "CREATE TABLE IF NOT EXISTS scrub_reports (
+      id INTEGER PRIMARY KEY,
+      started_time BIGINT NOT NULL,
+      finished_time BIGINT NOT NULL,
+      checked_files BIGINT NOT NULL,
+      missing_files BIGINT NOT NULL,
+      orphaned_files BIGINT NOT NULL,
+      size_mismatches BIGINT NOT NULL,
+      bad_magic_numbers BIGINT NOT NULL,
+      corrupt_chunks BIGINT NOT NULL DEFAULT 0,
+      quarantined_files BIGINT NOT NULL
+    )",
+    ()
+  )?;
+
+  // Scrub reports were briefly stored without checksum results
+  add_column_if_missing(tx, "scrub_reports", "corrupt_chunks", "BIGINT NOT NULL DEFAULT 0")?;
+
+  Ok(())
+}
+
+/// SQLite can't add keys or change foreign keys of an existing table so the affected tables are rebuilt. This relies
+/// on foreign key enforcement being off while migrating, which it is since it's only turned on after migrations run.
+fn add_keys_and_constraints(tx: &Transaction) -> Result<()> {
+  // Duplicate claim codes are interchangeable so only one of each is kept
+  tx.execute(
+    "DELETE FROM claim_codes WHERE rowid NOT IN (SELECT MIN(rowid) FROM claim_codes GROUP BY code)",
+    ()
+  )?;
+
+  tx.execute("CREATE UNIQUE INDEX IF NOT EXISTS claim_codes_code_index ON claim_codes(code)", ())?;
+
+  // Other duplicates can't be resolved automatically so they're reported for the admin to resolve
+  ensure_no_duplicates(tx, "users", "LOWER(username)", "'id ' || id || ' (' || username || ')'")?;
+  ensure_no_duplicates(tx, "filesystem", "handle", "'owner id ' || IFNULL(owner_id, 'none')")?;
+  ensure_no_duplicates(tx, "chunk_checksums", "handle || ' chunk ' || chunk_id", "'checksum ' || hex(checksum)")?;
+  ensure_no_duplicates(tx, "webauthn_credentials", "hex(credential_id)", "'user id ' || user_id")?;
+
+  // Usernames are looked up case-insensitively with LOWER(username) so the index uses the same expression
+  tx.execute("CREATE UNIQUE INDEX IF NOT EXISTS users_username_index ON users(LOWER(username))", ())?;
+
+  tx.execute(
+    "CREATE TABLE filesystem_new (
+      owner_id INTEGER REFERENCES users(id) ON DELETE CASCADE,
+      handle TEXT PRIMARY KEY NOT NULL,
+      parent_handle TEXT NOT NULL,
+      size BIGINT NOT NULL DEFAULT 0,
+      encrypted_file_crypt_key BLOB,
+      encrypted_metadata BLOB NOT NULL,
+      signature BLOB
+    )",
+    ()
+  )?;
+
+  tx.execute(
+    "INSERT INTO filesystem_new (owner_id, handle, parent_handle, size, encrypted_file_crypt_key, encrypted_metadata, signature)
+    SELECT owner_id, handle, parent_handle, size, encrypted_file_crypt_key, encrypted_metadata, signature FROM filesystem",
+    ()
+  )?;
+
+  tx.execute("DROP TABLE filesystem", ())?;
+  tx.execute("ALTER TABLE filesystem_new RENAME TO filesystem", ())?;
+  tx.execute("CREATE INDEX filesystem_owner_parent_index ON filesystem(owner_id, parent_handle)", ())?;
+
+  rebuild_table(
+    tx,
+    "chunk_checksums",
+    "handle TEXT NOT NULL REFERENCES filesystem(handle) ON DELETE CASCADE,
+    chunk_id INTEGER NOT NULL,
+    checksum BLOB NOT NULL,
+    PRIMARY KEY(handle, chunk_id)",
+    "handle, chunk_id, checksum"
+  )?;
+
+  rebuild_table(
+    tx,
+    "user_totp",
+    "user_id INTEGER PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+    secret BLOB NOT NULL,
+    enabled INTEGER NOT NULL DEFAULT 0,
+    last_used_step BIGINT NOT NULL DEFAULT 0",
+    "user_id, secret, enabled, last_used_step"
+  )?;
+
+  rebuild_table(
+    tx,
+    "totp_backup_codes",
+    "user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    code_hash TEXT NOT NULL",
+    "user_id, code_hash"
+  )?;
+
+  rebuild_table(
+    tx,
+    "webauthn_credentials",
+    "user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    credential_id BLOB NOT NULL UNIQUE,
+    credential TEXT NOT NULL",
+    "user_id, credential_id, credential"
+  )?;
+
+  tx.execute("CREATE INDEX totp_backup_codes_user_index ON totp_backup_codes(user_id)", ())?;
+  tx.execute("CREATE INDEX webauthn_credentials_user_index ON webauthn_credentials(user_id)", ())?;
+
+  ensure_foreign_keys_valid(tx)
+}
+
+fn add_disabled_accounts(tx: &Transaction) -> Result<()> {
+  tx.execute("ALTER TABLE users ADD COLUMN disabled INTEGER NOT NULL DEFAULT 0", ())?;
+
+  Ok(())
+}
+
+/// Claim codes used to be deleted once claimed, so every existing code is unused.
+fn add_claim_code_lifecycle(tx: &Transaction) -> Result<()> {
+  tx.execute("ALTER TABLE claim_codes ADD COLUMN expiry_time BIGINT", ())?;
+  tx.execute("ALTER TABLE claim_codes ADD COLUMN max_uses BIGINT NOT NULL DEFAULT 1", ())?;
+  tx.execute("ALTER TABLE claim_codes ADD COLUMN use_count BIGINT NOT NULL DEFAULT 0", ())?;
+  tx.execute("ALTER TABLE claim_codes ADD COLUMN note TEXT", ())?;
+  tx.execute("ALTER TABLE claim_codes ADD COLUMN created_by TEXT", ())?;
+  tx.execute("ALTER TABLE claim_codes ADD COLUMN created_time BIGINT", ())?;
+  tx.execute("ALTER TABLE claim_codes ADD COLUMN revoked_time BIGINT", ())?;
+
+  Ok(())
+}
+
+fn add_admin_accounts(tx: &Transaction) -> Result<()> {
+  tx.execute("ALTER TABLE users ADD COLUMN is_admin INTEGER NOT NULL DEFAULT 0", ())?;
+
+  Ok(())
+}
+
+/// Chunk checksums are stored in order, joined into one blob.
+fn add_suspended_uploads(tx: &Transaction) -> Result<()> {
+  tx.execute(
+    "CREATE TABLE suspended_uploads (
+      handle TEXT PRIMARY KEY NOT NULL,
+      owner_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+      file_size BIGINT NOT NULL,
+      written_bytes BIGINT NOT NULL,
+      prev_written_chunk_id BIGINT NOT NULL,
+      chunk_checksums BLOB NOT NULL,
+      suspended_time BIGINT NOT NULL
+    )",
+    ()
+  )?;
+
+  Ok(())
+}
+
+/// Files stored before the chunk size could be configured all use 2 MiB chunks.
+fn add_storage_format(tx: &Transaction) -> Result<()> {
+  tx.execute(
+    "CREATE TABLE storage_format (
+      id INTEGER PRIMARY KEY NOT NULL CHECK (id = 0),
+      chunk_data_size BIGINT NOT NULL
+    )",
+    ()
+  )?;
+
+  tx.execute(
+    "INSERT INTO storage_format (id, chunk_data_size)
+    SELECT 0, 2097152 WHERE EXISTS (SELECT 1 FROM filesystem WHERE encrypted_file_crypt_key IS NOT NULL)
+    OR EXISTS (SELECT 1 FROM suspended_uploads)",
+    ()
+  )?;
+
+  Ok(())
+}
+
+/// Sessions store the generation their user had when they logged in and stop being valid once it changes.
+fn add_session_generations(tx: &Transaction) -> Result<()> {
+  tx.execute("ALTER TABLE users ADD COLUMN session_generation BIGINT NOT NULL DEFAULT 0", ())?;
+
+  Ok(())
+}
+
+/// Recreates a table with a new definition while keeping its rows.
+fn rebuild_table(tx: &Transaction, table: &str, definition: &str, columns: &str) -> Result<()> {
+  tx.execute(&format!("CREATE TABLE {}_new ({})", table, definition), ())?;
+  tx.execute(&format!("INSERT INTO {}_new ({}) SELECT {} FROM {}", table, columns, columns, table), ())?;
+  tx.execute(&format!("DROP TABLE {}", table), ())?;
+  tx.execute(&format!("ALTER TABLE {}_new RENAME TO {}", table, table), ())?;
+
+  Ok(())
+}
+
+/// Fails the migration if any rows of a table share a key that is about to become unique, listing every conflicting
+/// row by the description expression so they can be resolved by hand before migrating again.
+fn ensure_no_duplicates(tx: &Transaction, table: &str, key: &str, description: &str) -> Result<()> {
+  let mut statement = tx.prepare(&format!(
+    "SELECT {key}, group_concat({description}, ', ') FROM {table} WHERE {key} IS NOT NULL GROUP BY {key} HAVING count(*) > 1",
+    key = key,
+    description = description,
+    table = table
+  ))?;
+
+  let duplicates: Vec<String> = statement
+    .query_map([], |row| Ok(format!("{} [{}]", row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?
+    .collect::<Result<_>>()?;
+
+  if !duplicates.is_empty() {
+    return Err(rusqlite::Error::SqliteFailure(
+      rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_CONSTRAINT_UNIQUE),
+      Some(format!(
+        "The {} table has rows with duplicate keys that must be removed or renamed before migrating: {}",
+        table,
+        duplicates.join("; ")
+      ))
+    ));
+  }
+
+  Ok(())
+}
+
+/// Fails the migration if any existing row points to a row that doesn't exist, since turning on foreign key
+/// enforcement would otherwise leave those rows impossible to update.
+fn ensure_foreign_keys_valid(tx: &Transaction) -> Result<()> {
+  let mut statement = tx.prepare("PRAGMA foreign_key_check")?;
+  let violating_tables: Vec<String> = statement.query_map([], |row| row.get(0))?.collect::<Result<_>>()?;
+
+  if let Some(table) = violating_tables.first() {
+    return Err(rusqlite::Error::SqliteFailure(
+      rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_CONSTRAINT_FOREIGNKEY),
+      Some(format!("{} row(s) starting in the {} table reference rows that don't exist.", violating_tables.len(), table))
+    ));
+  }
+
+  Ok(())
+}
@@ -1,7 +1,11 @@
-use axum::response::{Html, IntoResponse};
-use crate::constants;
+use axum::{extract::State, response::{Html, IntoResponse}};
+use std::sync::Arc;
+use tokio::sync::Mutex;
 
-pub async fn index_html_route() -> impl IntoResponse {
-  let html = std::fs::read_to_string(constants::INDEX_HTML_PATH).unwrap();
+use crate::AppState;
+
+pub async fn index_html_route(State(state): State<Arc<Mutex<AppState>>>) -> impl IntoResponse {
+  let index_html_path = state.lock().await.config.index_html_path.clone();
+  let html = std::fs::read_to_string(index_html_path).unwrap();
   Html(html)
 }
@@ -1,45 +1,93 @@
 use tokio::sync::Mutex;
-use std::env;
+use std::{env, process};
 use http::Method;
 use tower_http::{cors::{Any, CorsLayer}, CompressionLevel};
 use tower_sessions::{cookie::{time::Duration, SameSite}, Expiry, MemoryStore, SessionManagerLayer};
 use tower_http::services::{ServeDir, ServeFile};
 use tower_http::compression::CompressionLayer;
 use std::sync::Arc;
+use std::net::SocketAddr;
 use axum::{extract::DefaultBodyLimit, routing::{get, post, put}, Router};
-use log::info;
+use axum_server::Handle;
+use log::{error, info};
 
 use api::{
   utils::download_utils::DownloadsManager,
-  utils::upload_utils::UploadsManager
+  utils::upload_utils::UploadsManager,
+  utils::webauthn_utils::WebauthnManager
 };
 
+use backup::start_scheduled_backups;
+use cli::{build_cli, run_admin_command};
 use config::Config;
 use shell::interactive_shell;
+use shutdown::shutdown_signal;
+use tls::{load_tls_config, start_certificate_reloader, start_http_redirect_server};
 use database::Database;
+use storage::{
+  encryptedstore::EncryptedStore,
+  filestore::FileStore,
+  replication::{ReplicatedStore, ReplicationManager},
+  s3store::S3Store,
+  scrubber::start_scheduled_scrubs,
+  BlobStore
+};
 
+mod admin;
+mod backup;
+mod cli;
 mod config;
 mod database;
 mod shell;
+mod shutdown;
+mod tls;
 mod api;
 mod constants;
 mod util;
 mod html;
+mod storage;
+
+#[cfg(test)]
+mod tests;
 
 struct AppState {
   config: Config,
   database: Option<Database>,
   uploads_manager: UploadsManager,
-  downloads_manager: DownloadsManager
+  downloads_manager: DownloadsManager,
+  webauthn_manager: WebauthnManager,
+  replication_manager: Option<Arc<ReplicationManager>>,
+
+  /// The primary store and its mirror when encryption at rest is enabled
+  encrypted_stores: Vec<Arc<EncryptedStore>>
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
   // Get config
-  let config = Config::initialise()?;
+  let args = build_cli().get_matches();
+  let config = match Config::initialise(&args) {
+    Ok(config) => config,
+    Err(err) => {
+      eprintln!("{}", err);
+      process::exit(1);
+    }
+  };
+
+  // Print the resolved config without starting the server
+  if args.get_flag("print-config") {
+    print!("{}", config.to_toml_string());
+    return Ok(());
+  }
   
   // Initialise logger (configured with the RUST_LOG environment variable)
   env_logger::init();
+
+  // Admin subcommands work on the database and exit without starting the server
+  if let Some(admin_args) = args.subcommand_matches("admin") {
+    config.initialise_directories()?;
+    return run_admin_command(&config, admin_args);
+  }
   
   // Print working directory
   let working_dir = env::current_dir()?;
@@ -49,23 +97,172 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
   config.initialise_directories()?;
 
   // Initialise database
-  let database_instance = Some(Database::open(&config)?);
+  let mut database = Database::open(&config)?;
+  database.check_chunk_data_size(config.transfer_limits.chunk_data_size)?;
+
+  // Initialise the storage backend for user files
+  let mut blob_store: Arc<dyn BlobStore> = if config.storage_backend == "s3" {
+    info!("Storing user files in S3 bucket '{}' at: {}", config.s3_bucket, config.s3_endpoint);
+    Arc::new(S3Store::new(&config).map_err(|err| err.to_string())?)
+  } else {
+    Arc::new(FileStore::new(&config.user_files_root_directory))
+  };
+
+  // Optionally encrypt user files at rest with the server's key
+  let mut encrypted_stores = Vec::new();
+
+  let mut encrypt_at_rest = |blob_store: Arc<dyn BlobStore>| -> Arc<dyn BlobStore> {
+    match config.at_rest_key {
+      Some(at_rest_key) => {
+        let encrypted_store = Arc::new(EncryptedStore::new(blob_store, at_rest_key, &config.user_upload_directory));
+        encrypted_stores.push(encrypted_store.clone());
+        encrypted_store
+      },
+      None => blob_store
+    }
+  };
+
+  if config.at_rest_key.is_some() {
+    info!("Encryption at rest is enabled.");
+  }
+
+  blob_store = encrypt_at_rest(blob_store);
+
+  // Optionally mirror user files to a second directory
+  let replication_manager = if config.mirror_root_directory.is_empty() {
+    None
+  } else {
+    info!("Mirroring user files to: {}", config.mirror_root_directory);
+
+    let mirror = encrypt_at_rest(Arc::new(FileStore::new(&config.mirror_root_directory)));
+    let manager = ReplicationManager::start(blob_store.clone(), mirror, &config.user_upload_directory);
+    blob_store = Arc::new(ReplicatedStore::new(blob_store, manager.clone()));
+
+    Some(manager)
+  };
 
   // Initialise upload/download managers
-  let uploads_manager = UploadsManager::new(&config);
-  let mut downloads_manager = DownloadsManager::new(&config);
+  let mut uploads_manager = UploadsManager::new(&config, blob_store.clone());
+  let mirror_blob_store = replication_manager.as_ref().map(|manager| manager.mirror());
+  let mut downloads_manager = DownloadsManager::new(blob_store.clone(), mirror_blob_store, config.transfer_limits);
   downloads_manager.start_inactivity_detector();
+
+  // Continue the uploads that were in progress when the server last stopped
+  let restored_upload_count = uploads_manager.restore_uploads(database.get_suspended_uploads()?).await;
+
+  if restored_upload_count > 0 {
+    info!("Restored {} upload(s) that were in progress when the server stopped.", restored_upload_count);
+  }
+
+  // Initialise WebAuthn
+  let webauthn_manager = WebauthnManager::new(&config)?;
   
   // Create app state to be shared
   let config_clone = config.clone();
 
   let shared_app_state = Arc::new(Mutex::new(AppState {
     config,
-    database: database_instance,
+    database: Some(database),
     uploads_manager,
-    downloads_manager
+    downloads_manager,
+    webauthn_manager,
+    replication_manager,
+    encrypted_stores
   }));
 
+  // Periodically check the stored user files against the database
+  if config_clone.scrub_interval_hours > 0 {
+    start_scheduled_scrubs(shared_app_state.clone(), config_clone.scrub_interval_hours, config_clone.scrub_quarantine_orphans);
+  }
+
+  // Periodically snapshot the database
+  if config_clone.backup_interval_hours > 0 {
+    start_scheduled_backups(shared_app_state.clone(), config_clone.backup_interval_hours);
+  }
+
+  let router = build_router(shared_app_state.clone(), &config_clone);
+
+  // Create listener
+  let server_ip_address = format!("{}:{}", config_clone.ip_address, config_clone.port);
+  let listener = std::net::TcpListener::bind(server_ip_address)?;
+  listener.set_nonblocking(true)?;
+
+  // Load the certificate when serving HTTPS directly
+  let tls_config = if config_clone.is_tls_enabled() {
+    let tls_config = load_tls_config(&config_clone.tls_cert_path, &config_clone.tls_key_path).await?;
+    start_certificate_reloader(tls_config.clone(), &config_clone.tls_cert_path, &config_clone.tls_key_path)?;
+    Some(tls_config)
+  } else {
+    None
+  };
+
+  // Stops the servers once shutdown starts
+  let server_handle = Handle::new();
+
+  if config_clone.http_redirect_port != 0 {
+    start_http_redirect_server(&config_clone.ip_address, config_clone.http_redirect_port, config_clone.port, server_handle.clone())?;
+  }
+
+  // Start server
+  info!(
+    "Server listening on {}:{} ({})",
+    config_clone.ip_address,
+    config_clone.port,
+    if tls_config.is_some() { "HTTPS" } else { "HTTP" }
+  );
+  info!("Secure cookies: {}", config_clone.secure_cookies);
+
+  // Stop when the shell's 'exit' command is entered, or on a signal when running without the shell
+  let no_shell = args.get_flag("no-shell");
+
+  tokio::spawn({
+    let shared_app_state = shared_app_state.clone();
+    let server_handle = server_handle.clone();
+
+    async move {
+      if no_shell {
+        info!("Running without the interactive shell. Send SIGTERM or press CTRL+C to stop the server.");
+        shutdown_signal().await;
+      } else {
+        interactive_shell(shared_app_state.clone()).await;
+      }
+
+      shared_app_state.lock().await.uploads_manager.stop_accepting_chunks();
+
+      // New connections stop being accepted, and in-flight requests such as chunk uploads are given time to finish
+      server_handle.graceful_shutdown(Some(std::time::Duration::from_secs(constants::SHUTDOWN_DRAIN_TIMEOUT_SECONDS)));
+    }
+  });
+
+  let make_service = router.into_make_service_with_connect_info::<SocketAddr>();
+
+  match tls_config {
+    Some(tls_config) => axum_server::from_tcp_rustls(listener, tls_config).handle(server_handle).serve(make_service).await?,
+    None => axum_server::from_tcp(listener).handle(server_handle).serve(make_service).await?
+  };
+
+  // Save the progress of active uploads so clients can continue them after a restart
+  {
+    let mut app_state = shared_app_state.lock().await;
+    let suspended_uploads = app_state.uploads_manager.suspend_uploads().await;
+
+    match app_state.database.as_mut().unwrap().replace_suspended_uploads(&suspended_uploads) {
+      Ok(_) => info!("Saved {} active upload(s).", suspended_uploads.len()),
+      Err(err) => error!("Failed to save active uploads: {}", err)
+    };
+  }
+
+  // Close database
+  info!("Closing database...");
+
+  let database = shared_app_state.lock().await.database.take().expect("Database is none!");
+  database.close();
+
+  Ok(())
+}
+
+/// Creates the router with every route and layer the server serves.
+fn build_router(shared_app_state: Arc<Mutex<AppState>>, config: &Config) -> Router {
   // Create the CORS layer
   let cors = CorsLayer::new()
     .allow_methods([ Method::GET, Method::POST, Method::PUT ])
@@ -76,29 +273,44 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
   // Create layers
   let session_layer = SessionManagerLayer::new(session_store)
-    .with_secure(config_clone.secure_cookies)
+    .with_secure(config.secure_cookies)
     .with_same_site(SameSite::Strict)
     .with_expiry(Expiry::OnInactivity(Duration::seconds(constants::SESSION_EXPIRY_TIME_SECONDS)))
-    .with_signed(config_clone.session_secret_key);
+    .with_signed(config.session_secret_key.clone());
 
   let compression_layer = CompressionLayer::new() // TODO: more compression types? con: more dependencies
     .gzip(true)
     .quality(CompressionLevel::Default);
 
   // Create router
-  let router = Router::new()
-    .route_service("/", ServeFile::new(constants::INDEX_HTML_PATH))
-    .nest_service("/assets", ServeDir::new(constants::DIST_ASSETS_PATH))
+  Router::new()
+    .route_service("/", ServeFile::new(&config.index_html_path))
+    .nest_service("/assets", ServeDir::new(&config.dist_assets_path))
     .nest("/api", Router::new()
       .route("/sessiondata", get(api::general::get_session_data_api))
+      .route("/serverinfo", get(api::general::get_server_info_api))
       .route("/logout", post(api::general::logout_api))
       .route("/login", post(api::general::login_api))
+      .route("/login/totp", post(api::totp::login_totp_api))
+      .route("/login/webauthn/start", post(api::webauthn::login_webauthn_start_api))
+      .route("/login/webauthn/finish", post(api::webauthn::login_webauthn_finish_api))
       .nest("/accounts", Router::new()
         .route("/claim", post(api::account::claim_api))
         .route("/claimcode", get(api::account::get_claim_code_api))
         .route("/:username/salt", get(api::account::get_salt_api))
+        .route("/recovery", put(api::account::set_recovery_api))
+        .route("/recovery/key", post(api::account::get_recovery_key_api))
+        .route("/recovery/password", put(api::account::recover_password_api))
         .layer(compression_layer.clone())
       )
+      .nest("/totp", Router::new()
+        .route("/enrol", post(api::totp::enrol_totp_api))
+        .route("/confirm", post(api::totp::confirm_totp_api))
+      )
+      .nest("/webauthn", Router::new()
+        .route("/register/start", post(api::webauthn::start_registration_api))
+        .route("/register/finish", post(api::webauthn::finish_registration_api))
+      )
       .nest("/filesystem", Router::new()
         .route("/usage", get(api::filesystem::get_usage_api))
         .route("/folders", post(api::filesystem::create_folder_api))
@@ -108,44 +320,33 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
       )
       .nest("/uploads", Router::new()
         .route("/", post(api::uploads::start_upload_api))
+        .route("/:handle", get(api::uploads::get_upload_progress_api))
         .route("/:handle/finalise", put(api::uploads::finalise_upload_api))
         .route("/chunks", post(api::uploads::upload_chunk_api))
 
         // Make the default body size limit for the upload routes the chunk data size plus a bit of overhead
-        .layer(DefaultBodyLimit::max(constants::CHUNK_DATA_SIZE + 1024))
+        .layer(DefaultBodyLimit::max(config.transfer_limits.chunk_data_size + 1024))
         .layer(compression_layer.clone())
       )
       .nest("/downloads", Router::new()
         .route("/:handle/chunks/:chunk", get(api::downloads::download_chunk_api))
       )
+      .nest("/admin", Router::new()
+        .route("/users", get(api::admin::get_users_api))
+        .route("/users/:username/quota", put(api::admin::set_user_quota_api))
+        .route("/claimcodes", get(api::admin::get_claim_codes_api).post(api::admin::create_claim_code_api))
+        .route("/claimcodes/:code/revoke", post(api::admin::revoke_claim_code_api))
+        .route("/transfers", get(api::admin::get_transfers_api))
+        .route("/jobs/:job", post(api::admin::start_job_api))
+        .layer(compression_layer.clone())
+      )
     )
     .nest("/cdn", Router::new()
       .route("/:name", get(api::cdn::cdn_api))
       .layer(compression_layer.clone())
     )
     .fallback(get(html::index_html_route)) // Serve index.html as a fallback because of client side routing
-    .with_state(shared_app_state.clone())
+    .with_state(shared_app_state)
     .layer(session_layer)
-    .layer(cors);
-
-  // Create listener
-  let server_ip_address = format!("{}:{}", config_clone.ip_address, config_clone.port);
-  let listener = tokio::net::TcpListener::bind(server_ip_address).await.unwrap();
-
-  // Start server
-  info!("Server listening on {}:{}", config_clone.ip_address, config_clone.port);
-  info!("Secure cookies: {}", config_clone.secure_cookies);
-
-  axum::serve(listener, router)
-    .with_graceful_shutdown(interactive_shell(shared_app_state.clone())) // Start the interactive shell
-    .await
-    .unwrap();
-
-  // Close database
-  info!("Closing database...");
-
-  let database = shared_app_state.lock().await.database.take().expect("Database is none!");
-  database.close();
-
-  Ok(())
+    .layer(cors)
 }
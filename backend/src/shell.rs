@@ -1,14 +1,37 @@
 use tokio::sync::{broadcast, Mutex};
 use std::sync::Arc;
-use dialoguer::{theme::ColorfulTheme, Confirm, Input, Select};
+use dialoguer::{theme::ColorfulTheme, BasicHistory, Confirm, Input, Select};
 use console::style;
 use std::cmp;
 use log::{info, error};
 use crate::AppState;
+use crate::admin::{self, create_claim_code, rename_user, NewClaimCode};
+use crate::backup::{create_backup, list_snapshots, restore_backup};
+use crate::database::LoginLockoutKind;
+use crate::storage::{filestore::FileStore, scrubber::run_scrub};
 
-use crate::util::{generate_claim_code, parse_byte_size_str};
+use crate::util::{get_unix_timestamp_secs, parse_byte_size_str};
 use crate::constants;
 
+use registry::{CommandRegistry, ShellCommand};
+
+mod registry;
+
+/// The width of the storage quota column when listing users.
+const STORAGE_QUOTA_COLUMN_WIDTH: usize = 13;
+
+/// The width of the status column when listing claim codes.
+const CLAIM_CODE_STATUS_COLUMN_WIDTH: usize = 9;
+
+/// Recorded as the creator of claim codes made in the shell.
+const CLAIM_CODE_CREATOR: &str = "shell";
+
+/// The number of entered commands that can be recalled with the arrow keys.
+const SHELL_HISTORY_MAX_ENTRIES: usize = 100;
+
+/// Commands handled by the dispatcher itself rather than the registry.
+const BUILTIN_COMMAND_NAMES: [&str; 2] = ["help", "exit"];
+
 pub async fn interactive_shell(shared_app_state: Arc<Mutex<AppState>>) {
   // Recommend user to use the 'exit' command to close the server when they press CTRL+C
   ctrlc::set_handler(|| {
@@ -33,28 +56,43 @@ pub async fn interactive_shell(shared_app_state: Arc<Mutex<AppState>>) {
 
   tokio::spawn(async move {
     let shell_theme = ColorfulTheme::default();
+    let command_registry = build_command_registry();
+    let mut history = BasicHistory::new().max_entries(SHELL_HISTORY_MAX_ENTRIES).no_duplicates(true);
 
     loop {
-      let command: String = Input::with_theme(&shell_theme)
+      let input: String = Input::with_theme(&shell_theme)
+        .history_with(&mut history)
         .interact_text()
         .unwrap();
 
-      let command_lowercase = command.to_lowercase();
+      // The first word is the command and the rest are its arguments
+      let mut words = input.split_whitespace().map(str::to_string);
+
+      let command_name = match words.next() {
+        Some(name) => name.to_lowercase(),
+        None => continue
+      };
+
+      let args: Vec<String> = words.collect();
 
       // Immediately handle the exit command first
-      if command_lowercase == "exit" {
+      if command_name == "exit" {
         let _ = stop_shell_tx.send(());
         break;
       }
 
-      // Handle commands (TODO: better way with map string to function? + levenshtein distance for "did you mean this command" functionality)
-      if command_lowercase == "newcode" {
-        new_claim_code_command(shared_app_state.clone()).await;
-      } else if command_lowercase == "list" {
-        list_command(shared_app_state.clone()).await;
-      } else {
-        println!("{}", style("Unknown command.").yellow());
+      if command_name == "help" {
+        print_help(&command_registry, args.first());
+        continue;
       }
+
+      match command_registry.find(&command_name) {
+        Some(command) => (command.handler)(shared_app_state.clone(), args).await,
+        None => match command_registry.suggest(&command_name, &BUILTIN_COMMAND_NAMES) {
+          Some(suggestion) => println!("{}", style(format!("Unknown command. Did you mean '{}'?", suggestion)).yellow()),
+          None => println!("{}", style("Unknown command. Enter 'help' to list the commands.").yellow())
+        }
+      };
     }
   });
 
@@ -64,72 +102,349 @@ pub async fn interactive_shell(shared_app_state: Arc<Mutex<AppState>>) {
   }
 }
 
-// Commands
+/// Registers every shell command. New commands only need to be added here.
+fn build_command_registry() -> CommandRegistry {
+  let mut registry = CommandRegistry::new();
 
-async fn new_claim_code_command(shared_app_state: Arc<Mutex<AppState>>) {
-  let shell_theme = ColorfulTheme::default();
+  registry.register(ShellCommand {
+    name: "newcode",
+    aliases: &[],
+    arguments: "[storage quota] [max uses] [days valid]",
+    help: "Create a claim code that lets people register accounts.",
+    handler: |state, args| Box::pin(new_claim_code_command(state, args))
+  });
 
-  let storage_quota_str = Input::with_theme(&shell_theme)
-    .with_prompt("Storage quota")
-    .validate_with(|input: &String| {
-      parse_byte_size_str(input.clone())
-        .map(|_| ())
-        .map_err(|err| err.to_string())
-    })
-    .interact_text()
-    .unwrap();
+  registry.register(ShellCommand {
+    name: "revokecode",
+    aliases: &[],
+    arguments: "[claim code]",
+    help: "Stop a claim code from being used.",
+    handler: |state, args| Box::pin(revoke_claim_code_command(state, args))
+  });
 
-  let storage_quota = parse_byte_size_str(storage_quota_str).expect("The storage quota string is already validated!");
+  registry.register(ShellCommand {
+    name: "list",
+    aliases: &["ls"],
+    arguments: "[codes|users]",
+    help: "List the claim codes or the registered users.",
+    handler: |state, args| Box::pin(list_command(state, args))
+  });
 
-  // Confirm creation of new claim code
-  let bytes_formatted_str = bytesize::to_string(storage_quota, false);
+  registry.register(ShellCommand {
+    name: "unlock",
+    aliases: &[],
+    arguments: "[username|ip] [identifier]",
+    help: "Clear the login lockout of a username or IP address.",
+    handler: |state, args| Box::pin(unlock_command(state, args))
+  });
 
-  let confirmed = Confirm::with_theme(&shell_theme)
-    .with_prompt(format!("Create new claim code with a storage quota of {} bytes?", bytes_formatted_str))
-    .wait_for_newline(true)
-    .interact()
-    .unwrap();
+  registry.register(ShellCommand {
+    name: "resettotp",
+    aliases: &[],
+    arguments: "[username]",
+    help: "Remove a user's TOTP and backup codes.",
+    handler: |state, args| Box::pin(reset_totp_command(state, args))
+  });
+
+  registry.register(ShellCommand {
+    name: "resetwebauthn",
+    aliases: &[],
+    arguments: "[username]",
+    help: "Remove all of a user's WebAuthn credentials.",
+    handler: |state, args| Box::pin(reset_webauthn_command(state, args))
+  });
+
+  registry.register(ShellCommand {
+    name: "setquota",
+    aliases: &[],
+    arguments: "[username] [storage quota]",
+    help: "Change a user's storage quota.",
+    handler: |state, args| Box::pin(set_quota_command(state, args))
+  });
+
+  registry.register(ShellCommand {
+    name: "disable",
+    aliases: &[],
+    arguments: "[username]",
+    help: "Stop a user from logging in and log out their sessions.",
+    handler: |state, args| Box::pin(disable_user_command(state, args))
+  });
+
+  registry.register(ShellCommand {
+    name: "enable",
+    aliases: &[],
+    arguments: "[username]",
+    help: "Let a disabled user log in again.",
+    handler: |state, args| Box::pin(enable_user_command(state, args))
+  });
+
+  registry.register(ShellCommand {
+    name: "grantadmin",
+    aliases: &[],
+    arguments: "[username]",
+    help: "Let a user use the admin API.",
+    handler: |state, args| Box::pin(grant_admin_command(state, args))
+  });
+
+  registry.register(ShellCommand {
+    name: "revokeadmin",
+    aliases: &[],
+    arguments: "[username]",
+    help: "Stop a user from using the admin API.",
+    handler: |state, args| Box::pin(revoke_admin_command(state, args))
+  });
+
+  registry.register(ShellCommand {
+    name: "rename",
+    aliases: &[],
+    arguments: "[username] [new username]",
+    help: "Change a user's username.",
+    handler: |state, args| Box::pin(rename_user_command(state, args))
+  });
+
+  registry.register(ShellCommand {
+    name: "deleteuser",
+    aliases: &[],
+    arguments: "[username]",
+    help: "Permanently delete a user and all of their files.",
+    handler: |state, args| Box::pin(delete_user_command(state, args))
+  });
+
+  registry.register(ShellCommand {
+    name: "migratestorage",
+    aliases: &[],
+    arguments: "",
+    help: "Move user files stored in the old flat layout into sharded directories.",
+    handler: |state, args| Box::pin(migrate_storage_command(state, args))
+  });
+
+  registry.register(ShellCommand {
+    name: "replication",
+    aliases: &[],
+    arguments: "",
+    help: "Show the mirror's replication status and optionally re-sync it.",
+    handler: |state, args| Box::pin(replication_command(state, args))
+  });
+
+  registry.register(ShellCommand {
+    name: "encryptstorage",
+    aliases: &[],
+    arguments: "",
+    help: "Encrypt user files that were stored before encryption at rest was enabled.",
+    handler: |state, args| Box::pin(encrypt_storage_command(state, args))
+  });
+
+  registry.register(ShellCommand {
+    name: "scrub",
+    aliases: &[],
+    arguments: "",
+    help: "Check the stored user files against the database.",
+    handler: |state, args| Box::pin(scrub_command(state, args))
+  });
+
+  registry.register(ShellCommand {
+    name: "backup",
+    aliases: &[],
+    arguments: "",
+    help: "Take a snapshot of the database.",
+    handler: |state, args| Box::pin(backup_command(state, args))
+  });
+
+  registry.register(ShellCommand {
+    name: "restore",
+    aliases: &[],
+    arguments: "",
+    help: "Replace the database with one of its snapshots.",
+    handler: |state, args| Box::pin(restore_command(state, args))
+  });
+
+  registry
+}
+
+/// Lists every command, or describes one command if its name is given.
+fn print_help(command_registry: &CommandRegistry, command_name: Option<&String>) {
+  if let Some(command_name) = command_name {
+    match command_registry.find(command_name) {
+      Some(command) => {
+        println!("{} {}", style(command.name).cyan().bold(), command.arguments);
+        println!("  {}", command.help);
+
+        if !command.aliases.is_empty() {
+          println!("  Aliases: {}", command.aliases.join(", "));
+        }
+      },
+      None => println!("{}", style("Unknown command.").yellow())
+    };
 
-  if !confirmed {
     return;
   }
 
-  // Generate claim code
-  let claim_code = generate_claim_code();
+  let usages: Vec<String> = command_registry.commands().iter()
+    .map(|command| format!("{} {}", command.name, command.arguments))
+    .collect();
+
+  let usage_width = usages.iter().map(|usage| usage.len()).max().unwrap_or(0);
+
+  for (command, usage) in command_registry.commands().iter().zip(&usages) {
+    println!("{}   {}", style(format!("{:pad$}", usage, pad = usage_width)).cyan(), command.help);
+  }
+
+  println!("{}   Show the commands or describe one of them.", style(format!("{:pad$}", "help [command]", pad = usage_width)).cyan());
+  println!("{}   Stop the server.", style(format!("{:pad$}", "exit", pad = usage_width)).cyan());
+}
+
+/// Returns the argument at the index, or asks for it if it wasn't given.
+fn get_arg_or_input(args: &[String], index: usize, prompt: &str) -> String {
+  match args.get(index) {
+    Some(arg) => arg.clone(),
+    None => Input::with_theme(&ColorfulTheme::default())
+      .with_prompt(prompt)
+      .interact_text()
+      .unwrap()
+  }
+}
+
+/// Looks up a user's id from their username. Prints a message and returns `None` if the user doesn't exist.
+async fn find_user_id(shared_app_state: &Arc<Mutex<AppState>>, username: &String) -> Option<u64> {
+  let mut app_state = shared_app_state.lock().await;
+  let database = app_state.database.as_mut().unwrap();
+
+  match admin::find_user_id(database, username) {
+    Ok(user_id) => Some(user_id),
+    Err(err) => {
+      println!("{}", style(err).yellow());
+      None
+    }
+  }
+}
+
+// Commands
+
+async fn new_claim_code_command(shared_app_state: Arc<Mutex<AppState>>, args: Vec<String>) {
+  let shell_theme = ColorfulTheme::default();
+
+  // The claim code is created straight away when the storage quota is given as an argument
+  let (storage_quota, max_uses, days_valid, note) = if !args.is_empty() {
+    let storage_quota = parse_byte_size_str(args[0].clone());
+    let max_uses = args.get(1).map_or(Ok(1), |arg| arg.parse::<u64>());
+    let days_valid = args.get(2).map_or(Ok(0), |arg| arg.parse::<u64>());
+
+    match (storage_quota, max_uses, days_valid) {
+      (Ok(storage_quota), Ok(max_uses), Ok(days_valid)) if max_uses > 0 => (storage_quota, max_uses, days_valid, None),
+      (Err(err), _, _) => {
+        println!("{}", style(format!("Invalid storage quota: {}", err)).yellow());
+        return;
+      },
+      _ => {
+        println!("{}", style("The max uses must be a positive number and the days valid a number.").yellow());
+        return;
+      }
+    }
+  } else {
+    let storage_quota_str = Input::with_theme(&shell_theme)
+      .with_prompt("Storage quota")
+      .validate_with(|input: &String| {
+        parse_byte_size_str(input.clone())
+          .map(|_| ())
+          .map_err(|err| err.to_string())
+      })
+      .interact_text()
+      .unwrap();
+
+    let storage_quota = parse_byte_size_str(storage_quota_str).expect("The storage quota string is already validated!");
+
+    let max_uses: u64 = Input::with_theme(&shell_theme)
+      .with_prompt("Max uses")
+      .default(1)
+      .validate_with(|input: &u64| if *input > 0 { Ok(()) } else { Err("Must be at least 1.") })
+      .interact_text()
+      .unwrap();
+
+    let days_valid: u64 = Input::with_theme(&shell_theme)
+      .with_prompt("Days until expiry (0 for never)")
+      .default(0)
+      .interact_text()
+      .unwrap();
+
+    let note: String = Input::with_theme(&shell_theme)
+      .with_prompt("Note")
+      .allow_empty(true)
+      .interact_text()
+      .unwrap();
+
+    // Confirm creation of new claim code
+    let bytes_formatted_str = bytesize::to_string(storage_quota, false);
+
+    let confirmed = Confirm::with_theme(&shell_theme)
+      .with_prompt(format!("Create new claim code with a storage quota of {} bytes?", bytes_formatted_str))
+      .wait_for_newline(true)
+      .interact()
+      .unwrap();
+
+    if !confirmed {
+      return;
+    }
+
+    (storage_quota, max_uses, days_valid, Some(note))
+  };
+
+  let new_claim_code = NewClaimCode { storage_quota, max_uses, days_valid, note };
 
   // Insert into database
   let mut app_state = shared_app_state.lock().await;
   let database = app_state.database.as_mut().unwrap();
 
-  match database.insert_new_claim_code(claim_code.as_str(), storage_quota) {
-    Ok(_) => println!("New claim code: {}", style(claim_code).cyan().bold()),
+  match create_claim_code(database, new_claim_code, CLAIM_CODE_CREATOR) {
+    Ok(claim_code_data) => println!("New claim code: {}", style(claim_code_data.claim_code).cyan().bold()),
     Err(_) => error!("Failed to create new claim code.")
   };
 }
 
-async fn list_command(shared_app_state: Arc<Mutex<AppState>>) {
+async fn revoke_claim_code_command(shared_app_state: Arc<Mutex<AppState>>, args: Vec<String>) {
+  let claim_code = get_arg_or_input(&args, 0, "Claim code");
+
+  // Acquire database
+  let mut app_state = shared_app_state.lock().await;
+  let database = app_state.database.as_mut().unwrap();
+
+  match database.revoke_claim_code(claim_code.trim(), get_unix_timestamp_secs()) {
+    Ok(0) => println!("{}", style("No unrevoked claim code found.").yellow()),
+    Ok(_) => println!("Revoked claim code {}", style(claim_code.trim()).cyan().bold()),
+    Err(err) => error!("Failed to revoke claim code: {}", err)
+  };
+}
+
+async fn list_command(shared_app_state: Arc<Mutex<AppState>>, args: Vec<String>) {
   let shell_theme = ColorfulTheme::default();
 
-  // Ask user to select what type of info to list
-  let chosen_info_type = Select::with_theme(&shell_theme)
-    .with_prompt("Info to list")
-    .items(&["Available claim codes", "All registered users"])
-    .default(0)
-    .interact()
-    .unwrap();
+  // Ask user to select what type of info to list unless it was given as an argument
+  let chosen_info_type = match args.first().map(|arg| arg.to_lowercase()).as_deref() {
+    Some("codes") => 0,
+    Some("users") => 1,
+    Some(_) => {
+      println!("{}", style("Expected 'codes' or 'users'.").yellow());
+      return;
+    },
+    None => Select::with_theme(&shell_theme)
+      .with_prompt("Info to list")
+      .items(&["All claim codes", "All registered users"])
+      .default(0)
+      .interact()
+      .unwrap()
+  };
 
   // Acquire database
   let mut app_state = shared_app_state.lock().await;
   let database = app_state.database.as_mut().unwrap();
 
   if chosen_info_type == 0 {
-    // Get available claim codes from the database
-    let claim_codes = match database.get_available_claim_codes() {
+    // Get all claim codes from the database
+    let claim_codes = match database.get_all_claim_codes() {
       Ok(data) => data,
       Err(_) => return
     };
 
-    // Print message and return if no claim codes are available.
+    // Print message and return if there are no claim codes.
     if claim_codes.is_empty() {
       println!("{}", style("No claim codes found.").yellow());
       return;
@@ -137,18 +452,35 @@ async fn list_command(shared_app_state: Arc<Mutex<AppState>>) {
 
     // Create text
     let mut output_text = String::new();
+    let current_time = get_unix_timestamp_secs();
 
-    let header_text = format!("{:pad$} | Storage quota\n", "Claim code", pad = constants::CLAIM_CODE_LENGTH);
+    let header_text = format!(
+      "{:pad$} | {:quota_pad$} | {:status_pad$} | Uses  | Expires  | Created by | Note\n",
+      "Claim code", "Storage quota", "Status",
+      pad = constants::CLAIM_CODE_LENGTH, quota_pad = STORAGE_QUOTA_COLUMN_WIDTH, status_pad = CLAIM_CODE_STATUS_COLUMN_WIDTH
+    );
 
     output_text.push_str(style(header_text).cyan().bold().to_string().as_str());
   
     // Add rows
     for code in claim_codes {
+      let expires_str = match code.expiry_time {
+        Some(expiry_time) if expiry_time > current_time => format!("in {}d", (expiry_time - current_time).div_ceil(86400)),
+        Some(_) => "-".to_string(),
+        None => "Never".to_string()
+      };
+
       output_text.push_str(
         format!(
-          "{}   {}\n",
+          "{}   {:quota_pad$}   {:status_pad$}   {:5}   {:8}   {:10}   {}\n",
           code.claim_code,
-          bytesize::to_string(code.storage_quota, false)
+          bytesize::to_string(code.storage_quota, false),
+          code.get_status(current_time).as_str(),
+          format!("{}/{}", code.use_count, code.max_uses),
+          expires_str,
+          code.created_by.as_deref().unwrap_or("-"),
+          code.note.as_deref().unwrap_or(""),
+          quota_pad = STORAGE_QUOTA_COLUMN_WIDTH, status_pad = CLAIM_CODE_STATUS_COLUMN_WIDTH
         ).as_str()
       );
     };
@@ -176,7 +508,7 @@ async fn list_command(shared_app_state: Arc<Mutex<AppState>>) {
     // Create output text
     let mut output_text = String::new();
   
-    let header_text = format!("{:pad$} | Storage quota\n", "Username", pad = max_username_length);
+    let header_text = format!("{:pad$} | {:quota_pad$} | Recovery | Status\n", "Username", "Storage quota", pad = max_username_length, quota_pad = STORAGE_QUOTA_COLUMN_WIDTH);
     
     output_text.push_str(style(header_text).cyan().bold().to_string().as_str());
   
@@ -185,7 +517,18 @@ async fn list_command(shared_app_state: Arc<Mutex<AppState>>) {
 
     for user in all_users {
       let storage_quota_str = bytesize::to_string(user.storage_quota.unwrap(), false);
-      let row_str = format!("{:pad$}{}\n", user.username, storage_quota_str, pad = row_pad_width + 3);
+      let recovery_str = if user.recovery_key_hash.is_some() { "Set up" } else { "None" };
+      let status_str = match (user.disabled, user.is_admin) {
+        (true, _) => "Disabled",
+        (false, true) => "Admin",
+        (false, false) => "Active"
+      };
+
+      let row_str = format!(
+        "{:pad$}{:quota_pad$}{:recovery_pad$}{}\n",
+        user.username, storage_quota_str, recovery_str, status_str,
+        pad = row_pad_width + 3, quota_pad = STORAGE_QUOTA_COLUMN_WIDTH + 3, recovery_pad = "Recovery".len() + 3
+      );
 
       output_text.push_str(row_str.as_str());
     };
@@ -194,3 +537,491 @@ async fn list_command(shared_app_state: Arc<Mutex<AppState>>) {
     println!("\n{}", output_text);
   }
 }
+
+async fn unlock_command(shared_app_state: Arc<Mutex<AppState>>, args: Vec<String>) {
+  let shell_theme = ColorfulTheme::default();
+
+  // Ask user what type of login lockout to clear unless it was given as an argument
+  let chosen_kind = match args.first().map(|arg| arg.to_lowercase()).as_deref() {
+    Some("username") => 0,
+    Some("ip") => 1,
+    Some(_) => {
+      println!("{}", style("Expected 'username' or 'ip'.").yellow());
+      return;
+    },
+    None => Select::with_theme(&shell_theme)
+      .with_prompt("Lockout to clear")
+      .items(&["Username", "IP address"])
+      .default(0)
+      .interact()
+      .unwrap()
+  };
+
+  let identifier = get_arg_or_input(&args, 1, if chosen_kind == 0 { "Username" } else { "IP address" });
+
+  // Usernames are tracked case insensitively
+  let (kind, identifier) = if chosen_kind == 0 {
+    (LoginLockoutKind::Username, identifier.trim().to_ascii_lowercase())
+  } else {
+    (LoginLockoutKind::IpAddress, identifier.trim().to_string())
+  };
+
+  // Acquire database
+  let mut app_state = shared_app_state.lock().await;
+  let database = app_state.database.as_mut().unwrap();
+
+  match database.clear_login_lockout(kind, &identifier) {
+    Ok(0) => println!("{}", style("No failed login attempts found.").yellow()),
+    Ok(_) => println!("Cleared login lockout for {}", style(identifier).cyan().bold()),
+    Err(_) => error!("Failed to clear login lockout.")
+  };
+}
+
+async fn reset_totp_command(shared_app_state: Arc<Mutex<AppState>>, args: Vec<String>) {
+  let shell_theme = ColorfulTheme::default();
+
+  let username = get_arg_or_input(&args, 0, "Username");
+
+  let user_id = match find_user_id(&shared_app_state, &username).await {
+    Some(user_id) => user_id,
+    None => return
+  };
+
+  // Confirm reset of two-factor authentication
+  let confirmed = Confirm::with_theme(&shell_theme)
+    .with_prompt(format!("Remove TOTP and backup codes for {}?", username))
+    .wait_for_newline(true)
+    .interact()
+    .unwrap();
+
+  if !confirmed {
+    return;
+  }
+
+  // Acquire database
+  let mut app_state = shared_app_state.lock().await;
+  let database = app_state.database.as_mut().unwrap();
+
+  match database.delete_user_totp(user_id) {
+    Ok(_) => println!("Reset TOTP for {}", style(username).cyan().bold()),
+    Err(_) => error!("Failed to reset TOTP.")
+  };
+}
+
+async fn reset_webauthn_command(shared_app_state: Arc<Mutex<AppState>>, args: Vec<String>) {
+  let shell_theme = ColorfulTheme::default();
+
+  let username = get_arg_or_input(&args, 0, "Username");
+
+  let user_id = match find_user_id(&shared_app_state, &username).await {
+    Some(user_id) => user_id,
+    None => return
+  };
+
+  // Confirm removal of the user's security keys
+  let confirmed = Confirm::with_theme(&shell_theme)
+    .with_prompt(format!("Remove all WebAuthn credentials for {}?", username))
+    .wait_for_newline(true)
+    .interact()
+    .unwrap();
+
+  if !confirmed {
+    return;
+  }
+
+  // Acquire database
+  let mut app_state = shared_app_state.lock().await;
+  let database = app_state.database.as_mut().unwrap();
+
+  match database.delete_user_webauthn_credentials(user_id) {
+    Ok(count) => println!("Removed {} WebAuthn credential(s) for {}", count, style(username).cyan().bold()),
+    Err(_) => error!("Failed to remove WebAuthn credentials.")
+  };
+}
+
+async fn set_quota_command(shared_app_state: Arc<Mutex<AppState>>, args: Vec<String>) {
+  let username = get_arg_or_input(&args, 0, "Username");
+
+  let user_id = match find_user_id(&shared_app_state, &username).await {
+    Some(user_id) => user_id,
+    None => return
+  };
+
+  let storage_quota_result = if args.len() > 1 {
+    parse_byte_size_str(args[1..].join(" "))
+  } else {
+    let storage_quota_str = Input::with_theme(&ColorfulTheme::default())
+      .with_prompt("New storage quota")
+      .validate_with(|input: &String| {
+        parse_byte_size_str(input.clone())
+          .map(|_| ())
+          .map_err(|err| err.to_string())
+      })
+      .interact_text()
+      .unwrap();
+
+    parse_byte_size_str(storage_quota_str)
+  };
+
+  let storage_quota = match storage_quota_result {
+    Ok(storage_quota) => storage_quota,
+    Err(err) => {
+      println!("{}", style(format!("Invalid storage quota: {}", err)).yellow());
+      return;
+    }
+  };
+
+  // Acquire database
+  let mut app_state = shared_app_state.lock().await;
+  let database = app_state.database.as_mut().unwrap();
+
+  if let Err(err) = database.set_user_storage_quota(user_id, storage_quota) {
+    error!("Failed to set storage quota: {}", err);
+    return;
+  }
+
+  println!("Set the storage quota of {} to {}", style(&username).cyan().bold(), bytesize::to_string(storage_quota, false));
+
+  // Existing files are kept but the user can't upload more until they're under the quota
+  if let Ok(storage_used) = database.get_user_storage_used(user_id) {
+    if storage_used > storage_quota {
+      println!("{}", style(format!("{} is already using {}.", username, bytesize::to_string(storage_used, false))).yellow());
+    }
+  }
+}
+
+async fn disable_user_command(shared_app_state: Arc<Mutex<AppState>>, args: Vec<String>) {
+  set_user_disabled(shared_app_state, args, true).await;
+}
+
+async fn enable_user_command(shared_app_state: Arc<Mutex<AppState>>, args: Vec<String>) {
+  set_user_disabled(shared_app_state, args, false).await;
+}
+
+async fn set_user_disabled(shared_app_state: Arc<Mutex<AppState>>, args: Vec<String>, disabled: bool) {
+  let username = get_arg_or_input(&args, 0, "Username");
+
+  let user_id = match find_user_id(&shared_app_state, &username).await {
+    Some(user_id) => user_id,
+    None => return
+  };
+
+  // Acquire database
+  let mut app_state = shared_app_state.lock().await;
+  let database = app_state.database.as_mut().unwrap();
+
+  // Sessions check the account on every request so a disabled user is logged out straight away
+  match database.set_user_disabled(user_id, disabled) {
+    Ok(_) if disabled => println!("Disabled {}", style(username).cyan().bold()),
+    Ok(_) => println!("Enabled {}", style(username).cyan().bold()),
+    Err(err) => error!("Failed to update user: {}", err)
+  };
+}
+
+async fn grant_admin_command(shared_app_state: Arc<Mutex<AppState>>, args: Vec<String>) {
+  set_user_admin(shared_app_state, args, true).await;
+}
+
+async fn revoke_admin_command(shared_app_state: Arc<Mutex<AppState>>, args: Vec<String>) {
+  set_user_admin(shared_app_state, args, false).await;
+}
+
+async fn set_user_admin(shared_app_state: Arc<Mutex<AppState>>, args: Vec<String>, is_admin: bool) {
+  let username = get_arg_or_input(&args, 0, "Username");
+
+  let user_id = match find_user_id(&shared_app_state, &username).await {
+    Some(user_id) => user_id,
+    None => return
+  };
+
+  // Acquire database
+  let mut app_state = shared_app_state.lock().await;
+  let database = app_state.database.as_mut().unwrap();
+
+  match database.set_user_admin(user_id, is_admin) {
+    Ok(_) if is_admin => println!("{} is now an admin", style(username).cyan().bold()),
+    Ok(_) => println!("{} is no longer an admin", style(username).cyan().bold()),
+    Err(err) => error!("Failed to update user: {}", err)
+  };
+}
+
+async fn rename_user_command(shared_app_state: Arc<Mutex<AppState>>, args: Vec<String>) {
+  let username = get_arg_or_input(&args, 0, "Username");
+
+  if find_user_id(&shared_app_state, &username).await.is_none() {
+    return;
+  }
+
+  let new_username = get_arg_or_input(&args, 1, "New username");
+
+  // Acquire database
+  let mut app_state = shared_app_state.lock().await;
+  let database = app_state.database.as_mut().unwrap();
+
+  match rename_user(database, &username, &new_username) {
+    Ok(_) => println!("Renamed {} to {}", username, style(new_username).cyan().bold()),
+    Err(err) => println!("{}", style(format!("Failed to rename user: {}", err)).yellow())
+  };
+}
+
+async fn delete_user_command(shared_app_state: Arc<Mutex<AppState>>, args: Vec<String>) {
+  let shell_theme = ColorfulTheme::default();
+
+  let username = get_arg_or_input(&args, 0, "Username");
+
+  let user_id = match find_user_id(&shared_app_state, &username).await {
+    Some(user_id) => user_id,
+    None => return
+  };
+
+  let confirmed = Confirm::with_theme(&shell_theme)
+    .with_prompt(format!("Permanently delete {} and all of their files? This can't be undone.", username))
+    .default(false)
+    .wait_for_newline(true)
+    .interact()
+    .unwrap();
+
+  if !confirmed {
+    return;
+  }
+
+  // The database entries are deleted first so a failure part way through only leaves orphaned files for scrubbing
+  let (deleted_handles, cancelled_upload_count, blob_store) = {
+    let mut app_state = shared_app_state.lock().await;
+    let blob_store = app_state.uploads_manager.blob_store.clone();
+    let database = app_state.database.as_mut().unwrap();
+
+    let deleted_handles = match database.delete_user(user_id) {
+      Ok(handles) => handles,
+      Err(err) => {
+        error!("Failed to delete user: {}", err);
+        return;
+      }
+    };
+
+    let _ = database.clear_login_lockout(LoginLockoutKind::Username, &username.to_ascii_lowercase());
+
+    // Done while still locked so none of the user's transfers can carry on once their files are gone
+    let cancelled_upload_count = app_state.uploads_manager.cancel_user_uploads(user_id).await;
+    app_state.downloads_manager.close_downloads(&deleted_handles).await;
+
+    (deleted_handles, cancelled_upload_count, blob_store)
+  };
+
+  let mut deleted_file_count = 0;
+
+  for handle in deleted_handles {
+    match blob_store.delete(&(handle + constants::TREASURY_FILE_EXTENSION)).await {
+      Ok(_) => deleted_file_count += 1,
+      Err(err) => error!("Failed to delete stored file: {}", err)
+    };
+  }
+
+  println!(
+    "Deleted {}, {} stored file(s) and {} unfinished upload(s)",
+    style(username).cyan().bold(),
+    deleted_file_count,
+    cancelled_upload_count
+  );
+}
+
+async fn migrate_storage_command(shared_app_state: Arc<Mutex<AppState>>, _args: Vec<String>) {
+  let shell_theme = ColorfulTheme::default();
+
+  let (storage_backend, user_files_root_directory) = {
+    let app_state = shared_app_state.lock().await;
+    (app_state.config.storage_backend.clone(), app_state.config.user_files_root_directory.clone())
+  };
+
+  if storage_backend != "filesystem" {
+    println!("{}", style("Only the filesystem storage backend needs migrating.").yellow());
+    return;
+  }
+
+  let confirmed = Confirm::with_theme(&shell_theme)
+    .with_prompt(format!("Move user files in '{}' into sharded directories?", user_files_root_directory))
+    .wait_for_newline(true)
+    .interact()
+    .unwrap();
+
+  if !confirmed {
+    return;
+  }
+
+  // Files stay downloadable during the migration so the app state isn't locked
+  match FileStore::new(&user_files_root_directory).migrate_to_sharded_layout().await {
+    Ok(count) => println!("Moved {} user file(s) into sharded directories.", count),
+    Err(err) => error!("Failed to migrate user files: {}", err)
+  };
+}
+
+async fn scrub_command(shared_app_state: Arc<Mutex<AppState>>, _args: Vec<String>) {
+  let shell_theme = ColorfulTheme::default();
+
+  let quarantine_orphans = Confirm::with_theme(&shell_theme)
+    .with_prompt("Quarantine stored files that have no database entry?")
+    .default(false)
+    .wait_for_newline(true)
+    .interact()
+    .unwrap();
+
+  println!("Scrubbing user files...");
+
+  match run_scrub(shared_app_state, quarantine_orphans).await {
+    Ok(report) => {
+      println!("Checked:            {}", report.checked_files);
+      println!("Missing:            {}", report.missing_files);
+      println!("Orphaned:           {}", report.orphaned_files);
+      println!("Size mismatches:    {}", report.size_mismatches);
+      println!("Bad magic numbers:  {}", report.bad_magic_numbers);
+      println!("Corrupt chunks:     {}", report.corrupt_chunks);
+      println!("Quarantined:        {}", report.quarantined_files);
+    },
+    Err(err) => error!("Scrub failed: {}", err)
+  };
+}
+
+async fn replication_command(shared_app_state: Arc<Mutex<AppState>>, _args: Vec<String>) {
+  let shell_theme = ColorfulTheme::default();
+
+  let replication_manager = match shared_app_state.lock().await.replication_manager.clone() {
+    Some(manager) => manager,
+    None => {
+      println!("{}", style("Mirroring is disabled. Set MIRROR_ROOT_DIRECTORY in the .env file to enable it.").yellow());
+      return;
+    }
+  };
+
+  let status = replication_manager.get_status().await;
+  let lag_seconds = status.oldest_queued_time
+    .map(|queued_time| get_unix_timestamp_secs().saturating_sub(queued_time))
+    .unwrap_or(0);
+
+  println!("Pending:  {}", status.pending_count);
+  println!("Failed:   {}", status.failed_count);
+  println!("Lag:      {}s", lag_seconds);
+
+  let confirmed = Confirm::with_theme(&shell_theme)
+    .with_prompt("Re-sync the mirror with the primary store?")
+    .default(false)
+    .wait_for_newline(true)
+    .interact()
+    .unwrap();
+
+  if !confirmed {
+    return;
+  }
+
+  match replication_manager.resync().await {
+    Ok((copy_count, delete_count)) => println!("Queued {} copies and {} deletes.", copy_count, delete_count),
+    Err(err) => error!("Failed to re-sync the mirror: {}", err)
+  };
+}
+
+async fn encrypt_storage_command(shared_app_state: Arc<Mutex<AppState>>, _args: Vec<String>) {
+  let shell_theme = ColorfulTheme::default();
+
+  let encrypted_stores = shared_app_state.lock().await.encrypted_stores.clone();
+
+  if encrypted_stores.is_empty() {
+    println!("{}", style("Encryption at rest is disabled. Set AT_REST_KEY or AT_REST_KEY_FILE in the .env file to enable it.").yellow());
+    return;
+  }
+
+  let confirmed = Confirm::with_theme(&shell_theme)
+    .with_prompt("Encrypt all user files that were stored before encryption at rest was enabled?")
+    .wait_for_newline(true)
+    .interact()
+    .unwrap();
+
+  if !confirmed {
+    return;
+  }
+
+  for encrypted_store in encrypted_stores {
+    match encrypted_store.wrap_existing_blobs().await {
+      Ok(count) => println!("Encrypted {} user file(s).", count),
+      Err(err) => error!("Failed to encrypt user files: {}", err)
+    };
+  }
+}
+
+async fn backup_command(shared_app_state: Arc<Mutex<AppState>>, _args: Vec<String>) {
+  println!("Backing up the database...");
+
+  match create_backup(shared_app_state).await {
+    Ok(report) => {
+      println!("Saved snapshot: {}", report.snapshot_path.display());
+
+      if !report.has_manifest {
+        println!("{}", style("The snapshot has no manifest, so restoring it can't check for missing stored user files.").yellow());
+      }
+    },
+    Err(err) => error!("Database backup failed: {}", err)
+  };
+}
+
+async fn restore_command(shared_app_state: Arc<Mutex<AppState>>, _args: Vec<String>) {
+  let shell_theme = ColorfulTheme::default();
+
+  let config = shared_app_state.lock().await.config.clone();
+
+  let snapshots = match list_snapshots(&config) {
+    Ok(snapshots) => snapshots,
+    Err(err) => {
+      error!("Failed to list database backups: {}", err);
+      return;
+    }
+  };
+
+  if snapshots.is_empty() {
+    println!("{}", style("There are no database backups to restore.").yellow());
+    return;
+  }
+
+  // Show how long ago each snapshot was taken, newest first
+  let current_time = get_unix_timestamp_secs();
+  let snapshot_names: Vec<String> = snapshots.iter()
+    .map(|snapshot| {
+      let file_name = snapshot.path.file_name().unwrap_or_default().to_string_lossy().to_string();
+      let age_hours = current_time.saturating_sub(snapshot.created_time) / 3600;
+
+      format!("{} ({}h ago)", file_name, age_hours)
+    })
+    .collect();
+
+  let selection = Select::with_theme(&shell_theme)
+    .with_prompt("Select a snapshot to restore")
+    .items(&snapshot_names)
+    .default(0)
+    .interact()
+    .unwrap();
+
+  let confirmed = Confirm::with_theme(&shell_theme)
+    .with_prompt(format!("Replace the database with {}? Any changes made since it was taken will be lost.", snapshot_names[selection]))
+    .default(false)
+    .wait_for_newline(true)
+    .interact()
+    .unwrap();
+
+  if !confirmed {
+    return;
+  }
+
+  match restore_backup(shared_app_state, &snapshots[selection].path).await {
+    Ok(report) => {
+      println!("Restored the database at schema version {}.", report.schema_version);
+
+      if !report.has_manifest {
+        println!("{}", style("The snapshot has no manifest, so stored user files weren't compared. Run 'scrub' to check them.").yellow());
+      } else if report.missing_blob_count > 0 || report.orphaned_blob_count > 0 {
+        println!("{}", style(format!(
+          "{} stored file(s) from the snapshot are missing and {} were added since. Run 'scrub' to find and quarantine them.",
+          report.missing_blob_count,
+          report.orphaned_blob_count
+        )).yellow());
+      }
+    },
+    Err(err) => error!("Database restore failed: {}", err)
+  };
+}
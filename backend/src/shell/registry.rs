@@ -0,0 +1,95 @@
+//! The commands available in the interactive shell. Each command is registered with its name, aliases and help text
+//! so the dispatcher can find it, list it in `help` and suggest it when the name is mistyped.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+use crate::AppState;
+
+/// Commands whose name is within this edit distance of the input are suggested.
+const MAX_SUGGESTION_DISTANCE: usize = 2;
+
+pub type CommandFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Runs a command with the arguments entered after its name. Missing arguments are usually prompted for.
+pub type CommandHandler = fn(Arc<Mutex<AppState>>, Vec<String>) -> CommandFuture;
+
+pub struct ShellCommand {
+  pub name: &'static str,
+  pub aliases: &'static [&'static str],
+
+  /// The arguments the command accepts, e.g. "[storage quota]".
+  pub arguments: &'static str,
+  pub help: &'static str,
+
+  pub handler: CommandHandler
+}
+
+pub struct CommandRegistry {
+  commands: Vec<ShellCommand>
+}
+
+impl CommandRegistry {
+  pub fn new() -> Self {
+    Self {
+      commands: Vec::new()
+    }
+  }
+
+  /// Adds a command. Panics if the name or one of the aliases is already taken.
+  pub fn register(&mut self, command: ShellCommand) {
+    for name in std::iter::once(command.name).chain(command.aliases.iter().copied()) {
+      assert!(self.find(name).is_none(), "The shell command '{}' is registered more than once.", name);
+    }
+
+    self.commands.push(command);
+  }
+
+  /// Finds a command by its name or one of its aliases, ignoring case.
+  pub fn find(&self, name: &str) -> Option<&ShellCommand> {
+    let name = name.to_lowercase();
+
+    self.commands.iter().find(|command| command.name == name || command.aliases.contains(&name.as_str()))
+  }
+
+  pub fn commands(&self) -> &[ShellCommand] {
+    &self.commands
+  }
+
+  /// Returns the command or extra name closest to a mistyped name, if any is close enough.
+  pub fn suggest<'a>(&'a self, name: &str, extra_names: &[&'a str]) -> Option<&'a str> {
+    let name = name.to_lowercase();
+
+    self.commands.iter()
+      .flat_map(|command| std::iter::once(command.name).chain(command.aliases.iter().copied()))
+      .chain(extra_names.iter().copied())
+      .map(|candidate| (candidate, calc_edit_distance(&name, candidate)))
+      .filter(|(_, distance)| *distance <= MAX_SUGGESTION_DISTANCE)
+      .min_by_key(|(_, distance)| *distance)
+      .map(|(candidate, _)| candidate)
+  }
+}
+
+/// The Levenshtein distance between two strings.
+fn calc_edit_distance(a: &str, b: &str) -> usize {
+  let b_chars: Vec<char> = b.chars().collect();
+  let mut previous_row: Vec<usize> = (0..=b_chars.len()).collect();
+
+  for (i, a_char) in a.chars().enumerate() {
+    let mut current_row = vec![i + 1; b_chars.len() + 1];
+
+    for (j, b_char) in b_chars.iter().enumerate() {
+      let substitution_cost = if a_char == *b_char { 0 } else { 1 };
+
+      current_row[j + 1] = (previous_row[j] + substitution_cost)
+        .min(previous_row[j + 1] + 1)
+        .min(current_row[j] + 1);
+    }
+
+    previous_row = current_row;
+  }
+
+  previous_row[b_chars.len()]
+}
@@ -0,0 +1,30 @@
+//! Stopping the server cleanly when it runs without the interactive shell, e.g. under systemd or in a container.
+
+use tokio::signal;
+use log::info;
+
+/// Resolves when the process receives SIGINT (CTRL+C) or SIGTERM, which is how service managers and container
+/// runtimes ask the server to stop.
+pub async fn shutdown_signal() {
+  let ctrl_c = async {
+    signal::ctrl_c()
+      .await
+      .expect("Failed to install CTRL+C handler.");
+  };
+
+  #[cfg(unix)]
+  let terminate = async {
+    signal::unix::signal(signal::unix::SignalKind::terminate())
+      .expect("Failed to install SIGTERM handler.")
+      .recv()
+      .await;
+  };
+
+  #[cfg(not(unix))]
+  let terminate = std::future::pending::<()>();
+
+  tokio::select! {
+    _ = ctrl_c => info!("Received SIGINT. Stopping server..."),
+    _ = terminate => info!("Received SIGTERM. Stopping server...")
+  }
+}
@@ -0,0 +1,305 @@
+use async_trait::async_trait;
+use chacha20poly1305::{
+  aead::{rand_core::RngCore, Aead, KeyInit, OsRng},
+  ChaCha20Poly1305, Key, Nonce
+};
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::fs::{self, File};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufWriter};
+use log::info;
+
+use super::{BlobReader, BlobStat, BlobStore, StorageResult};
+use crate::constants;
+
+/// Wraps another store so that blobs are encrypted with a server-held key before they're stored. Blobs are encrypted
+/// in fixed size segments so ranges can be read without decrypting the whole blob.
+///
+/// A wrapped blob is laid out as: magic number | salt | segment 0 | segment 1 | ...
+/// where every segment is encrypted with ChaCha20-Poly1305 using a key derived from the server key and the blob's
+/// random salt, and a nonce made of the segment's index and a flag marking the last segment (the STREAM construction).
+/// The flag means a blob truncated at a segment boundary fails to decrypt instead of reading as a shorter blob, and
+/// every blob has at least one segment so even an empty blob has an authenticated end.
+///
+/// Blobs stored before encryption at rest was enabled don't start with the magic number and are read as is.
+pub struct EncryptedStore {
+  inner: Arc<dyn BlobStore>,
+  key: [u8; constants::AT_REST_KEY_SIZE],
+
+  /// Where plaintext blobs are staged while being wrapped.
+  temp_directory: PathBuf
+}
+
+impl EncryptedStore {
+  pub fn new(inner: Arc<dyn BlobStore>, key: [u8; constants::AT_REST_KEY_SIZE], temp_directory: impl Into<PathBuf>) -> Self {
+    Self {
+      inner,
+      key,
+      temp_directory: temp_directory.into()
+    }
+  }
+
+  /// Encrypts every blob that was stored before encryption at rest was enabled. Returns the number of blobs wrapped.
+  pub async fn wrap_existing_blobs(&self) -> StorageResult<usize> {
+    let mut wrapped_count = 0;
+
+    for key in self.inner.list().await? {
+      if self.read_salt(&key).await?.is_some() {
+        continue;
+      }
+
+      let size = match self.inner.stat(&key).await? {
+        Some(stat) => stat.size,
+        None => continue
+      };
+
+      // Stage a plaintext copy which `put` then wraps and moves back into the store
+      let temp_path = self.temp_directory.join(format!("{}.unwrapped", key));
+
+      let result = async {
+        let mut reader = self.inner.read_range(&key, 0, size).await?;
+        let mut file = File::create(&temp_path).await?;
+
+        tokio::io::copy(&mut reader, &mut file).await?;
+        file.flush().await?;
+
+        self.put(&key, &temp_path).await
+      }.await;
+
+      if result.is_err() {
+        let _ = fs::remove_file(&temp_path).await;
+      }
+
+      result?;
+      wrapped_count += 1;
+
+      info!("Encrypted at rest: {}", key);
+    }
+
+    Ok(wrapped_count)
+  }
+
+  fn get_cipher(&self, salt: &[u8]) -> ChaCha20Poly1305 {
+    let mut key_material = self.key.to_vec();
+    key_material.extend_from_slice(salt);
+
+    let blob_key = blake3::derive_key(constants::AT_REST_BLOB_KEY_CONTEXT, &key_material);
+    ChaCha20Poly1305::new(Key::from_slice(&blob_key))
+  }
+
+  /// Reads the salt of a wrapped blob or `None` if the blob isn't wrapped.
+  async fn read_salt(&self, key: &str) -> StorageResult<Option<Vec<u8>>> {
+    let header_size = constants::AT_REST_WRAPPED_HEADER_SIZE as u64;
+    let magic_number_size = constants::AT_REST_WRAPPED_MAGIC_NUMBER.len();
+
+    let mut header = Vec::with_capacity(header_size as usize);
+    self.inner.read_range(key, 0, header_size).await?.read_to_end(&mut header).await?;
+
+    if header.len() != header_size as usize || header[..magic_number_size] != constants::AT_REST_WRAPPED_MAGIC_NUMBER {
+      return Ok(None);
+    }
+
+    Ok(Some(header[magic_number_size..].to_vec()))
+  }
+}
+
+#[async_trait]
+impl BlobStore for EncryptedStore {
+  async fn put(&self, key: &str, source_path: &Path) -> StorageResult<()> {
+    let mut salt = [0u8; constants::AT_REST_SALT_SIZE];
+    OsRng.fill_bytes(&mut salt);
+
+    let cipher = self.get_cipher(&salt);
+    let wrapped_path = source_path.with_extension("wrapped");
+
+    let result = async {
+      let mut source = File::open(source_path).await?;
+      let mut writer = BufWriter::new(File::create(&wrapped_path).await?);
+
+      writer.write_all(&constants::AT_REST_WRAPPED_MAGIC_NUMBER).await?;
+      writer.write_all(&salt).await?;
+
+      let mut segment = vec![0u8; constants::AT_REST_SEGMENT_SIZE];
+      let mut next_segment = vec![0u8; constants::AT_REST_SEGMENT_SIZE];
+      let mut segment_size = read_segment(&mut source, &mut segment).await?;
+      let mut segment_index = 0u64;
+
+      // Read one segment ahead so the last segment can be flagged as such
+      loop {
+        let next_segment_size = read_segment(&mut source, &mut next_segment).await?;
+        let is_last = next_segment_size == 0;
+
+        let wrapped_segment = cipher.encrypt(&segment_nonce(segment_index, is_last), &segment[..segment_size])
+          .map_err(|_| "Failed to encrypt segment.")?;
+
+        writer.write_all(&wrapped_segment).await?;
+
+        if is_last {
+          break;
+        }
+
+        std::mem::swap(&mut segment, &mut next_segment);
+        segment_size = next_segment_size;
+        segment_index += 1;
+      }
+
+      writer.shutdown().await?;
+
+      self.inner.put(key, &wrapped_path).await
+    }.await;
+
+    if result.is_err() {
+      let _ = fs::remove_file(&wrapped_path).await;
+      return result;
+    }
+
+    fs::remove_file(source_path).await?;
+
+    Ok(())
+  }
+
+  /// Appending changes the last segment and moves the last segment flag, and the inner store can only replace whole
+  /// blobs, so the blob is staged as plaintext with the data appended and wrapped again.
+  async fn append(&self, key: &str, data: &[u8]) -> StorageResult<()> {
+    let temp_path = self.temp_directory.join(format!("{}.appending", key));
+
+    let result = async {
+      let mut file = File::create(&temp_path).await?;
+
+      if let Some(stat) = self.stat(key).await? {
+        let mut reader = self.read_range(key, 0, stat.size).await?;
+        tokio::io::copy(&mut reader, &mut file).await?;
+      }
+
+      file.write_all(data).await?;
+      file.flush().await?;
+
+      self.put(key, &temp_path).await
+    }.await;
+
+    if result.is_err() {
+      let _ = fs::remove_file(&temp_path).await;
+    }
+
+    result
+  }
+
+  async fn read_range(&self, key: &str, offset: u64, length: u64) -> StorageResult<BlobReader> {
+    let salt = match self.read_salt(key).await? {
+      Some(salt) => salt,
+      None => return self.inner.read_range(key, offset, length).await
+    };
+
+    if length == 0 {
+      return Ok(Box::pin(Cursor::new(Vec::new())));
+    }
+
+    let segment_size = constants::AT_REST_SEGMENT_SIZE as u64;
+    let wrapped_segment_size = constants::AT_REST_WRAPPED_SEGMENT_SIZE as u64;
+
+    // The wrapped size tells which segment was written last
+    let wrapped_size = self.inner.stat(key).await?.map(|stat| stat.size).unwrap_or_default();
+    let segment_count = calc_segment_count(wrapped_size);
+
+    if segment_count == 0 {
+      return Err(format!("{} has no segments and must have been truncated.", key).into());
+    }
+
+    // Read every segment that overlaps the range
+    let first_segment = offset / segment_size;
+    let last_segment = (offset + length - 1) / segment_size;
+    let wrapped_offset = constants::AT_REST_WRAPPED_HEADER_SIZE as u64 + first_segment * wrapped_segment_size;
+    let wrapped_length = (last_segment - first_segment + 1) * wrapped_segment_size;
+
+    let mut wrapped = Vec::with_capacity(wrapped_length as usize);
+    self.inner.read_range(key, wrapped_offset, wrapped_length).await?.read_to_end(&mut wrapped).await?;
+
+    let cipher = self.get_cipher(&salt);
+    let mut plaintext = Vec::with_capacity(wrapped.len());
+
+    for (index, wrapped_segment) in wrapped.chunks(wrapped_segment_size as usize).enumerate() {
+      let segment_index = first_segment + index as u64;
+      let is_last = segment_index + 1 == segment_count;
+
+      let segment = cipher.decrypt(&segment_nonce(segment_index, is_last), wrapped_segment)
+        .map_err(|_| format!("Segment {} of {} failed to decrypt.", segment_index, key))?;
+
+      plaintext.extend_from_slice(&segment);
+    }
+
+    // Trim the segments down to the requested range
+    let start = (offset - first_segment * segment_size) as usize;
+    let end = std::cmp::min(start + length as usize, plaintext.len());
+    let range = plaintext.get(start..end).unwrap_or_default().to_vec();
+
+    Ok(Box::pin(Cursor::new(range)))
+  }
+
+  async fn delete(&self, key: &str) -> StorageResult<()> {
+    self.inner.delete(key).await
+  }
+
+  /// Reports the plaintext size of wrapped blobs.
+  async fn stat(&self, key: &str) -> StorageResult<Option<BlobStat>> {
+    let stat = match self.inner.stat(key).await? {
+      Some(stat) => stat,
+      None => return Ok(None)
+    };
+
+    if self.read_salt(key).await?.is_none() {
+      return Ok(Some(stat));
+    }
+
+    Ok(Some(BlobStat { size: calc_unwrapped_size(stat.size) }))
+  }
+
+  async fn list(&self) -> StorageResult<Vec<String>> {
+    self.inner.list().await
+  }
+
+  async fn quarantine(&self, key: &str) -> StorageResult<()> {
+    self.inner.quarantine(key).await
+  }
+}
+
+fn segment_nonce(segment_index: u64, is_last: bool) -> Nonce {
+  let mut nonce = [0u8; 12];
+  nonce[..8].copy_from_slice(&segment_index.to_le_bytes());
+  nonce[8] = is_last as u8;
+
+  Nonce::clone_from_slice(&nonce)
+}
+
+/// Counts the segments in a wrapped blob, including a final partial segment.
+fn calc_segment_count(wrapped_size: u64) -> u64 {
+  let body_size = wrapped_size.saturating_sub(constants::AT_REST_WRAPPED_HEADER_SIZE as u64);
+  body_size.div_ceil(constants::AT_REST_WRAPPED_SEGMENT_SIZE as u64)
+}
+
+fn calc_unwrapped_size(wrapped_size: u64) -> u64 {
+  let wrapped_segment_size = constants::AT_REST_WRAPPED_SEGMENT_SIZE as u64;
+  let body_size = wrapped_size.saturating_sub(constants::AT_REST_WRAPPED_HEADER_SIZE as u64);
+
+  let full_segments = body_size / wrapped_segment_size;
+  let last_segment_size = (body_size % wrapped_segment_size).saturating_sub(constants::POLY1305_TAG_BYTE_SIZE as u64);
+
+  full_segments * constants::AT_REST_SEGMENT_SIZE as u64 + last_segment_size
+}
+
+/// Fills the buffer from the reader unless the end is reached first. Returns the number of bytes read.
+async fn read_segment(source: &mut File, buffer: &mut [u8]) -> std::io::Result<usize> {
+  let mut filled = 0;
+
+  while filled < buffer.len() {
+    let read = source.read(&mut buffer[filled..]).await?;
+
+    if read == 0 {
+      break;
+    }
+
+    filled += read;
+  }
+
+  Ok(filled)
+}
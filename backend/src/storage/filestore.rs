@@ -0,0 +1,193 @@
+use async_trait::async_trait;
+use std::io::{ErrorKind, SeekFrom};
+use std::path::{Path, PathBuf};
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+use super::{BlobReader, BlobStat, BlobStore, StorageResult, QUARANTINE_KEY_PREFIX};
+
+/// The number of nested shard directories a blob is stored under.
+const SHARD_DEPTH: usize = 2;
+
+/// The number of key characters used to name each shard directory.
+const SHARD_NAME_LENGTH: usize = 2;
+
+/// Stores blobs as files in a directory on the local filesystem. Blobs are sharded into nested directories named
+/// after the start of their key (e.g. "ab/cd/abcdef.tef") so no single directory grows too large.
+pub struct FileStore {
+  root_directory: PathBuf
+}
+
+impl FileStore {
+  pub fn new(root_directory: impl Into<PathBuf>) -> Self {
+    Self {
+      root_directory: root_directory.into()
+    }
+  }
+
+  /// Gets the path of the file that a blob is stored in.
+  pub fn get_blob_path(&self, key: &str) -> PathBuf {
+    let mut path = self.root_directory.clone();
+
+    // Keys too short to shard are kept in the root directory
+    if key.len() > SHARD_DEPTH * SHARD_NAME_LENGTH && key.is_char_boundary(SHARD_DEPTH * SHARD_NAME_LENGTH) {
+      for depth in 0..SHARD_DEPTH {
+        path.push(&key[depth * SHARD_NAME_LENGTH..(depth + 1) * SHARD_NAME_LENGTH]);
+      }
+    }
+
+    path.join(key)
+  }
+
+  /// Gets the path a blob was stored at before sharding was introduced.
+  fn get_legacy_blob_path(&self, key: &str) -> PathBuf {
+    self.root_directory.join(key)
+  }
+
+  /// Gets the path of an existing blob, falling back to the legacy flat layout for blobs that haven't been migrated.
+  async fn find_blob_path(&self, key: &str) -> PathBuf {
+    let path = self.get_blob_path(key);
+
+    if fs::try_exists(&path).await.unwrap_or(false) {
+      return path;
+    }
+
+    let legacy_path = self.get_legacy_blob_path(key);
+
+    match fs::try_exists(&legacy_path).await {
+      Ok(true) => legacy_path,
+      _ => path
+    }
+  }
+
+  /// Gets the path of a blob that is about to be written, creating its shard directories if needed.
+  async fn prepare_blob_path(&self, key: &str) -> StorageResult<PathBuf> {
+    let path = self.get_blob_path(key);
+    create_parent_directory(&path).await?;
+
+    Ok(path)
+  }
+
+  /// Moves blobs stored flat in the root directory into their shard directories. Returns the number of blobs moved.
+  /// Blobs remain readable while this runs since lookups fall back to the flat layout.
+  pub async fn migrate_to_sharded_layout(&self) -> StorageResult<usize> {
+    let mut moved_count = 0;
+    let mut entries = fs::read_dir(&self.root_directory).await?;
+
+    while let Some(entry) = entries.next_entry().await? {
+      if !entry.file_type().await?.is_file() {
+        continue;
+      }
+
+      let key = entry.file_name().to_string_lossy().to_string();
+      let path = self.prepare_blob_path(&key).await?;
+
+      if path != entry.path() {
+        fs::rename(entry.path(), path).await?;
+        moved_count += 1;
+      }
+    }
+
+    Ok(moved_count)
+  }
+
+  /// Recursively collects the keys of every blob in the root directory, skipping the quarantine directory.
+  async fn collect_keys(&self, keys: &mut Vec<String>) -> StorageResult<()> {
+    let quarantine_directory = self.root_directory.join(QUARANTINE_KEY_PREFIX);
+    let mut directories = vec![self.root_directory.clone()];
+
+    while let Some(directory) = directories.pop() {
+      let mut entries = fs::read_dir(&directory).await?;
+
+      while let Some(entry) = entries.next_entry().await? {
+        let file_type = entry.file_type().await?;
+
+        if file_type.is_dir() && entry.path() != quarantine_directory {
+          directories.push(entry.path());
+        } else if file_type.is_file() {
+          keys.push(entry.file_name().to_string_lossy().to_string());
+        }
+      }
+    }
+
+    Ok(())
+  }
+}
+
+#[async_trait]
+impl BlobStore for FileStore {
+  async fn put(&self, key: &str, source_path: &Path) -> StorageResult<()> {
+    let path = self.prepare_blob_path(key).await?;
+
+    // Renaming fails when the source is on a different filesystem, so fall back to copying in that case
+    if fs::rename(source_path, &path).await.is_err() {
+      fs::copy(source_path, &path).await?;
+      fs::remove_file(source_path).await?;
+    }
+
+    Ok(())
+  }
+
+  async fn append(&self, key: &str, data: &[u8]) -> StorageResult<()> {
+    // Blobs that haven't been migrated yet keep being appended to in the flat layout
+    let path = self.find_blob_path(key).await;
+    create_parent_directory(&path).await?;
+
+    let mut file = OpenOptions::new()
+      .create(true)
+      .append(true)
+      .open(path)
+      .await?;
+
+    file.write_all(data).await?;
+    file.flush().await?;
+
+    Ok(())
+  }
+
+  async fn read_range(&self, key: &str, offset: u64, length: u64) -> StorageResult<BlobReader> {
+    let mut file = File::open(self.find_blob_path(key).await).await?;
+    file.seek(SeekFrom::Start(offset)).await?;
+
+    Ok(Box::pin(file.take(length)))
+  }
+
+  async fn delete(&self, key: &str) -> StorageResult<()> {
+    match fs::remove_file(self.find_blob_path(key).await).await {
+      Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+      _ => Ok(())
+    }
+  }
+
+  async fn stat(&self, key: &str) -> StorageResult<Option<BlobStat>> {
+    match fs::metadata(self.find_blob_path(key).await).await {
+      Ok(metadata) => Ok(Some(BlobStat { size: metadata.len() })),
+      Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+      Err(err) => Err(err.into())
+    }
+  }
+
+  async fn list(&self) -> StorageResult<Vec<String>> {
+    let mut keys = Vec::new();
+    self.collect_keys(&mut keys).await?;
+
+    Ok(keys)
+  }
+
+  async fn quarantine(&self, key: &str) -> StorageResult<()> {
+    // Quarantined blobs are kept flat since there should only ever be a few of them
+    let path = self.root_directory.join(QUARANTINE_KEY_PREFIX).join(key);
+    create_parent_directory(&path).await?;
+    fs::rename(self.find_blob_path(key).await, path).await?;
+
+    Ok(())
+  }
+}
+
+async fn create_parent_directory(path: &Path) -> StorageResult<()> {
+  if let Some(parent) = path.parent() {
+    fs::create_dir_all(parent).await?;
+  }
+
+  Ok(())
+}
@@ -0,0 +1,51 @@
+use async_trait::async_trait;
+use std::error::Error;
+use std::path::Path;
+use std::pin::Pin;
+use tokio::io::AsyncRead;
+
+pub mod encryptedstore;
+pub mod filestore;
+pub mod replication;
+pub mod s3store;
+pub mod scrubber;
+
+/// Quarantined blobs are kept under this key prefix and aren't included when listing a store.
+pub const QUARANTINE_KEY_PREFIX: &str = "quarantine/";
+
+pub type StorageResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+/// A stream of bytes read from a blob.
+pub type BlobReader = Pin<Box<dyn AsyncRead + Send>>;
+
+pub struct BlobStat {
+  /// The size of the blob in bytes.
+  pub size: u64
+}
+
+/// A place where finalised user files (blobs) are kept. Blobs are addressed by a key which is the file name of the
+/// blob, e.g. "<handle>.tef". Implementations decide how keys map to their underlying storage.
+#[async_trait]
+pub trait BlobStore: Send + Sync {
+  /// Moves a completed local file into the store under the key, replacing any existing blob.
+  async fn put(&self, key: &str, source_path: &Path) -> StorageResult<()>;
+
+  /// Appends data to the end of a blob, creating the blob if it doesn't exist.
+  #[allow(dead_code)]
+  async fn append(&self, key: &str, data: &[u8]) -> StorageResult<()>;
+
+  /// Reads `length` bytes of a blob starting at `offset`.
+  async fn read_range(&self, key: &str, offset: u64, length: u64) -> StorageResult<BlobReader>;
+
+  /// Deletes a blob. Deleting a blob that doesn't exist is not an error.
+  async fn delete(&self, key: &str) -> StorageResult<()>;
+
+  /// Gets information about a blob or `None` if it doesn't exist.
+  async fn stat(&self, key: &str) -> StorageResult<Option<BlobStat>>;
+
+  /// Lists the keys of every blob in the store, excluding quarantined blobs.
+  async fn list(&self) -> StorageResult<Vec<String>>;
+
+  /// Moves a blob out of the way so it's no longer listed but can still be inspected or restored manually.
+  async fn quarantine(&self, key: &str) -> StorageResult<()>;
+}
@@ -0,0 +1,268 @@
+use async_trait::async_trait;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{mpsc::{self, UnboundedReceiver, UnboundedSender}, Mutex};
+use log::{debug, error, info};
+
+use super::{BlobReader, BlobStat, BlobStore, StorageResult};
+use crate::util::get_unix_timestamp_secs;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ReplicationOperation {
+  Copy,
+  Delete
+}
+
+#[derive(Clone)]
+struct PendingReplication {
+  operation: ReplicationOperation,
+
+  /// Unix timestamp in seconds of when the operation was queued.
+  queued_time: u64,
+
+  /// Increases every time an operation is queued so a completed operation doesn't remove a newer one for the same key.
+  sequence: u64,
+
+  /// Whether the last attempt failed. Failed operations stay pending until the mirror is re-synced.
+  failed: bool
+}
+
+pub struct ReplicationStatus {
+  pub pending_count: usize,
+  pub failed_count: usize,
+
+  /// Unix timestamp in seconds of when the oldest pending operation was queued.
+  pub oldest_queued_time: Option<u64>
+}
+
+/// Copies blobs from the primary store to a mirror in the background. Only the latest operation queued for a key is
+/// performed, so a copy followed by a delete just deletes.
+pub struct ReplicationManager {
+  primary: Arc<dyn BlobStore>,
+  mirror: Arc<dyn BlobStore>,
+
+  /// Where blobs are staged while being copied to the mirror.
+  temp_directory: PathBuf,
+
+  pending: Mutex<HashMap<String, PendingReplication>>,
+  next_sequence: Mutex<u64>,
+  queue_tx: UnboundedSender<String>
+}
+
+impl ReplicationManager {
+  /// Creates the manager and starts the background task that replicates queued blobs. Pending operations are only
+  /// kept in memory, so the mirror is re-synced in the background to catch up on any that were lost when the server
+  /// last stopped.
+  pub fn start(primary: Arc<dyn BlobStore>, mirror: Arc<dyn BlobStore>, temp_directory: impl Into<PathBuf>) -> Arc<Self> {
+    let (queue_tx, queue_rx) = mpsc::unbounded_channel();
+
+    let manager = Arc::new(Self {
+      primary,
+      mirror,
+      temp_directory: temp_directory.into(),
+      pending: Mutex::new(HashMap::new()),
+      next_sequence: Mutex::new(0),
+      queue_tx
+    });
+
+    tokio::spawn(manager.clone().run_worker(queue_rx));
+
+    tokio::spawn({
+      let manager = manager.clone();
+
+      async move {
+        match manager.resync().await {
+          Ok((0, 0)) => debug!("The mirror is in sync with the primary store."),
+          Ok((copy_count, delete_count)) => info!("Re-syncing the mirror: queued {} copies and {} deletes.", copy_count, delete_count),
+          Err(err) => error!("Failed to re-sync the mirror: {}", err)
+        };
+      }
+    });
+
+    manager
+  }
+
+  pub fn mirror(&self) -> Arc<dyn BlobStore> {
+    self.mirror.clone()
+  }
+
+  pub async fn queue(&self, key: &str, operation: ReplicationOperation) {
+    let sequence = {
+      let mut next_sequence = self.next_sequence.lock().await;
+      *next_sequence += 1;
+      *next_sequence
+    };
+
+    self.pending.lock().await.insert(key.to_string(), PendingReplication {
+      operation,
+      queued_time: get_unix_timestamp_secs(),
+      sequence,
+      failed: false
+    });
+
+    let _ = self.queue_tx.send(key.to_string());
+  }
+
+  pub async fn get_status(&self) -> ReplicationStatus {
+    let pending = self.pending.lock().await;
+
+    ReplicationStatus {
+      pending_count: pending.len(),
+      failed_count: pending.values().filter(|replication| replication.failed).count(),
+      oldest_queued_time: pending.values().map(|replication| replication.queued_time).min()
+    }
+  }
+
+  /// Compares the primary store with the mirror and queues whatever is needed to make them match, including retrying
+  /// failed operations. Returns the number of copies and deletes queued.
+  pub async fn resync(&self) -> StorageResult<(usize, usize)> {
+    let primary_keys: HashSet<String> = self.primary.list().await?.into_iter().collect();
+    let mirror_keys = self.mirror.list().await?;
+
+    let mut copy_count = 0;
+    let mut delete_count = 0;
+
+    for key in &primary_keys {
+      let primary_stat = self.primary.stat(key).await?;
+      let mirror_stat = self.mirror.stat(key).await?;
+
+      let is_replicated = match (primary_stat, mirror_stat) {
+        (Some(primary_stat), Some(mirror_stat)) => primary_stat.size == mirror_stat.size,
+        _ => false
+      };
+
+      if !is_replicated {
+        self.queue(key, ReplicationOperation::Copy).await;
+        copy_count += 1;
+      }
+    }
+
+    for key in mirror_keys.iter().filter(|key| !primary_keys.contains(*key)) {
+      self.queue(key, ReplicationOperation::Delete).await;
+      delete_count += 1;
+    }
+
+    Ok((copy_count, delete_count))
+  }
+
+  async fn run_worker(self: Arc<Self>, mut queue_rx: UnboundedReceiver<String>) {
+    while let Some(key) = queue_rx.recv().await {
+      // The key may have already been replicated if it was queued more than once
+      let replication = match self.pending.lock().await.get(&key) {
+        Some(replication) => replication.clone(),
+        None => continue
+      };
+
+      let result = match replication.operation {
+        ReplicationOperation::Copy => self.copy_to_mirror(&key).await,
+        ReplicationOperation::Delete => self.mirror.delete(&key).await
+      };
+
+      let mut pending = self.pending.lock().await;
+
+      match result {
+        Ok(_) => {
+          debug!("Replicated: {}", key);
+
+          if pending.get(&key).is_some_and(|current| current.sequence == replication.sequence) {
+            pending.remove(&key);
+          }
+        },
+        Err(err) => {
+          error!("Failed to replicate {} to the mirror: {}", key, err);
+
+          if let Some(current) = pending.get_mut(&key).filter(|current| current.sequence == replication.sequence) {
+            current.failed = true;
+          }
+        }
+      }
+    }
+  }
+
+  async fn copy_to_mirror(&self, key: &str) -> StorageResult<()> {
+    // Nothing to copy when the blob was removed after being queued
+    let stat = match self.primary.stat(key).await? {
+      Some(stat) => stat,
+      None => return Ok(())
+    };
+
+    let temp_path = self.temp_directory.join(format!("{}.replica", key));
+
+    let result = async {
+      let mut reader = self.primary.read_range(key, 0, stat.size).await?;
+      let mut file = File::create(&temp_path).await?;
+
+      tokio::io::copy(&mut reader, &mut file).await?;
+      file.flush().await?;
+
+      self.mirror.put(key, &temp_path).await
+    }.await;
+
+    if result.is_err() {
+      let _ = tokio::fs::remove_file(&temp_path).await;
+    }
+
+    result
+  }
+}
+
+/// Wraps the primary store so that every change to it is replicated to the mirror.
+pub struct ReplicatedStore {
+  primary: Arc<dyn BlobStore>,
+  replication_manager: Arc<ReplicationManager>
+}
+
+impl ReplicatedStore {
+  pub fn new(primary: Arc<dyn BlobStore>, replication_manager: Arc<ReplicationManager>) -> Self {
+    Self {
+      primary,
+      replication_manager
+    }
+  }
+}
+
+#[async_trait]
+impl BlobStore for ReplicatedStore {
+  async fn put(&self, key: &str, source_path: &Path) -> StorageResult<()> {
+    self.primary.put(key, source_path).await?;
+    self.replication_manager.queue(key, ReplicationOperation::Copy).await;
+
+    Ok(())
+  }
+
+  async fn append(&self, key: &str, data: &[u8]) -> StorageResult<()> {
+    self.primary.append(key, data).await?;
+    self.replication_manager.queue(key, ReplicationOperation::Copy).await;
+
+    Ok(())
+  }
+
+  async fn read_range(&self, key: &str, offset: u64, length: u64) -> StorageResult<BlobReader> {
+    self.primary.read_range(key, offset, length).await
+  }
+
+  async fn delete(&self, key: &str) -> StorageResult<()> {
+    self.primary.delete(key).await?;
+    self.replication_manager.queue(key, ReplicationOperation::Delete).await;
+
+    Ok(())
+  }
+
+  async fn stat(&self, key: &str) -> StorageResult<Option<BlobStat>> {
+    self.primary.stat(key).await
+  }
+
+  async fn list(&self) -> StorageResult<Vec<String>> {
+    self.primary.list().await
+  }
+
+  async fn quarantine(&self, key: &str) -> StorageResult<()> {
+    self.primary.quarantine(key).await?;
+    self.replication_manager.queue(key, ReplicationOperation::Delete).await;
+
+    Ok(())
+  }
+}
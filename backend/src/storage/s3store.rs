@@ -0,0 +1,136 @@
+use async_trait::async_trait;
+use s3::{creds::Credentials, error::S3Error, Bucket, Region};
+use std::io::{self, Cursor};
+use std::path::Path;
+use tokio::fs::File;
+
+use super::{BlobReader, BlobStat, BlobStore, StorageResult, QUARANTINE_KEY_PREFIX};
+use crate::config::Config;
+
+/// Stores blobs as objects in an S3-compatible bucket, e.g. AWS S3 or MinIO.
+pub struct S3Store {
+  bucket: Box<Bucket>,
+
+  /// Prepended to every key so that a bucket can be shared with other data.
+  key_prefix: String
+}
+
+impl S3Store {
+  pub fn new(config: &Config) -> StorageResult<Self> {
+    let region = Region::Custom {
+      region: config.s3_region.clone(),
+      endpoint: config.s3_endpoint.clone()
+    };
+
+    let credentials = Credentials::new(
+      Some(&config.s3_access_key),
+      Some(&config.s3_secret_key),
+      None,
+      None,
+      None
+    )?;
+
+    let mut bucket = Bucket::new(&config.s3_bucket, region, credentials)?;
+
+    // Most self-hosted S3-compatible servers only support path-style addressing
+    if config.s3_path_style {
+      bucket = bucket.with_path_style();
+    }
+
+    Ok(Self {
+      bucket,
+      key_prefix: config.s3_key_prefix.clone()
+    })
+  }
+
+  fn get_object_path(&self, key: &str) -> String {
+    format!("{}{}", self.key_prefix, key)
+  }
+}
+
+#[async_trait]
+impl BlobStore for S3Store {
+  /// Uploads the file using a multipart upload so large files don't have to be held in memory.
+  async fn put(&self, key: &str, source_path: &Path) -> StorageResult<()> {
+    let mut file = File::open(source_path).await?;
+    self.bucket.put_object_stream(&mut file, self.get_object_path(key)).await?;
+    tokio::fs::remove_file(source_path).await?;
+
+    Ok(())
+  }
+
+  /// Objects can't be changed once uploaded, so the object is downloaded, extended and uploaded again. The whole
+  /// object is held in memory while appending.
+  async fn append(&self, key: &str, data: &[u8]) -> StorageResult<()> {
+    let object_path = self.get_object_path(key);
+
+    let mut contents = match self.bucket.get_object(&object_path).await {
+      Ok(response) => response.to_vec(),
+      Err(S3Error::HttpFailWithBody(404, _)) => Vec::new(),
+      Err(err) => return Err(err.into())
+    };
+
+    contents.extend_from_slice(data);
+    self.bucket.put_object(&object_path, &contents).await?;
+
+    Ok(())
+  }
+
+  async fn read_range(&self, key: &str, offset: u64, length: u64) -> StorageResult<BlobReader> {
+    // An empty range can't be expressed with the Range header
+    if length == 0 {
+      return Ok(Box::pin(Cursor::new(Vec::new())));
+    }
+
+    let response = match self.bucket.get_object_range(self.get_object_path(key), offset, Some(offset + length - 1)).await {
+      Ok(response) => response,
+
+      // Reported the same way as a missing file in a `FileStore`
+      Err(S3Error::HttpFailWithBody(404, _)) => {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("Object '{}' doesn't exist.", key)).into());
+      },
+      Err(err) => return Err(err.into())
+    };
+
+    Ok(Box::pin(Cursor::new(response.to_vec())))
+  }
+
+  async fn delete(&self, key: &str) -> StorageResult<()> {
+    match self.bucket.delete_object(self.get_object_path(key)).await {
+      Err(S3Error::HttpFailWithBody(404, _)) => Ok(()),
+      Err(err) => Err(err.into()),
+      Ok(_) => Ok(())
+    }
+  }
+
+  async fn stat(&self, key: &str) -> StorageResult<Option<BlobStat>> {
+    match self.bucket.head_object(self.get_object_path(key)).await {
+      Ok((head, _)) => Ok(Some(BlobStat { size: head.content_length.unwrap_or(0) as u64 })),
+      Err(S3Error::HttpFailWithBody(404, _)) => Ok(None),
+      Err(err) => Err(err.into())
+    }
+  }
+
+  async fn list(&self) -> StorageResult<Vec<String>> {
+    let results = self.bucket.list(self.key_prefix.clone(), None).await?;
+
+    let keys = results.into_iter()
+      .flat_map(|result| result.contents)
+      .filter_map(|object| object.key.strip_prefix(&self.key_prefix).map(|key| key.to_string()))
+      .filter(|key| !key.starts_with(QUARANTINE_KEY_PREFIX))
+      .collect();
+
+    Ok(keys)
+  }
+
+  /// Objects can't be renamed so the object is copied into quarantine and then deleted.
+  async fn quarantine(&self, key: &str) -> StorageResult<()> {
+    let object_path = self.get_object_path(key);
+    let quarantine_path = self.get_object_path(&format!("{}{}", QUARANTINE_KEY_PREFIX, key));
+
+    self.bucket.copy_object_internal(&object_path, &quarantine_path).await?;
+    self.bucket.delete_object(&object_path).await?;
+
+    Ok(())
+  }
+}
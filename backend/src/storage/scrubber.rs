@@ -0,0 +1,164 @@
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::sync::Arc;
+use tokio::io::AsyncReadExt;
+use tokio::sync::Mutex;
+use tokio::time::{interval, Duration};
+use log::{error, info, warn};
+
+use super::BlobStore;
+use crate::{
+  AppState,
+  api::formats::{calc_chunk_checksum, calc_encrypted_file_size},
+  constants,
+  database::ScrubReport,
+  util::get_unix_timestamp_secs
+};
+
+/// Checks that every file entry in the database has a stored file of the expected size and magic number, and that
+/// every stored file has a database entry. Chunks are verified against their recorded checksums to detect bit rot.
+/// Orphaned files are moved into quarantine if `quarantine_orphans` is set. The summary is logged and saved to the
+/// database.
+pub async fn run_scrub(shared_app_state: Arc<Mutex<AppState>>, quarantine_orphans: bool) -> Result<ScrubReport, Box<dyn Error + Send + Sync>> {
+  let mut report = ScrubReport {
+    started_time: get_unix_timestamp_secs(),
+    ..Default::default()
+  };
+
+  let (blob_store, chunk_data_size) = {
+    let app_state = shared_app_state.lock().await;
+    (app_state.uploads_manager.blob_store.clone(), app_state.config.transfer_limits.chunk_data_size)
+  };
+
+  // The stored files must be listed before the database is read. Uploads are finalised and inserted into the
+  // database while the app state is locked, so any file listed here is guaranteed to have its entry by then.
+  let stored_keys: HashSet<String> = blob_store.list().await?.into_iter().collect();
+
+  let expected_files: HashMap<String, u64> = {
+    let mut app_state = shared_app_state.lock().await;
+    let database = app_state.database.as_mut().unwrap();
+
+    database.get_all_stored_files()?
+      .into_iter()
+      .map(|(handle, size)| (handle + constants::TREASURY_FILE_EXTENSION, calc_encrypted_file_size(size, chunk_data_size)))
+      .collect()
+  };
+
+  for (key, expected_size) in &expected_files {
+    report.checked_files += 1;
+
+    // Files finalised after listing won't be in the listed keys, so check those individually
+    let stat = match blob_store.stat(key).await? {
+      Some(stat) => stat,
+      None => {
+        warn!("Scrub: stored file {} is missing.", key);
+        report.missing_files += 1;
+        continue;
+      }
+    };
+
+    if stat.size != *expected_size {
+      warn!("Scrub: stored file {} is {} bytes but should be {} bytes.", key, stat.size, expected_size);
+      report.size_mismatches += 1;
+      continue;
+    }
+
+    if !has_valid_magic_number(blob_store.as_ref(), key, stat.size).await? {
+      warn!("Scrub: stored file {} has a bad magic number.", key);
+      report.bad_magic_numbers += 1;
+    }
+
+    let handle = key.trim_end_matches(constants::TREASURY_FILE_EXTENSION);
+    let chunk_checksums = shared_app_state.lock().await.database.as_mut().unwrap().get_chunk_checksums(handle)?;
+
+    for chunk_id in find_corrupt_chunks(blob_store.as_ref(), key, stat.size, chunk_data_size, &chunk_checksums).await? {
+      warn!("Scrub: chunk {} of stored file {} doesn't match its checksum.", chunk_id, key);
+      report.corrupt_chunks += 1;
+    }
+  }
+
+  for key in stored_keys.iter().filter(|key| !expected_files.contains_key(*key)) {
+    warn!("Scrub: stored file {} has no database entry.", key);
+    report.orphaned_files += 1;
+
+    if quarantine_orphans {
+      match blob_store.quarantine(key).await {
+        Ok(_) => report.quarantined_files += 1,
+        Err(err) => warn!("Scrub: failed to quarantine {}: {}", key, err)
+      }
+    }
+  }
+
+  report.finished_time = get_unix_timestamp_secs();
+
+  info!(
+    "Scrub finished in {}s. Checked: {}, missing: {}, orphaned: {}, size mismatches: {}, bad magic numbers: {}, corrupt chunks: {}, quarantined: {}",
+    report.finished_time - report.started_time,
+    report.checked_files,
+    report.missing_files,
+    report.orphaned_files,
+    report.size_mismatches,
+    report.bad_magic_numbers,
+    report.corrupt_chunks,
+    report.quarantined_files
+  );
+
+  shared_app_state.lock().await.database.as_mut().unwrap().insert_scrub_report(&report)?;
+
+  Ok(report)
+}
+
+/// Spawns a task that runs a scrub every `interval_hours`. The first scrub runs one interval after starting.
+pub fn start_scheduled_scrubs(shared_app_state: Arc<Mutex<AppState>>, interval_hours: u64, quarantine_orphans: bool) {
+  tokio::spawn(async move {
+    let mut scrub_interval = interval(Duration::from_secs(interval_hours * 60 * 60));
+
+    // The first tick completes immediately
+    scrub_interval.tick().await;
+
+    loop {
+      scrub_interval.tick().await;
+
+      if let Err(err) = run_scrub(shared_app_state.clone(), quarantine_orphans).await {
+        error!("Scheduled scrub failed: {}", err);
+      }
+    }
+  });
+}
+
+async fn has_valid_magic_number(blob_store: &dyn BlobStore, key: &str, size: u64) -> Result<bool, Box<dyn Error + Send + Sync>> {
+  let magic_number_size = constants::ENCRYPTED_FILE_MAGIC_NUMBER.len();
+
+  if size < magic_number_size as u64 {
+    return Ok(false);
+  }
+
+  let mut magic_number = vec![0u8; magic_number_size];
+  let mut reader = blob_store.read_range(key, 0, magic_number_size as u64).await?;
+  reader.read_exact(&mut magic_number).await?;
+
+  Ok(magic_number == constants::ENCRYPTED_FILE_MAGIC_NUMBER)
+}
+
+/// Returns the ids of the chunks that don't match their checksum. Files without checksums are skipped.
+async fn find_corrupt_chunks(blob_store: &dyn BlobStore, key: &str, size: u64, chunk_data_size: usize, chunk_checksums: &[Vec<u8>]) -> Result<Vec<u64>, Box<dyn Error + Send + Sync>> {
+  let enc_chunk_size = (chunk_data_size + constants::ENCRYPTED_CHUNK_EXTRA_DATA_SIZE) as u64;
+  let mut corrupt_chunk_ids = Vec::new();
+  let mut offset = constants::ENCRYPTED_FILE_HEADER_SIZE as u64;
+
+  for (chunk_id, checksum) in chunk_checksums.iter().enumerate() {
+    let read_size = cmp::min(enc_chunk_size, size.saturating_sub(offset));
+
+    let mut chunk = Vec::with_capacity(read_size as usize);
+    blob_store.read_range(key, offset, read_size).await?.read_to_end(&mut chunk).await?;
+
+    if calc_chunk_checksum(&chunk) != *checksum {
+      corrupt_chunk_ids.push(chunk_id as u64);
+    }
+
+    offset += read_size;
+  }
+
+  Ok(corrupt_chunk_ids)
+}
@@ -0,0 +1,68 @@
+use http::{Method, StatusCode};
+use serde_json::{json, Value};
+
+use super::{TestClient, TestServer};
+use crate::constants;
+
+const AUTH_KEY: &[u8] = &[7; constants::AUTH_KEY_SIZE];
+
+/// Sends a valid request to every admin route and returns the statuses.
+async fn request_admin_routes(client: &mut TestClient) -> Vec<(&'static str, StatusCode)> {
+  let claim_code = "A".repeat(constants::CLAIM_CODE_LENGTH);
+  let revoke_uri = format!("/api/admin/claimcodes/{}/revoke", claim_code);
+
+  let requests: [(&'static str, Method, &str, Option<Value>); 7] = [
+    ("list users", Method::GET, "/api/admin/users", None),
+    ("set quota", Method::PUT, "/api/admin/users/alice/quota", Some(json!({ "storageQuota": 5 }))),
+    ("list claim codes", Method::GET, "/api/admin/claimcodes", None),
+    ("create claim code", Method::POST, "/api/admin/claimcodes", Some(json!({ "storageQuota": 5, "maxUses": 1, "daysValid": 0 }))),
+    ("revoke claim code", Method::POST, &revoke_uri, None),
+    ("list transfers", Method::GET, "/api/admin/transfers", None),
+    ("start job", Method::POST, "/api/admin/jobs/scrub", None)
+  ];
+
+  let mut statuses = Vec::with_capacity(requests.len());
+
+  for (name, method, uri, body) in requests {
+    statuses.push((name, client.request(method, uri, body).await.0));
+  }
+
+  statuses
+}
+
+#[tokio::test]
+async fn admin_routes_require_an_admin() {
+  let server = TestServer::start();
+  let user_id = server.create_user("alice", AUTH_KEY).await;
+
+  let mut client = server.client();
+
+  for (name, status) in request_admin_routes(&mut client).await {
+    assert_eq!(status, StatusCode::UNAUTHORIZED, "{}", name);
+  }
+
+  assert_eq!(client.login("alice", AUTH_KEY).await.0, StatusCode::OK);
+
+  for (name, status) in request_admin_routes(&mut client).await {
+    assert_eq!(status, StatusCode::FORBIDDEN, "{}", name);
+  }
+
+  // Nothing was changed by the refused requests
+  {
+    let mut app_state = server.state.lock().await;
+    let database = app_state.database.as_mut().unwrap();
+    assert_eq!(database.get_user_data(&"alice".to_string()).unwrap().storage_quota, Some(1_000_000));
+    assert_eq!(database.get_all_claim_codes().unwrap().len(), 1);
+  }
+
+  // The admin flag is read on every request, so promoting the user takes effect straight away
+  server.state.lock().await.database.as_mut().unwrap().set_user_admin(user_id, true).unwrap();
+
+  let (status, response) = client.request(Method::GET, "/api/admin/users", None).await;
+  assert_eq!(status, StatusCode::OK);
+  assert_eq!(response[0]["username"], "alice");
+  assert_eq!(response[0]["isAdmin"], true);
+
+  server.state.lock().await.database.as_mut().unwrap().set_user_admin(user_id, false).unwrap();
+  assert_eq!(client.request(Method::GET, "/api/admin/users", None).await.0, StatusCode::FORBIDDEN);
+}
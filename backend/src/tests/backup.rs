@@ -0,0 +1,178 @@
+use rusqlite::Connection;
+use std::fs::{self, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+
+use super::TestServer;
+use crate::{
+  backup::{create_backup, list_snapshots, restore_backup},
+  constants,
+  database::LATEST_SCHEMA_VERSION
+};
+
+/// Creates a snapshot at schema version 1, from before the chunk size was recorded, with one stored file.
+fn create_legacy_snapshot(directory: &TempDir) -> PathBuf {
+  let snapshot_path = directory.path().join("snapshot.db");
+  let connection = Connection::open(&snapshot_path).unwrap();
+
+  connection.execute_batch(
+    "CREATE TABLE claim_codes (code TEXT NOT NULL, storage_quota BIGINT NOT NULL DEFAULT 0);
+    CREATE TABLE users (
+      id INTEGER PRIMARY KEY,
+      username TEXT NOT NULL,
+      storage_quota BIGINT NOT NULL DEFAULT 0,
+      auth_key_hash TEXT NOT NULL,
+      salt BLOB NOT NULL,
+      encrypted_master_key BLOB NOT NULL,
+      encrypted_ed25519_private_key BLOB NOT NULL,
+      ed25519_public_key BLOB NOT NULL,
+      encrypted_x25519_private_key BLOB NOT NULL,
+      x25519_public_key BLOB NOT NULL
+    );
+    CREATE TABLE filesystem (
+      owner_id INTEGER REFERENCES users(id),
+      handle TEXT NOT NULL,
+      parent_handle TEXT NOT NULL,
+      size BIGINT NOT NULL DEFAULT 0,
+      encrypted_file_crypt_key BLOB,
+      encrypted_metadata BLOB NOT NULL,
+      signature BLOB
+    );
+    INSERT INTO users (id, username, auth_key_hash, salt, encrypted_master_key, encrypted_ed25519_private_key,
+      ed25519_public_key, encrypted_x25519_private_key, x25519_public_key)
+    VALUES (1, 'alice', '', x'', x'', x'', x'', x'', x'');
+    INSERT INTO filesystem (owner_id, handle, parent_handle, size, encrypted_file_crypt_key, encrypted_metadata)
+    VALUES (1, 'abcdefghijklmnop', '0000000000000000', 100, x'00', x'00');
+    PRAGMA user_version = 1;"
+  ).unwrap();
+
+  snapshot_path
+}
+
+#[tokio::test]
+async fn restore_refuses_snapshot_with_different_chunk_size() {
+  let server = TestServer::start();
+  let directory = TempDir::new().unwrap();
+  let snapshot_path = create_legacy_snapshot(&directory);
+
+  // Files stored before the chunk size was recorded use 2 MiB chunks
+  server.state.lock().await.config.transfer_limits.chunk_data_size = 1024 * 1024;
+
+  let err = match restore_backup(server.state.clone(), &snapshot_path).await {
+    Ok(_) => panic!("Restoring a snapshot with a different chunk size succeeded."),
+    Err(err) => err.to_string()
+  };
+
+  assert!(err.contains("chunk size of 2097152"), "{}", err);
+
+  // Nothing was replaced, so there's still no user from the snapshot
+  assert!(server.state.lock().await.database.as_mut().unwrap().get_user_data(&"alice".to_string()).is_err());
+
+  server.state.lock().await.config.transfer_limits.chunk_data_size = 2 * 1024 * 1024;
+  restore_backup(server.state.clone(), &snapshot_path).await.unwrap();
+
+  let user_data = server.state.lock().await.database.as_mut().unwrap().get_user_data(&"alice".to_string()).unwrap();
+  assert_eq!(user_data.user_id, Some(1));
+}
+
+async fn restore_error(server: &TestServer, snapshot_path: &Path) -> String {
+  match restore_backup(server.state.clone(), snapshot_path).await {
+    Ok(_) => panic!("Restoring {} succeeded.", snapshot_path.display()),
+    Err(err) => err.to_string()
+  }
+}
+
+#[tokio::test]
+async fn restore_refuses_newer_schema_version() {
+  let server = TestServer::start();
+  let directory = TempDir::new().unwrap();
+  let snapshot_path = create_legacy_snapshot(&directory);
+
+  Connection::open(&snapshot_path).unwrap()
+    .pragma_update(None, "user_version", LATEST_SCHEMA_VERSION + 1)
+    .unwrap();
+
+  let err = restore_error(&server, &snapshot_path).await;
+  assert!(err.contains(&format!("schema version is {}", LATEST_SCHEMA_VERSION + 1)), "{}", err);
+  assert!(server.state.lock().await.database.as_mut().unwrap().get_user_data(&"alice".to_string()).is_err());
+}
+
+#[tokio::test]
+async fn restore_refuses_corrupted_snapshot() {
+  let server = TestServer::start();
+  let directory = TempDir::new().unwrap();
+  let snapshot_path = directory.path().join("snapshot.db");
+
+  let (page_size, root_page): (u64, u64) = {
+    let connection = Connection::open(&snapshot_path).unwrap();
+    connection.execute_batch(
+      "CREATE TABLE numbers (value INTEGER NOT NULL);
+      CREATE INDEX numbers_value ON numbers (value);
+      WITH RECURSIVE sequence(value) AS (SELECT 1 UNION ALL SELECT value + 1 FROM sequence LIMIT 1000)
+      INSERT INTO numbers SELECT value FROM sequence;
+      PRAGMA user_version = 1;"
+    ).unwrap();
+
+    (
+      connection.pragma_query_value(None, "page_size", |row| row.get(0)).unwrap(),
+      connection.query_row("SELECT rootpage FROM sqlite_master WHERE name = 'numbers'", [], |row| row.get(0)).unwrap()
+    )
+  };
+
+  // Overwrite the table's root page, leaving the schema on the first page readable
+  let mut file = OpenOptions::new().write(true).open(&snapshot_path).unwrap();
+  file.seek(SeekFrom::Start((root_page - 1) * page_size)).unwrap();
+  file.write_all(&vec![0xff; page_size as usize]).unwrap();
+  drop(file);
+
+  let err = restore_error(&server, &snapshot_path).await;
+  assert!(err.contains("integrity check"), "{}", err);
+}
+
+#[tokio::test]
+async fn backups_beyond_the_retention_count_are_deleted() {
+  let server = TestServer::start();
+
+  let backup_directory = {
+    let mut app_state = server.state.lock().await;
+    app_state.config.backup_retention_count = 2;
+    PathBuf::from(&app_state.config.backup_directory)
+  };
+
+  // Two older snapshots, one of them with a manifest
+  let snapshot_path = |created_time: u64| backup_directory.join(
+    format!("{}{}{}", constants::DATABASE_BACKUP_FILE_PREFIX, created_time, constants::DATABASE_BACKUP_FILE_EXTENSION)
+  );
+
+  fs::write(snapshot_path(100), b"").unwrap();
+  fs::write(snapshot_path(200), b"").unwrap();
+  fs::write(backup_directory.join(format!("{}100{}", constants::DATABASE_BACKUP_FILE_PREFIX, constants::DATABASE_BACKUP_MANIFEST_EXTENSION)), b"{}").unwrap();
+
+  let report = create_backup(server.state.clone()).await.unwrap();
+  assert!(report.has_manifest);
+
+  let config = server.state.lock().await.config.clone();
+  let remaining: Vec<PathBuf> = list_snapshots(&config).unwrap().into_iter().map(|snapshot| snapshot.path).collect();
+  assert_eq!(remaining, vec![report.snapshot_path, snapshot_path(200)]);
+
+  // The oldest snapshot's manifest went with it
+  let manifest_count = fs::read_dir(&backup_directory).unwrap()
+    .filter(|entry| entry.as_ref().unwrap().file_name().to_string_lossy().ends_with(constants::DATABASE_BACKUP_MANIFEST_EXTENSION))
+    .count();
+
+  assert_eq!(manifest_count, 1);
+}
+
+#[tokio::test]
+async fn backup_reports_a_missing_manifest() {
+  let server = TestServer::start();
+
+  // Listing the stored user files fails without their directory
+  let user_files_root_directory = server.state.lock().await.config.user_files_root_directory.clone();
+  fs::remove_dir_all(user_files_root_directory).unwrap();
+
+  let report = create_backup(server.state.clone()).await.unwrap();
+  assert!(!report.has_manifest);
+  assert!(report.snapshot_path.is_file());
+}
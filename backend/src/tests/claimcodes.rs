@@ -0,0 +1,136 @@
+use base64::{engine::general_purpose, Engine as _};
+use http::{Method, StatusCode};
+use serde_json::{json, Value};
+
+use super::{TestClient, TestServer};
+use crate::{
+  constants,
+  database::{ClaimCodeData, ClaimUserRequest, UserData},
+  util::get_unix_timestamp_secs
+};
+
+async fn insert_claim_code(server: &TestServer, claim_code: &str, expiry_time: Option<u64>, max_uses: u64) {
+  server.state.lock().await.database.as_mut().unwrap().insert_new_claim_code(&ClaimCodeData {
+    claim_code: claim_code.to_string(),
+    storage_quota: 1_000_000,
+    expiry_time,
+    max_uses,
+    use_count: 0,
+    note: None,
+    created_by: None,
+    created_time: None,
+    revoked_time: None
+  }).unwrap();
+}
+
+async fn is_valid(client: &mut TestClient, claim_code: &str) -> bool {
+  let (status, response) = client.request(Method::GET, &format!("/api/accounts/claimcode?code={}", claim_code), None).await;
+  assert_eq!(status, StatusCode::OK);
+
+  response["isValid"].as_bool().unwrap()
+}
+
+async fn claim(client: &mut TestClient, claim_code: &str, username: &str) -> StatusCode {
+  let encode = |size: usize| Value::from(general_purpose::STANDARD.encode(vec![0; size]));
+
+  let body = json!({
+    "claimCode": claim_code,
+    "username": username,
+    "authKey": encode(constants::AUTH_KEY_SIZE),
+    "encryptedMasterKey": encode(constants::ENCRYPTED_MASTER_KEY_SIZE),
+    "encryptedEd25519PrivateKey": encode(constants::ENCRYPTED_CURVE25519_KEY_SIZE),
+    "encryptedX25519PrivateKey": encode(constants::ENCRYPTED_CURVE25519_KEY_SIZE),
+    "ed25519PublicKey": encode(constants::CURVE25519_KEY_SIZE),
+    "x25519PublicKey": encode(constants::CURVE25519_KEY_SIZE),
+    "salt": encode(constants::USER_AUTH_HASH_SALT_SIZE)
+  });
+
+  client.request(Method::POST, "/api/accounts/claim", Some(body)).await.0
+}
+
+#[tokio::test]
+async fn claim_code_can_be_used_up_to_its_max_uses() {
+  let server = TestServer::start();
+  let mut client = server.client();
+  let claim_code = "B".repeat(constants::CLAIM_CODE_LENGTH);
+  insert_claim_code(&server, &claim_code, None, 2).await;
+
+  assert!(is_valid(&mut client, &claim_code).await);
+  assert_eq!(claim(&mut client, &claim_code, "alice").await, StatusCode::OK);
+  assert!(is_valid(&mut client, &claim_code).await);
+  assert_eq!(claim(&mut client, &claim_code, "bob").await, StatusCode::OK);
+
+  assert!(!is_valid(&mut client, &claim_code).await);
+  assert_eq!(claim(&mut client, &claim_code, "carol").await, StatusCode::FORBIDDEN);
+
+  let info = server.state.lock().await.database.as_mut().unwrap().get_claim_code_info(&claim_code).unwrap();
+  assert_eq!(info.use_count, 2);
+}
+
+#[tokio::test]
+async fn expired_claim_code_is_refused() {
+  let server = TestServer::start();
+  let mut client = server.client();
+  let now = get_unix_timestamp_secs();
+
+  let expired_code = "C".repeat(constants::CLAIM_CODE_LENGTH);
+  let unexpired_code = "D".repeat(constants::CLAIM_CODE_LENGTH);
+  insert_claim_code(&server, &expired_code, Some(now - 1), 1).await;
+  insert_claim_code(&server, &unexpired_code, Some(now + 60 * 60), 1).await;
+
+  assert!(!is_valid(&mut client, &expired_code).await);
+  assert_eq!(claim(&mut client, &expired_code, "alice").await, StatusCode::FORBIDDEN);
+
+  assert!(is_valid(&mut client, &unexpired_code).await);
+  assert_eq!(claim(&mut client, &unexpired_code, "alice").await, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn revoked_claim_code_is_refused() {
+  let server = TestServer::start();
+  let mut client = server.client();
+  let claim_code = "E".repeat(constants::CLAIM_CODE_LENGTH);
+  insert_claim_code(&server, &claim_code, None, 5).await;
+
+  assert_eq!(claim(&mut client, &claim_code, "alice").await, StatusCode::OK);
+
+  server.state.lock().await.database.as_mut().unwrap().revoke_claim_code(&claim_code, get_unix_timestamp_secs()).unwrap();
+
+  assert!(!is_valid(&mut client, &claim_code).await);
+  assert_eq!(claim(&mut client, &claim_code, "bob").await, StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn claim_user_checks_the_code_again() {
+  let server = TestServer::start();
+  let claim_code = "F".repeat(constants::CLAIM_CODE_LENGTH);
+  insert_claim_code(&server, &claim_code, None, 1).await;
+
+  let mut app_state = server.state.lock().await;
+  let database = app_state.database.as_mut().unwrap();
+  database.revoke_claim_code(&claim_code, get_unix_timestamp_secs()).unwrap();
+
+  // The code is rechecked when it's used, in case it changed after the API checked it
+  let result = database.claim_user(&ClaimUserRequest {
+    claim_code: claim_code.clone(),
+    user_data: UserData {
+      username: "alice".to_string(),
+      auth_key_hash: String::new(),
+      salt: Vec::new(),
+      encrypted_master_key: Vec::new(),
+      encrypted_ed25519_private_key: Vec::new(),
+      ed25519_public_key: Vec::new(),
+      encrypted_x25519_private_key: Vec::new(),
+      x25519_public_key: Vec::new(),
+      recovery_key_hash: None,
+      encrypted_recovery_master_key: None,
+      storage_quota: None,
+      user_id: None,
+      disabled: false,
+      is_admin: false
+    }
+  });
+
+  assert!(matches!(result, Err(rusqlite::Error::QueryReturnedNoRows)));
+  assert!(database.get_user_data(&"alice".to_string()).is_err());
+}
@@ -0,0 +1,65 @@
+use base64::{engine::general_purpose, Engine as _};
+use std::env;
+use std::fs;
+use tempfile::TempDir;
+
+use crate::{cli::build_cli, config::{get_setting_flag_name, Config}};
+
+/// Writes a config file with a session secret key and `settings`, and reads the config with the command line flags
+/// in `flags`.
+fn read_config(settings: &str, flags: &[&str]) -> Result<Config, String> {
+  let directory = TempDir::new().unwrap();
+  let config_path = directory.path().join("treasury.toml");
+  let session_secret_key = general_purpose::STANDARD.encode([1; 64]);
+
+  fs::write(&config_path, format!("session_secret_key = \"{}\"\n{}", session_secret_key, settings)).unwrap();
+
+  let mut args = vec!["backend", "--config", config_path.to_str().unwrap()];
+  args.extend_from_slice(flags);
+
+  Config::read(&build_cli().get_matches_from(args)).map_err(|err| err.to_string())
+}
+
+#[test]
+fn flags_override_the_environment_which_overrides_the_config_file() {
+  // These settings aren't read from the environment by any other test
+  env::set_var("BACKUP_RETENTION_COUNT", "4");
+  env::set_var("SCRUB_INTERVAL_HOURS", "6");
+
+  let config = read_config(
+    "max_file_size = 100\nbackup_retention_count = 3\nscrub_interval_hours = 5\n",
+    &["--scrub-interval-hours", "7", "--address", "127.0.0.2"]
+  ).unwrap();
+
+  env::remove_var("BACKUP_RETENTION_COUNT");
+  env::remove_var("SCRUB_INTERVAL_HOURS");
+
+  assert_eq!(config.transfer_limits.max_file_size, 100);
+  assert_eq!(config.backup_retention_count, 4);
+  assert_eq!(config.scrub_interval_hours, 7);
+
+  // The old name of the ip address flag still works
+  assert_eq!(config.ip_address, "127.0.0.2");
+}
+
+#[test]
+fn every_setting_has_a_flag() {
+  let command = build_cli();
+
+  for name in Config::setting_names() {
+    let flag_name = get_setting_flag_name(name);
+    assert!(command.get_arguments().any(|arg| arg.get_long() == Some(flag_name.as_str())), "{}", name);
+  }
+}
+
+#[test]
+fn invalid_settings_are_all_reported() {
+  let err = match read_config("prot = 3001\nchunk_data_size = 1\n", &["--http-redirect-port", "abc"]) {
+    Ok(_) => panic!("The invalid config was read."),
+    Err(err) => err
+  };
+
+  assert!(err.contains("Unknown setting 'prot'"), "{}", err);
+  assert!(err.contains("CHUNK_DATA_SIZE must be between"), "{}", err);
+  assert!(err.contains("HTTP_REDIRECT_PORT 'abc' from the command line is invalid"), "{}", err);
+}
@@ -0,0 +1,100 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::Arc;
+use tempfile::{NamedTempFile, TempDir};
+use tokio::io::AsyncReadExt;
+
+use crate::{
+  constants,
+  storage::{encryptedstore::EncryptedStore, filestore::FileStore, BlobStore}
+};
+
+struct TestStore {
+  store: EncryptedStore,
+  file_store: Arc<FileStore>,
+  _directory: TempDir
+}
+
+fn create_store() -> TestStore {
+  let directory = TempDir::new().unwrap();
+  let file_store = Arc::new(FileStore::new(directory.path()));
+  let store = EncryptedStore::new(file_store.clone(), [7u8; constants::AT_REST_KEY_SIZE], directory.path());
+
+  TestStore { store, file_store, _directory: directory }
+}
+
+async fn put(store: &EncryptedStore, key: &str, contents: &[u8]) {
+  let mut source = NamedTempFile::new().unwrap();
+  source.write_all(contents).unwrap();
+  let (_, source_path) = source.keep().unwrap();
+
+  store.put(key, &source_path).await.unwrap();
+}
+
+async fn read(store: &EncryptedStore, key: &str, offset: u64, length: u64) -> Result<Vec<u8>, String> {
+  let mut reader = store.read_range(key, offset, length).await.map_err(|err| err.to_string())?;
+  let mut data = Vec::new();
+  reader.read_to_end(&mut data).await.unwrap();
+
+  Ok(data)
+}
+
+/// Cuts the wrapped blob down to a number of whole segments after the header.
+fn truncate_to_segments(test_store: &TestStore, key: &str, segment_count: u64) {
+  let size = constants::AT_REST_WRAPPED_HEADER_SIZE as u64 + segment_count * constants::AT_REST_WRAPPED_SEGMENT_SIZE as u64;
+
+  OpenOptions::new().write(true).open(test_store.file_store.get_blob_path(key)).unwrap().set_len(size).unwrap();
+}
+
+#[tokio::test]
+async fn encrypted_store_round_trip() {
+  let test_store = create_store();
+  let store = &test_store.store;
+  let contents: Vec<u8> = (0..=255).cycle().take(constants::AT_REST_SEGMENT_SIZE * 2 + 100).collect();
+
+  put(store, "file.tef", &contents).await;
+
+  assert_eq!(store.stat("file.tef").await.unwrap().map(|stat| stat.size), Some(contents.len() as u64));
+  assert_eq!(read(store, "file.tef", 0, contents.len() as u64).await.unwrap(), contents);
+  assert_eq!(read(store, "file.tef", 65_000, 1_000).await.unwrap(), contents[65_000..66_000]);
+
+  // Empty blobs still get a final segment
+  put(store, "empty.tef", &[]).await;
+
+  assert_eq!(store.stat("empty.tef").await.unwrap().map(|stat| stat.size), Some(0));
+  assert!(read(store, "empty.tef", 0, 10).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn encrypted_store_detects_truncation_at_segment_boundary() {
+  let test_store = create_store();
+  let store = &test_store.store;
+  let contents = vec![1u8; constants::AT_REST_SEGMENT_SIZE * 2];
+
+  put(store, "file.tef", &contents).await;
+  truncate_to_segments(&test_store, "file.tef", 1);
+
+  assert!(read(store, "file.tef", 0, contents.len() as u64).await.is_err());
+
+  // Cutting off every segment mustn't read as an empty blob
+  truncate_to_segments(&test_store, "file.tef", 0);
+
+  assert!(read(store, "file.tef", 0, contents.len() as u64).await.is_err());
+}
+
+#[tokio::test]
+async fn encrypted_store_append() {
+  let test_store = create_store();
+  let store = &test_store.store;
+  let mut contents: Vec<u8> = (0..=255).cycle().take(constants::AT_REST_SEGMENT_SIZE - 10).collect();
+
+  store.append("file.tef", &contents).await.unwrap();
+
+  // Crosses into a second segment, so the old last segment stops being the last one
+  let extra = vec![9u8; 100];
+  store.append("file.tef", &extra).await.unwrap();
+  contents.extend_from_slice(&extra);
+
+  assert_eq!(store.stat("file.tef").await.unwrap().map(|stat| stat.size), Some(contents.len() as u64));
+  assert_eq!(read(store, "file.tef", 0, contents.len() as u64).await.unwrap(), contents);
+}
@@ -0,0 +1,69 @@
+use tempfile::TempDir;
+use tokio::io::AsyncReadExt;
+
+use crate::storage::{filestore::FileStore, BlobStore};
+
+async fn read_to_end(store: &FileStore, key: &str, offset: u64, length: u64) -> Vec<u8> {
+  let mut data = Vec::new();
+  store.read_range(key, offset, length).await.unwrap().read_to_end(&mut data).await.unwrap();
+
+  data
+}
+
+#[tokio::test]
+async fn file_store_append() {
+  let directory = TempDir::new().unwrap();
+  let store = FileStore::new(directory.path());
+
+  // Appending to a missing blob creates it
+  store.append("abcdefgh.tef", b"hello").await.unwrap();
+  store.append("abcdefgh.tef", b" world").await.unwrap();
+
+  assert_eq!(store.stat("abcdefgh.tef").await.unwrap().map(|stat| stat.size), Some(11));
+  assert_eq!(read_to_end(&store, "abcdefgh.tef", 0, 11).await, b"hello world");
+}
+
+#[tokio::test]
+async fn file_store_shards_blobs() {
+  let directory = TempDir::new().unwrap();
+  let store = FileStore::new(directory.path());
+
+  let source_path = directory.path().join("upload");
+  std::fs::write(&source_path, b"data").unwrap();
+  store.put("abcdefgh.tef", &source_path).await.unwrap();
+
+  assert_eq!(store.get_blob_path("abcdefgh.tef"), directory.path().join("ab/cd/abcdefgh.tef"));
+  assert!(directory.path().join("ab/cd/abcdefgh.tef").is_file());
+
+  // Keys too short to shard stay in the root directory
+  assert_eq!(store.get_blob_path("abcd"), directory.path().join("abcd"));
+
+  assert_eq!(store.list().await.unwrap(), vec!["abcdefgh.tef".to_string()]);
+}
+
+#[tokio::test]
+async fn file_store_reads_legacy_blobs_until_migrated() {
+  let directory = TempDir::new().unwrap();
+  let store = FileStore::new(directory.path());
+
+  // Blobs written before sharding are stored flat in the root directory
+  std::fs::write(directory.path().join("abcdefgh.tef"), b"hello").unwrap();
+  std::fs::write(directory.path().join("ijklmnop.tef"), b"legacy").unwrap();
+
+  assert_eq!(store.stat("abcdefgh.tef").await.unwrap().map(|stat| stat.size), Some(5));
+  assert_eq!(read_to_end(&store, "abcdefgh.tef", 0, 5).await, b"hello");
+
+  // Appends keep going to the legacy file rather than starting a new sharded one
+  store.append("abcdefgh.tef", b" world").await.unwrap();
+  assert!(!directory.path().join("ab").exists());
+
+  store.delete("ijklmnop.tef").await.unwrap();
+  assert!(!directory.path().join("ijklmnop.tef").exists());
+
+  assert_eq!(store.migrate_to_sharded_layout().await.unwrap(), 1);
+  assert!(!directory.path().join("abcdefgh.tef").exists());
+  assert_eq!(read_to_end(&store, "abcdefgh.tef", 0, 11).await, b"hello world");
+
+  // Running the migration again has nothing left to move
+  assert_eq!(store.migrate_to_sharded_layout().await.unwrap(), 0);
+}
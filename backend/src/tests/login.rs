@@ -0,0 +1,63 @@
+use base64::{engine::general_purpose, Engine as _};
+use http::{header::RETRY_AFTER, Method, StatusCode};
+
+use super::{TestClient, TestServer};
+use crate::constants;
+
+const AUTH_KEY: &[u8] = &[7; constants::AUTH_KEY_SIZE];
+const WRONG_AUTH_KEY: &[u8] = &[8; constants::AUTH_KEY_SIZE];
+
+/// Attempts a login and returns the status along with the Retry-After header in seconds, if any.
+async fn login(client: &mut TestClient, username: &str, auth_key: &[u8]) -> (StatusCode, Option<u64>) {
+  let body = serde_json::json!({
+    "username": username,
+    "authKey": general_purpose::STANDARD.encode(auth_key)
+  });
+
+  let (status, headers, _) = client.request_with_headers(Method::POST, "/api/login", Some(body)).await;
+  let retry_after = headers.get(RETRY_AFTER).map(|value| value.to_str().unwrap().parse().unwrap());
+
+  (status, retry_after)
+}
+
+#[tokio::test]
+async fn repeated_failures_lock_out_the_username() {
+  let server = TestServer::start();
+  server.create_user("alice", AUTH_KEY).await;
+
+  let mut client = server.client();
+
+  for _ in 0..constants::LOGIN_USERNAME_FREE_ATTEMPTS {
+    assert_eq!(login(&mut client, "alice", WRONG_AUTH_KEY).await, (StatusCode::UNAUTHORIZED, None));
+  }
+
+  // Locked out even with the right key, and usernames are tracked case insensitively
+  let (status, retry_after) = login(&mut client, "ALICE", AUTH_KEY).await;
+
+  assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+  assert!(matches!(retry_after, Some(1..=constants::LOGIN_LOCKOUT_BASE_SECONDS)), "{:?}", retry_after);
+  assert!(!client.is_logged_in().await);
+}
+
+#[tokio::test]
+async fn successful_login_clears_failures() {
+  let server = TestServer::start();
+  server.create_user("alice", AUTH_KEY).await;
+
+  let mut client = server.client();
+
+  for _ in 0..constants::LOGIN_USERNAME_FREE_ATTEMPTS - 1 {
+    assert_eq!(login(&mut client, "alice", WRONG_AUTH_KEY).await.0, StatusCode::UNAUTHORIZED);
+  }
+
+  assert_eq!(login(&mut client, "alice", AUTH_KEY).await.0, StatusCode::OK);
+
+  // The earlier failures were forgotten, so this many more still aren't locked out
+  let mut other_client = server.client();
+
+  for _ in 0..constants::LOGIN_USERNAME_FREE_ATTEMPTS - 1 {
+    assert_eq!(login(&mut other_client, "alice", WRONG_AUTH_KEY).await.0, StatusCode::UNAUTHORIZED);
+  }
+
+  assert_eq!(login(&mut other_client, "alice", AUTH_KEY).await.0, StatusCode::OK);
+}
@@ -0,0 +1,43 @@
+use rusqlite::Connection;
+use tempfile::TempDir;
+
+use crate::{config::Config, database::Database};
+
+#[test]
+fn migration_reports_duplicate_usernames() {
+  let directory = TempDir::new().unwrap();
+  let database_path = directory.path().join("database.db");
+
+  // Databases from before migrations existed are at version 0 and nothing stopped usernames differing only by case
+  let connection = Connection::open(&database_path).unwrap();
+  connection.execute_batch(
+    "CREATE TABLE users (
+      id INTEGER PRIMARY KEY,
+      username TEXT NOT NULL,
+      storage_quota BIGINT NOT NULL DEFAULT 0,
+      auth_key_hash TEXT NOT NULL,
+      salt BLOB NOT NULL,
+      encrypted_master_key BLOB NOT NULL,
+      encrypted_ed25519_private_key BLOB NOT NULL,
+      ed25519_public_key BLOB NOT NULL,
+      encrypted_x25519_private_key BLOB NOT NULL,
+      x25519_public_key BLOB NOT NULL
+    );
+    INSERT INTO users (id, username, auth_key_hash, salt, encrypted_master_key, encrypted_ed25519_private_key,
+      ed25519_public_key, encrypted_x25519_private_key, x25519_public_key)
+    VALUES (1, 'Alice', '', x'', x'', x'', x'', x'', x''), (2, 'alice', '', x'', x'', x'', x'', x'', x''),
+      (3, 'bob', '', x'', x'', x'', x'', x'', x'');"
+  ).unwrap();
+  drop(connection);
+
+  let mut config = Config::default();
+  config.database_path = database_path.to_str().unwrap().to_string();
+
+  let err = match Database::open(&config) {
+    Ok(_) => panic!("Migrating a database with duplicate usernames succeeded."),
+    Err(err) => err.to_string()
+  };
+
+  assert!(err.contains("id 1 (Alice)") && err.contains("id 2 (alice)"), "{}", err);
+  assert!(!err.contains("bob"), "{}", err);
+}
@@ -0,0 +1,99 @@
+use axum::body::Body;
+use std::sync::Arc;
+use tempfile::TempDir;
+
+use crate::{
+  api::{formats::calc_chunk_checksum, utils::download_utils::{CorruptChunkError, DownloadsManager}},
+  config::Config,
+  constants,
+  storage::{filestore::FileStore, BlobStore}
+};
+
+const HANDLE: &str = "abcdefghijklmnop";
+const CHUNK_DATA_SIZE: usize = 16;
+
+/// A stored file with two full chunks, where every byte of chunk `n` is `n + 1`.
+fn stored_file() -> Vec<u8> {
+  let enc_chunk_size = CHUNK_DATA_SIZE + constants::ENCRYPTED_CHUNK_EXTRA_DATA_SIZE;
+  let mut contents = constants::ENCRYPTED_FILE_MAGIC_NUMBER.to_vec();
+  contents.extend(vec![1; enc_chunk_size]);
+  contents.extend(vec![2; enc_chunk_size]);
+
+  contents
+}
+
+fn chunk(contents: &[u8], chunk_id: usize) -> &[u8] {
+  let enc_chunk_size = CHUNK_DATA_SIZE + constants::ENCRYPTED_CHUNK_EXTRA_DATA_SIZE;
+  let offset = constants::ENCRYPTED_FILE_HEADER_SIZE + chunk_id * enc_chunk_size;
+
+  &contents[offset..offset + enc_chunk_size]
+}
+
+fn downloads_manager(primary: &TempDir, mirror: Option<&TempDir>) -> DownloadsManager {
+  let mut transfer_limits = Config::default().transfer_limits;
+  transfer_limits.chunk_data_size = CHUNK_DATA_SIZE;
+
+  let mirror = mirror.map(|mirror| Arc::new(FileStore::new(mirror.path())) as Arc<dyn BlobStore>);
+  DownloadsManager::new(Arc::new(FileStore::new(primary.path())), mirror, transfer_limits)
+}
+
+async fn store(directory: &TempDir, contents: &[u8]) {
+  FileStore::new(directory.path())
+    .append(&format!("{}{}", HANDLE, constants::TREASURY_FILE_EXTENSION), contents)
+    .await
+    .unwrap();
+}
+
+async fn read_chunk(manager: &mut DownloadsManager, chunk_id: u64, checksum: Option<Vec<u8>>) -> Result<Vec<u8>, String> {
+  let chunk_read = manager.prepare_chunk_read(1, &HANDLE.to_string(), chunk_id).await.map_err(|err| err.to_string())?;
+
+  match chunk_read.read(checksum).await {
+    Ok(stream) => Ok(axum::body::to_bytes(Body::from_stream(stream), usize::MAX).await.unwrap().to_vec()),
+    Err(err) if err.is::<CorruptChunkError>() => Err("corrupt".to_string()),
+    Err(err) => Err(err.to_string())
+  }
+}
+
+#[tokio::test]
+async fn files_missing_from_the_primary_store_are_read_from_the_mirror() {
+  let (primary, mirror) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+  let contents = stored_file();
+  store(&mirror, &contents).await;
+
+  let mut manager = downloads_manager(&primary, Some(&mirror));
+  assert_eq!(read_chunk(&mut manager, 1, None).await.unwrap(), chunk(&contents, 1));
+
+  // Without a mirror the file can't be found
+  let mut manager = downloads_manager(&primary, None);
+  assert!(read_chunk(&mut manager, 1, None).await.is_err());
+}
+
+#[tokio::test]
+async fn corrupt_chunks_are_read_from_the_mirror() {
+  let (primary, mirror) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+  let contents = stored_file();
+  let checksum = calc_chunk_checksum(chunk(&contents, 0));
+
+  let mut corrupted_contents = contents.clone();
+  corrupted_contents[constants::ENCRYPTED_FILE_HEADER_SIZE] ^= 0xff;
+  store(&primary, &corrupted_contents).await;
+  store(&mirror, &contents).await;
+
+  let mut manager = downloads_manager(&primary, Some(&mirror));
+  assert_eq!(read_chunk(&mut manager, 0, Some(checksum.clone())).await.unwrap(), chunk(&contents, 0));
+
+  // Chunks that aren't corrupted are still read from the primary store
+  let checksum_1 = calc_chunk_checksum(chunk(&contents, 1));
+  assert_eq!(read_chunk(&mut manager, 1, Some(checksum_1)).await.unwrap(), chunk(&contents, 1));
+
+  // Without a mirror the corruption is reported instead of streaming bad data
+  let mut manager = downloads_manager(&primary, None);
+  assert_eq!(read_chunk(&mut manager, 0, Some(checksum.clone())).await, Err("corrupt".to_string()));
+
+  // A chunk that's corrupted in both stores is reported too
+  let mirror_path = FileStore::new(mirror.path()).get_blob_path(&format!("{}{}", HANDLE, constants::TREASURY_FILE_EXTENSION));
+  std::fs::write(mirror_path, &corrupted_contents).unwrap();
+
+  let mut manager = downloads_manager(&primary, Some(&mirror));
+  assert_eq!(read_chunk(&mut manager, 0, Some(checksum)).await, Err("corrupt".to_string()));
+}
@@ -0,0 +1,185 @@
+//! Tests that run requests through the server's router against a temporary database, storage backends against
+//! in-process services and migrations against legacy databases. The server is a binary crate, so these live inside it
+//! instead of in a `tests` directory.
+
+mod admin;
+mod backup;
+mod claimcodes;
+mod config;
+mod encryptedstore;
+mod filestore;
+mod login;
+mod migrations;
+mod mirror;
+mod s3store;
+mod scrub;
+mod totp;
+mod uploads;
+mod webauthn;
+
+use axum::{body::Body, extract::connect_info::MockConnectInfo, Router};
+use base64::{engine::general_purpose, Engine as _};
+use http::{header, HeaderMap, Method, Request, StatusCode};
+use serde_json::Value;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tempfile::TempDir;
+use tokio::sync::Mutex;
+use tower::ServiceExt;
+
+use crate::{
+  api::utils::{
+    auth_utils::hash_auth_key,
+    download_utils::DownloadsManager,
+    upload_utils::UploadsManager,
+    webauthn_utils::WebauthnManager
+  },
+  build_router,
+  config::Config,
+  constants,
+  database::{ClaimCodeData, ClaimUserRequest, Database, UserData},
+  storage::filestore::FileStore,
+  AppState
+};
+
+pub struct TestServer {
+  pub state: Arc<Mutex<AppState>>,
+  router: Router,
+  _directory: TempDir
+}
+
+impl TestServer {
+  pub fn start() -> Self {
+    let directory = TempDir::new().unwrap();
+    let path = |name: &str| directory.path().join(name).to_str().unwrap().to_string();
+
+    let mut config = Config::default();
+    config.database_path = path("databases/database.db");
+    config.user_upload_directory = path("uploads");
+    config.user_files_root_directory = path("userfiles");
+    config.backup_directory = path("backups");
+    config.secure_cookies = false;
+    config.argon2_memory_size = 8;
+    config.argon2_iterations = 1;
+    config.initialise_directories().unwrap();
+
+    let database = Database::open(&config).unwrap();
+    let blob_store = Arc::new(FileStore::new(&config.user_files_root_directory));
+
+    let state = Arc::new(Mutex::new(AppState {
+      uploads_manager: UploadsManager::new(&config, blob_store.clone()),
+      downloads_manager: DownloadsManager::new(blob_store, None, config.transfer_limits),
+      webauthn_manager: WebauthnManager::new(&config).unwrap(),
+      database: Some(database),
+      replication_manager: None,
+      encrypted_stores: Vec::new(),
+      config: config.clone()
+    }));
+
+    let router = build_router(state.clone(), &config)
+      .layer(MockConnectInfo(SocketAddr::from(([127, 0, 0, 1], 40000))));
+
+    Self { state, router, _directory: directory }
+  }
+
+  /// Creates a user through a claim code and returns their id.
+  pub async fn create_user(&self, username: &str, auth_key: &[u8]) -> u64 {
+    let mut app_state = self.state.lock().await;
+    let argon2_params = app_state.config.argon2_params().unwrap();
+    let database = app_state.database.as_mut().unwrap();
+    let claim_code = "A".repeat(constants::CLAIM_CODE_LENGTH);
+
+    database.insert_new_claim_code(&ClaimCodeData {
+      claim_code: claim_code.clone(),
+      storage_quota: 1_000_000,
+      expiry_time: None,
+      max_uses: 1,
+      use_count: 0,
+      note: None,
+      created_by: None,
+      created_time: None,
+      revoked_time: None
+    }).unwrap();
+
+    database.claim_user(&ClaimUserRequest {
+      claim_code,
+      user_data: UserData {
+        username: username.to_string(),
+        auth_key_hash: hash_auth_key(auth_key, &argon2_params).unwrap(),
+        salt: vec![0; constants::USER_AUTH_HASH_SALT_SIZE],
+        encrypted_master_key: vec![0; constants::ENCRYPTED_MASTER_KEY_SIZE],
+        encrypted_ed25519_private_key: vec![0; constants::ENCRYPTED_CURVE25519_KEY_SIZE],
+        ed25519_public_key: vec![0; constants::CURVE25519_KEY_SIZE],
+        encrypted_x25519_private_key: vec![0; constants::ENCRYPTED_CURVE25519_KEY_SIZE],
+        x25519_public_key: vec![0; constants::CURVE25519_KEY_SIZE],
+        recovery_key_hash: None,
+        encrypted_recovery_master_key: None,
+        storage_quota: None,
+        user_id: None,
+        disabled: false,
+        is_admin: false
+      }
+    }).unwrap();
+
+    database.get_user_data(&username.to_string()).unwrap().user_id.unwrap()
+  }
+
+  /// A client with its own session cookie.
+  pub fn client(&self) -> TestClient {
+    TestClient { router: self.router.clone(), cookie: None }
+  }
+}
+
+pub struct TestClient {
+  router: Router,
+  cookie: Option<String>
+}
+
+impl TestClient {
+  /// Sends a request with the session cookie and returns the status and the JSON body, or `Value::Null` if the body
+  /// isn't JSON.
+  pub async fn request(&mut self, method: Method, uri: &str, body: Option<Value>) -> (StatusCode, Value) {
+    let (status, _, body) = self.request_with_headers(method, uri, body).await;
+
+    (status, body)
+  }
+
+  /// Like `request` but also returns the response headers.
+  pub async fn request_with_headers(&mut self, method: Method, uri: &str, body: Option<Value>) -> (StatusCode, HeaderMap, Value) {
+    let mut request = Request::builder().method(method).uri(uri);
+
+    if let Some(cookie) = &self.cookie {
+      request = request.header(header::COOKIE, cookie);
+    }
+
+    let request = match body {
+      Some(body) => request.header(header::CONTENT_TYPE, "application/json").body(Body::from(body.to_string())),
+      None => request.body(Body::empty())
+    };
+
+    let response = self.router.clone().oneshot(request.unwrap()).await.unwrap();
+
+    if let Some(set_cookie) = response.headers().get(header::SET_COOKIE) {
+      self.cookie = set_cookie.to_str().unwrap().split(';').next().map(str::to_string);
+    }
+
+    let status = response.status();
+    let headers = response.headers().clone();
+    let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+
+    (status, headers, serde_json::from_slice(&bytes).unwrap_or(Value::Null))
+  }
+
+  pub async fn login(&mut self, username: &str, auth_key: &[u8]) -> (StatusCode, Value) {
+    let body = serde_json::json!({
+      "username": username,
+      "authKey": general_purpose::STANDARD.encode(auth_key)
+    });
+
+    self.request(Method::POST, "/api/login", Some(body)).await
+  }
+
+  pub async fn is_logged_in(&mut self) -> bool {
+    self.request(Method::GET, "/api/sessiondata", None).await.0 == StatusCode::OK
+  }
+}
@@ -0,0 +1,142 @@
+use axum::{
+  body::Bytes,
+  extract::{Path, State},
+  http::{header, HeaderMap, Method, StatusCode},
+  response::{IntoResponse, Response},
+  routing::any,
+  Router
+};
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use tempfile::NamedTempFile;
+use tokio::io::AsyncReadExt;
+
+use crate::{
+  config::Config,
+  storage::{s3store::S3Store, BlobStore}
+};
+
+type Objects = Arc<Mutex<HashMap<String, Vec<u8>>>>;
+
+/// Handles the subset of the S3 API that `S3Store` uses, with path-style addressing and without checking signatures.
+async fn handle_object(
+  State(objects): State<Objects>,
+  Path(path): Path<String>,
+  method: Method,
+  headers: HeaderMap,
+  body: Bytes
+) -> Response {
+  let mut objects = objects.lock().unwrap();
+
+  match method {
+    Method::PUT => {
+      objects.insert(path, body.to_vec());
+      StatusCode::OK.into_response()
+    },
+    Method::DELETE => {
+      objects.remove(&path);
+      StatusCode::NO_CONTENT.into_response()
+    },
+    Method::HEAD => match objects.get(&path) {
+      Some(object) => (StatusCode::OK, [(header::CONTENT_LENGTH, object.len().to_string())]).into_response(),
+      None => StatusCode::NOT_FOUND.into_response()
+    },
+    Method::GET => {
+      let object = match objects.get(&path) {
+        Some(object) => object,
+        None => return (StatusCode::NOT_FOUND, "<Error><Code>NoSuchKey</Code></Error>").into_response()
+      };
+
+      // Only the "bytes=<start>-<end>" form is sent by S3Store
+      let range = headers.get(header::RANGE)
+        .and_then(|range| range.to_str().ok())
+        .and_then(|range| range.strip_prefix("bytes="))
+        .and_then(|range| range.split_once('-'))
+        .and_then(|(start, end)| Some((start.parse::<usize>().ok()?, end.parse::<usize>().ok()?)));
+
+      match range {
+        Some((start, end)) => (StatusCode::PARTIAL_CONTENT, object[start..=end.min(object.len() - 1)].to_vec()).into_response(),
+        None => object.clone().into_response()
+      }
+    },
+    _ => StatusCode::METHOD_NOT_ALLOWED.into_response()
+  }
+}
+
+/// Starts an in-process S3 server and returns a store that uses it.
+async fn start_mock_s3() -> S3Store {
+  let router = Router::new()
+    .route("/*path", any(handle_object))
+    .with_state(Objects::default());
+
+  let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+  let address = listener.local_addr().unwrap();
+  tokio::spawn(async move { axum::serve(listener, router).await });
+
+  let mut config = Config::default();
+  config.s3_endpoint = format!("http://{}", address);
+  config.s3_access_key = "access".to_string();
+  config.s3_secret_key = "secret".to_string();
+  config.s3_key_prefix = "userfiles/".to_string();
+
+  S3Store::new(&config).unwrap()
+}
+
+async fn read_to_end(store: &S3Store, key: &str, offset: u64, length: u64) -> Vec<u8> {
+  let mut data = Vec::new();
+  store.read_range(key, offset, length).await.unwrap().read_to_end(&mut data).await.unwrap();
+
+  data
+}
+
+#[tokio::test]
+async fn s3_store_round_trip() {
+  let store = start_mock_s3().await;
+  let contents: Vec<u8> = (0..=255).cycle().take(10_000).collect();
+
+  let mut source = NamedTempFile::new().unwrap();
+  source.write_all(&contents).unwrap();
+  let (_, source_path) = source.keep().unwrap();
+
+  // Putting moves the local file into the store
+  store.put("file.tef", &source_path).await.unwrap();
+  assert!(!source_path.exists());
+
+  assert_eq!(store.stat("file.tef").await.unwrap().map(|stat| stat.size), Some(contents.len() as u64));
+  assert_eq!(read_to_end(&store, "file.tef", 0, contents.len() as u64).await, contents);
+  assert_eq!(read_to_end(&store, "file.tef", 1000, 500).await, contents[1000..1500]);
+  assert_eq!(read_to_end(&store, "file.tef", 9990, 10).await, contents[9990..]);
+  assert!(read_to_end(&store, "file.tef", 5, 0).await.is_empty());
+
+  store.delete("file.tef").await.unwrap();
+  assert!(store.stat("file.tef").await.unwrap().is_none());
+
+  // Deleting a missing object isn't an error
+  store.delete("file.tef").await.unwrap();
+}
+
+#[tokio::test]
+async fn s3_store_missing_object_is_not_found() {
+  let store = start_mock_s3().await;
+
+  assert!(store.stat("missing.tef").await.unwrap().is_none());
+
+  let err = match store.read_range("missing.tef", 0, 10).await {
+    Ok(_) => panic!("Reading a missing object succeeded."),
+    Err(err) => err
+  };
+
+  assert_eq!(err.downcast_ref::<io::Error>().map(io::Error::kind), Some(io::ErrorKind::NotFound));
+}
+
+#[tokio::test]
+async fn s3_store_append() {
+  let store = start_mock_s3().await;
+
+  // Appending to a missing object creates it
+  store.append("file.tef", b"hello").await.unwrap();
+  store.append("file.tef", b" world").await.unwrap();
+
+  assert_eq!(read_to_end(&store, "file.tef", 0, 11).await, b"hello world");
+}
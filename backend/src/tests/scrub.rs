@@ -0,0 +1,64 @@
+use std::path::PathBuf;
+
+use super::TestServer;
+use crate::{
+  api::formats::calc_encrypted_file_size,
+  constants,
+  database::UserFileEntry,
+  storage::{scrubber::run_scrub, QUARANTINE_KEY_PREFIX}
+};
+
+fn stored_file_key(handle: &str) -> String {
+  format!("{}{}", handle, constants::TREASURY_FILE_EXTENSION)
+}
+
+#[tokio::test]
+async fn scrub_quarantines_orphaned_files_when_asked() {
+  let server = TestServer::start();
+  let user_id = server.create_user("alice", &[7; constants::AUTH_KEY_SIZE]).await;
+  let tracked_handle = "a".repeat(constants::FILE_HANDLE_LENGTH);
+  let orphan_handle = "b".repeat(constants::FILE_HANDLE_LENGTH);
+
+  let (blob_store, root_directory) = {
+    let mut app_state = server.state.lock().await;
+    let chunk_data_size = app_state.config.transfer_limits.chunk_data_size;
+    let root_directory = PathBuf::from(&app_state.config.user_files_root_directory);
+
+    // An empty file that has a database entry, and a stored file that doesn't
+    app_state.database.as_mut().unwrap().insert_new_user_file(&UserFileEntry {
+      owner_id: user_id,
+      handle: tracked_handle.clone(),
+      parent_handle: "0".repeat(constants::FILE_HANDLE_LENGTH),
+      size: 0,
+      encrypted_crypt_key: Some(vec![0]),
+      encrypted_metadata: vec![0],
+      signature: None
+    }).unwrap();
+
+    let mut contents = constants::ENCRYPTED_FILE_MAGIC_NUMBER.to_vec();
+    contents.resize(calc_encrypted_file_size(0, chunk_data_size) as usize, 0);
+
+    let blob_store = app_state.uploads_manager.blob_store.clone();
+    blob_store.append(&stored_file_key(&tracked_handle), &contents).await.unwrap();
+    blob_store.append(&stored_file_key(&orphan_handle), &contents).await.unwrap();
+
+    (blob_store, root_directory)
+  };
+
+  // Without quarantining, orphans are only reported
+  let report = run_scrub(server.state.clone(), false).await.unwrap();
+  assert_eq!((report.checked_files, report.orphaned_files, report.quarantined_files), (1, 1, 0));
+  assert_eq!((report.missing_files, report.size_mismatches, report.bad_magic_numbers), (0, 0, 0));
+  assert_eq!(blob_store.list().await.unwrap().len(), 2);
+
+  let report = run_scrub(server.state.clone(), true).await.unwrap();
+  assert_eq!((report.checked_files, report.orphaned_files, report.quarantined_files), (1, 1, 1));
+
+  // The orphan is kept in quarantine instead of being deleted, and the tracked file is left alone
+  assert!(root_directory.join(QUARANTINE_KEY_PREFIX).join(stored_file_key(&orphan_handle)).is_file());
+  assert_eq!(blob_store.list().await.unwrap(), vec![stored_file_key(&tracked_handle)]);
+
+  // Quarantined files aren't reported again
+  let report = run_scrub(server.state.clone(), true).await.unwrap();
+  assert_eq!((report.checked_files, report.orphaned_files, report.quarantined_files), (1, 0, 0));
+}
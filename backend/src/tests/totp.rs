@@ -0,0 +1,92 @@
+use http::{Method, StatusCode};
+use serde_json::{json, Value};
+use totp_rs::{Secret, Totp};
+
+use super::{TestClient, TestServer};
+use crate::{api::totp::build_totp, constants, util::get_unix_timestamp_secs};
+
+const AUTH_KEY: &[u8] = &[7; constants::AUTH_KEY_SIZE];
+
+/// Logs a new client in with the password, leaving the TOTP step pending.
+async fn start_login(server: &TestServer) -> TestClient {
+  let mut client = server.client();
+  let (status, response) = client.login("alice", AUTH_KEY).await;
+
+  assert_eq!(status, StatusCode::OK);
+  assert_eq!(response["secondFactors"], json!(["totp"]));
+  assert!(!client.is_logged_in().await);
+
+  client
+}
+
+async fn finish_login(client: &mut TestClient, body: Value) -> StatusCode {
+  client.request(Method::POST, "/api/login/totp", Some(body)).await.0
+}
+
+/// Enrols and confirms TOTP for alice, returning the authenticator, the time its confirmation code was generated at and
+/// the backup codes.
+async fn enable_totp(server: &TestServer) -> (Totp, u64, Vec<String>) {
+  let mut client = server.client();
+  assert_eq!(client.login("alice", AUTH_KEY).await.0, StatusCode::OK);
+
+  let (status, response) = client.request(Method::POST, "/api/totp/enrol", None).await;
+  assert_eq!(status, StatusCode::OK);
+
+  let secret = Secret::try_from_base32(response["secret"].as_str().unwrap()).unwrap();
+  let totp = build_totp(&secret, "alice").unwrap();
+
+  let confirmed_at = get_unix_timestamp_secs();
+  let code = totp.generate(confirmed_at).to_string();
+  let (status, response) = client.request(Method::POST, "/api/totp/confirm", Some(json!({ "code": code }))).await;
+  assert_eq!(status, StatusCode::OK);
+
+  let backup_codes = response["backupCodes"].as_array().unwrap().iter()
+    .map(|code| code.as_str().unwrap().to_string())
+    .collect();
+
+  (totp, confirmed_at, backup_codes)
+}
+
+#[tokio::test]
+async fn totp_codes_cannot_be_replayed() {
+  let server = TestServer::start();
+  server.create_user("alice", AUTH_KEY).await;
+
+  let (totp, confirmed_at, _) = enable_totp(&server).await;
+
+  // The code used to confirm enrolment was spent there
+  let mut client = start_login(&server).await;
+  assert_eq!(finish_login(&mut client, json!({ "code": totp.generate(confirmed_at).to_string() })).await, StatusCode::UNAUTHORIZED);
+  assert!(!client.is_logged_in().await);
+
+  // The next 30 second time step is within the allowed skew
+  let code = totp.generate(confirmed_at + 30).to_string();
+  assert_eq!(finish_login(&mut client, json!({ "code": code })).await, StatusCode::OK);
+  assert!(client.is_logged_in().await);
+
+  // Once used, the same code doesn't work for another login
+  let mut client = start_login(&server).await;
+  assert_eq!(finish_login(&mut client, json!({ "code": code })).await, StatusCode::UNAUTHORIZED);
+  assert!(!client.is_logged_in().await);
+}
+
+#[tokio::test]
+async fn backup_codes_work_once() {
+  let server = TestServer::start();
+  server.create_user("alice", AUTH_KEY).await;
+
+  let (_, _, backup_codes) = enable_totp(&server).await;
+  assert_eq!(backup_codes.len(), constants::TOTP_BACKUP_CODE_COUNT);
+
+  let mut client = start_login(&server).await;
+  assert_eq!(finish_login(&mut client, json!({ "backupCode": backup_codes[0] })).await, StatusCode::OK);
+  assert!(client.is_logged_in().await);
+
+  let mut client = start_login(&server).await;
+  assert_eq!(finish_login(&mut client, json!({ "backupCode": backup_codes[0] })).await, StatusCode::UNAUTHORIZED);
+  assert!(!client.is_logged_in().await);
+
+  // The other codes are unaffected
+  assert_eq!(finish_login(&mut client, json!({ "backupCode": backup_codes[1] })).await, StatusCode::OK);
+  assert!(client.is_logged_in().await);
+}
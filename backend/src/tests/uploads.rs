@@ -0,0 +1,128 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use tokio::io::AsyncReadExt;
+
+use super::TestServer;
+use crate::{
+  api::{formats::calc_chunk_checksum, utils::upload_utils::UploadsManager},
+  config::Config,
+  constants
+};
+
+const HANDLE: &str = "abcdefghijklmnop";
+const CHUNK_DATA_SIZE: usize = 16;
+
+/// A config with small chunks, and a full sized encrypted chunk for each id where every byte is `id + 1`.
+async fn small_chunk_config(server: &TestServer) -> (Config, Vec<Vec<u8>>) {
+  let mut config = server.state.lock().await.config.clone();
+  config.transfer_limits.chunk_data_size = CHUNK_DATA_SIZE;
+
+  let chunks = (0..3)
+    .map(|chunk_id| vec![chunk_id + 1; CHUNK_DATA_SIZE + constants::ENCRYPTED_CHUNK_EXTRA_DATA_SIZE])
+    .collect();
+
+  (config, chunks)
+}
+
+fn upload_file_path(config: &Config) -> PathBuf {
+  PathBuf::from(&config.user_upload_directory).join(format!("{}{}", HANDLE, constants::TREASURY_FILE_EXTENSION))
+}
+
+#[tokio::test]
+async fn cancelling_a_users_uploads_leaves_other_users_alone() {
+  let server = TestServer::start();
+  let mut app_state = server.state.lock().await;
+  let uploads_manager = &mut app_state.uploads_manager;
+
+  uploads_manager.new_upload(1, "aaaaaaaaaaaaaaaa", 100).await.unwrap();
+  uploads_manager.new_upload(1, "bbbbbbbbbbbbbbbb", 100).await.unwrap();
+  uploads_manager.new_upload(2, "cccccccccccccccc", 100).await.unwrap();
+
+  let upload_file_path = uploads_manager.get_active_upload(&"aaaaaaaaaaaaaaaa".to_string()).await.unwrap().upload_file_path.clone();
+
+  assert_eq!(uploads_manager.cancel_user_uploads(1).await, 2);
+  assert!(!upload_file_path.exists());
+  assert!(!uploads_manager.is_handle_valid(&"aaaaaaaaaaaaaaaa".to_string()));
+  assert!(!uploads_manager.is_handle_valid(&"bbbbbbbbbbbbbbbb".to_string()));
+  assert!(uploads_manager.is_handle_valid(&"cccccccccccccccc".to_string()));
+}
+
+#[tokio::test]
+async fn suspended_uploads_continue_after_a_restart() {
+  let server = TestServer::start();
+  let user_id = server.create_user("alice", &[7; constants::AUTH_KEY_SIZE]).await;
+  let (config, chunks) = small_chunk_config(&server).await;
+  let blob_store = server.state.lock().await.uploads_manager.blob_store.clone();
+  let handle = HANDLE.to_string();
+
+  let mut uploads_manager = UploadsManager::new(&config, blob_store.clone());
+  uploads_manager.new_upload(user_id, HANDLE, 3 * CHUNK_DATA_SIZE as u64).await.unwrap();
+
+  // The last chunk arrives early, so it's only buffered and the client has to send it again
+  let upload = uploads_manager.get_active_upload(&handle).await.unwrap();
+  upload.try_write_chunk(0, chunks[0].clone()).await.unwrap();
+  upload.try_write_chunk(2, chunks[2].clone()).await.unwrap();
+
+  // An upload of a user that no longer exists isn't saved
+  uploads_manager.new_upload(user_id + 1, "ponmlkjihgfedcba", 100).await.unwrap();
+
+  let suspended_uploads = uploads_manager.suspend_uploads().await;
+  assert_eq!(suspended_uploads.len(), 2);
+
+  let suspended_uploads = {
+    let mut app_state = server.state.lock().await;
+    let database = app_state.database.as_mut().unwrap();
+    database.replace_suspended_uploads(&suspended_uploads).unwrap();
+    database.get_suspended_uploads().unwrap()
+  };
+
+  assert_eq!(suspended_uploads.len(), 1);
+
+  // Data written after the progress was saved, e.g. before a crash, is cut off when restoring
+  OpenOptions::new().append(true).open(upload_file_path(&config)).unwrap().write_all(&chunks[1][..10]).unwrap();
+
+  let mut uploads_manager = UploadsManager::new(&config, blob_store.clone());
+  assert_eq!(uploads_manager.restore_uploads(suspended_uploads).await, 1);
+
+  let upload = uploads_manager.get_active_upload(&handle).await.unwrap();
+  assert_eq!((upload.written_bytes, upload.prev_written_chunk_id), (CHUNK_DATA_SIZE as u64, 0));
+
+  upload.try_write_chunk(1, chunks[1].clone()).await.unwrap();
+  upload.try_write_chunk(2, chunks[2].clone()).await.unwrap();
+
+  let chunk_checksums = uploads_manager.finalise_upload(&handle).await.unwrap();
+  assert_eq!(chunk_checksums, chunks.iter().map(|chunk| calc_chunk_checksum(chunk)).collect::<Vec<_>>());
+
+  let mut expected_contents = constants::ENCRYPTED_FILE_MAGIC_NUMBER.to_vec();
+  expected_contents.extend(chunks.concat());
+
+  let file_name = format!("{}{}", HANDLE, constants::TREASURY_FILE_EXTENSION);
+  let mut contents = Vec::new();
+  blob_store.read_range(&file_name, 0, expected_contents.len() as u64 + 1).await.unwrap().read_to_end(&mut contents).await.unwrap();
+  assert_eq!(contents, expected_contents);
+}
+
+#[tokio::test]
+async fn uploads_with_a_short_temporary_file_are_not_restored() {
+  let server = TestServer::start();
+  let user_id = server.create_user("alice", &[7; constants::AUTH_KEY_SIZE]).await;
+  let (config, chunks) = small_chunk_config(&server).await;
+  let blob_store = server.state.lock().await.uploads_manager.blob_store.clone();
+
+  let mut uploads_manager = UploadsManager::new(&config, blob_store.clone());
+  uploads_manager.new_upload(user_id, HANDLE, 3 * CHUNK_DATA_SIZE as u64).await.unwrap();
+  uploads_manager.get_active_upload(&HANDLE.to_string()).await.unwrap().try_write_chunk(0, chunks[0].clone()).await.unwrap();
+
+  let suspended_uploads = uploads_manager.suspend_uploads().await;
+
+  // Lose part of the written chunk
+  let upload_file_path = upload_file_path(&config);
+  let file_size = std::fs::metadata(&upload_file_path).unwrap().len();
+  OpenOptions::new().write(true).open(&upload_file_path).unwrap().set_len(file_size - 1).unwrap();
+
+  let mut uploads_manager = UploadsManager::new(&config, blob_store);
+  assert_eq!(uploads_manager.restore_uploads(suspended_uploads).await, 0);
+  assert!(!uploads_manager.is_handle_valid(&HANDLE.to_string()));
+  assert!(!upload_file_path.exists());
+}
@@ -0,0 +1,240 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use http::{Method, StatusCode};
+use openssl::{
+  bn::{BigNum, BigNumContext},
+  ec::{EcGroup, EcKey},
+  hash::MessageDigest,
+  nid::Nid,
+  pkey::{PKey, Private},
+  sha::sha256,
+  sign::Signer
+};
+use serde_cbor_2::Value as CborValue;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+
+use super::{TestClient, TestServer};
+use crate::{api::totp::build_totp, constants, util::get_unix_timestamp_secs};
+
+const RP_ID: &str = "localhost";
+const ORIGIN: &str = "http://localhost:3001";
+
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_USER_VERIFIED: u8 = 0x04;
+const FLAG_ATTESTED_CREDENTIAL_DATA: u8 = 0x40;
+
+/// A software authenticator with a single ES256 credential, answering challenges the same way a browser and a
+/// security key would.
+struct SoftAuthenticator {
+  credential_id: Vec<u8>,
+  key: PKey<Private>,
+  counter: u32
+}
+
+impl SoftAuthenticator {
+  fn new() -> Self {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+
+    Self {
+      credential_id: (0..32).collect(),
+      key: PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap(),
+      counter: 0
+    }
+  }
+
+  fn client_data_json(ceremony_type: &str, challenge: &Value) -> Vec<u8> {
+    json!({
+      "type": ceremony_type,
+      "challenge": challenge["publicKey"]["challenge"],
+      "origin": ORIGIN,
+      "crossOrigin": false
+    }).to_string().into_bytes()
+  }
+
+  fn authenticator_data(&mut self, flags: u8) -> Vec<u8> {
+    self.counter += 1;
+
+    let mut data = sha256(RP_ID.as_bytes()).to_vec();
+    data.push(flags);
+    data.extend_from_slice(&self.counter.to_be_bytes());
+    data
+  }
+
+  fn cose_public_key(&self) -> Vec<u8> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+    let (mut x, mut y) = (BigNum::new().unwrap(), BigNum::new().unwrap());
+    let mut context = BigNumContext::new().unwrap();
+
+    self.key.ec_key().unwrap().public_key()
+      .affine_coordinates(&group, &mut x, &mut y, &mut context)
+      .unwrap();
+
+    let entries = [
+      (1, CborValue::Integer(2)), // Key type: EC2
+      (3, CborValue::Integer(-7)), // Algorithm: ES256
+      (-1, CborValue::Integer(1)), // Curve: P-256
+      (-2, CborValue::Bytes(x.to_vec_padded(32).unwrap())),
+      (-3, CborValue::Bytes(y.to_vec_padded(32).unwrap()))
+    ];
+
+    let map = entries.into_iter().map(|(label, value)| (CborValue::Integer(label), value)).collect::<BTreeMap<_, _>>();
+    serde_cbor_2::to_vec(&CborValue::Map(map)).unwrap()
+  }
+
+  /// Answers the challenge from `/api/webauthn/register/start` with "none" attestation.
+  fn register(&mut self, challenge: &Value) -> Value {
+    let mut authenticator_data = self.authenticator_data(FLAG_USER_PRESENT | FLAG_USER_VERIFIED | FLAG_ATTESTED_CREDENTIAL_DATA);
+    authenticator_data.extend_from_slice(&[0; 16]); // AAGUID
+    authenticator_data.extend_from_slice(&(self.credential_id.len() as u16).to_be_bytes());
+    authenticator_data.extend_from_slice(&self.credential_id);
+    authenticator_data.extend_from_slice(&self.cose_public_key());
+
+    let attestation_object = CborValue::Map(BTreeMap::from([
+      (CborValue::Text("fmt".to_string()), CborValue::Text("none".to_string())),
+      (CborValue::Text("attStmt".to_string()), CborValue::Map(BTreeMap::new())),
+      (CborValue::Text("authData".to_string()), CborValue::Bytes(authenticator_data))
+    ]));
+
+    json!({
+      "id": URL_SAFE_NO_PAD.encode(&self.credential_id),
+      "rawId": URL_SAFE_NO_PAD.encode(&self.credential_id),
+      "type": "public-key",
+      "response": {
+        "attestationObject": URL_SAFE_NO_PAD.encode(serde_cbor_2::to_vec(&attestation_object).unwrap()),
+        "clientDataJSON": URL_SAFE_NO_PAD.encode(Self::client_data_json("webauthn.create", challenge))
+      }
+    })
+  }
+
+  /// Signs the challenge from `/api/login/webauthn/start`.
+  fn assert(&mut self, challenge: &Value) -> Value {
+    let authenticator_data = self.authenticator_data(FLAG_USER_PRESENT | FLAG_USER_VERIFIED);
+    let client_data_json = Self::client_data_json("webauthn.get", challenge);
+
+    let mut signer = Signer::new(MessageDigest::sha256(), &self.key).unwrap();
+    signer.update(&authenticator_data).unwrap();
+    signer.update(&sha256(&client_data_json)).unwrap();
+
+    json!({
+      "id": URL_SAFE_NO_PAD.encode(&self.credential_id),
+      "rawId": URL_SAFE_NO_PAD.encode(&self.credential_id),
+      "type": "public-key",
+      "response": {
+        "authenticatorData": URL_SAFE_NO_PAD.encode(authenticator_data),
+        "clientDataJSON": URL_SAFE_NO_PAD.encode(client_data_json),
+        "signature": URL_SAFE_NO_PAD.encode(signer.sign_to_vec().unwrap()),
+        "userHandle": null
+      }
+    })
+  }
+}
+
+async fn start_assertion(client: &mut TestClient) -> Value {
+  let (status, challenge) = client.request(Method::POST, "/api/login/webauthn/start", None).await;
+  assert_eq!(status, StatusCode::OK);
+
+  challenge
+}
+
+#[tokio::test]
+async fn passkey_is_required_to_log_in() {
+  let server = TestServer::start();
+  let auth_key = [7; constants::AUTH_KEY_SIZE];
+  let user_id = server.create_user("alice", &auth_key).await;
+
+  let mut authenticator = SoftAuthenticator::new();
+  let mut client = server.client();
+
+  // Without second factors the password is enough
+  assert_eq!(client.login("alice", &auth_key).await.0, StatusCode::OK);
+  assert!(client.is_logged_in().await);
+
+  // Register the authenticator
+  let (status, challenge) = client.request(Method::POST, "/api/webauthn/register/start", None).await;
+  assert_eq!(status, StatusCode::OK);
+
+  let credential = authenticator.register(&challenge);
+  let (status, _) = client.request(Method::POST, "/api/webauthn/register/finish", Some(credential)).await;
+  assert_eq!(status, StatusCode::OK);
+
+  // A registration challenge can only be answered once
+  let credential = authenticator.register(&challenge);
+  let (status, _) = client.request(Method::POST, "/api/webauthn/register/finish", Some(credential)).await;
+  assert_eq!(status, StatusCode::BAD_REQUEST);
+
+  // Also enable TOTP
+  let totp_secret = [3; constants::TOTP_SECRET_SIZE];
+  {
+    let mut app_state = server.state.lock().await;
+    let database = app_state.database.as_mut().unwrap();
+    database.set_pending_user_totp(user_id, &totp_secret).unwrap();
+    database.enable_user_totp(user_id, 0, &[]).unwrap();
+  }
+
+  // The password step now asks for both factors
+  let mut client = server.client();
+  let (status, response) = client.login("alice", &auth_key).await;
+  assert_eq!(status, StatusCode::OK);
+  assert_eq!(response["secondFactors"], json!(["totp", "webauthn"]));
+  assert!(!client.is_logged_in().await);
+
+  // TOTP alone doesn't log the session in
+  let code = build_totp(&totp_secret, "alice").unwrap().generate(get_unix_timestamp_secs()).to_string();
+  let (status, response) = client.request(Method::POST, "/api/login/totp", Some(json!({ "code": code }))).await;
+  assert_eq!(status, StatusCode::OK);
+  assert_eq!(response["secondFactors"], json!(["webauthn"]));
+  assert!(!client.is_logged_in().await);
+
+  // TOTP can't be passed twice
+  let (status, _) = client.request(Method::POST, "/api/login/totp", Some(json!({ "code": code }))).await;
+  assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+  // An assertion with an invalid signature is rejected
+  let challenge = start_assertion(&mut client).await;
+  let mut assertion = authenticator.assert(&challenge);
+  let mut signature = URL_SAFE_NO_PAD.decode(assertion["response"]["signature"].as_str().unwrap()).unwrap();
+  let last_byte = signature.len() - 1;
+  signature[last_byte] ^= 0xff;
+  assertion["response"]["signature"] = json!(URL_SAFE_NO_PAD.encode(signature));
+
+  let (status, _) = client.request(Method::POST, "/api/login/webauthn/finish", Some(assertion)).await;
+  assert_eq!(status, StatusCode::UNAUTHORIZED);
+  assert!(!client.is_logged_in().await);
+
+  // The failed challenge can't be retried
+  let assertion = authenticator.assert(&challenge);
+  let (status, _) = client.request(Method::POST, "/api/login/webauthn/finish", Some(assertion)).await;
+  assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+  // A valid assertion for a new challenge finishes the login
+  let challenge = start_assertion(&mut client).await;
+  let assertion = authenticator.assert(&challenge);
+  let (status, response) = client.request(Method::POST, "/api/login/webauthn/finish", Some(assertion)).await;
+  assert_eq!(status, StatusCode::OK);
+  assert!(response["encryptedMasterKey"].is_string());
+  assert!(client.is_logged_in().await);
+}
+
+#[tokio::test]
+async fn totp_is_refused_for_passkey_only_user() {
+  let server = TestServer::start();
+  let auth_key = [9; constants::AUTH_KEY_SIZE];
+  server.create_user("bob", &auth_key).await;
+
+  let mut authenticator = SoftAuthenticator::new();
+  let mut client = server.client();
+  client.login("bob", &auth_key).await;
+
+  let (_, challenge) = client.request(Method::POST, "/api/webauthn/register/start", None).await;
+  let credential = authenticator.register(&challenge);
+  assert_eq!(client.request(Method::POST, "/api/webauthn/register/finish", Some(credential)).await.0, StatusCode::OK);
+
+  let mut client = server.client();
+  let (_, response) = client.login("bob", &auth_key).await;
+  assert_eq!(response["secondFactors"], json!(["webauthn"]));
+
+  // Backup codes and TOTP codes don't stand in for the passkey
+  let (status, _) = client.request(Method::POST, "/api/login/totp", Some(json!({ "backupCode": "abcde-fghij" }))).await;
+  assert_eq!(status, StatusCode::UNAUTHORIZED);
+  assert!(!client.is_logged_in().await);
+}
@@ -0,0 +1,132 @@
+//! Serving HTTPS without a reverse proxy. The certificate is reloaded whenever its files change or the process
+//! receives SIGHUP, so renewed certificates are picked up without restarting the server.
+
+use axum::{extract::Host, http::Uri, response::Redirect, Router};
+use axum_server::{tls_rustls::RustlsConfig, Handle};
+use notify::{EventKind, RecursiveMode, Watcher};
+use std::error::Error;
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use log::{error, info};
+
+use crate::constants;
+
+/// Reads the PEM certificate chain and private key.
+pub async fn load_tls_config(cert_path: &str, key_path: &str) -> Result<RustlsConfig, Box<dyn Error>> {
+  RustlsConfig::from_pem_file(cert_path, key_path)
+    .await
+    .map_err(|err| format!("Failed to load the TLS certificate {}: {}", cert_path, err).into())
+}
+
+/// Reloads the certificate when its files change or on SIGHUP. A certificate that fails to load is logged and the
+/// previous one keeps being served.
+pub fn start_certificate_reloader(rustls_config: RustlsConfig, cert_path: &str, key_path: &str) -> Result<(), Box<dyn Error>> {
+  let cert_path = PathBuf::from(cert_path);
+  let key_path = PathBuf::from(key_path);
+  let (reload_tx, mut reload_rx) = mpsc::unbounded_channel::<()>();
+
+  // Certificate tools such as certbot replace the files instead of writing to them, so the directories are watched
+  let watched_file_names: Vec<OsString> = [&cert_path, &key_path].iter()
+    .filter_map(|path| path.file_name())
+    .map(OsStr::to_os_string)
+    .collect();
+
+  let watch_tx = reload_tx.clone();
+
+  let mut watcher = notify::recommended_watcher(move |result: notify::Result<notify::Event>| {
+    let Ok(event) = result else { return };
+
+    // Reading the certificate causes access events, which must not cause another reload
+    let is_change = matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_));
+
+    if is_change && event.paths.iter().any(|path| path.file_name().is_some_and(|name| watched_file_names.iter().any(|watched| watched == name))) {
+      let _ = watch_tx.send(());
+    }
+  })?;
+
+  watcher.watch(parent_directory(&cert_path), RecursiveMode::NonRecursive)?;
+
+  if parent_directory(&key_path) != parent_directory(&cert_path) {
+    watcher.watch(parent_directory(&key_path), RecursiveMode::NonRecursive)?;
+  }
+
+  #[cfg(unix)]
+  tokio::spawn(async move {
+    let mut hangup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+      Ok(hangup) => hangup,
+      Err(err) => return error!("Failed to install SIGHUP handler: {}", err)
+    };
+
+    while hangup.recv().await.is_some() {
+      info!("Received SIGHUP. Reloading the TLS certificate...");
+
+      if reload_tx.send(()).is_err() {
+        break;
+      }
+    }
+  });
+
+  tokio::spawn(async move {
+    // The watcher stops when it's dropped
+    let _watcher = watcher;
+
+    while reload_rx.recv().await.is_some() {
+      // A renewal changes several files in a row, so wait for it to finish and reload once
+      tokio::time::sleep(Duration::from_millis(constants::TLS_RELOAD_DEBOUNCE_MS)).await;
+      while reload_rx.try_recv().is_ok() {}
+
+      match rustls_config.reload_from_pem_file(&cert_path, &key_path).await {
+        Ok(_) => info!("Reloaded the TLS certificate."),
+        Err(err) => error!("Failed to reload the TLS certificate, still using the previous one: {}", err)
+      };
+    }
+  });
+
+  Ok(())
+}
+
+/// Listens for plain HTTP on `redirect_port` and redirects every request to the same url on HTTPS.
+pub fn start_http_redirect_server(ip_address: &str, redirect_port: u16, https_port: u16, handle: Handle) -> Result<(), Box<dyn Error>> {
+  let listener = std::net::TcpListener::bind(format!("{}:{}", ip_address, redirect_port))?;
+  listener.set_nonblocking(true)?;
+
+  let router = Router::new().fallback(move |Host(host): Host, uri: Uri| async move {
+    Redirect::permanent(&get_https_url(&host, https_port, &uri))
+  });
+
+  info!("Redirecting HTTP on {}:{} to HTTPS", ip_address, redirect_port);
+
+  tokio::spawn(async move {
+    if let Err(err) = axum_server::from_tcp(listener).handle(handle).serve(router.into_make_service()).await {
+      error!("HTTP redirect server error: {}", err);
+    }
+  });
+
+  Ok(())
+}
+
+/// The url on HTTPS for a request that was made to `host` on plain HTTP. The port is left out when it's the default.
+fn get_https_url(host: &str, https_port: u16, uri: &Uri) -> String {
+  // Remove the HTTP port from the host, keeping IPv6 addresses such as "[::1]" intact
+  let host_name = match host.rsplit_once(':') {
+    Some((host_name, port)) if !port.contains(']') => host_name,
+    _ => host
+  };
+
+  let path_and_query = uri.path_and_query().map(|path_and_query| path_and_query.as_str()).unwrap_or("/");
+
+  if https_port == 443 {
+    format!("https://{}{}", host_name, path_and_query)
+  } else {
+    format!("https://{}:{}{}", host_name, https_port, path_and_query)
+  }
+}
+
+fn parent_directory(path: &Path) -> &Path {
+  match path.parent() {
+    Some(parent) if !parent.as_os_str().is_empty() => parent,
+    _ => Path::new(".")
+  }
+}
@@ -1,4 +1,5 @@
 use std::error::Error;
+use std::time::{SystemTime, UNIX_EPOCH};
 use regex::Regex;
 use nanoid::nanoid;
 
@@ -16,15 +17,33 @@ pub fn generate_claim_code() -> String {
   )
 }
 
+pub fn generate_backup_code() -> String {
+  let section_length = 5;
+
+  format!(
+    "{}-{}",
+    nanoid!(section_length, &constants::LOWER_CASE_ALPHANUMERIC_CHARS),
+    nanoid!(section_length, &constants::LOWER_CASE_ALPHANUMERIC_CHARS)
+  )
+}
+
 pub fn generate_file_handle() -> String {
   let length = constants::FILE_HANDLE_LENGTH;
   nanoid!(length, &constants::ALPHANUMERIC_CHARS)
 }
 
+/// Returns the current unix timestamp in seconds.
+pub fn get_unix_timestamp_secs() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|duration| duration.as_secs())
+    .unwrap_or(0)
+}
+
 // TODO: handle possible integer overflow!
 pub fn parse_byte_size_str(mut input: String) -> Result<u64, Box<dyn Error + Send + Sync>> {
   // 'b' must be last because all units share 'b' as the last character.
-  let unit_multipliers = vec!["kb", "mb", "gb", "tb", "pb", "b"];
+  let unit_multipliers = ["kb", "mb", "gb", "tb", "pb", "b"];
 
   input = input.replace(" ", ""); // Remove any spaces
   input = input.to_lowercase(); // Make operation case insensitive by making it all lowercase
@@ -34,9 +53,7 @@ pub fn parse_byte_size_str(mut input: String) -> Result<u64, Box<dyn Error + Sen
   let mut chosen_unit = "";
   let mut exponent: i64 = 0;
   
-  for i in 0..unit_multipliers.len() {
-    let unit = unit_multipliers[i];
-
+  for (i, unit) in unit_multipliers.iter().enumerate() {
     if input.ends_with(unit) {
       found_valid_unit = true;
       chosen_unit = unit;
@@ -67,8 +84,8 @@ pub fn parse_byte_size_str(mut input: String) -> Result<u64, Box<dyn Error + Sen
   if let Ok(number_part) = number_part_str.parse::<f64>() {
     let result_as_float = number_part * 1000f64.powf(exponent as f64);
     
-    return Ok(result_as_float as u64);
+    Ok(result_as_float as u64)
   } else {
-    return Err("Invalid number provided.".into());
+    Err("Invalid number provided.".into())
   }
 }
@@ -35,7 +35,7 @@ const CONSTANTS = {
   MAX_SIGNED_32_BIT_INTEGER: 2147483647,
 
   ENCRYPTED_FILE_HEADER_SIZE: 4, // Consists of: Magic number (4B)
-  CHUNK_DATA_SIZE: 2 * 1024 * 1024, // In bytes
+  CHUNK_DATA_SIZE: 2 * 1024 * 1024, // In bytes. Replaced by the server's value from /api/serverinfo
   CHUNK_EXTRA_DATA_SIZE: 0, // Calculated below...
   CHUNK_FULL_SIZE: 0, // Calculated below...
 
@@ -49,7 +49,7 @@ const CONSTANTS = {
   MAX_USER_SETTINGS_ENCRYPTED_BLOB_SIZE: 8 * 1024, // 8 KiB (should be plenty because user settings are encrypted compressed jsons and there aren't that many user settings.)
 
   // Related to transfers
-  MAX_UPLOAD_CONCURRENT_CHUNKS: 4, // Maximum number of chunks that can be uploaded to the server concurrently.
+  MAX_UPLOAD_CONCURRENT_CHUNKS: 4, // Maximum number of chunks that can be uploaded to the server concurrently. Replaced by the server's value from /api/serverinfo
   MAX_DOWNLOAD_CONCURRENT_CHUNKS: 5, // Maximum number of chunks that can be downloaded concurrently for each file transfer.
   TARGET_CONCURRENT_UPLOADS_COUNT: 4, // How many concurrent uploads the client will try to perform if possible when uploading files to the server
   CONCURRENT_CHUNK_TRANSFER_SPEED_INCREMENT: 5000000, // Bytes per second speed required to add another concurrent chunk (TODO: explain better)
@@ -0,0 +1,28 @@
+import CONSTANTS from "./constants";
+
+/**
+ * Fetches the server's transfer limits from /api/serverinfo and applies them to CONSTANTS.
+ * The chunk size and the upload concurrency are configurable on the server, so this must be done before any file is
+ * uploaded or downloaded.
+ */
+async function loadServerInfo(): Promise<void> {
+  const response = await fetch("/api/serverinfo");
+
+  if (!response.ok) {
+    throw new Error(`/api/serverinfo responded with status ${response.status}`);
+  }
+
+  const serverInfoJson = await response.json();
+
+  if (serverInfoJson.chunkDataSize == undefined || serverInfoJson.maxUploadConcurrentChunks == undefined) {
+    throw new Error(`Failed to get transfer limits from server info json!`);
+  }
+
+  CONSTANTS.CHUNK_DATA_SIZE = serverInfoJson.chunkDataSize;
+  CONSTANTS.CHUNK_FULL_SIZE = CONSTANTS.CHUNK_DATA_SIZE + CONSTANTS.CHUNK_EXTRA_DATA_SIZE;
+  CONSTANTS.MAX_UPLOAD_CONCURRENT_CHUNKS = serverInfoJson.maxUploadConcurrentChunks;
+}
+
+export {
+  loadServerInfo
+}
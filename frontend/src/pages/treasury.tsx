@@ -6,6 +6,7 @@ import { UploadFileRequest } from "../components/uploadFilesPopup";
 import { TransferListMenuEntry, TransfersMenuEntryContext } from "../components/transferMenuEntry";
 import { clearLocalStorageAuthenticationData, getLocalStorageUserCryptoInfo } from "../client/localStorage";
 import { UserFilesystem } from "../client/userFilesystem";
+import { loadServerInfo } from "../client/serverInfo";
 import { showSaveFilePicker } from "native-file-system-adapter";
 import { getDefaultUserSettings, getTimeOffsetInMinutesFromTimezoneName, UserSettings } from "../client/userSettings";
 import { Vector2D } from "../client/clientEnumsAndTypes";
@@ -379,6 +380,9 @@ function TreasuryPage() {
     
     // Load all user data
     try {
+      // Get the server's transfer limits before anything is transferred
+      await loadServerInfo();
+
       // Get session info
       const sessionInfo = await fetch("/api/sessiondata");
 